@@ -3,6 +3,7 @@ import { AlertCircle, Calendar, FileText, Loader2, Upload, X } from 'lucide-reac
 import React, { useState } from 'react'
 import api from '../services/api'
 import { createTimelineEntryWithWallet } from '../services/transaction'
+import { CONVERSION_STAGE_NAMES } from '../utils/constants'
 import Alert from './Alert'
 import LoadingSpinner from './LoadingSpinner'
 
@@ -34,6 +35,7 @@ export default function CreateClaimModal({
   const [loading, setLoading] = useState(false)
   const [error, setError] = useState(null)
   const [uploadProgress, setUploadProgress] = useState(0)
+  const [progressMessage, setProgressMessage] = useState('Uploading...')
 
   const entryTypes = [
     { value: '0', label: 'Visit Summary' },
@@ -133,14 +135,19 @@ export default function CreateClaimModal({
 
             setUploadProgress(30)
 
-            // Send to process_data endpoint
-            console.log('Processing file content through process_data endpoint...', {
+            // Send to process_data_stream endpoint, showing each conversion stage
+            console.log('Processing file content through process_data_stream endpoint...', {
               fileName: formData.file.name,
               sourceFormat,
               size: rawData.length
             })
 
-            const processedData = await api.processData(rawData, sourceFormat, true)
+            const processedData = await api.processDataStream(rawData, sourceFormat, true, (event) => {
+              const stage = CONVERSION_STAGE_NAMES[event.stage] || event.stage
+              const attempt = event.attempt > 1 ? ` (attempt ${event.attempt})` : ''
+              const part = event.parts ? ` (part ${event.part} of ${event.parts})` : ''
+              setProgressMessage(`${stage}${attempt}${part}...`)
+            })
             console.log('File processed successfully:', processedData)
           } catch (processError) {
             // Log error but don't fail - processing is optional
@@ -148,6 +155,7 @@ export default function CreateClaimModal({
           }
 
           // Calculate content hash (SHA-256) from file content
+          setProgressMessage('Uploading...')
           setUploadProgress(50)
           const hashBuffer = await crypto.subtle.digest('SHA-256', fileBytes)
           const hashArray = Array.from(new Uint8Array(hashBuffer))
@@ -222,6 +230,7 @@ export default function CreateClaimModal({
     } finally {
       setLoading(false)
       setUploadProgress(0)
+      setProgressMessage('Uploading...')
     }
   }
 
@@ -444,7 +453,7 @@ export default function CreateClaimModal({
           {loading && uploadProgress > 0 && (
             <div>
               <div className="flex items-center justify-between mb-2">
-                <span className="text-sm text-text-muted">Uploading...</span>
+                <span className="text-sm text-text-muted">{progressMessage}</span>
                 <span className="text-sm text-text-muted">{uploadProgress}%</span>
               </div>
               <div className="w-full bg-gray-200 dark:bg-gray-700 rounded-full h-2">
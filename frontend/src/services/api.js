@@ -1,4 +1,4 @@
-import { API_BASE_URL } from '../utils/constants';
+import { API_BASE_URL, ENCLAVE_API_URL } from '../utils/constants';
 
 /**
  * API Client for Medical Vault Backend
@@ -193,7 +193,7 @@ class ApiClient {
    * @returns {Promise<Object>} Processed data result
    */
   async processData(rawData, sourceFormat = 'text', includePhi = true, processDataUrl = null) {
-    const url = processDataUrl || 'http://3.0.207.181:3000/process_data';
+    const url = processDataUrl || `${ENCLAVE_API_URL}/process_data`;
     
     try {
       const response = await fetch(url, {
@@ -223,6 +223,72 @@ class ApiClient {
     }
   }
 
+  /**
+   * Process file content through the streaming endpoint, reporting each conversion stage
+   * @param {string} rawData - File content as string
+   * @param {string} sourceFormat - Source format (e.g., 'text', 'hl7v2', 'ccda')
+   * @param {boolean} includePhi - Whether to include PHI (Protected Health Information)
+   * @param {Function} onProgress - Called with each progress event, e.g. { stage: 'calling_model', attempt: 1 }
+   * @param {string} processDataStreamUrl - Optional custom URL for process_data_stream endpoint
+   * @returns {Promise<Object>} Processed data result, as returned by processData
+   */
+  async processDataStream(rawData, sourceFormat = 'text', includePhi = true, onProgress = () => {}, processDataStreamUrl = null) {
+    const url = processDataStreamUrl || `${ENCLAVE_API_URL}/process_data_stream`;
+
+    try {
+      const response = await fetch(url, {
+        method: 'POST',
+        headers: {
+          'Content-Type': 'application/json',
+          Accept: 'text/event-stream',
+        },
+        body: JSON.stringify({
+          raw_data: rawData,
+          source_format: sourceFormat,
+          include_phi: includePhi,
+        }),
+        signal: AbortSignal.timeout(180000), // 3 minute timeout, progress keeps the user informed
+      });
+
+      if (!response.ok) {
+        const error = await response
+          .json()
+          .catch(() => ({ message: "Process data failed" }));
+        throw new Error(error.error || error.message || "Process data failed");
+      }
+
+      // Server-Sent Events: "event: <name>" and "data: <json>" lines, separated by blank lines
+      const reader = response.body.getReader();
+      const decoder = new TextDecoder();
+      let buffer = '';
+      for (;;) {
+        const { done, value } = await reader.read();
+        if (done) break;
+        buffer += decoder.decode(value, { stream: true });
+        let boundary;
+        while ((boundary = buffer.indexOf('\n\n')) !== -1) {
+          const block = buffer.slice(0, boundary);
+          buffer = buffer.slice(boundary + 2);
+          let event = 'message';
+          let data = '';
+          for (const line of block.split('\n')) {
+            if (line.startsWith('event:')) event = line.slice(6).trim();
+            else if (line.startsWith('data:')) data += line.slice(5).trim();
+          }
+          if (!data) continue; // keep-alive comment
+          const payload = JSON.parse(data);
+          if (event === 'progress') onProgress(payload);
+          else if (event === 'result') return payload;
+          else if (event === 'error') throw new Error(payload.error || "Process data failed");
+        }
+      }
+      throw new Error("Process data stream ended without a result");
+    } catch (error) {
+      console.error("Process Data Stream Error:", error);
+      throw error;
+    }
+  }
+
   /**
    * Download and decrypt file
    */
@@ -1,5 +1,7 @@
 // API Configuration
 export const API_BASE_URL = import.meta.env.VITE_API_BASE_URL || 'http://localhost:3000';
+// Nautilus enclave serving the FHIR conversion endpoints
+export const ENCLAVE_API_URL = import.meta.env.VITE_ENCLAVE_API_URL || 'http://localhost:3000';
 
 // Sui Network Configuration
 export const SUI_NETWORK = import.meta.env.VITE_SUI_NETWORK || 'testnet';
@@ -37,6 +39,17 @@ export const ROLE_NAMES = {
   255: 'No Access',
 };
 
+// Stages reported by /process_data_stream while a record is converted to FHIR
+export const CONVERSION_STAGE_NAMES = {
+  validating_input: 'Validating input',
+  converting: 'Converting',
+  calling_model: 'Calling the model',
+  repairing_json: 'Repairing the model output',
+  validating_profile: 'Validating the FHIR profile',
+  deidentifying: 'De-identifying',
+  hashing: 'Hashing',
+};
+
 // Document Types (theo backend API)
 export const DOC_TYPES = {
   LAB: 0,
@@ -0,0 +1,37 @@
+import { sha3_256 } from '@noble/hashes/sha3';
+import { bytesToHex } from '@noble/hashes/utils';
+
+/**
+ * Canonicalize a JSON value following RFC 8785 (JCS). JSON.stringify already serializes strings
+ * and numbers as the RFC requires, so only object members need to be sorted by their UTF-16 code
+ * units, which is the default order of Array.prototype.sort on strings.
+ * @param {*} value - Parsed JSON value
+ * @returns {string} Canonical JSON text
+ */
+export function canonicalize(value) {
+  if (value === null || typeof value !== 'object') {
+    if (typeof value === 'number' && !Number.isFinite(value)) {
+      throw new Error('NaN and Infinity are not valid JSON numbers');
+    }
+    return JSON.stringify(value);
+  }
+  if (Array.isArray(value)) {
+    return `[${value.map(canonicalize).join(',')}]`;
+  }
+  const members = Object.keys(value)
+    .filter((key) => value[key] !== undefined)
+    .sort()
+    .map((key) => `${JSON.stringify(key)}:${canonicalize(value[key])}`);
+  return `{${members.join(',')}}`;
+}
+
+/**
+ * Compute the semantic hash of a FHIR bundle as the enclave does: hex encoded SHA3-256 of its
+ * RFC 8785 canonical form.
+ * @param {object} bundle - FHIR bundle, bare or in the `{ bundle: {...} }` envelope returned by
+ *   /process_data
+ * @returns {string} Hex encoded semantic hash
+ */
+export function computeSemanticHash(bundle) {
+  return bytesToHex(sha3_256(new TextEncoder().encode(canonicalize(bundle))));
+}
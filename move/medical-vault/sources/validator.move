@@ -0,0 +1,674 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// On-chain verification of FHIR conversion, bundle validation, bundle verification, claim
+// validation, predicate results and usage receipts signed by the medical-vault-insurer enclave. The
+// payload structs must keep the same BCS layout as the Rust structs in
+// `src/nautilus-server/src/apps/medical-vault-insurer/`: `FhirConversionAttestation` in `mod.rs`,
+// `PredicateAttestation` in `predicate.rs`, `UsageReceipt` in `usage.rs` and the structs of
+// `validation.rs`.
+
+module medical_vault::validator;
+
+use enclave::enclave::{Self, Enclave};
+use std::hash::sha3_256;
+use std::string::String;
+use sui::event;
+
+const EInvalidSignature: u64 = 0;
+const ENotValidated: u64 = 1;
+const EInvalidProof: u64 = 2;
+
+// Intent scopes, see `IntentScope` in the enclave.
+const VALIDATE_BUNDLE_INTENT: u8 = 100;
+const VERIFY_BUNDLE_INTENT: u8 = 101;
+const VALIDATE_CLAIM_INTENT: u8 = 102;
+const FHIR_CONVERSION_INTENT: u8 = 103;
+const BATCH_ROOT_INTENT: u8 = 104;
+const PREDICATE_ATTESTATION_INTENT: u8 = 105;
+const USAGE_RECEIPT_INTENT: u8 = 106;
+
+// Signed by the enclave after validating a FHIR bundle stored on Walrus.
+public struct BundleValidation has copy, drop {
+    walrus_blob_id: String,
+    semantic_hash: vector<u8>,
+    patient_id: String,
+    resource_count: u64,
+    resource_types: vector<String>,
+    validated: bool,
+    validated_at: u64,
+}
+
+// Signed by the enclave after re-hashing a FHIR bundle stored on Walrus.
+public struct BundleVerification has copy, drop {
+    walrus_blob_id: String,
+    semantic_hash: vector<u8>,
+    verified: bool,
+    verified_at: u64,
+}
+
+// Signed by the enclave after checking the codes of an insurance claim against a bundle.
+public struct ClaimValidation has copy, drop {
+    claim_id: String,
+    policy_id: String,
+    semantic_hash: vector<u8>,
+    validated: bool,
+    unsupported_codes: vector<String>,
+    validated_at: u64,
+}
+
+// Signed by the enclave with each FHIR conversion. Move has no floating point, so the temperature is
+// its decimal string, and `model`, `prompt_hash` and `temperature` are empty without the LLM.
+// `warnings_hash` is the SHA3-256 of the RFC 8785 canonical JSON of the conversion warnings, empty
+// without any.
+public struct FhirConversion has copy, drop {
+    semantic_hash: vector<u8>,
+    resource_root: vector<u8>,
+    resources_created: vector<String>,
+    converter: String,
+    model: String,
+    prompt_hash: vector<u8>,
+    temperature: String,
+    input_hash: vector<u8>,
+    warnings_hash: vector<u8>,
+}
+
+// Signed by the enclave for a batch of FHIR conversions: the Merkle root over the BCS bytes of the
+// `FhirConversion` intent message of every conversion, signed at the timestamp of the root.
+public struct BatchRoot has copy, drop {
+    root: vector<u8>,
+    leaf_count: u64,
+}
+
+// Signed by the enclave with the yes/no answer to a predicate over a bundle. `predicate_hash` is the
+// SHA3-256 of the RFC 8785 canonical JSON of the predicate.
+public struct PredicateAttestation has copy, drop {
+    semantic_hash: vector<u8>,
+    resource_root: vector<u8>,
+    predicate_hash: vector<u8>,
+    result: bool,
+}
+
+// Signed by the enclave with each FHIR conversion: the LLM usage it is billed for and who it is
+// charged to. `cost_micro_usd` is in millionths of a US dollar.
+public struct UsageReceipt has copy, drop {
+    caller_id: String,
+    app: String,
+    semantic_hash: vector<u8>,
+    input_hash: vector<u8>,
+    model: String,
+    llm_calls: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost_micro_usd: u64,
+}
+
+public struct FhirConversionRecorded has copy, drop {
+    semantic_hash: vector<u8>,
+    resource_root: vector<u8>,
+    converter: String,
+    model: String,
+    prompt_hash: vector<u8>,
+    input_hash: vector<u8>,
+    warnings_hash: vector<u8>,
+    converted_at: u64,
+}
+
+public struct BundleValidated has copy, drop {
+    walrus_blob_id: String,
+    semantic_hash: vector<u8>,
+    patient_id: String,
+    resource_count: u64,
+    validated_at: u64,
+}
+
+public struct BundleVerified has copy, drop {
+    walrus_blob_id: String,
+    semantic_hash: vector<u8>,
+    verified_at: u64,
+}
+
+public struct ClaimValidated has copy, drop {
+    claim_id: String,
+    policy_id: String,
+    semantic_hash: vector<u8>,
+    validated_at: u64,
+}
+
+public struct PredicateAttested has copy, drop {
+    semantic_hash: vector<u8>,
+    resource_root: vector<u8>,
+    predicate_hash: vector<u8>,
+    result: bool,
+    attested_at: u64,
+}
+
+public struct UsageCharged has copy, drop {
+    caller_id: String,
+    app: String,
+    semantic_hash: vector<u8>,
+    llm_calls: u64,
+    total_tokens: u64,
+    cost_micro_usd: u64,
+    charged_at: u64,
+}
+
+/// Semantic hash of a bundle from its RFC 8785 (JCS) canonical bytes, as computed by the enclave.
+/// Lets a contract check that bytes supplied by a caller are the bundle an attestation refers to.
+public fun semantic_hash(canonical_bundle: vector<u8>): vector<u8> {
+    sha3_256(canonical_bundle)
+}
+
+/// Check that a resource, given as its RFC 8785 canonical bytes, is included under the
+/// `resource_root` signed with a FHIR conversion.
+public fun verify_resource_inclusion(
+    resource_root: vector<u8>,
+    canonical_resource: vector<u8>,
+    leaf_index: u64,
+    leaf_count: u64,
+    siblings: vector<vector<u8>>,
+): bool {
+    verify_inclusion(resource_root, canonical_resource, leaf_index, leaf_count, siblings)
+}
+
+/// Check that `data` is the leaf at `leaf_index` of the Merkle tree with `root`. Leaves are
+/// `sha3_256(0x00 || data)` and nodes `sha3_256(0x01 || left || right)`. The last node of an
+/// odd-sized level is promoted unchanged.
+public fun verify_inclusion(
+    root: vector<u8>,
+    data: vector<u8>,
+    leaf_index: u64,
+    leaf_count: u64,
+    siblings: vector<vector<u8>>,
+): bool {
+    if (leaf_index >= leaf_count) return false;
+
+    let mut node = vector[0u8];
+    node.append(data);
+    node = sha3_256(node);
+
+    let mut idx = leaf_index;
+    let mut width = leaf_count;
+    let mut used = 0;
+    while (width > 1) {
+        if (idx % 2 == 1 || idx + 1 < width) {
+            assert!(used < siblings.length(), EInvalidProof);
+            let sibling = siblings[used];
+            used = used + 1;
+            let mut preimage = vector[1u8];
+            if (idx % 2 == 1) {
+                preimage.append(sibling);
+                preimage.append(node);
+            } else {
+                preimage.append(node);
+                preimage.append(sibling);
+            };
+            node = sha3_256(preimage);
+        };
+        idx = idx / 2;
+        width = (width + 1) / 2;
+    };
+
+    used == siblings.length() && node == root
+}
+
+/// Verify a FHIR conversion signed by the enclave and emit `FhirConversionRecorded`.
+public fun record_fhir_conversion<T>(
+    enclave: &Enclave<T>,
+    semantic_hash: vector<u8>,
+    resource_root: vector<u8>,
+    resources_created: vector<String>,
+    converter: String,
+    model: String,
+    prompt_hash: vector<u8>,
+    temperature: String,
+    input_hash: vector<u8>,
+    warnings_hash: vector<u8>,
+    timestamp_ms: u64,
+    signature: &vector<u8>,
+) {
+    let payload = FhirConversion {
+        semantic_hash,
+        resource_root,
+        resources_created,
+        converter,
+        model,
+        prompt_hash,
+        temperature,
+        input_hash,
+        warnings_hash,
+    };
+    assert!(
+        enclave.verify_signature(FHIR_CONVERSION_INTENT, timestamp_ms, payload, signature),
+        EInvalidSignature,
+    );
+    emit_fhir_conversion(payload, timestamp_ms);
+}
+
+/// Verify a FHIR conversion of a batch signed by the enclave, through the signature on the batch
+/// root and the inclusion proof of the conversion under it, and emit `FhirConversionRecorded`.
+public fun record_batch_fhir_conversion<T>(
+    enclave: &Enclave<T>,
+    batch_root: vector<u8>,
+    leaf_count: u64,
+    timestamp_ms: u64,
+    signature: &vector<u8>,
+    semantic_hash: vector<u8>,
+    resource_root: vector<u8>,
+    resources_created: vector<String>,
+    converter: String,
+    model: String,
+    prompt_hash: vector<u8>,
+    temperature: String,
+    input_hash: vector<u8>,
+    warnings_hash: vector<u8>,
+    leaf_index: u64,
+    siblings: vector<vector<u8>>,
+) {
+    let root = BatchRoot { root: batch_root, leaf_count };
+    assert!(
+        enclave.verify_signature(BATCH_ROOT_INTENT, timestamp_ms, root, signature),
+        EInvalidSignature,
+    );
+    let payload = FhirConversion {
+        semantic_hash,
+        resource_root,
+        resources_created,
+        converter,
+        model,
+        prompt_hash,
+        temperature,
+        input_hash,
+        warnings_hash,
+    };
+    let leaf = std::bcs::to_bytes(
+        &enclave::create_intent_message(FHIR_CONVERSION_INTENT, timestamp_ms, payload),
+    );
+    assert!(verify_inclusion(root.root, leaf, leaf_index, leaf_count, siblings), EInvalidProof);
+    emit_fhir_conversion(payload, timestamp_ms);
+}
+
+fun emit_fhir_conversion(payload: FhirConversion, timestamp_ms: u64) {
+    event::emit(FhirConversionRecorded {
+        semantic_hash: payload.semantic_hash,
+        resource_root: payload.resource_root,
+        converter: payload.converter,
+        model: payload.model,
+        prompt_hash: payload.prompt_hash,
+        input_hash: payload.input_hash,
+        warnings_hash: payload.warnings_hash,
+        converted_at: timestamp_ms,
+    });
+}
+
+/// Verify a successful bundle validation signed by the enclave and emit `BundleValidated`.
+public fun record_bundle_validation<T>(
+    enclave: &Enclave<T>,
+    walrus_blob_id: String,
+    semantic_hash: vector<u8>,
+    patient_id: String,
+    resource_count: u64,
+    resource_types: vector<String>,
+    timestamp_ms: u64,
+    signature: &vector<u8>,
+) {
+    let payload = BundleValidation {
+        walrus_blob_id,
+        semantic_hash,
+        patient_id,
+        resource_count,
+        resource_types,
+        validated: true,
+        validated_at: timestamp_ms,
+    };
+    assert!(
+        enclave.verify_signature(VALIDATE_BUNDLE_INTENT, timestamp_ms, payload, signature),
+        EInvalidSignature,
+    );
+    event::emit(BundleValidated {
+        walrus_blob_id: payload.walrus_blob_id,
+        semantic_hash: payload.semantic_hash,
+        patient_id: payload.patient_id,
+        resource_count,
+        validated_at: timestamp_ms,
+    });
+}
+
+/// Verify a successful bundle verification signed by the enclave and emit `BundleVerified`.
+public fun record_bundle_verification<T>(
+    enclave: &Enclave<T>,
+    walrus_blob_id: String,
+    semantic_hash: vector<u8>,
+    timestamp_ms: u64,
+    signature: &vector<u8>,
+) {
+    let payload = BundleVerification {
+        walrus_blob_id,
+        semantic_hash,
+        verified: true,
+        verified_at: timestamp_ms,
+    };
+    assert!(
+        enclave.verify_signature(VERIFY_BUNDLE_INTENT, timestamp_ms, payload, signature),
+        EInvalidSignature,
+    );
+    event::emit(BundleVerified {
+        walrus_blob_id: payload.walrus_blob_id,
+        semantic_hash: payload.semantic_hash,
+        verified_at: timestamp_ms,
+    });
+}
+
+/// Verify a claim validation signed by the enclave and emit `ClaimValidated`. Aborts unless every
+/// code of the claim was supported by the bundle.
+public fun record_claim_validation<T>(
+    enclave: &Enclave<T>,
+    claim_id: String,
+    policy_id: String,
+    semantic_hash: vector<u8>,
+    unsupported_codes: vector<String>,
+    timestamp_ms: u64,
+    signature: &vector<u8>,
+) {
+    assert!(unsupported_codes.is_empty(), ENotValidated);
+    let payload = ClaimValidation {
+        claim_id,
+        policy_id,
+        semantic_hash,
+        validated: true,
+        unsupported_codes,
+        validated_at: timestamp_ms,
+    };
+    assert!(
+        enclave.verify_signature(VALIDATE_CLAIM_INTENT, timestamp_ms, payload, signature),
+        EInvalidSignature,
+    );
+    event::emit(ClaimValidated {
+        claim_id: payload.claim_id,
+        policy_id: payload.policy_id,
+        semantic_hash: payload.semantic_hash,
+        validated_at: timestamp_ms,
+    });
+}
+
+/// Verify a predicate attestation signed by the enclave and emit `PredicateAttested`. The caller
+/// checks `predicate_hash` against the predicate it asked for and acts on `result`.
+public fun record_predicate_attestation<T>(
+    enclave: &Enclave<T>,
+    semantic_hash: vector<u8>,
+    resource_root: vector<u8>,
+    predicate_hash: vector<u8>,
+    result: bool,
+    timestamp_ms: u64,
+    signature: &vector<u8>,
+) {
+    let payload = PredicateAttestation {
+        semantic_hash,
+        resource_root,
+        predicate_hash,
+        result,
+    };
+    assert!(
+        enclave.verify_signature(PREDICATE_ATTESTATION_INTENT, timestamp_ms, payload, signature),
+        EInvalidSignature,
+    );
+    event::emit(PredicateAttested {
+        semantic_hash: payload.semantic_hash,
+        resource_root: payload.resource_root,
+        predicate_hash: payload.predicate_hash,
+        result: payload.result,
+        attested_at: timestamp_ms,
+    });
+}
+
+/// Verify a usage receipt signed by the enclave and emit `UsageCharged`, so that a conversion can
+/// be billed on-chain without disclosing its bundle.
+public fun record_usage_receipt<T>(
+    enclave: &Enclave<T>,
+    caller_id: String,
+    app: String,
+    semantic_hash: vector<u8>,
+    input_hash: vector<u8>,
+    model: String,
+    llm_calls: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost_micro_usd: u64,
+    timestamp_ms: u64,
+    signature: &vector<u8>,
+) {
+    let payload = UsageReceipt {
+        caller_id,
+        app,
+        semantic_hash,
+        input_hash,
+        model,
+        llm_calls,
+        prompt_tokens,
+        completion_tokens,
+        cost_micro_usd,
+    };
+    assert!(
+        enclave.verify_signature(USAGE_RECEIPT_INTENT, timestamp_ms, payload, signature),
+        EInvalidSignature,
+    );
+    event::emit(UsageCharged {
+        caller_id: payload.caller_id,
+        app: payload.app,
+        semantic_hash: payload.semantic_hash,
+        llm_calls: payload.llm_calls,
+        total_tokens: payload.prompt_tokens + payload.completion_tokens,
+        cost_micro_usd: payload.cost_micro_usd,
+        charged_at: timestamp_ms,
+    });
+}
+
+#[test]
+fun test_serde() {
+    // serialization should be consistent with rust test see `fn test_validation_serde` in
+    // `src/nautilus-server/src/apps/medical-vault-insurer/validation.rs`.
+    use std::bcs;
+
+    let timestamp = 1744038900000;
+    let signing_payload = enclave::create_intent_message(
+        VALIDATE_BUNDLE_INTENT,
+        timestamp,
+        BundleValidation {
+            walrus_blob_id: b"blob123".to_string(),
+            semantic_hash: x"abcd",
+            patient_id: b"patient456".to_string(),
+            resource_count: 5,
+            resource_types: vector[b"Patient".to_string(), b"Observation".to_string()],
+            validated: true,
+            validated_at: timestamp,
+        },
+    );
+    let bytes = bcs::to_bytes(&signing_payload);
+    assert!(
+        bytes == x"6420b1d1109601000007626c6f6231323302abcd0a70617469656e743435360500000000000000020750617469656e740b4f62736572766174696f6e0120b1d11096010000",
+        0,
+    );
+
+    let signing_payload = enclave::create_intent_message(
+        VERIFY_BUNDLE_INTENT,
+        timestamp,
+        BundleVerification {
+            walrus_blob_id: b"blob123".to_string(),
+            semantic_hash: x"abcd",
+            verified: true,
+            verified_at: timestamp,
+        },
+    );
+    let bytes = bcs::to_bytes(&signing_payload);
+    assert!(bytes == x"6520b1d1109601000007626c6f6231323302abcd0120b1d11096010000", 0);
+
+    let signing_payload = enclave::create_intent_message(
+        VALIDATE_CLAIM_INTENT,
+        timestamp,
+        ClaimValidation {
+            claim_id: b"claim789".to_string(),
+            policy_id: b"policy42".to_string(),
+            semantic_hash: x"abcd",
+            validated: false,
+            unsupported_codes: vector[b"http://snomed.info/sct|44054006".to_string()],
+            validated_at: timestamp,
+        },
+    );
+    let bytes = bcs::to_bytes(&signing_payload);
+    assert!(
+        bytes == x"6620b1d1109601000008636c61696d37383908706f6c696379343202abcd00011f687474703a2f2f736e6f6d65642e696e666f2f7363747c343430353430303620b1d11096010000",
+        0,
+    );
+}
+
+#[test]
+fun test_fhir_conversion_serde() {
+    // serialization should be consistent with rust test see `fn test_fhir_conversion_serde` in
+    // `src/nautilus-server/src/apps/medical-vault-insurer/mod.rs`.
+    use std::bcs;
+
+    let signing_payload = enclave::create_intent_message(
+        FHIR_CONVERSION_INTENT,
+        1744038900000,
+        FhirConversion {
+            semantic_hash: x"cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd",
+            resource_root: x"efefefefefefefefefefefefefefefefefefefefefefefefefefefefefefefef",
+            resources_created: vector[b"Patient".to_string()],
+            converter: b"llm".to_string(),
+            model: b"mock:mock".to_string(),
+            prompt_hash: x"3434343434343434343434343434343434343434343434343434343434343434",
+            temperature: b"0.1".to_string(),
+            input_hash: x"1212121212121212121212121212121212121212121212121212121212121212",
+            warnings_hash: x"",
+        },
+    );
+    let bytes = bcs::to_bytes(&signing_payload);
+    assert!(
+        bytes == x"6720b1d1109601000020cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd20efefefefefefefefefefefefefefefefefefefefefefefefefefefefefefefef010750617469656e74036c6c6d096d6f636b3a6d6f636b20343434343434343434343434343434343434343434343434343434343434343403302e3120121212121212121212121212121212121212121212121212121212121212121200",
+        0,
+    );
+}
+
+#[test]
+fun test_predicate_serde() {
+    // serialization should be consistent with rust test see `fn test_predicate_serde` in
+    // `src/nautilus-server/src/apps/medical-vault-insurer/predicate.rs`.
+    use std::bcs;
+
+    let signing_payload = enclave::create_intent_message(
+        PREDICATE_ATTESTATION_INTENT,
+        1744038900000,
+        PredicateAttestation {
+            semantic_hash: x"cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd",
+            resource_root: x"efefefefefefefefefefefefefefefefefefefefefefefefefefefefefefefef",
+            predicate_hash: x"abababababababababababababababababababababababababababababababab",
+            result: true,
+        },
+    );
+    let bytes = bcs::to_bytes(&signing_payload);
+    assert!(
+        bytes == x"6920b1d1109601000020cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd20efefefefefefefefefefefefefefefefefefefefefefefefefefefefefefefef20abababababababababababababababababababababababababababababababab01",
+        0,
+    );
+}
+
+#[test]
+fun test_usage_receipt_serde() {
+    // serialization should be consistent with rust test see `fn test_usage_receipt_serde` in
+    // `src/nautilus-server/src/apps/medical-vault-insurer/usage.rs`.
+    use std::bcs;
+
+    let signing_payload = enclave::create_intent_message(
+        USAGE_RECEIPT_INTENT,
+        1744038900000,
+        UsageReceipt {
+            caller_id: b"clinic-1".to_string(),
+            app: b"app".to_string(),
+            semantic_hash: x"cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd",
+            input_hash: x"1212121212121212121212121212121212121212121212121212121212121212",
+            model: b"mock:mock".to_string(),
+            llm_calls: 2,
+            prompt_tokens: 1000,
+            completion_tokens: 200,
+            cost_micro_usd: 4200,
+        },
+    );
+    let bytes = bcs::to_bytes(&signing_payload);
+    assert!(
+        bytes == x"6a20b1d1109601000008636c696e69632d310361707020cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd201212121212121212121212121212121212121212121212121212121212121212096d6f636b3a6d6f636b0200000000000000e803000000000000c8000000000000006810000000000000",
+        0,
+    );
+}
+
+#[test]
+fun test_batch_fhir_conversion() {
+    // Should be consistent with rust test see `fn test_batch_signing` in
+    // `src/nautilus-server/src/apps/medical-vault-insurer/mod.rs`: a batch of three conversions of
+    // bundles with decimal values, of which the last one is checked.
+    use std::bcs;
+
+    let timestamp = 1744038900000;
+    let root = x"dd1778fd5e71fe9638e6e603c4c7a3a6bce81c43f78611118c000e4b2ae6aabd";
+    let signing_payload = enclave::create_intent_message(
+        BATCH_ROOT_INTENT,
+        timestamp,
+        BatchRoot { root, leaf_count: 3 },
+    );
+    assert!(
+        bcs::to_bytes(&signing_payload) == x"6820b1d1109601000020dd1778fd5e71fe9638e6e603c4c7a3a6bce81c43f78611118c000e4b2ae6aabd0300000000000000",
+        0,
+    );
+
+    let conversion = enclave::create_intent_message(
+        FHIR_CONVERSION_INTENT,
+        timestamp,
+        FhirConversion {
+            semantic_hash: x"62cbbb57469669236911a9000e6afa8beaedf5c464ca1d06c1fa9eb22cced58a",
+            resource_root: x"a1b255e52f525568fa88020556ac4adb2fb161efaf684c4b438b560499e78e60",
+            resources_created: vector[b"Observation".to_string()],
+            converter: b"llm".to_string(),
+            model: b"mock:mock".to_string(),
+            prompt_hash: x"3434343434343434343434343434343434343434343434343434343434343434",
+            temperature: b"0.1".to_string(),
+            input_hash: x"1212121212121212121212121212121212121212121212121212121212121212",
+            warnings_hash: x"",
+        },
+    );
+    let leaf = bcs::to_bytes(&conversion);
+    let siblings = vector[x"52d6af40a778f6e2a330566d43cb5f34a79c0b08430ad25d4aa613e44682788e"];
+    assert!(verify_inclusion(root, leaf, 2, 3, siblings), 1);
+    assert!(!verify_inclusion(root, leaf, 1, 3, siblings), 2);
+}
+
+#[test]
+fun test_semantic_hash() {
+    // Should be consistent with rust test see `fn test_semantic_hash_is_canonical` in
+    // `src/nautilus-server/src/apps/medical-vault-insurer/mod.rs`.
+    let canonical_bundle = b"{\"entry\":[{\"resource\":{\"resourceType\":\"Observation\",\"valueQuantity\":{\"unit\":\"/min\",\"value\":72.5}}}],\"resourceType\":\"Bundle\",\"type\":\"collection\"}";
+    assert!(
+        semantic_hash(canonical_bundle) == x"2ad1cc91fd0ac1049891ff1e6b30d63fea7859b872f2d5184227ac699768ddf3",
+        0,
+    );
+}
+
+#[test]
+fun test_resource_inclusion() {
+    // Should be consistent with rust test see `fn test_resource_commitment` in
+    // `src/nautilus-server/src/apps/medical-vault-insurer/commitment.rs`.
+    let root = x"ed5524882957a5bb6ca5825ee6d01d36c7441325f0502ee36fd77f38b902ae1a";
+    let condition = b"{\"code\":{\"text\":\"Asthma\"},\"resourceType\":\"Condition\"}";
+    let patient = b"{\"id\":\"p1\",\"resourceType\":\"Patient\"}";
+    let observation = b"{\"resourceType\":\"Observation\",\"valueQuantity\":{\"value\":72.5}}";
+    let mut patient_leaf = vector[0u8];
+    patient_leaf.append(patient);
+    let observation_leaf = x"e53de1d6a28cfebfedf52f22c60c182c131e047ba787d8b43b505997e70c0a0c";
+
+    let siblings = vector[sha3_256(patient_leaf), observation_leaf];
+    assert!(verify_resource_inclusion(root, condition, 1, 3, siblings), 0);
+    assert!(!verify_resource_inclusion(root, patient, 1, 3, siblings), 1);
+    assert!(!verify_resource_inclusion(root, condition, 3, 3, siblings), 2);
+
+    // The last leaf of the odd level only needs the hash of the first two leaves.
+    let first_pair = x"04ecbd0bb6df1212ea2e750dec455f5b7274f0bbf6825663017180e04fc0115b";
+    assert!(verify_resource_inclusion(root, observation, 2, 3, vector[first_pair]), 3);
+}
@@ -0,0 +1,312 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Content-addressed cache of LLM conversions. Re-uploading the same record would otherwise pay for
+// a new LLM call and could produce a different bundle, so the bundle of each conversion is kept
+// under a key derived from everything that shapes it: the raw data, source format, PHI mode and
+// patient context of the request, and the model, temperature and prompt hash of the service. A
+// repeated conversion returns the same bundle at once, and so the same semantic hash.
+//
+// Entries are encrypted in memory with AES-256-GCM under a key generated at startup, which never
+// leaves the enclave, with a random nonce per entry and the cache key as associated data binding
+// each ciphertext to its entry. Cache keys are HMAC-SHA3-256 under a second key, so they cannot be
+// matched against known inputs. The least recently used entry is evicted once the cache is full,
+// and everything is dropped when the model or the prompt changes.
+
+use super::fhir::FhirBuildRequest;
+use fastcrypto::aes::{Aes256Gcm, AesKey, AuthenticatedCipher, InitializationVector};
+use fastcrypto::hmac::{hmac_sha3_256, HmacKey};
+use fastcrypto::traits::ToFromBytes;
+use rand::{thread_rng, RngCore};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use typenum::{U12, U32};
+
+const NONCE_LENGTH: usize = 12;
+
+struct Entry {
+    nonce: [u8; NONCE_LENGTH],
+    /// Ciphertext followed by the GCM tag.
+    ciphertext: Vec<u8>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Model, temperature and prompt the entries were produced with.
+    version: String,
+    entries: HashMap<[u8; 32], Entry>,
+    /// Incremented on each access, orders the entries by last use.
+    clock: u64,
+}
+
+/// Bounded, encrypted cache of converted bundles.
+pub struct LlmCache {
+    capacity: usize,
+    cipher: Aes256Gcm<U12>,
+    mac_key: HmacKey,
+    state: Mutex<CacheState>,
+}
+
+impl LlmCache {
+    /// Cache of at most `capacity` bundles, none when zero, under fresh random keys.
+    pub fn new(capacity: usize) -> Self {
+        let mut keys = [0u8; 64];
+        thread_rng().fill_bytes(&mut keys);
+        Self {
+            capacity,
+            cipher: Aes256Gcm::new(
+                AesKey::<U32>::from_bytes(&keys[..32]).expect("valid AES key length"),
+            ),
+            mac_key: HmacKey::from_bytes(&keys[32..]).expect("valid HMAC key length"),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Number of bundles cached.
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("cache lock").entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bundle cached for `request` under `version`, if any. A different version than the
+    /// cached entries' drops them all.
+    pub fn get(&self, version: &str, request: &FhirBuildRequest) -> Option<Value> {
+        let key = self.key(request);
+        let mut state = self.state.lock().expect("cache lock");
+        state.check_version(version);
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(&key)?;
+        entry.last_used = clock;
+        let nonce = InitializationVector::<U12>::from_bytes(&entry.nonce).ok()?;
+        match self
+            .cipher
+            .decrypt_authenticated(&nonce, &key, &entry.ciphertext)
+        {
+            Ok(plaintext) => serde_json::from_slice(&plaintext).ok(),
+            Err(_) => {
+                tracing::warn!("LLM cache entry failed authentication, dropped");
+                state.entries.remove(&key);
+                None
+            }
+        }
+    }
+
+    /// Cache the bundle converted for `request` under `version`.
+    pub fn insert(&self, version: &str, request: &FhirBuildRequest, bundle: &Value) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = self.key(request);
+        let mut nonce = [0u8; NONCE_LENGTH];
+        thread_rng().fill_bytes(&mut nonce);
+        let iv = InitializationVector::<U12>::from_bytes(&nonce).expect("valid nonce length");
+        let ciphertext =
+            self.cipher
+                .encrypt_authenticated(&iv, &key, bundle.to_string().as_bytes());
+
+        let mut state = self.state.lock().expect("cache lock");
+        state.check_version(version);
+        if state.entries.len() >= self.capacity && !state.entries.contains_key(&key) {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.clock += 1;
+        let last_used = state.clock;
+        state.entries.insert(
+            key,
+            Entry {
+                nonce,
+                ciphertext,
+                last_used,
+            },
+        );
+    }
+
+    /// HMAC of the fields of `request` shaping the bundle, each prefixed with its length.
+    fn key(&self, request: &FhirBuildRequest) -> [u8; 32] {
+        let patient_context =
+            serde_json::to_string(&request.patient_context).expect("patient context serializes");
+        let mut message = Vec::new();
+        for field in [
+            request.source_format.as_bytes(),
+            &[request.include_phi as u8],
+            patient_context.as_bytes(),
+            request.raw_data.as_bytes(),
+        ] {
+            message.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            message.extend_from_slice(field);
+        }
+        hmac_sha3_256(&self.mac_key, &message).digest
+    }
+}
+
+impl CacheState {
+    fn check_version(&mut self, version: &str) {
+        if self.version != version {
+            if !self.entries.is_empty() {
+                tracing::info!("LLM model or prompt changed, cache cleared");
+            }
+            self.entries.clear();
+            self.version = version.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::fhir::PatientContext;
+    use serde_json::json;
+
+    fn request(raw_data: &str) -> FhirBuildRequest {
+        FhirBuildRequest {
+            raw_data: raw_data.to_string(),
+            source_format: "text".to_string(),
+            patient_context: None,
+            include_phi: false,
+        }
+    }
+
+    fn bundle(gender: &str) -> Value {
+        json!({ "bundle": { "resourceType": "Bundle", "entry": [
+            { "resource": { "resourceType": "Patient", "gender": gender, "valueQuantity": { "value": 98.6 } } }
+        ] } })
+    }
+
+    #[test]
+    fn test_cache_keys() {
+        let cache = LlmCache::new(8);
+        let base = request("BP 120/80");
+        cache.insert("v1", &base, &bundle("female"));
+        assert_eq!(cache.get("v1", &base), Some(bundle("female")));
+
+        // Every field shaping the bundle is part of the key, and fields cannot run into each other.
+        let variants = [
+            FhirBuildRequest {
+                include_phi: true,
+                ..request("BP 120/80")
+            },
+            FhirBuildRequest {
+                source_format: "json".to_string(),
+                ..request("BP 120/80")
+            },
+            FhirBuildRequest {
+                patient_context: Some(PatientContext {
+                    patient_id: "P-1".to_string(),
+                    name: None,
+                    birth_date: None,
+                    gender: None,
+                }),
+                ..request("BP 120/80")
+            },
+            request("BP 120/81"),
+            FhirBuildRequest {
+                source_format: "textBP 120/80".to_string(),
+                ..request("")
+            },
+        ];
+        for variant in &variants {
+            assert!(cache.get("v1", variant).is_none());
+        }
+        assert_ne!(cache.key(&base), cache.key(&variants[4]));
+
+        // Another cache has other keys, so its entries cannot be matched against these.
+        assert_ne!(LlmCache::new(8).key(&base), cache.key(&base));
+    }
+
+    #[test]
+    fn test_cache_encryption() {
+        let cache = LlmCache::new(8);
+        let first = request("BP 120/80");
+        let second = request("HR 72");
+        cache.insert("v1", &first, &bundle("female"));
+        cache.insert("v1", &second, &bundle("female"));
+
+        {
+            let state = cache.state.lock().unwrap();
+            let a = &state.entries[&cache.key(&first)];
+            let b = &state.entries[&cache.key(&second)];
+            // A fresh nonce per entry, so the same bundle never encrypts the same way.
+            assert_ne!(a.nonce, b.nonce);
+            assert_ne!(a.ciphertext, b.ciphertext);
+            let plaintext = bundle("female").to_string();
+            assert_eq!(a.ciphertext.len(), plaintext.len() + 16);
+            assert!(!a.ciphertext.windows(6).any(|w| w == b"female"));
+        }
+
+        // A ciphertext moved to another entry or altered fails authentication and is dropped.
+        {
+            let mut state = cache.state.lock().unwrap();
+            let moved = state.entries.remove(&cache.key(&first)).unwrap();
+            state.entries.insert(cache.key(&second), moved);
+        }
+        assert!(cache.get("v1", &second).is_none());
+        assert!(cache.is_empty());
+
+        cache.insert("v1", &first, &bundle("female"));
+        cache
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(&cache.key(&first))
+            .unwrap()
+            .ciphertext[0] ^= 1;
+        assert!(cache.get("v1", &first).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let cache = LlmCache::new(2);
+        let (a, b, c) = (request("a"), request("b"), request("c"));
+        cache.insert("v1", &a, &bundle("female"));
+        cache.insert("v1", &b, &bundle("male"));
+
+        // Reading `a` makes `b` the least recently used entry.
+        assert!(cache.get("v1", &a).is_some());
+        cache.insert("v1", &c, &bundle("other"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("v1", &b).is_none());
+        assert_eq!(cache.get("v1", &c), Some(bundle("other")));
+
+        // Replacing an entry does not evict another one.
+        cache.insert("v1", &c, &bundle("unknown"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("v1", &c), Some(bundle("unknown")));
+        assert!(cache.get("v1", &a).is_some());
+
+        // A disabled cache keeps nothing.
+        let disabled = LlmCache::new(0);
+        disabled.insert("v1", &a, &bundle("female"));
+        assert!(disabled.is_empty());
+        assert!(disabled.get("v1", &a).is_none());
+    }
+
+    #[test]
+    fn test_cache_version() {
+        let cache = LlmCache::new(8);
+        let a = request("a");
+        cache.insert("v1", &a, &bundle("female"));
+        assert!(cache.get("v2", &a).is_none());
+        assert!(cache.is_empty());
+
+        // Inserting under a new version drops the entries of the previous one as well.
+        cache.insert("v1", &a, &bundle("female"));
+        cache.insert("v2", &request("b"), &bundle("male"));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("v2", &a).is_none());
+    }
+}
@@ -0,0 +1,1071 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// C-CDA (Consolidated CDA) ingestion. The patient of the record target and the coded entries of
+// the problems, medications, allergies, results, vital signs and encounters sections are mapped to
+// FHIR R5 resources by rules, without sending the document to the LLM. Codes keep their original
+// value, display name and translations; well known code system OIDs are replaced by their FHIR
+// URI and any other OID is kept as `urn:oid:<oid>`.
+
+use super::hl7v2::{fhir_date, fhir_date_time};
+use super::synthea::{derived_uuid, entry, insert_opt};
+use super::*;
+use roxmltree::{Document, Node, ParsingOptions};
+use serde_json::{json, Map, Value};
+
+/// `source_format` selecting this converter.
+pub const CCDA_SOURCE_FORMAT: &str = "ccda";
+
+/// Namespace of CDA documents.
+const HL7_V3: &str = "urn:hl7-org:v3";
+
+/// Maximum number of XML nodes in a document.
+const MAX_CCDA_NODES: u32 = 200_000;
+/// Maximum number of resources in a converted bundle.
+const MAX_CCDA_RESOURCES: usize = 1000;
+
+const SNOMED: &str = "http://snomed.info/sct";
+const LOINC: &str = "http://loinc.org";
+const UCUM: &str = "http://unitsofmeasure.org";
+const SSN_OID: &str = "2.16.840.1.113883.4.1";
+const ACT_CODE: &str = "http://terminology.hl7.org/CodeSystem/v3-ActCode";
+const OBSERVATION_CATEGORY: &str = "http://terminology.hl7.org/CodeSystem/observation-category";
+const CONDITION_CLINICAL: &str = "http://terminology.hl7.org/CodeSystem/condition-clinical";
+const CONDITION_VERIFICATION: &str = "http://terminology.hl7.org/CodeSystem/condition-ver-status";
+const ALLERGY_CLINICAL: &str = "http://terminology.hl7.org/CodeSystem/allergyintolerance-clinical";
+const ALLERGY_VERIFICATION: &str =
+    "http://terminology.hl7.org/CodeSystem/allergyintolerance-verification";
+
+/// FHIR URIs of the code system OIDs found in C-CDA documents.
+const CODE_SYSTEMS: &[(&str, &str)] = &[
+    ("2.16.840.1.113883.6.1", LOINC),
+    ("2.16.840.1.113883.6.96", SNOMED),
+    (
+        "2.16.840.1.113883.6.88",
+        "http://www.nlm.nih.gov/research/umls/rxnorm",
+    ),
+    (
+        "2.16.840.1.113883.6.90",
+        "http://hl7.org/fhir/sid/icd-10-cm",
+    ),
+    (
+        "2.16.840.1.113883.6.103",
+        "http://hl7.org/fhir/sid/icd-9-cm",
+    ),
+    ("2.16.840.1.113883.6.12", "http://www.ama-assn.org/go/cpt"),
+    ("2.16.840.1.113883.6.69", "http://hl7.org/fhir/sid/ndc"),
+    ("2.16.840.1.113883.4.9", "http://fdasis.nlm.nih.gov"),
+    ("2.16.840.1.113883.12.292", "http://hl7.org/fhir/sid/cvx"),
+    ("2.16.840.1.113883.5.4", ACT_CODE),
+    ("2.16.840.1.113883.6.8", UCUM),
+];
+
+/// C-CDA sections that are converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Problems,
+    Medications,
+    Allergies,
+    Results,
+    VitalSigns,
+    Encounters,
+}
+
+impl Section {
+    /// Recognize a section from its LOINC code, or its C-CDA template id.
+    fn detect(section: Node) -> Option<Self> {
+        let by_code = match child(section, "code").and_then(|c| c.attribute("code")) {
+            Some("11450-4") => Some(Self::Problems),
+            Some("10160-0") => Some(Self::Medications),
+            Some("48765-2") => Some(Self::Allergies),
+            Some("30954-2") => Some(Self::Results),
+            Some("8716-3") => Some(Self::VitalSigns),
+            Some("46240-8") => Some(Self::Encounters),
+            _ => None,
+        };
+        by_code.or_else(|| {
+            children(section, "templateId").find_map(|t| {
+                let root = t.attribute("root")?;
+                let root = root.strip_suffix(".1").unwrap_or(root);
+                match root {
+                    "2.16.840.1.113883.10.20.22.2.5" => Some(Self::Problems),
+                    "2.16.840.1.113883.10.20.22.2.1" => Some(Self::Medications),
+                    "2.16.840.1.113883.10.20.22.2.6" => Some(Self::Allergies),
+                    "2.16.840.1.113883.10.20.22.2.3" => Some(Self::Results),
+                    "2.16.840.1.113883.10.20.22.2.4" => Some(Self::VitalSigns),
+                    "2.16.840.1.113883.10.20.22.2.22" => Some(Self::Encounters),
+                    _ => None,
+                }
+            })
+        })
+    }
+}
+
+/// Convert a C-CDA document to a FHIR R5 bundle in the `{"bundle": {...}}` envelope returned by
+/// the LLM. Entries without a usable code or, for results and vital signs, without a physical
+/// quantity value are skipped.
+pub fn convert_ccda(request: &FhirBuildRequest) -> Result<Value, EnclaveError> {
+    let options = ParsingOptions {
+        allow_dtd: false,
+        nodes_limit: MAX_CCDA_NODES,
+    };
+    let document = Document::parse_with_options(&request.raw_data, options)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid C-CDA XML: {e}")))?;
+    let root = document.root_element();
+    if !is(root, "ClinicalDocument") {
+        return Err(EnclaveError::GenericError(
+            "C-CDA document must have a ClinicalDocument root in the urn:hl7-org:v3 namespace"
+                .to_string(),
+        ));
+    }
+
+    let patient_role = path(root, &["recordTarget", "patientRole"]).ok_or_else(|| {
+        EnclaveError::GenericError("C-CDA document has no recordTarget patientRole".to_string())
+    })?;
+    let (patient_id, patient) = patient_resource(patient_role, request.include_phi)?;
+    let patient_url = format!("urn:uuid:{patient_id}");
+    let mut entries = vec![entry(&patient_url, patient)];
+
+    let sections = path(root, &["component", "structuredBody"])
+        .into_iter()
+        .flat_map(|body| children(body, "component"))
+        .filter_map(|component| child(component, "section"));
+    for section in sections {
+        let Some(kind) = Section::detect(section) else {
+            continue;
+        };
+        for section_entry in children(section, "entry") {
+            for (node, resource) in entry_resources(kind, section_entry, &patient_url) {
+                let id = derived_uuid(
+                    "ccda",
+                    &[
+                        resource["resourceType"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_string(),
+                        node.range().start.to_string(),
+                        entry_id(node),
+                    ],
+                );
+                let mut resource = resource;
+                resource["id"] = json!(id);
+                entries.push(entry(&format!("urn:uuid:{id}"), resource));
+            }
+        }
+    }
+
+    if entries.len() > MAX_CCDA_RESOURCES {
+        return Err(EnclaveError::GenericError(format!(
+            "C-CDA document produces {} resources, at most {MAX_CCDA_RESOURCES} are allowed",
+            entries.len()
+        )));
+    }
+
+    info!("C-CDA conversion complete: {} resources", entries.len());
+
+    Ok(json!({
+        "bundle": {
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": entries,
+        }
+    }))
+}
+
+/// Resources of one section entry, with the clinical statement each was read from.
+fn entry_resources<'a>(
+    kind: Section,
+    section_entry: Node<'a, 'a>,
+    patient_url: &str,
+) -> Vec<(Node<'a, 'a>, Value)> {
+    let mut resources = Vec::new();
+    match kind {
+        // Problem concern act > problem observation
+        Section::Problems => {
+            for act in children(section_entry, "act") {
+                let concern_active = status_code(act) != Some("completed");
+                for observation in related(act, "observation") {
+                    if let Some(r) = condition_resource(observation, concern_active, patient_url) {
+                        resources.push((observation, r));
+                    }
+                }
+            }
+        }
+        Section::Medications => {
+            for administration in children(section_entry, "substanceAdministration") {
+                if let Some(r) = medication_request_resource(administration, patient_url) {
+                    resources.push((administration, r));
+                }
+            }
+        }
+        // Allergy concern act > allergy intolerance observation
+        Section::Allergies => {
+            for act in children(section_entry, "act") {
+                for observation in related(act, "observation") {
+                    if let Some(r) = allergy_resource(observation, patient_url) {
+                        resources.push((observation, r));
+                    }
+                }
+            }
+        }
+        // Result or vital signs organizer > observations
+        Section::Results | Section::VitalSigns => {
+            let category = if kind == Section::VitalSigns {
+                "vital-signs"
+            } else {
+                "laboratory"
+            };
+            let observations = children(section_entry, "organizer")
+                .flat_map(|organizer| children(organizer, "component"))
+                .chain(std::iter::once(section_entry))
+                .flat_map(|parent| children(parent, "observation"));
+            for observation in observations {
+                if let Some(r) = observation_resource(observation, category, patient_url) {
+                    resources.push((observation, r));
+                }
+            }
+        }
+        Section::Encounters => {
+            for encounter in children(section_entry, "encounter") {
+                resources.push((encounter, encounter_resource(encounter, patient_url)));
+            }
+        }
+    }
+    resources
+}
+
+/// Patient resource from the record target, and its id derived from the first identifier.
+/// Without `include_phi` names are masked, dates are reduced to the year, only the state is kept
+/// of the address and the SSN is dropped.
+fn patient_resource(role: Node, include_phi: bool) -> Result<(String, Value), EnclaveError> {
+    let mut identifier = Vec::new();
+    for id in children(role, "id") {
+        let Some(root) = id.attribute("root") else {
+            continue;
+        };
+        if root == SSN_OID && !include_phi {
+            continue;
+        }
+        let value = match id.attribute("extension") {
+            Some(extension) => json!({ "system": format!("urn:oid:{root}"), "value": extension }),
+            None => json!({ "system": "urn:ietf:rfc:3986", "value": format!("urn:oid:{root}") }),
+        };
+        identifier.push(value);
+    }
+    let first = identifier
+        .first()
+        .cloned()
+        .ok_or_else(|| EnclaveError::GenericError("C-CDA patientRole has no id".to_string()))?;
+    let patient_id = derived_uuid(
+        "ccda-patient",
+        &[
+            first["system"].as_str().unwrap_or_default().to_string(),
+            first["value"].as_str().unwrap_or_default().to_string(),
+        ],
+    );
+
+    let person = child(role, "patient");
+    let mut patient = Map::new();
+    patient.insert("resourceType".to_string(), json!("Patient"));
+    patient.insert("id".to_string(), json!(patient_id));
+    patient.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/Patient"] }),
+    );
+    patient.insert("identifier".to_string(), json!(identifier));
+
+    let name = if include_phi {
+        let mut name = Map::new();
+        name.insert("use".to_string(), json!("official"));
+        let person_name = person.and_then(|p| child(p, "name"));
+        let parts = |part: &str| -> Vec<String> {
+            person_name
+                .into_iter()
+                .flat_map(|n| children(n, part))
+                .filter_map(text)
+                .collect()
+        };
+        insert_opt(&mut name, "family", parts("family").into_iter().next());
+        name.insert("given".to_string(), json!(parts("given")));
+        let prefix = parts("prefix");
+        if !prefix.is_empty() {
+            name.insert("prefix".to_string(), json!(prefix));
+        }
+        let suffix = parts("suffix");
+        if !suffix.is_empty() {
+            name.insert("suffix".to_string(), json!(suffix));
+        }
+        Value::Object(name)
+    } else {
+        json!({ "family": "***", "given": ["***"] })
+    };
+    patient.insert("name".to_string(), json!([name]));
+
+    let gender = match person
+        .and_then(|p| child(p, "administrativeGenderCode"))
+        .and_then(|g| g.attribute("code"))
+    {
+        Some("M") => "male",
+        Some("F") => "female",
+        Some("UN") => "other",
+        _ => "unknown",
+    };
+    patient.insert("gender".to_string(), json!(gender));
+
+    let birth_date = person
+        .and_then(|p| child(p, "birthTime"))
+        .and_then(|t| t.attribute("value"))
+        .and_then(fhir_date)
+        .map(|date| {
+            if include_phi {
+                date
+            } else {
+                date.chars().take(4).collect()
+            }
+        });
+    insert_opt(&mut patient, "birthDate", birth_date);
+
+    let mut address = Map::new();
+    if let Some(addr) = child(role, "addr") {
+        if include_phi {
+            let line: Vec<String> = children(addr, "streetAddressLine")
+                .filter_map(text)
+                .collect();
+            if !line.is_empty() {
+                address.insert("line".to_string(), json!(line));
+            }
+            insert_opt(&mut address, "city", child(addr, "city").and_then(text));
+            insert_opt(&mut address, "state", child(addr, "state").and_then(text));
+            insert_opt(
+                &mut address,
+                "postalCode",
+                child(addr, "postalCode").and_then(text),
+            );
+        } else {
+            insert_opt(&mut address, "state", child(addr, "state").and_then(text));
+        }
+        insert_opt(
+            &mut address,
+            "country",
+            child(addr, "country").and_then(text),
+        );
+    }
+    if !address.contains_key("country") {
+        address.insert("country".to_string(), json!("US"));
+    }
+    patient.insert("address".to_string(), json!([address]));
+
+    Ok((patient_id, Value::Object(patient)))
+}
+
+fn condition_resource(observation: Node, concern_active: bool, patient_url: &str) -> Option<Value> {
+    let code = child(observation, "value").and_then(coded_concept)?;
+    let time = child(observation, "effectiveTime");
+    let onset = time.and_then(|t| time_value(t, "low"));
+    let abatement = time.and_then(|t| time_value(t, "high"));
+    let clinical_status = if concern_active && abatement.is_none() {
+        "active"
+    } else {
+        "resolved"
+    };
+
+    let mut condition = Map::new();
+    condition.insert("resourceType".to_string(), json!("Condition"));
+    condition.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/Condition"] }),
+    );
+    condition.insert(
+        "clinicalStatus".to_string(),
+        json!({ "coding": [{ "system": CONDITION_CLINICAL, "code": clinical_status }] }),
+    );
+    condition.insert(
+        "verificationStatus".to_string(),
+        json!({ "coding": [{ "system": CONDITION_VERIFICATION, "code": "confirmed" }] }),
+    );
+    condition.insert("code".to_string(), code);
+    condition.insert("subject".to_string(), json!({ "reference": patient_url }));
+    insert_opt(&mut condition, "onsetDateTime", onset.clone());
+    insert_opt(&mut condition, "abatementDateTime", abatement);
+    insert_opt(&mut condition, "recordedDate", onset);
+    Some(Value::Object(condition))
+}
+
+fn medication_request_resource(administration: Node, patient_url: &str) -> Option<Value> {
+    let medication = path(
+        administration,
+        &[
+            "consumable",
+            "manufacturedProduct",
+            "manufacturedMaterial",
+            "code",
+        ],
+    )
+    .and_then(coded_concept)?;
+    let status = match status_code(administration) {
+        Some("completed") => "completed",
+        Some("aborted") | Some("cancelled") => "stopped",
+        Some("suspended") => "on-hold",
+        _ => "active",
+    };
+    // Planned medications (moodCode INT) are orders, the others were already taken.
+    let intent = if administration.attribute("moodCode") == Some("INT") {
+        "order"
+    } else {
+        "plan"
+    };
+
+    let mut request = Map::new();
+    request.insert("resourceType".to_string(), json!("MedicationRequest"));
+    request.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/MedicationRequest"] }),
+    );
+    request.insert("status".to_string(), json!(status));
+    request.insert("intent".to_string(), json!(intent));
+    request.insert("medication".to_string(), json!({ "concept": medication }));
+    request.insert("subject".to_string(), json!({ "reference": patient_url }));
+    insert_opt(
+        &mut request,
+        "authoredOn",
+        children(administration, "effectiveTime").find_map(|t| time_value(t, "low")),
+    );
+    if let Some(dose) = child(administration, "doseQuantity") {
+        let text = match (dose.attribute("value"), dose.attribute("unit")) {
+            (Some(value), Some(unit)) if unit != "1" => Some(format!("{value} {unit}")),
+            (Some(value), _) => Some(value.to_string()),
+            _ => None,
+        };
+        if let Some(text) = text {
+            request.insert("dosageInstruction".to_string(), json!([{ "text": text }]));
+        }
+    }
+    Some(Value::Object(request))
+}
+
+fn allergy_resource(observation: Node, patient_url: &str) -> Option<Value> {
+    let substance = path(
+        observation,
+        &["participant", "participantRole", "playingEntity", "code"],
+    )
+    .and_then(coded_concept)?;
+    let category = match child(observation, "value").and_then(|v| v.attribute("code")) {
+        Some("416098002") | Some("419511003") | Some("59037007") => Some("medication"),
+        Some("414285001") | Some("418471000") | Some("235719002") => Some("food"),
+        Some("426232007") | Some("419199007") => Some("environment"),
+        _ => None,
+    };
+    let time = child(observation, "effectiveTime");
+    let resolved = time.and_then(|t| time_value(t, "high")).is_some();
+
+    let mut allergy = Map::new();
+    allergy.insert("resourceType".to_string(), json!("AllergyIntolerance"));
+    allergy.insert(
+        "clinicalStatus".to_string(),
+        json!({ "coding": [{
+            "system": ALLERGY_CLINICAL,
+            "code": if resolved { "resolved" } else { "active" }
+        }] }),
+    );
+    allergy.insert(
+        "verificationStatus".to_string(),
+        json!({ "coding": [{ "system": ALLERGY_VERIFICATION, "code": "confirmed" }] }),
+    );
+    if let Some(category) = category {
+        allergy.insert("category".to_string(), json!([category]));
+    }
+    allergy.insert("code".to_string(), substance);
+    allergy.insert("patient".to_string(), json!({ "reference": patient_url }));
+    insert_opt(
+        &mut allergy,
+        "onsetDateTime",
+        time.and_then(|t| time_value(t, "low")),
+    );
+
+    // Reaction observations carry the manifestation as their value.
+    let manifestations: Vec<Value> = related(observation, "observation")
+        .filter(|r| {
+            children(*r, "templateId")
+                .any(|t| t.attribute("root") == Some("2.16.840.1.113883.10.20.22.4.9"))
+        })
+        .filter_map(|r| child(r, "value").and_then(coded_concept))
+        .map(|concept| json!({ "concept": concept }))
+        .collect();
+    if !manifestations.is_empty() {
+        allergy.insert(
+            "reaction".to_string(),
+            json!([{ "manifestation": manifestations }]),
+        );
+    }
+    Some(Value::Object(allergy))
+}
+
+/// Observation with a physical quantity value, or None for any other value type.
+fn observation_resource(observation: Node, category: &str, patient_url: &str) -> Option<Value> {
+    let code = child(observation, "code").and_then(coded_concept)?;
+    let value_node = child(observation, "value")?;
+    let value = value_node
+        .attribute("value")?
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())?;
+    let unit = value_node.attribute("unit").filter(|u| *u != "1");
+    let profile = if category == "vital-signs" {
+        "http://hl7.org/fhir/StructureDefinition/vitalsigns"
+    } else {
+        "http://hl7.org/fhir/StructureDefinition/Observation"
+    };
+    let status = match status_code(observation) {
+        Some("completed") | None => "final",
+        Some("active") => "preliminary",
+        Some("aborted") | Some("cancelled") => "cancelled",
+        Some(_) => "unknown",
+    };
+
+    let mut quantity = Map::new();
+    quantity.insert("value".to_string(), json!(value));
+    // PQ units are UCUM by definition.
+    if let Some(unit) = unit {
+        quantity.insert("unit".to_string(), json!(unit));
+        quantity.insert("system".to_string(), json!(UCUM));
+        quantity.insert("code".to_string(), json!(unit));
+    }
+
+    let mut resource = Map::new();
+    resource.insert("resourceType".to_string(), json!("Observation"));
+    resource.insert("meta".to_string(), json!({ "profile": [profile] }));
+    resource.insert("status".to_string(), json!(status));
+    resource.insert(
+        "category".to_string(),
+        json!([{ "coding": [{ "system": OBSERVATION_CATEGORY, "code": category }] }]),
+    );
+    resource.insert("code".to_string(), code);
+    resource.insert("subject".to_string(), json!({ "reference": patient_url }));
+    insert_opt(
+        &mut resource,
+        "effectiveDateTime",
+        child(observation, "effectiveTime").and_then(|t| {
+            t.attribute("value")
+                .and_then(fhir_date_time)
+                .or_else(|| time_value(t, "low"))
+        }),
+    );
+    resource.insert("valueQuantity".to_string(), Value::Object(quantity));
+    Some(Value::Object(resource))
+}
+
+fn encounter_resource(encounter: Node, patient_url: &str) -> Value {
+    let time = child(encounter, "effectiveTime");
+    let start = time.and_then(|t| {
+        t.attribute("value")
+            .and_then(fhir_date_time)
+            .or_else(|| time_value(t, "low"))
+    });
+    let end = time.and_then(|t| time_value(t, "high"));
+    let status = match status_code(encounter) {
+        Some("active") => "in-progress",
+        _ => "completed",
+    };
+
+    let mut resource = Map::new();
+    resource.insert("resourceType".to_string(), json!("Encounter"));
+    resource.insert("status".to_string(), json!(status));
+    resource.insert(
+        "class".to_string(),
+        json!([{ "coding": [{ "system": ACT_CODE, "code": "AMB", "display": "ambulatory" }] }]),
+    );
+    if let Some(code) = child(encounter, "code").and_then(coded_concept) {
+        resource.insert("type".to_string(), json!([code]));
+    }
+    resource.insert("subject".to_string(), json!({ "reference": patient_url }));
+    let mut period = Map::new();
+    insert_opt(&mut period, "start", start);
+    insert_opt(&mut period, "end", end);
+    if !period.is_empty() {
+        resource.insert("actualPeriod".to_string(), Value::Object(period));
+    }
+    Value::Object(resource)
+}
+
+/// CodeableConcept from a CD/CE element and its translations. A code with a nullFlavor only
+/// contributes its original text.
+fn coded_concept(code: Node) -> Option<Value> {
+    let codings: Vec<Value> = std::iter::once(code)
+        .chain(children(code, "translation"))
+        .filter_map(|c| {
+            let value = c.attribute("code")?;
+            let oid = c.attribute("codeSystem")?;
+            let mut coding = Map::new();
+            coding.insert("system".to_string(), json!(code_system_uri(oid)));
+            coding.insert("code".to_string(), json!(value));
+            insert_opt(&mut coding, "display", c.attribute("displayName"));
+            Some(Value::Object(coding))
+        })
+        .collect();
+    let text = child(code, "originalText")
+        .and_then(text)
+        .or_else(|| code.attribute("displayName").map(str::to_string));
+    if codings.is_empty() && text.is_none() {
+        return None;
+    }
+    let mut concept = Map::new();
+    concept.insert("coding".to_string(), json!(codings));
+    insert_opt(&mut concept, "text", text);
+    Some(Value::Object(concept))
+}
+
+/// FHIR URI of a code system OID, or the OID itself as a URN.
+fn code_system_uri(oid: &str) -> String {
+    CODE_SYSTEMS
+        .iter()
+        .find(|(known, _)| *known == oid)
+        .map(|(_, uri)| uri.to_string())
+        .unwrap_or_else(|| format!("urn:oid:{oid}"))
+}
+
+/// Identifier of a clinical statement, used with its position to derive a stable resource id.
+fn entry_id(node: Node) -> String {
+    child(node, "id")
+        .map(|id| {
+            format!(
+                "{}^{}",
+                id.attribute("root").unwrap_or_default(),
+                id.attribute("extension").unwrap_or_default()
+            )
+        })
+        .unwrap_or_default()
+}
+
+fn status_code<'a>(node: Node<'a, 'a>) -> Option<&'a str> {
+    child(node, "statusCode").and_then(|s| s.attribute("code"))
+}
+
+/// FHIR dateTime of the `low` or `high` bound of an IVL_TS.
+fn time_value(time: Node, bound: &str) -> Option<String> {
+    child(time, bound)
+        .and_then(|b| b.attribute("value"))
+        .and_then(fhir_date_time)
+}
+
+/// Clinical statements nested under `entryRelationship` elements.
+fn related<'a>(node: Node<'a, 'a>, name: &'a str) -> impl Iterator<Item = Node<'a, 'a>> {
+    children(node, "entryRelationship").flat_map(move |r| children(r, name))
+}
+
+fn is(node: Node, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().name() == name
+        && node.tag_name().namespace() == Some(HL7_V3)
+}
+
+fn children<'a>(node: Node<'a, 'a>, name: &'a str) -> impl Iterator<Item = Node<'a, 'a>> {
+    node.children().filter(move |c| is(*c, name))
+}
+
+fn child<'a>(node: Node<'a, 'a>, name: &str) -> Option<Node<'a, 'a>> {
+    node.children().find(|c| is(*c, name))
+}
+
+fn path<'a>(node: Node<'a, 'a>, names: &[&str]) -> Option<Node<'a, 'a>> {
+    names.iter().try_fold(node, |node, name| child(node, name))
+}
+
+/// Trimmed text content of an element, None when empty.
+fn text(node: Node) -> Option<String> {
+    let text: String = node
+        .descendants()
+        .filter(|n| n.is_text())
+        .filter_map(|n| n.text())
+        .collect::<Vec<_>>()
+        .join("");
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then_some(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(raw_data: &str, include_phi: bool) -> FhirBuildRequest {
+        FhirBuildRequest {
+            raw_data: raw_data.to_string(),
+            source_format: CCDA_SOURCE_FORMAT.to_string(),
+            patient_context: None,
+            include_phi,
+        }
+    }
+
+    fn section(code: &str, entries: &str) -> String {
+        format!(
+            r#"<component><section><code code="{code}" codeSystem="2.16.840.1.113883.6.1"/>{entries}</section></component>"#
+        )
+    }
+
+    /// Document of a patient with a single identifier and the given sections.
+    fn document(sections: &str) -> String {
+        format!(
+            r#"<ClinicalDocument xmlns="urn:hl7-org:v3" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+                <recordTarget><patientRole>
+                    <id root="2.16.840.1.113883.19.5" extension="MRN1"/>
+                    <patient><name><given>Ann</given><family>Lee</family></name></patient>
+                </patientRole></recordTarget>
+                <component><structuredBody>{sections}</structuredBody></component>
+            </ClinicalDocument>"#
+        )
+    }
+
+    #[test]
+    fn test_ccda_conversion() {
+        let problems = section(
+            "11450-4",
+            r#"<entry><act classCode="ACT" moodCode="EVN"><statusCode code="active"/>
+                <entryRelationship typeCode="SUBJ"><observation classCode="OBS" moodCode="EVN">
+                    <id root="ab1791b0-5c71-11db-b0de-0800200c9a66"/>
+                    <effectiveTime><low value="20200301"/></effectiveTime>
+                    <value xsi:type="CD" code="44054006" codeSystem="2.16.840.1.113883.6.96" displayName="Diabetes mellitus type 2">
+                        <translation code="E11.9" codeSystem="2.16.840.1.113883.6.90" displayName="Type 2 diabetes"/>
+                    </value>
+                </observation></entryRelationship>
+            </act></entry>"#,
+        );
+        let medications = section(
+            "10160-0",
+            r#"<entry><substanceAdministration classCode="SBADM" moodCode="INT">
+                <statusCode code="active"/>
+                <effectiveTime xsi:type="IVL_TS"><low value="20240105"/></effectiveTime>
+                <doseQuantity value="1"/>
+                <consumable><manufacturedProduct><manufacturedMaterial>
+                    <code code="197361" codeSystem="2.16.840.1.113883.6.88" displayName="Amlodipine 5 MG Oral Tablet"/>
+                </manufacturedMaterial></manufacturedProduct></consumable>
+            </substanceAdministration></entry>"#,
+        );
+        let allergies = section(
+            "48765-2",
+            r#"<entry><act classCode="ACT" moodCode="EVN">
+                <entryRelationship typeCode="SUBJ"><observation classCode="OBS" moodCode="EVN">
+                    <value xsi:type="CD" code="416098002" codeSystem="2.16.840.1.113883.6.96"/>
+                    <participant typeCode="CSM"><participantRole classCode="MANU"><playingEntity classCode="MMAT">
+                        <code code="7980" codeSystem="2.16.840.1.113883.6.88" displayName="Penicillin G"/>
+                    </playingEntity></participantRole></participant>
+                    <entryRelationship typeCode="MFST" inversionInd="true"><observation classCode="OBS" moodCode="EVN">
+                        <templateId root="2.16.840.1.113883.10.20.22.4.9" extension="2014-06-09"/>
+                        <value xsi:type="CD" code="247472004" codeSystem="2.16.840.1.113883.6.96" displayName="Hives"/>
+                    </observation></entryRelationship>
+                </observation></entryRelationship>
+            </act></entry>"#,
+        );
+        let results = section(
+            "30954-2",
+            r#"<entry><organizer classCode="BATTERY" moodCode="EVN">
+                <component><observation classCode="OBS" moodCode="EVN">
+                    <code code="2093-3" codeSystem="2.16.840.1.113883.6.1" displayName="Cholesterol"/>
+                    <statusCode code="completed"/>
+                    <effectiveTime value="20240105080000-0500"/>
+                    <value xsi:type="PQ" value="185" unit="mg/dL"/>
+                </observation></component>
+                <component><observation classCode="OBS" moodCode="EVN">
+                    <code code="5778-6" codeSystem="2.16.840.1.113883.6.1" displayName="Color of Urine"/>
+                    <statusCode code="completed"/>
+                    <value xsi:type="ST">Yellow</value>
+                </observation></component>
+            </organizer></entry>"#,
+        );
+        let vitals = section(
+            "8716-3",
+            r#"<entry><organizer classCode="CLUSTER" moodCode="EVN">
+                <component><observation classCode="OBS" moodCode="EVN">
+                    <code code="8867-4" codeSystem="2.16.840.1.113883.6.1" displayName="Heart rate"/>
+                    <statusCode code="completed"/>
+                    <effectiveTime value="202401050815-0500"/>
+                    <value xsi:type="PQ" value="72" unit="/min"/>
+                </observation></component>
+            </organizer></entry>"#,
+        );
+        let encounters = section(
+            "46240-8",
+            r#"<entry><encounter classCode="ENC" moodCode="EVN">
+                <code code="99213" codeSystem="2.16.840.1.113883.6.12" displayName="Office visit"/>
+                <effectiveTime><low value="20240105080000-0500"/><high value="20240105090000-0500"/></effectiveTime>
+            </encounter></entry>"#,
+        );
+        let ccda = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <ClinicalDocument xmlns="urn:hl7-org:v3" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+                <recordTarget><patientRole>
+                    <id root="2.16.840.1.113883.19.5" extension="MRN123"/>
+                    <id root="2.16.840.1.113883.4.1" extension="999-62-4431"/>
+                    <addr><streetAddressLine>1 Main St</streetAddressLine><city>Boston</city><state>MA</state><postalCode>02101</postalCode><country>US</country></addr>
+                    <patient>
+                        <name><given>Jane</given><given>Q</given><family>Doe</family></name>
+                        <administrativeGenderCode code="F" codeSystem="2.16.840.1.113883.5.1"/>
+                        <birthTime value="19800201"/>
+                    </patient>
+                </patientRole></recordTarget>
+                <component><structuredBody>{problems}{medications}{allergies}{results}{vitals}{encounters}</structuredBody></component>
+            </ClinicalDocument>"#
+        );
+
+        let bundle = convert_ccda(&request(&ccda, true)).unwrap();
+        let outcome = validate_profile(&bundle);
+        assert!(!outcome.has_errors(), "{}", outcome.error_summary());
+        assert_eq!(
+            extract_resource_types(&bundle),
+            vec![
+                "Patient",
+                "Condition",
+                "MedicationRequest",
+                "AllergyIntolerance",
+                "Observation",
+                "Encounter"
+            ]
+        );
+        let resources = bundle_resources(&bundle);
+        // The string result is left out, as the profile requires a valueQuantity.
+        assert_eq!(resources.len(), 7);
+        assert_eq!(resources[0]["name"][0]["given"], json!(["Jane", "Q"]));
+        assert_eq!(resources[0]["birthDate"], "1980-02-01");
+        assert_eq!(resources[0]["identifier"].as_array().unwrap().len(), 2);
+        // Original codes and OIDs are kept next to each other as codings.
+        assert_eq!(
+            resources[1]["code"]["coding"],
+            json!([
+                { "system": "http://snomed.info/sct", "code": "44054006", "display": "Diabetes mellitus type 2" },
+                { "system": "http://hl7.org/fhir/sid/icd-10-cm", "code": "E11.9", "display": "Type 2 diabetes" }
+            ])
+        );
+        assert_eq!(
+            resources[1]["clinicalStatus"]["coding"][0]["code"],
+            "active"
+        );
+        assert_eq!(resources[2]["intent"], "order");
+        assert_eq!(resources[2]["authoredOn"], "2024-01-05");
+        assert_eq!(
+            resources[3]["reaction"][0]["manifestation"][0]["concept"]["coding"][0]["code"],
+            "247472004"
+        );
+        assert_eq!(resources[3]["category"], json!(["medication"]));
+        assert_eq!(
+            resources[4]["valueQuantity"],
+            json!({ "value": 185.0, "unit": "mg/dL", "system": "http://unitsofmeasure.org", "code": "mg/dL" })
+        );
+        assert_eq!(
+            resources[4]["effectiveDateTime"],
+            "2024-01-05T08:00:00-05:00"
+        );
+        assert_eq!(
+            resources[5]["category"][0]["coding"][0]["code"],
+            "vital-signs"
+        );
+        assert_eq!(
+            resources[6]["actualPeriod"],
+            json!({ "start": "2024-01-05T08:00:00-05:00", "end": "2024-01-05T09:00:00-05:00" })
+        );
+        assert_eq!(
+            resources[6]["type"][0]["coding"][0]["system"],
+            "http://www.ama-assn.org/go/cpt"
+        );
+
+        // The same document always yields the same bundle.
+        assert_eq!(
+            compute_semantic_hash(&bundle).unwrap(),
+            compute_semantic_hash(&convert_ccda(&request(&ccda, true)).unwrap()).unwrap()
+        );
+
+        let bundle = convert_ccda(&request(&ccda, false)).unwrap();
+        assert!(!validate_profile(&bundle).has_errors());
+        let patient = &bundle_resources(&bundle)[0];
+        assert_eq!(patient["name"][0]["family"], "***");
+        assert_eq!(patient["birthDate"], "1980");
+        assert_eq!(patient["identifier"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            patient["address"],
+            json!([{ "state": "MA", "country": "US" }])
+        );
+
+        // Documents declaring a DTD are rejected, which rules out entity expansion.
+        let dtd = format!("<!DOCTYPE ClinicalDocument [<!ENTITY x \"y\">]>{ccda}");
+        assert!(convert_ccda(&request(&dtd, true)).is_err());
+        assert!(convert_ccda(&request("<ClinicalDocument/>", true)).is_err());
+        assert!(convert_ccda(&request("not xml", true)).is_err());
+    }
+
+    #[test]
+    fn test_ccda_observations() {
+        let observation = |status: &str, time: &str, value: &str| {
+            format!(
+                r#"<component><observation classCode="OBS" moodCode="EVN">
+                    <code code="2345-7" codeSystem="2.16.840.1.113883.6.1"/>
+                    {status}<effectiveTime{time}{value}
+                </observation></component>"#
+            )
+        };
+        let glucose = r#"<value xsi:type="PQ" value="95" unit="mg/dL"/>"#;
+        let results = [
+            // Status mapping, and the point in time or the start of the interval.
+            observation("", r#" value="20240105"/>"#, glucose),
+            observation(
+                r#"<statusCode code="active"/>"#,
+                r#" value="202401051030-0500"/>"#,
+                glucose,
+            ),
+            observation(
+                r#"<statusCode code="aborted"/>"#,
+                r#"><low value="20240105"/></effectiveTime>"#,
+                glucose,
+            ),
+            observation(r#"<statusCode code="held"/>"#, "/>", glucose),
+            // Values that are not a finite physical quantity are skipped.
+            observation("", "/>", r#"<value xsi:type="ST">Yellow</value>"#),
+            observation("", "/>", r#"<value xsi:type="PQ" nullFlavor="NI"/>"#),
+            observation(
+                "",
+                "/>",
+                r#"<value xsi:type="PQ" value="INF" unit="mg/dL"/>"#,
+            ),
+            observation("", "/>", ""),
+        ]
+        .concat();
+        // The vital signs section is recognized from its template id, and a unit of 1 is no unit.
+        let vitals = format!(
+            r#"<component><section><templateId root="2.16.840.1.113883.10.20.22.2.4.1"/>
+                <entry><organizer classCode="CLUSTER" moodCode="EVN">{}</organizer></entry>
+            </section></component>"#,
+            observation("", "/>", r#"<value xsi:type="PQ" value="16" unit="1"/>"#)
+        );
+        let ccda = document(&format!(
+            "{}{vitals}",
+            section(
+                "30954-2",
+                &format!(
+                    r#"<entry><organizer classCode="BATTERY" moodCode="EVN">{results}</organizer></entry>"#
+                )
+            )
+        ));
+        let bundle = convert_ccda(&request(&ccda, true)).unwrap();
+        let resources = bundle_resources(&bundle);
+        assert_eq!(resources.len(), 6);
+        let statuses: Vec<&Value> = resources[1..].iter().map(|r| &r["status"]).collect();
+        assert_eq!(
+            statuses,
+            ["final", "preliminary", "cancelled", "unknown", "final"]
+        );
+        let times: Vec<&Value> = resources[1..]
+            .iter()
+            .map(|r| &r["effectiveDateTime"])
+            .collect();
+        assert_eq!(
+            times,
+            [
+                &json!("2024-01-05"),
+                &json!("2024-01-05T10:30:00-05:00"),
+                &json!("2024-01-05"),
+                &Value::Null,
+                &Value::Null
+            ]
+        );
+        assert_eq!(
+            resources[1]["valueQuantity"],
+            json!({ "value": 95.0, "unit": "mg/dL", "system": UCUM, "code": "mg/dL" })
+        );
+        assert_eq!(resources[5]["valueQuantity"], json!({ "value": 16.0 }));
+        assert_eq!(
+            resources[5]["category"][0]["coding"][0]["code"],
+            "vital-signs"
+        );
+        assert_eq!(
+            resources[5]["meta"]["profile"][0],
+            "http://hl7.org/fhir/StructureDefinition/vitalsigns"
+        );
+    }
+
+    #[test]
+    fn test_ccda_entries() {
+        let problem = |status: &str, time: &str, value: &str| {
+            format!(
+                r#"<entry><act classCode="ACT" moodCode="EVN"><statusCode code="{status}"/>
+                    <entryRelationship typeCode="SUBJ"><observation classCode="OBS" moodCode="EVN">
+                        <effectiveTime>{time}</effectiveTime>{value}
+                    </observation></entryRelationship>
+                </act></entry>"#
+            )
+        };
+        let diabetes =
+            r#"<value xsi:type="CD" code="44054006" codeSystem="2.16.840.1.113883.6.96"/>"#;
+        let problems = [
+            problem("active", r#"<low value="20200301"/>"#, diabetes),
+            problem("completed", r#"<low value="20200301"/>"#, diabetes),
+            problem(
+                "active",
+                r#"<low value="20200301"/><high value="20210301"/>"#,
+                diabetes,
+            ),
+            // A code with a nullFlavor keeps its original text, and an unknown OID is kept.
+            problem(
+                "active",
+                "",
+                r#"<value xsi:type="CD" nullFlavor="OTH"><originalText>Chest pain</originalText>
+                    <translation code="X1" codeSystem="1.2.3.4"/></value>"#,
+            ),
+            problem("active", "", r#"<value xsi:type="CD" nullFlavor="UNK"/>"#),
+        ]
+        .concat();
+        let unknown = section("29762-2", &problem("active", "", diabetes));
+        let bundle = convert_ccda(&request(
+            &document(&format!("{}{unknown}", section("11450-4", &problems))),
+            true,
+        ))
+        .unwrap();
+        let resources = bundle_resources(&bundle);
+        // The uncoded problem and the social history section are left out.
+        assert_eq!(resources.len(), 5);
+        let statuses: Vec<&Value> = resources[1..]
+            .iter()
+            .map(|r| &r["clinicalStatus"]["coding"][0]["code"])
+            .collect();
+        assert_eq!(statuses, ["active", "resolved", "resolved", "active"]);
+        assert_eq!(resources[3]["abatementDateTime"], "2021-03-01");
+        assert_eq!(
+            resources[4]["code"],
+            json!({ "coding": [{ "system": "urn:oid:1.2.3.4", "code": "X1" }], "text": "Chest pain" })
+        );
+        // Identical entries at different positions are different resources.
+        assert_ne!(resources[1]["id"], resources[2]["id"]);
+        assert_eq!(resources[0]["identifier"][0]["value"], "MRN1");
+    }
+
+    #[test]
+    fn test_ccda_malformed() {
+        let error = |raw_data: &str| match convert_ccda(&request(raw_data, true)) {
+            Err(EnclaveError::GenericError(message)) => message,
+            Err(other) => panic!("unexpected {other:?}"),
+            Ok(_) => panic!("{raw_data:?} converted"),
+        };
+        assert!(error("").contains("Invalid C-CDA XML"));
+        // The root must be in the HL7 v3 namespace.
+        assert!(
+            error("<ClinicalDocument><recordTarget/></ClinicalDocument>").contains("namespace")
+        );
+        assert!(error(r#"<ClinicalDocument xmlns="urn:hl7-org:v3"/>"#).contains("recordTarget"));
+        let without_id = document("").replace(
+            r#"<id root="2.16.840.1.113883.19.5" extension="MRN1"/>"#,
+            r#"<id extension="MRN1"/>"#,
+        );
+        assert!(error(&without_id).contains("has no id"));
+        // The SSN alone does not identify the patient when PHI is masked.
+        let ssn_only = document("").replace("2.16.840.1.113883.19.5", SSN_OID);
+        assert!(convert_ccda(&request(&ssn_only, true)).is_ok());
+        assert!(matches!(
+            convert_ccda(&request(&ssn_only, false)),
+            Err(EnclaveError::GenericError(_))
+        ));
+
+        // Documents too large to parse, or producing too many resources, are rejected.
+        let encounter = r#"<entry><encounter classCode="ENC" moodCode="EVN"/></entry>"#;
+        let many = section("46240-8", &encounter.repeat(MAX_CCDA_RESOURCES));
+        assert!(error(&document(&many)).contains("at most"));
+        let fewer = section("46240-8", &encounter.repeat(MAX_CCDA_RESOURCES - 1));
+        assert!(convert_ccda(&request(&document(&fewer), true)).is_ok());
+        let nodes = "<x/>".repeat(MAX_CCDA_NODES as usize);
+        assert!(error(&document(&nodes)).contains("Invalid C-CDA XML"));
+    }
+}
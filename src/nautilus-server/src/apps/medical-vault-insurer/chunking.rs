@@ -0,0 +1,505 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Chunked conversion of inputs too large for one LLM call. `split_raw_data` cuts the free text into
+// chunks at encounter, date and section headings, repeating the text before the first heading
+// (usually the demographics) in each chunk so every partial bundle describes the same Patient. The
+// chunks are converted in parallel and `merge_bundles` joins the partial bundles into one: a single
+// Patient, resources identical across chunks kept once and every reference re-linked. Resource ids
+// are derived from the position of the resource in the chunks alone, so the same input always
+// merges into the same bundle and semantic hash.
+
+use super::synthea::derived_uuid;
+use crate::jcs::canonicalize;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// Maximum number of chunks of one input.
+pub const MAX_CHUNKS: usize = 16;
+
+/// Resources referenced by others, merged before the resources referencing them.
+const REFERENCED_RESOURCES: &[&str] = &["Encounter", "Practitioner", "Organization", "Location"];
+
+lazy_static::lazy_static! {
+    /// Lines starting a new encounter, dated entry or section of a clinical document.
+    static ref SECTION_HEADING: Regex = Regex::new(
+        r"(?ix)^\s*(?:
+            \#{1,6}\s
+            | (?:encounter|visit|admission|discharge|date\s+of\s+(?:service|visit|admission)|progress\s+note)\b
+            | (?:hpi|history\s+of\s+present\s+illness|past\s+medical\s+history|assessment|plan|medications?|allergies|labs?|results|vital\s+signs|procedures?|immunizations?)\s*:
+            | \d{4}-\d{2}-\d{2}\b
+            | \d{1,2}/\d{1,2}/\d{2,4}\b
+        )"
+    )
+    .expect("valid section heading pattern");
+}
+
+/// Split `raw_data` into chunks of at most `max_chars` characters at section headings, each
+/// prefixed with the text before the first heading. Sections longer than a chunk are split at blank
+/// lines, then at lines, then anywhere. Input within `max_chars` is returned as is.
+pub fn split_raw_data(raw_data: &str, max_chars: usize) -> Vec<String> {
+    if raw_data.chars().count() <= max_chars {
+        return vec![raw_data.to_string()];
+    }
+
+    let mut sections: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for line in raw_data.split_inclusive('\n') {
+        if SECTION_HEADING.is_match(line) && !current.trim().is_empty() {
+            sections.push(std::mem::take(&mut current));
+        }
+        current.push_str(line);
+    }
+    sections.push(current);
+
+    // The preamble is repeated in every chunk unless it would take half of it.
+    let preamble = if !SECTION_HEADING.is_match(&sections[0]) && sections.len() > 1 {
+        let preamble = sections.remove(0);
+        if preamble.chars().count() <= max_chars / 2 {
+            preamble
+        } else {
+            sections.insert(0, preamble);
+            String::new()
+        }
+    } else {
+        String::new()
+    };
+    let budget = max_chars - preamble.chars().count();
+
+    let mut chunks: Vec<String> = Vec::new();
+    let mut chunk = String::new();
+    for piece in sections.iter().flat_map(|s| split_section(s, budget)) {
+        if !chunk.is_empty() && chunk.chars().count() + piece.chars().count() > budget {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        chunk.push_str(&piece);
+    }
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+        .into_iter()
+        .map(|chunk| format!("{preamble}{chunk}"))
+        .collect()
+}
+
+/// Pieces of at most `max_chars` characters of a section, split at the coarsest boundary that fits.
+fn split_section(section: &str, max_chars: usize) -> Vec<String> {
+    if section.chars().count() <= max_chars {
+        return vec![section.to_string()];
+    }
+    for separator in ["\n\n", "\n"] {
+        let parts: Vec<&str> = section.split_inclusive(separator).collect();
+        if parts.len() > 1 {
+            return parts
+                .into_iter()
+                .flat_map(|part| split_section(part, max_chars))
+                .collect();
+        }
+    }
+    section
+        .chars()
+        .collect::<Vec<_>>()
+        .chunks(max_chars.max(1))
+        .map(|c| c.iter().collect())
+        .collect()
+}
+
+/// Merge the partial bundles of the chunks, in chunk order, into one bundle in the
+/// `{"bundle": {...}}` envelope.
+///
+/// - The first Patient is kept and completed with the elements and identifiers of the others, and
+///   every reference to any of them points to it.
+/// - Other resources get an id derived from their chunk and position, and references are re-linked
+///   to these ids.
+/// - A resource identical to an earlier one once re-linked is dropped, and references to it point
+///   to the earlier one.
+pub fn merge_bundles(parts: &[Value]) -> Value {
+    // Resources with their chunk, position and the references of their chunk.
+    let mut resources: Vec<(usize, usize, &Map<String, Value>)> = Vec::new();
+    let mut patient: Option<Map<String, Value>> = None;
+    let mut patient_url = String::new();
+    let mut references: Vec<HashMap<String, String>> = vec![HashMap::new(); parts.len()];
+
+    for (part_index, part) in parts.iter().enumerate() {
+        let bundle = part.get("bundle").unwrap_or(part);
+        let entries = bundle.get("entry").and_then(|e| e.as_array());
+        for (entry_index, entry) in entries.into_iter().flatten().enumerate() {
+            let Some(resource) = entry.get("resource").and_then(|r| r.as_object()) else {
+                continue;
+            };
+            let resource_type = resource
+                .get("resourceType")
+                .and_then(|t| t.as_str())
+                .unwrap_or_default();
+            let url = if resource_type == "Patient" {
+                match patient.as_mut() {
+                    Some(kept) => complete_patient(kept, resource),
+                    None => {
+                        let mut kept = resource.clone();
+                        let id = derived_uuid("merged-bundle", &["Patient".to_string()]);
+                        kept.insert("id".to_string(), json!(id));
+                        patient_url = format!("urn:uuid:{id}");
+                        patient = Some(kept);
+                    }
+                }
+                patient_url.clone()
+            } else {
+                resources.push((part_index, entry_index, resource));
+                let id = derived_uuid(
+                    "merged-bundle",
+                    &[part_index.to_string(), entry_index.to_string()],
+                );
+                format!("urn:uuid:{id}")
+            };
+
+            let chunk_references = &mut references[part_index];
+            if let Some(full_url) = entry.get("fullUrl").and_then(|u| u.as_str()) {
+                chunk_references.insert(full_url.to_string(), url.clone());
+            }
+            if let Some(id) = resource.get("id").and_then(|i| i.as_str()) {
+                chunk_references.insert(format!("{resource_type}/{id}"), url);
+            }
+        }
+    }
+
+    // Referenced resources go first so that their duplicates are known when the resources
+    // referencing them are compared.
+    let mut order: Vec<usize> = (0..resources.len()).collect();
+    order.sort_by_key(|&index| {
+        let resource_type = resources[index]
+            .2
+            .get("resourceType")
+            .and_then(|t| t.as_str());
+        let referenced = resource_type.is_some_and(|t| REFERENCED_RESOURCES.contains(&t));
+        (!referenced, index)
+    });
+
+    let mut aliases: HashMap<String, String> = HashMap::new();
+    let mut seen: HashMap<String, String> = HashMap::new();
+    let mut kept: Vec<Option<(String, Value)>> = vec![None; resources.len()];
+    for index in order {
+        let (part_index, entry_index, resource) = resources[index];
+        let mut resource = Value::Object(resource.clone());
+        resource
+            .as_object_mut()
+            .expect("resource is an object")
+            .remove("id");
+        relink(&mut resource, &references[part_index], &aliases);
+
+        let id = derived_uuid(
+            "merged-bundle",
+            &[part_index.to_string(), entry_index.to_string()],
+        );
+        let url = format!("urn:uuid:{id}");
+        // Canonical form, so that numbers such as 38 and 38.0 compare equal.
+        let key = canonicalize(&resource).unwrap_or_else(|_| resource.to_string());
+        if let Some(existing) = seen.get(&key) {
+            aliases.insert(url, existing.clone());
+            continue;
+        }
+        seen.insert(key, url.clone());
+        resource["id"] = json!(id);
+        kept[index] = Some((url, resource));
+    }
+
+    let mut entry = Vec::new();
+    if let Some(patient) = patient {
+        entry.push(json!({ "fullUrl": patient_url, "resource": patient }));
+    }
+    for (url, mut resource) in kept.into_iter().flatten() {
+        // References to duplicates found after the resource was compared.
+        relink(&mut resource, &HashMap::new(), &aliases);
+        entry.push(json!({ "fullUrl": url, "resource": resource }));
+    }
+    json!({
+        "bundle": {
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": entry
+        }
+    })
+}
+
+/// Add the elements of `other` missing from the kept Patient, and its identifiers.
+fn complete_patient(kept: &mut Map<String, Value>, other: &Map<String, Value>) {
+    for (key, value) in other {
+        match (key.as_str(), kept.get_mut(key)) {
+            ("id", _) => {}
+            ("identifier", Some(Value::Array(identifiers))) => {
+                for identifier in value.as_array().into_iter().flatten() {
+                    if !identifiers.contains(identifier) {
+                        identifiers.push(identifier.clone());
+                    }
+                }
+            }
+            (_, Some(_)) => {}
+            (_, None) => {
+                kept.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Rewrite every `reference` below `value` through the references of its chunk, then the aliases
+/// of dropped duplicates.
+fn relink(
+    value: &mut Value,
+    references: &HashMap<String, String>,
+    aliases: &HashMap<String, String>,
+) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object.iter_mut() {
+                match child {
+                    Value::String(reference) if key == "reference" => {
+                        if let Some(url) = references.get(reference.as_str()) {
+                            *reference = url.clone();
+                        }
+                        if let Some(url) = aliases.get(reference.as_str()) {
+                            *reference = url.clone();
+                        }
+                    }
+                    _ => relink(child, references, aliases),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                relink(item, references, aliases);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::profile::validate_profile;
+
+    #[test]
+    fn test_split_raw_data() {
+        // The preamble is repeated in every chunk, which start at dates and encounter headings.
+        let raw_data = "Patient: Jane Doe, F, DOB 1980-02-03\n\n\
+            2024-01-05 Clinic visit\nBP 120/80\n\n\
+            Encounter: follow-up 2024-03-01\nHbA1c 6.1 %\n\n\
+            03/15/2024 Labs\nLDL 100 mg/dL\n";
+        let chunks = split_raw_data(raw_data, 90);
+        assert_eq!(chunks.len(), 3);
+        for (chunk, heading) in
+            chunks
+                .iter()
+                .zip(["2024-01-05", "Encounter: follow-up", "03/15/2024"])
+        {
+            assert!(chunk.starts_with("Patient: Jane Doe"));
+            assert!(chunk.lines().nth(2).unwrap().starts_with(heading));
+            assert!(chunk.chars().count() <= 90);
+        }
+        assert_eq!(split_raw_data(raw_data, 1000), vec![raw_data.to_string()]);
+        // A section without any boundary is still cut to size.
+        let long = "x".repeat(250);
+        assert!(split_raw_data(&long, 100).iter().all(|c| c.len() <= 100));
+    }
+
+    #[test]
+    fn test_merge_bundles() {
+        // Each chunk describes the Patient, the first chunk's Practitioner again in the second,
+        // and an Encounter referenced by id within its own chunk.
+        let patient = |id: &str, extra: Value| {
+            let mut resource = json!({
+                "resourceType": "Patient",
+                "id": id,
+                "identifier": [{ "system": "urn:ietf:rfc:3986", "value": format!("urn:uuid:{id}") }],
+                "name": [{ "family": "***", "given": ["***"] }],
+                "gender": "female"
+            });
+            resource
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            json!({ "fullUrl": format!("urn:uuid:{id}"), "resource": resource })
+        };
+        let practitioner = |id: &str| {
+            json!({
+                "fullUrl": format!("urn:uuid:{id}"),
+                "resource": { "resourceType": "Practitioner", "id": id, "name": [{ "family": "***" }] }
+            })
+        };
+        let encounter = |id: &str, patient: &str, practitioner: &str| {
+            json!({
+                "fullUrl": format!("urn:uuid:{id}"),
+                "resource": {
+                    "resourceType": "Encounter",
+                    "id": id,
+                    "status": "completed",
+                    "subject": { "reference": format!("urn:uuid:{patient}") },
+                    "participant": [{ "actor": { "reference": format!("Practitioner/{practitioner}") } }]
+                }
+            })
+        };
+        let first = json!({ "bundle": { "resourceType": "Bundle", "type": "collection", "entry": [
+            patient("p1", json!({})),
+            practitioner("dr"),
+            encounter("e1", "p1", "dr"),
+        ]}});
+        let second = json!({ "bundle": { "resourceType": "Bundle", "type": "collection", "entry": [
+            patient("p2", json!({ "birthDate": "1980" })),
+            practitioner("dr2"),
+            encounter("e1", "p2", "dr2"),
+            encounter("e2", "p2", "dr2"),
+        ]}});
+
+        let merged = merge_bundles(&[first.clone(), second.clone()]);
+        assert_eq!(merged, merge_bundles(&[first.clone(), second.clone()]));
+        assert!(!validate_profile(&merged).has_errors());
+        let entries = merged["bundle"]["entry"].as_array().unwrap();
+        let types: Vec<&str> = entries
+            .iter()
+            .map(|e| e["resource"]["resourceType"].as_str().unwrap())
+            .collect();
+        // The second Practitioner and first Encounter duplicate those of the first chunk once
+        // re-linked, the second Encounter only differs by its id.
+        assert_eq!(types, ["Patient", "Practitioner", "Encounter"]);
+        let patient_url = entries[0]["fullUrl"].as_str().unwrap();
+        assert_eq!(entries[0]["resource"]["birthDate"], "1980");
+        assert_eq!(
+            entries[0]["resource"]["identifier"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(entries[2]["resource"]["subject"]["reference"], patient_url);
+        assert_eq!(
+            entries[2]["resource"]["participant"][0]["actor"]["reference"],
+            entries[1]["fullUrl"]
+        );
+        for entry in entries {
+            assert_eq!(
+                entry["fullUrl"],
+                format!("urn:uuid:{}", entry["resource"]["id"].as_str().unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_raw_data_edges() {
+        // Characters are counted rather than bytes.
+        let accented = "é".repeat(100);
+        assert_eq!(split_raw_data(&accented, 100), vec![accented.clone()]);
+        let chunks = split_raw_data(&accented, 30);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 30));
+        assert_eq!(chunks.concat(), accented);
+
+        // Without a heading, sections are split at blank lines first, then at lines.
+        let paragraphs = "aaaa aaaa\nbbbb\n\ncccc cccc\n\ndddd\n";
+        assert_eq!(
+            split_raw_data(paragraphs, 16),
+            ["aaaa aaaa\nbbbb\n\n", "cccc cccc\n\ndddd\n"]
+        );
+        assert_eq!(
+            split_raw_data(paragraphs, 12),
+            ["aaaa aaaa\n", "bbbb\n\n", "cccc cccc\n\n", "dddd\n"]
+        );
+
+        // Markdown, section and date headings start chunks, dates and colons inside a line do not.
+        let notes = "Seen on 2024-01-05\n\
+            # Visit\nNote: BP 120/80\n\
+            Assessment: stable\nf/u 3/5/24\n\
+            3/5/24 Labs\nLDL 100 mg/dL\n";
+        let chunks = split_raw_data(notes, 50);
+        assert_eq!(chunks.len(), 3);
+        let headings: Vec<&str> = chunks.iter().map(|c| c.lines().nth(1).unwrap()).collect();
+        assert_eq!(headings, ["# Visit", "Assessment: stable", "3/5/24 Labs"]);
+        // Without the repeated preamble, the chunks are the input.
+        let preamble = "Seen on 2024-01-05\n";
+        let rest: String = chunks
+            .iter()
+            .map(|c| c.strip_prefix(preamble).unwrap())
+            .collect();
+        assert_eq!(format!("{preamble}{rest}"), notes);
+
+        // A preamble longer than half a chunk is not repeated.
+        let long_preamble = format!("{}\n2024-01-05 Visit\nBP 120/80\n", "x".repeat(30));
+        let chunks = split_raw_data(&long_preamble, 40);
+        assert_eq!(chunks.concat(), long_preamble);
+        assert_eq!(chunks.iter().filter(|c| c.contains("xxx")).count(), 1);
+    }
+
+    #[test]
+    fn test_merge_bundles_edges() {
+        let first = json!({ "resourceType": "Bundle", "entry": [
+            { "resource": {
+                "resourceType": "Patient",
+                "id": "pa",
+                "identifier": [{ "system": "urn:mrn", "value": "1" }],
+                "gender": "female"
+            } },
+            { "resource": {
+                "resourceType": "Condition",
+                "id": "ca",
+                "code": { "text": "Asthma", "coding": [{ "system": "http://snomed.info/sct", "code": "195967001" }] },
+                "subject": { "reference": "Patient/pa" }
+            } },
+            // Ids are only resolved within their own chunk.
+            { "resource": {
+                "resourceType": "Observation",
+                "focus": [{ "reference": "Condition/cb" }],
+                "performer": [{ "reference": "Organization/external" }]
+            } }
+        ]});
+        let second = json!({ "bundle": { "resourceType": "Bundle", "entry": [
+            { "resource": {
+                "resourceType": "Patient",
+                "id": "pb",
+                "identifier": [{ "system": "urn:mrn", "value": "1" }],
+                "gender": "male",
+                "name": [{ "family": "***" }]
+            } },
+            // The same Condition with its members in another order.
+            { "resource": {
+                "subject": { "reference": "Patient/pb" },
+                "code": { "coding": [{ "code": "195967001", "system": "http://snomed.info/sct" }], "text": "Asthma" },
+                "id": "cb",
+                "resourceType": "Condition"
+            } },
+            { "resource": {
+                "resourceType": "Observation",
+                "focus": [{ "reference": "Condition/cb" }]
+            } }
+        ]}});
+
+        let merged = merge_bundles(&[first, second]);
+        let resources: Vec<&Value> = merged["bundle"]["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| &e["resource"])
+            .collect();
+        assert_eq!(resources.len(), 4);
+        // The first Patient wins, completed with what it lacks and without repeated identifiers.
+        assert_eq!(resources[0]["gender"], "female");
+        assert_eq!(resources[0]["name"], json!([{ "family": "***" }]));
+        assert_eq!(resources[0]["identifier"].as_array().unwrap().len(), 1);
+        let condition_url = format!("urn:uuid:{}", resources[1]["id"].as_str().unwrap());
+        assert_eq!(resources[2]["focus"][0]["reference"], "Condition/cb");
+        assert_eq!(
+            resources[2]["performer"][0]["reference"],
+            "Organization/external"
+        );
+        // A reference to a dropped duplicate points to the resource kept.
+        assert_eq!(resources[3]["focus"][0]["reference"], condition_url);
+
+        // Nothing to merge yields an empty bundle, and entries without a resource are skipped.
+        let empty =
+            json!({ "bundle": { "resourceType": "Bundle", "type": "collection", "entry": [] } });
+        assert_eq!(merge_bundles(&[]), empty);
+        assert_eq!(
+            merge_bundles(&[json!({ "entry": [{ "fullUrl": "urn:uuid:x" }] })]),
+            empty
+        );
+    }
+}
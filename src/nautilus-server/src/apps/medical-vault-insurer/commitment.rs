@@ -0,0 +1,199 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Per-resource commitments over FHIR bundles. Every `entry.resource` is hashed from its RFC 8785
+// canonical bytes into a leaf of a Merkle tree (see `crate::merkle`) whose root is signed with the
+// conversion attestation. The inclusion proof of a single resource lets a patient show that it was
+// part of an attested bundle without revealing the other resources.
+
+use super::*;
+use crate::jcs::canonicalize_to_vec;
+use crate::merkle::{leaf_hash, verify_proof, MerkleProof, MerkleTree, Node};
+use serde_json::Value;
+
+/// Inclusion proof of one resource of a bundle, in bundle order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceProof {
+    pub resource_type: String,
+    /// Hex encoded leaf hash, `sha3_256(0x00 || canonical resource)`.
+    pub resource_hash: String,
+    pub proof: MerkleProof,
+}
+
+/// Merkle commitment to the resources of a bundle together with the proof of every resource.
+#[derive(Debug, Clone)]
+pub struct ResourceCommitment {
+    pub root: Node,
+    pub proofs: Vec<ResourceProof>,
+}
+
+/// Build the resource commitment of a bundle, bare or in the `{ bundle: {...} }` envelope.
+pub fn commit_resources(bundle: &Value) -> Result<ResourceCommitment, EnclaveError> {
+    let resources = bundle_resources(bundle);
+    let leaves = resources
+        .iter()
+        .map(|resource| canonicalize_to_vec(resource).map(|bytes| leaf_hash(&bytes)))
+        .collect::<Result<Vec<Node>, String>>()
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to canonicalize resource: {e}")))?;
+    let tree = MerkleTree::from_leaf_hashes(leaves.clone()).ok_or_else(|| {
+        EnclaveError::GenericError("Bundle has no resources to commit to".to_string())
+    })?;
+
+    let proofs = resources
+        .iter()
+        .zip(&leaves)
+        .enumerate()
+        .map(|(index, (resource, leaf))| ResourceProof {
+            resource_type: resource
+                .get("resourceType")
+                .and_then(|t| t.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            resource_hash: Hex::encode(leaf),
+            proof: tree.proof(index).expect("index is within the leaves"),
+        })
+        .collect();
+
+    Ok(ResourceCommitment {
+        root: tree.root(),
+        proofs,
+    })
+}
+
+/// Verify that `resource` is included under a signed `resource_root` according to `proof`.
+pub fn verify_resource_inclusion(
+    resource_root: &[u8],
+    resource: &Value,
+    proof: &MerkleProof,
+) -> bool {
+    let Ok(root) = Node::try_from(resource_root) else {
+        return false;
+    };
+    canonicalize_to_vec(resource)
+        .map(|bytes| verify_proof(&root, &bytes, proof))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_resource_commitment() {
+        // The root should be consistent with `test_resource_inclusion` in
+        // `move/medical-vault/sources/validator.move`.
+        let bundle = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "type": "collection",
+                "entry": [
+                    { "resource": { "resourceType": "Patient", "id": "p1" } },
+                    { "resource": { "resourceType": "Condition", "code": { "text": "Asthma" } } },
+                    { "resource": { "resourceType": "Observation", "valueQuantity": { "value": 72.5 } } }
+                ]
+            }
+        });
+        let commitment = commit_resources(&bundle).unwrap();
+        assert_eq!(
+            Hex::encode(commitment.root),
+            "ed5524882957a5bb6ca5825ee6d01d36c7441325f0502ee36fd77f38b902ae1a"
+        );
+
+        let resources = bundle_resources(&bundle);
+        assert_eq!(commitment.proofs.len(), 3);
+        for (resource, proof) in resources.iter().zip(&commitment.proofs) {
+            assert_eq!(proof.resource_type, resource["resourceType"]);
+            assert!(verify_resource_inclusion(
+                &commitment.root,
+                resource,
+                &proof.proof
+            ));
+        }
+        // Member order does not matter, the resources are hashed canonically.
+        let reordered = json!({ "code": { "text": "Asthma" }, "resourceType": "Condition" });
+        assert!(verify_resource_inclusion(
+            &commitment.root,
+            &reordered,
+            &commitment.proofs[1].proof
+        ));
+
+        let tampered = json!({ "resourceType": "Condition", "code": { "text": "Diabetes" } });
+        assert!(!verify_resource_inclusion(
+            &commitment.root,
+            &tampered,
+            &commitment.proofs[1].proof
+        ));
+        assert!(!verify_resource_inclusion(
+            &commitment.root,
+            resources[1],
+            &commitment.proofs[0].proof
+        ));
+        let out_of_range = MerkleProof {
+            leaf_index: 3,
+            ..commitment.proofs[2].proof.clone()
+        };
+        assert!(!verify_resource_inclusion(
+            &commitment.root,
+            resources[2],
+            &out_of_range
+        ));
+        assert!(!verify_resource_inclusion(
+            &[0u8; 31],
+            resources[0],
+            &commitment.proofs[0].proof
+        ));
+
+        assert!(commit_resources(&json!({ "resourceType": "Bundle", "entry": [] })).is_err());
+    }
+
+    #[test]
+    fn test_resource_commitment_shapes() {
+        // A single resource is its own root and needs no siblings.
+        let patient = json!({ "resourceType": "Patient", "id": "p1" });
+        let commitment = commit_resources(&json!({
+            "resourceType": "Bundle",
+            "entry": [{ "resource": patient }]
+        }))
+        .unwrap();
+        let leaf = leaf_hash(&canonicalize_to_vec(&patient).unwrap());
+        assert_eq!(commitment.root, leaf);
+        assert_eq!(commitment.proofs[0].resource_hash, Hex::encode(leaf));
+        assert!(commitment.proofs[0].proof.siblings.is_empty());
+
+        // Entries without a resource are skipped, resources without a type keep an empty one and
+        // an odd number of leaves still proves every resource.
+        let resources: Vec<Value> = (0..5)
+            .map(|i| json!({ "resourceType": "Observation", "id": format!("o{i}") }))
+            .chain([json!({ "id": "untyped" })])
+            .collect();
+        let mut entries: Vec<Value> = resources.iter().map(|r| json!({ "resource": r })).collect();
+        entries.insert(2, json!({ "fullUrl": "urn:uuid:empty" }));
+        let bare = json!({ "resourceType": "Bundle", "entry": entries });
+        let commitment = commit_resources(&bare).unwrap();
+        assert_eq!(commitment.proofs.len(), 6);
+        assert_eq!(commitment.proofs[5].resource_type, "");
+        for (resource, proof) in resources.iter().zip(&commitment.proofs) {
+            assert_eq!(proof.proof.leaf_count, 6);
+            assert!(verify_resource_inclusion(
+                &commitment.root,
+                resource,
+                &proof.proof
+            ));
+        }
+        // The envelope commits to the same resources.
+        let enveloped = commit_resources(&json!({ "bundle": bare })).unwrap();
+        assert_eq!(enveloped.root, commitment.root);
+
+        // A proof claiming another tree size does not verify.
+        let resized = MerkleProof {
+            leaf_count: 7,
+            ..commitment.proofs[4].proof.clone()
+        };
+        assert!(!verify_resource_inclusion(
+            &commitment.root,
+            &resources[4],
+            &resized
+        ));
+    }
+}
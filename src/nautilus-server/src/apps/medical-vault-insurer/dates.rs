@@ -0,0 +1,113 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Minimal FHIR date handling: FHIR `date` / `dateTime` values may be partial (YYYY, YYYY-MM,
+// YYYY-MM-DD) and optionally carry a time and timezone.
+
+/// A calendar date with the precision it was given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialDate {
+    pub year: i64,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+}
+
+impl PartialDate {
+    /// Earliest day covered by the date, in days since 1970-01-01.
+    pub fn earliest_day(&self) -> i64 {
+        days_from_civil(self.year, self.month.unwrap_or(1), self.day.unwrap_or(1))
+    }
+
+    /// Latest day covered by the date, in days since 1970-01-01.
+    pub fn latest_day(&self) -> i64 {
+        let month = self.month.unwrap_or(12);
+        let day = self.day.unwrap_or_else(|| days_in_month(self.year, month));
+        days_from_civil(self.year, month, day)
+    }
+}
+
+/// Parse the date part of a FHIR `date`, `dateTime` or `instant`. Returns None when the value is
+/// not a valid (possibly partial) ISO 8601 date.
+pub fn parse_fhir_date(value: &str) -> Option<PartialDate> {
+    let date = value.split('T').next()?;
+    let mut parts = date.split('-');
+
+    let year_str = parts.next()?;
+    if year_str.len() != 4 || !year_str.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let year: i64 = year_str.parse().ok()?;
+
+    let month = match parts.next() {
+        Some(m) => Some(parse_two_digits(m).filter(|m| (1..=12).contains(m))?),
+        None => None,
+    };
+    let day = match parts.next() {
+        Some(d) => {
+            let month = month?;
+            Some(parse_two_digits(d).filter(|d| *d >= 1 && *d <= days_in_month(year, month))?)
+        }
+        None => None,
+    };
+    if parts.next().is_some() || (day.is_none() && date.len() != value.len()) {
+        // Trailing components, or a time without a full date.
+        return None;
+    }
+
+    Some(PartialDate { year, month, day })
+}
+
+fn parse_two_digits(s: &str) -> Option<u32> {
+    if s.len() == 2 && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        _ => 28,
+    }
+}
+
+/// Days since 1970-01-01 of a civil date.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Civil (year, month, day) of a unix timestamp in milliseconds.
+pub fn civil_from_timestamp_ms(timestamp_ms: u64) -> (i64, u32, u32) {
+    let days = (timestamp_ms / 86_400_000) as i64;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Full years between a birth day and a reference day, both as civil dates.
+pub fn age_in_years(birth: (i64, u32, u32), on: (i64, u32, u32)) -> i64 {
+    let mut age = on.0 - birth.0;
+    if (on.1, on.2) < (birth.1, birth.2) {
+        age -= 1;
+    }
+    age
+}
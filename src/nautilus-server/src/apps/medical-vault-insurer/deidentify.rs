@@ -0,0 +1,1335 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// HIPAA Safe Harbor de-identification (45 CFR 164.514(b)(2)) of converted bundles. Instead of
+// relying on the converters or on the PHI rules of the LLM prompt, every bundle produced without
+// `include_phi` goes through `deidentify_bundle`, which removes or generalizes the 18 identifier
+// categories and reports each element it changed, never the removed value. `check_safe_harbor`
+// runs the same rules on a bundle without changing it: a bundle the rules would still change is not
+// signed.
+
+use super::dates::parse_fhir_date;
+use super::profile::{IssueSeverity, IssueType, OperationOutcomeIssue};
+use super::pseudonym::{is_pseudonym, Pseudonymizer};
+use super::synthea::derived_uuid;
+use super::*;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// Replacement of masked names and free-text matches.
+const MASK: &str = "***";
+
+/// Resources describing the individual or their relatives, whose demographics are identifiers.
+const PERSON_RESOURCES: &[&str] = &["Patient", "RelatedPerson", "Person"];
+
+/// Elements holding codes, references or other structural values rather than free text.
+const STRUCTURAL_ELEMENTS: &[&str] = &[
+    "resourceType",
+    "id",
+    "fullUrl",
+    "reference",
+    "system",
+    "code",
+    "url",
+    "status",
+    "intent",
+    "gender",
+    "unit",
+    "use",
+    "type",
+    "profile",
+    "language",
+    "contentType",
+    "comparator",
+];
+
+/// Systems of US social security numbers.
+const SSN_SYSTEMS: &[&str] = &[
+    "http://hl7.org/fhir/sid/us-ssn",
+    "urn:oid:2.16.840.1.113883.4.1",
+];
+
+/// LOINC codes of observations whose value is the patient's age.
+const AGE_CODES: &[&str] = &["30525-0", "21612-7", "29553-5"];
+
+/// Units of age quantities measured in years.
+const YEAR_UNITS: &[&str] = &["a", "y", "yr", "yrs", "year", "years"];
+
+/// Identifier categories of 45 CFR 164.514(b)(2)(i) (A) to (R). Ages over 89 are reported apart
+/// from the other date elements of (C).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SafeHarborIdentifier {
+    Name,
+    Geography,
+    Date,
+    Age,
+    Telephone,
+    Fax,
+    Email,
+    Ssn,
+    MedicalRecordNumber,
+    HealthPlanNumber,
+    AccountNumber,
+    LicenseNumber,
+    VehicleIdentifier,
+    DeviceIdentifier,
+    Url,
+    IpAddress,
+    Biometric,
+    Photo,
+    OtherIdentifier,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeidentificationAction {
+    /// The element was deleted.
+    Removed,
+    /// The element was reduced to what Safe Harbor allows: a year, the state, an age of 90 or older.
+    Generalized,
+    /// The element or the matches in its text were replaced by `***`.
+    Masked,
+    /// The element was replaced by a value carrying no information about the individual.
+    Replaced,
+}
+
+/// An element changed by the de-identification. The original value is not part of the report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeidentifiedElement {
+    pub identifier: SafeHarborIdentifier,
+    pub action: DeidentificationAction,
+    /// FHIRPath of the element, e.g. `Bundle.entry[0].resource.birthDate`.
+    pub expression: String,
+}
+
+struct TextPattern {
+    regex: Regex,
+    identifier: SafeHarborIdentifier,
+    action: DeidentificationAction,
+    /// Replacement of each match, may refer to the `keep`, `year`, `unit` and `rest` groups.
+    replacement: &'static str,
+}
+
+lazy_static::lazy_static! {
+    /// Identifiers found in free text, in the order they are applied.
+    static ref TEXT_PATTERNS: Vec<TextPattern> = {
+        use DeidentificationAction::*;
+        use SafeHarborIdentifier::*;
+        // Label followed by a value containing a digit.
+        let labeled = |labels: &str| {
+            format!(r"(?i)(?P<keep>\b(?:{labels})\s*[:#]?\s*)[a-z0-9-]*\d[a-z0-9-]*")
+        };
+        let patterns: Vec<(String, SafeHarborIdentifier, DeidentificationAction, &str)> = vec![
+            (r#"(?i)\b(?:https?|ftp)://[^\s<>"']+|\bwww\.[^\s<>"']+"#.to_string(), Url, Masked, MASK),
+            (r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b".to_string(), Email, Masked, MASK),
+            (
+                r"\b(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\b"
+                    .to_string(),
+                IpAddress,
+                Masked,
+                MASK,
+            ),
+            (
+                r"(?i)\b(?:[0-9a-f]{1,4}:){7}[0-9a-f]{1,4}\b|\b(?:[0-9a-f]{1,4}:){1,7}:(?:[0-9a-f]{1,4}(?::[0-9a-f]{1,4})*)?"
+                    .to_string(),
+                IpAddress,
+                Masked,
+                MASK,
+            ),
+            (
+                labeled(r"SSN|social security (?:number|no\.?|#)"),
+                Ssn,
+                Masked,
+                "${keep}***",
+            ),
+            (
+                labeled(r"MRN|MR\s?#|medical record (?:number|no\.?|#)|chart (?:number|no\.?|#)|patient (?:id|number)"),
+                MedicalRecordNumber,
+                Masked,
+                "${keep}***",
+            ),
+            (
+                labeled(r"(?:member|subscriber|beneficiary|medicare|medicaid|insurance) (?:id|number|no\.?|#)|policy (?:number|no\.?|#)|group (?:number|no\.?|#)"),
+                HealthPlanNumber,
+                Masked,
+                "${keep}***",
+            ),
+            (
+                labeled(r"account (?:number|no\.?|#)|acct\.?\s?(?:number|no\.?|#)?"),
+                AccountNumber,
+                Masked,
+                "${keep}***",
+            ),
+            (
+                labeled(r"licen[cs]e (?:number|no\.?|#)|certificate (?:number|no\.?|#)|driver'?s licen[cs]e|DL\s?#|passport (?:number|no\.?|#)?"),
+                LicenseNumber,
+                Masked,
+                "${keep}***",
+            ),
+            (
+                labeled(r"VIN|vehicle (?:id|identification number)|licen[cs]e plate|plate (?:number|no\.?|#)"),
+                VehicleIdentifier,
+                Masked,
+                "${keep}***",
+            ),
+            (
+                labeled(r"serial (?:number|no\.?|#)|S/N|UDI|device (?:id|identifier)"),
+                DeviceIdentifier,
+                Masked,
+                "${keep}***",
+            ),
+            (
+                r"(?i)(?P<keep>\bfax(?:\s*(?:no\.?|number|#))?\s*:?\s*)(?:\+?1[-. ]?)?(?:\(\d{3}\)\s?|\d{3}[-. ])\d{3}[-. ]\d{4}\b"
+                    .to_string(),
+                Fax,
+                Masked,
+                "${keep}***",
+            ),
+            (r"\b\d{3}-\d{2}-\d{4}\b".to_string(), Ssn, Masked, MASK),
+            (
+                r"(?:\+?1[-. ]?)?(?:\(\d{3}\)\s?|\b\d{3}[-. ])\d{3}[-. ]\d{4}\b".to_string(),
+                Telephone,
+                Masked,
+                MASK,
+            ),
+            (
+                r"\b(?P<year>\d{4})-(?:0[1-9]|1[0-2])(?:-(?:0[1-9]|[12]\d|3[01]))?(?:T[0-9:.]+(?:Z|[+-]\d{2}:?\d{2})?)?\b"
+                    .to_string(),
+                Date,
+                Generalized,
+                "${year}",
+            ),
+            (
+                r"\b(?:0?[1-9]|1[0-2])[/-](?:0?[1-9]|[12]\d|3[01])[/-](?P<year>(?:19|20)\d{2})\b"
+                    .to_string(),
+                Date,
+                Generalized,
+                "${year}",
+            ),
+            (
+                r"\b(?:0?[1-9]|1[0-2])/(?:0?[1-9]|[12]\d|3[01])/\d{2}\b".to_string(),
+                Date,
+                Masked,
+                MASK,
+            ),
+            (
+                r"(?i)\b(?:\d{1,2}(?:st|nd|rd|th)?\s+)?(?:jan(?:uary)?|feb(?:ruary)?|mar(?:ch)?|apr(?:il)?|may|june?|july?|aug(?:ust)?|sep(?:t(?:ember)?)?|oct(?:ober)?|nov(?:ember)?|dec(?:ember)?)\.?(?:\s+\d{1,2}(?:st|nd|rd|th)?)?,?\s+(?P<year>\d{4})\b"
+                    .to_string(),
+                Date,
+                Generalized,
+                "${year}",
+            ),
+            (
+                r"(?i)\b(?:9\d|1[0-4]\d)(?:\.\d+)?(?P<unit>[- ]?(?:years?|yrs?|y/?o)\b(?:[- ]old)?)".to_string(),
+                Age,
+                Generalized,
+                "90+${unit}",
+            ),
+            (
+                r"(?i)(?P<keep>\baged?\s*:?\s*)(?:9\d|1[0-4]\d)(?:\.\d+)?(?P<rest>[^+\d]|$)".to_string(),
+                Age,
+                Generalized,
+                "${keep}90+${rest}",
+            ),
+            (
+                r"\b\d{1,6}\s+(?:[A-Z][A-Za-z]*\.?\s+){1,3}(?:Street|St|Avenue|Ave|Road|Rd|Boulevard|Blvd|Lane|Ln|Drive|Dr|Court|Ct|Way|Place|Pl|Terrace|Circle|Highway|Hwy|Parkway|Pkwy)\b\.?"
+                    .to_string(),
+                Geography,
+                Masked,
+                MASK,
+            ),
+            (r"(?i)\bP\.?\s?O\.?\s+Box\s+\d+".to_string(), Geography, Masked, MASK),
+            (
+                r"(?P<keep>\b[A-Z]{2},?\s+)\d{5}(?:-\d{4})?\b".to_string(),
+                Geography,
+                Masked,
+                "${keep}***",
+            ),
+        ];
+        patterns
+            .into_iter()
+            .map(|(pattern, identifier, action, replacement)| TextPattern {
+                regex: Regex::new(&pattern).expect("Safe Harbor patterns are valid"),
+                identifier,
+                action,
+                replacement,
+            })
+            .collect()
+    };
+}
+
+/// De-identify a bundle, bare or in the `{"bundle": {...}}` envelope, in place and return the
+/// elements that were changed. Ages are computed against `reference_year`, the year of the
+/// conversion. Applying it twice changes nothing the second time.
+///
+/// - Names of the patient and related persons are masked, their contacts, telecoms and photos
+///   removed and their addresses reduced to state and country.
+/// - Birth dates indicating an age over 89 are removed, age quantities over 89 years become `>= 90`
+///   and every other date is reduced to its year.
+/// - Identifiers are removed. The Patient's are replaced by their keyed pseudonyms when a
+///   `pseudonymizer` is given, by a single `urn:uuid` identifier otherwise.
+///   Resources are renumbered from their position in the bundle and references rewritten.
+/// - Attachments and narratives are removed.
+/// - Values removed from structured elements and SSN, phone, fax, email, URL, IP address, record
+///   number, date, age and street address patterns are masked in free text.
+pub fn deidentify_bundle(
+    bundle: &mut Value,
+    reference_year: i64,
+    pseudonymizer: Option<&mut Pseudonymizer>,
+) -> Vec<DeidentifiedElement> {
+    apply_safe_harbor(bundle, reference_year, pseudonymizer, false)
+}
+
+/// Check a bundle against the Safe Harbor rules of `deidentify_bundle` without changing it. Every
+/// element the rules would change is reported as an error. Pseudonyms cannot be recomputed without
+/// the key, so they pass as long as they have the shape of one.
+pub fn check_safe_harbor(bundle: &Value, reference_year: i64) -> OperationOutcome {
+    let mut copy = bundle.clone();
+    let issue = apply_safe_harbor(&mut copy, reference_year, None, true)
+        .into_iter()
+        .map(|element| OperationOutcomeIssue {
+            severity: IssueSeverity::Error,
+            code: IssueType::Security,
+            diagnostics: format!(
+                "{:?} identifier must be {:?}",
+                element.identifier, element.action
+            )
+            .to_lowercase(),
+            expression: vec![element.expression],
+        })
+        .collect();
+    OperationOutcome {
+        resource_type: "OperationOutcome".to_string(),
+        issue,
+    }
+}
+
+fn apply_safe_harbor(
+    bundle: &mut Value,
+    reference_year: i64,
+    pseudonymizer: Option<&mut Pseudonymizer>,
+    keep_pseudonyms: bool,
+) -> Vec<DeidentifiedElement> {
+    let bundle = if bundle.get("bundle").is_some() {
+        &mut bundle["bundle"]
+    } else {
+        bundle
+    };
+    let Some(bundle) = bundle.as_object_mut() else {
+        return Vec::new();
+    };
+    let mut engine = SafeHarbor {
+        reference_year,
+        pseudonymizer,
+        keep_pseudonyms,
+        known: Vec::new(),
+        changed: Vec::new(),
+    };
+    engine.bundle(bundle);
+    engine.changed
+}
+
+struct SafeHarbor<'a> {
+    reference_year: i64,
+    pseudonymizer: Option<&'a mut Pseudonymizer>,
+    /// Whether pseudonyms found in the bundle are left as they are, only when checking it.
+    keep_pseudonyms: bool,
+    /// Values removed from structured elements, masked wherever they appear in free text.
+    known: Vec<(String, SafeHarborIdentifier)>,
+    changed: Vec<DeidentifiedElement>,
+}
+
+impl SafeHarbor<'_> {
+    fn record(
+        &mut self,
+        identifier: SafeHarborIdentifier,
+        action: DeidentificationAction,
+        expression: String,
+    ) {
+        let element = DeidentifiedElement {
+            identifier,
+            action,
+            expression,
+        };
+        if !self.changed.contains(&element) {
+            self.changed.push(element);
+        }
+    }
+
+    fn know(&mut self, value: Option<&Value>, identifier: SafeHarborIdentifier) {
+        match value {
+            Some(Value::String(text)) if text.chars().count() >= 2 && text != MASK => {
+                self.known.push((text.clone(), identifier));
+            }
+            Some(Value::Array(items)) => {
+                for item in items {
+                    self.know(Some(item), identifier);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn bundle(&mut self, bundle: &mut Map<String, Value>) {
+        for key in ["id", "identifier"] {
+            if bundle.remove(key).is_some() {
+                self.record(
+                    SafeHarborIdentifier::OtherIdentifier,
+                    DeidentificationAction::Removed,
+                    format!("Bundle.{key}"),
+                );
+            }
+        }
+
+        let references = self.renumber(bundle);
+        if let Some(entries) = bundle.get_mut("entry").and_then(|e| e.as_array_mut()) {
+            for (index, entry) in entries.iter_mut().enumerate() {
+                if let Some(resource) = entry.get_mut("resource").and_then(|r| r.as_object_mut()) {
+                    let path = format!("Bundle.entry[{index}].resource");
+                    self.resource(resource, &path, &references);
+                }
+            }
+        }
+
+        // Free text is scanned once every structured value has been collected.
+        let known = self.known_patterns();
+        for (key, value) in bundle.iter_mut() {
+            self.text(value, key, &format!("Bundle.{key}"), &known);
+        }
+    }
+
+    /// Give every resource an id derived from its position alone, and return the new reference of
+    /// each way a resource could be referenced before.
+    fn renumber(&mut self, bundle: &mut Map<String, Value>) -> HashMap<String, String> {
+        let mut references = HashMap::new();
+        let Some(entries) = bundle.get_mut("entry").and_then(|e| e.as_array_mut()) else {
+            return references;
+        };
+        for (index, entry) in entries.iter_mut().enumerate() {
+            let Some(entry) = entry.as_object_mut() else {
+                continue;
+            };
+            let id = derived_uuid("safe-harbor", &[index.to_string()]);
+            let full_url = format!("urn:uuid:{id}");
+            if let Some(old) = entry.get("fullUrl").and_then(|u| u.as_str()) {
+                references.insert(old.to_string(), full_url.clone());
+            }
+            let mut changed = replace(entry, "fullUrl", json!(full_url));
+            if let Some(resource) = entry.get_mut("resource").and_then(|r| r.as_object_mut()) {
+                if let (Some(resource_type), Some(old)) = (
+                    resource.get("resourceType").and_then(|t| t.as_str()),
+                    resource.get("id").and_then(|i| i.as_str()),
+                ) {
+                    references.insert(format!("{resource_type}/{old}"), full_url.clone());
+                }
+                changed |= replace(resource, "id", json!(id));
+            }
+            if changed {
+                self.record(
+                    SafeHarborIdentifier::OtherIdentifier,
+                    DeidentificationAction::Replaced,
+                    format!("Bundle.entry[{index}].resource.id"),
+                );
+            }
+        }
+        references
+    }
+
+    fn resource(
+        &mut self,
+        resource: &mut Map<String, Value>,
+        path: &str,
+        references: &HashMap<String, String>,
+    ) {
+        let resource_type = resource
+            .get("resourceType")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        // The narrative repeats the resource in free-form XHTML.
+        if resource.get("text").is_some_and(|t| t.get("div").is_some()) {
+            resource.remove("text");
+            self.record(
+                SafeHarborIdentifier::OtherIdentifier,
+                DeidentificationAction::Removed,
+                format!("{path}.text"),
+            );
+        }
+
+        if PERSON_RESOURCES.contains(&resource_type.as_str()) {
+            self.person(resource, path);
+        }
+
+        let is_patient = resource_type == "Patient";
+        if is_patient {
+            // Every identifier is replaced by its pseudonym or, without a pseudonym key, dropped for
+            // a local identifier. Pseudonyms of the input are dropped too: only the enclave may
+            // issue them, and one copied from another bundle would link this one to its patient.
+            let mut patient_identifiers: Vec<Value> = resource
+                .get("identifier")
+                .and_then(|i| i.as_array())
+                .into_iter()
+                .flatten()
+                .filter(|identifier| self.keep_pseudonyms && is_pseudonym(identifier))
+                .cloned()
+                .collect();
+            if let Some(pseudonymizer) = self.pseudonymizer.as_deref_mut() {
+                for identifier in pseudonymizer.patient_identifiers(resource.get("identifier")) {
+                    if !patient_identifiers.contains(&identifier) {
+                        patient_identifiers.push(identifier);
+                    }
+                }
+            }
+            if patient_identifiers.is_empty() {
+                let id = resource
+                    .get("id")
+                    .and_then(|i| i.as_str())
+                    .unwrap_or_default();
+                patient_identifiers.push(
+                    json!({ "system": "urn:ietf:rfc:3986", "value": format!("urn:uuid:{id}") }),
+                );
+            }
+            let patient_identifiers = Value::Array(patient_identifiers);
+            if resource.get("identifier") != Some(&patient_identifiers) {
+                if let Some(identifiers) =
+                    resource.insert("identifier".to_string(), patient_identifiers)
+                {
+                    self.identifiers(
+                        &identifiers,
+                        &format!("{path}.identifier"),
+                        SafeHarborIdentifier::MedicalRecordNumber,
+                        DeidentificationAction::Replaced,
+                    );
+                }
+            }
+        }
+
+        if resource_type == "Observation" && is_age_observation(resource) {
+            if let Some(quantity) = resource.get_mut("valueQuantity") {
+                if aggregate_age(quantity) {
+                    self.record(
+                        SafeHarborIdentifier::Age,
+                        DeidentificationAction::Generalized,
+                        format!("{path}.valueQuantity"),
+                    );
+                }
+            }
+        }
+
+        self.elements(resource, path, references, is_patient);
+    }
+
+    /// Masks the demographics of a Patient, RelatedPerson or Person.
+    fn person(&mut self, person: &mut Map<String, Value>, path: &str) {
+        let masked = json!([{ "family": MASK, "given": [MASK] }]);
+        if let Some(names) = person
+            .get("name")
+            .filter(|names| **names != masked)
+            .cloned()
+        {
+            self.names(&names);
+            person.insert("name".to_string(), masked);
+            self.record(
+                SafeHarborIdentifier::Name,
+                DeidentificationAction::Masked,
+                format!("{path}.name"),
+            );
+        }
+
+        if let Some(contacts) = person.remove("contact") {
+            for contact in contacts.as_array().into_iter().flatten() {
+                if let Some(name) = contact.get("name") {
+                    self.names(&json!([name]));
+                }
+            }
+            self.record(
+                SafeHarborIdentifier::Name,
+                DeidentificationAction::Removed,
+                format!("{path}.contact"),
+            );
+        }
+
+        if let Some(telecom) = person.remove("telecom") {
+            for (index, contact_point) in telecom.as_array().into_iter().flatten().enumerate() {
+                let identifier = match contact_point.get("system").and_then(|s| s.as_str()) {
+                    Some("fax") => SafeHarborIdentifier::Fax,
+                    Some("email") => SafeHarborIdentifier::Email,
+                    Some("url") => SafeHarborIdentifier::Url,
+                    _ => SafeHarborIdentifier::Telephone,
+                };
+                self.know(contact_point.get("value"), identifier);
+                self.record(
+                    identifier,
+                    DeidentificationAction::Removed,
+                    format!("{path}.telecom[{index}]"),
+                );
+            }
+        }
+
+        if let Some(addresses) = person.get_mut("address").and_then(|a| a.as_array_mut()) {
+            let mut generalized = Vec::new();
+            let mut removed = Vec::new();
+            for (index, address) in addresses.iter_mut().enumerate() {
+                let Some(address) = address.as_object_mut() else {
+                    continue;
+                };
+                let before = address.len();
+                address.retain(|key, value| {
+                    let keep = key == "state" || key == "country";
+                    if !keep
+                        && ["line", "city", "district", "postalCode", "text"]
+                            .contains(&key.as_str())
+                    {
+                        removed.push(value.clone());
+                    }
+                    keep
+                });
+                if address.len() != before {
+                    generalized.push(index);
+                }
+            }
+            addresses.retain(|a| a.as_object().is_none_or(|a| !a.is_empty()));
+            for value in removed {
+                self.know(Some(&value), SafeHarborIdentifier::Geography);
+            }
+            for index in generalized {
+                self.record(
+                    SafeHarborIdentifier::Geography,
+                    DeidentificationAction::Generalized,
+                    format!("{path}.address[{index}]"),
+                );
+            }
+        }
+
+        if person.remove("photo").is_some() {
+            self.record(
+                SafeHarborIdentifier::Photo,
+                DeidentificationAction::Removed,
+                format!("{path}.photo"),
+            );
+        }
+
+        let birth_year = person
+            .get("birthDate")
+            .and_then(|d| d.as_str())
+            .and_then(parse_fhir_date)
+            .map(|d| d.year);
+        if birth_year.is_some_and(|year| self.reference_year - year > 89) {
+            person.remove("birthDate");
+            self.record(
+                SafeHarborIdentifier::Age,
+                DeidentificationAction::Removed,
+                format!("{path}.birthDate"),
+            );
+        }
+    }
+
+    fn names(&mut self, names: &Value) {
+        for name in names.as_array().into_iter().flatten() {
+            for part in ["family", "given", "text"] {
+                self.know(name.get(part), SafeHarborIdentifier::Name);
+            }
+        }
+    }
+
+    fn identifiers(
+        &mut self,
+        identifiers: &Value,
+        path: &str,
+        default: SafeHarborIdentifier,
+        action: DeidentificationAction,
+    ) {
+        let identifiers = match identifiers {
+            Value::Array(items) => items.iter().collect(),
+            other => vec![other],
+        };
+        for (index, identifier) in identifiers.into_iter().enumerate() {
+            if self.keep_pseudonyms && is_pseudonym(identifier) {
+                continue;
+            }
+            let kind = identifier_kind(identifier, default);
+            // Short values would mask unrelated numbers in free text.
+            if identifier
+                .get("value")
+                .and_then(|v| v.as_str())
+                .is_some_and(|v| v.chars().count() >= 4)
+            {
+                self.know(identifier.get("value"), kind);
+            }
+            self.record(kind, action, format!("{path}[{index}]"));
+        }
+    }
+
+    /// Identifiers, references, attachments and ages at any depth of a resource.
+    fn elements(
+        &mut self,
+        object: &mut Map<String, Value>,
+        path: &str,
+        references: &HashMap<String, String>,
+        keep_identifier: bool,
+    ) {
+        if !keep_identifier {
+            if let Some(identifiers) = object.remove("identifier") {
+                self.identifiers(
+                    &identifiers,
+                    &format!("{path}.identifier"),
+                    SafeHarborIdentifier::OtherIdentifier,
+                    DeidentificationAction::Removed,
+                );
+            }
+        }
+
+        for key in ["serialNumber", "udiCarrier"] {
+            if object.remove(key).is_some() {
+                self.record(
+                    SafeHarborIdentifier::DeviceIdentifier,
+                    DeidentificationAction::Removed,
+                    format!("{path}.{key}"),
+                );
+            }
+        }
+
+        if let Some(reference) = object.get("reference").and_then(|r| r.as_str()) {
+            match references.get(reference) {
+                Some(new) => {
+                    let new = new.clone();
+                    object.insert("reference".to_string(), json!(new));
+                }
+                None if points_to_person(reference) => {
+                    object.remove("reference");
+                    self.record(
+                        SafeHarborIdentifier::OtherIdentifier,
+                        DeidentificationAction::Removed,
+                        format!("{path}.reference"),
+                    );
+                }
+                None => {}
+            }
+        }
+
+        // Attachment contents cannot be checked: images may show the face, other media may hold
+        // biometrics such as voice prints.
+        if let Some(content_type) = object.get("contentType").and_then(|c| c.as_str()) {
+            let identifier = if content_type.starts_with("image/") {
+                SafeHarborIdentifier::Photo
+            } else if content_type.starts_with("audio/") || content_type.starts_with("video/") {
+                SafeHarborIdentifier::Biometric
+            } else {
+                SafeHarborIdentifier::OtherIdentifier
+            };
+            for key in ["data", "url"] {
+                if object.remove(key).is_some() {
+                    self.record(
+                        identifier,
+                        DeidentificationAction::Removed,
+                        format!("{path}.{key}"),
+                    );
+                }
+            }
+        }
+
+        for (key, value) in object.iter_mut() {
+            let child_path = format!("{path}.{key}");
+            // onsetAge, abatementAge, ...
+            if key.ends_with("Age") && aggregate_age(value) {
+                self.record(
+                    SafeHarborIdentifier::Age,
+                    DeidentificationAction::Generalized,
+                    child_path.clone(),
+                );
+            }
+            match value {
+                Value::Object(child) => self.elements(child, &child_path, references, false),
+                Value::Array(items) => {
+                    for (index, item) in items.iter_mut().enumerate() {
+                        if let Some(child) = item.as_object_mut() {
+                            let item_path = format!("{child_path}[{index}]");
+                            self.elements(child, &item_path, references, false);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Whole-word patterns of the known values, longest first.
+    fn known_patterns(&self) -> Vec<(Regex, SafeHarborIdentifier)> {
+        let mut known = self.known.clone();
+        known.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        known.dedup_by(|a, b| a.0 == b.0);
+        known
+            .into_iter()
+            .map(|(value, identifier)| {
+                let escaped = regex::escape(&value);
+                let word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+                let pattern = match (word(value.chars().next()), word(value.chars().last())) {
+                    (true, true) => format!(r"\b{escaped}\b"),
+                    (true, false) => format!(r"\b{escaped}"),
+                    (false, true) => format!(r"{escaped}\b"),
+                    (false, false) => escaped,
+                };
+                let regex = Regex::new(&pattern).expect("escaped values are valid patterns");
+                (regex, identifier)
+            })
+            .collect()
+    }
+
+    /// Generalize dates and mask identifiers in every free-text string below `value`.
+    fn text(
+        &mut self,
+        value: &mut Value,
+        key: &str,
+        path: &str,
+        known: &[(Regex, SafeHarborIdentifier)],
+    ) {
+        match value {
+            Value::String(text) if !STRUCTURAL_ELEMENTS.contains(&key) => {
+                match parse_fhir_date(text) {
+                    Some(date) if date.month.is_some() => {
+                        *text = format!("{:04}", date.year);
+                        self.record(
+                            SafeHarborIdentifier::Date,
+                            DeidentificationAction::Generalized,
+                            path.to_string(),
+                        );
+                    }
+                    Some(_) => {}
+                    None => self.redact(text, path, known),
+                }
+            }
+            Value::Array(items) => {
+                for (index, item) in items.iter_mut().enumerate() {
+                    self.text(item, key, &format!("{path}[{index}]"), known);
+                }
+            }
+            Value::Object(object) => {
+                for (child_key, child) in object.iter_mut() {
+                    self.text(child, child_key, &format!("{path}.{child_key}"), known);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn redact(&mut self, text: &mut String, path: &str, known: &[(Regex, SafeHarborIdentifier)]) {
+        let known = known
+            .iter()
+            .map(|(regex, identifier)| (regex, *identifier, DeidentificationAction::Masked, MASK));
+        let patterns = TEXT_PATTERNS
+            .iter()
+            .map(|p| (&p.regex, p.identifier, p.action, p.replacement));
+        for (regex, identifier, action, replacement) in known.chain(patterns) {
+            if regex.is_match(text) {
+                *text = regex.replace_all(text, replacement).into_owned();
+                self.record(identifier, action, path.to_string());
+            }
+        }
+    }
+}
+
+/// Insert `value` under `key` and return whether it changed.
+fn replace(object: &mut Map<String, Value>, key: &str, value: Value) -> bool {
+    if object.get(key) == Some(&value) {
+        return false;
+    }
+    object.insert(key.to_string(), value);
+    true
+}
+
+/// Category of an identifier from its system or type, `default` when neither tells.
+fn identifier_kind(identifier: &Value, default: SafeHarborIdentifier) -> SafeHarborIdentifier {
+    let system = identifier.get("system").and_then(|s| s.as_str());
+    if system.is_some_and(|s| SSN_SYSTEMS.contains(&s)) {
+        return SafeHarborIdentifier::Ssn;
+    }
+    let type_code = identifier
+        .pointer("/type/coding/0/code")
+        .and_then(|c| c.as_str());
+    match type_code {
+        Some("SS") => SafeHarborIdentifier::Ssn,
+        Some("MR") => SafeHarborIdentifier::MedicalRecordNumber,
+        Some("MB") | Some("SN") | Some("MA") | Some("MC") => SafeHarborIdentifier::HealthPlanNumber,
+        Some("AN") => SafeHarborIdentifier::AccountNumber,
+        Some("DL") | Some("PPN") => SafeHarborIdentifier::LicenseNumber,
+        Some("UDI") | Some("SNO") => SafeHarborIdentifier::DeviceIdentifier,
+        _ => default,
+    }
+}
+
+/// Whether an unresolved reference points to a person, e.g. `Patient/MRN123`.
+fn points_to_person(reference: &str) -> bool {
+    PERSON_RESOURCES.iter().any(|resource_type| {
+        reference.starts_with(&format!("{resource_type}/"))
+            || reference.contains(&format!("/{resource_type}/"))
+    })
+}
+
+fn is_age_observation(observation: &Map<String, Value>) -> bool {
+    observation
+        .get("code")
+        .and_then(|c| c.get("coding"))
+        .and_then(|c| c.as_array())
+        .is_some_and(|codings| {
+            codings.iter().any(|c| {
+                c.get("code")
+                    .and_then(|code| code.as_str())
+                    .is_some_and(|code| AGE_CODES.contains(&code))
+            })
+        })
+}
+
+/// Replace an age quantity over 89 years by `>= 90` and return whether it changed. A quantity
+/// without a unit is taken to be in years.
+fn aggregate_age(quantity: &mut Value) -> bool {
+    let Some(quantity) = quantity.as_object_mut() else {
+        return false;
+    };
+    let unit = quantity
+        .get("code")
+        .or_else(|| quantity.get("unit"))
+        .and_then(|u| u.as_str())
+        .map(str::to_lowercase);
+    if unit.is_some_and(|u| !YEAR_UNITS.contains(&u.as_str())) {
+        return false;
+    }
+    let Some(value) = quantity.get("value").and_then(|v| v.as_f64()) else {
+        return false;
+    };
+    let aggregated = quantity.get("value") == Some(&json!(90))
+        && quantity.get("comparator").and_then(|c| c.as_str()) == Some(">=");
+    if value <= 89.0 || aggregated {
+        return false;
+    }
+    quantity.insert("value".to_string(), json!(90));
+    quantity.insert("comparator".to_string(), json!(">="));
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Bundle of one Observation with the given fields.
+    fn observation(fields: Value) -> Value {
+        let mut resource = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "2093-3" }] },
+        });
+        resource
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        json!({ "resourceType": "Bundle", "entry": [{ "resource": resource }] })
+    }
+
+    /// Free text of an Observation note after de-identification, and the categories reported.
+    fn redacted(text: &str) -> (String, Vec<SafeHarborIdentifier>) {
+        let mut bundle = observation(json!({ "note": [{ "text": text }] }));
+        let changed = deidentify_bundle(&mut bundle, 2025, None);
+        let text = bundle["entry"][0]["resource"]["note"][0]["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let identifiers = changed
+            .iter()
+            .filter(|e| e.expression.ends_with(".note[0].text"))
+            .map(|e| e.identifier)
+            .collect();
+        (text, identifiers)
+    }
+
+    #[test]
+    fn test_safe_harbor_deidentification() {
+        let mut bundle = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "id": "bundle-jane-doe",
+                "type": "collection",
+                "entry": [
+                    {
+                        "fullUrl": "urn:uuid:patient-jane",
+                        "resource": {
+                            "resourceType": "Patient",
+                            "id": "patient-jane",
+                            "identifier": [
+                                { "system": "urn:oid:2.16.840.1.113883.19.5", "value": "MRN123456" },
+                                { "system": "http://hl7.org/fhir/sid/us-ssn", "value": "999-62-4431" }
+                            ],
+                            "name": [{ "use": "official", "family": "Doe", "given": ["Jane", "Q"] }],
+                            "telecom": [
+                                { "system": "phone", "value": "(617) 555-0100" },
+                                { "system": "email", "value": "jane@example.com" }
+                            ],
+                            "gender": "female",
+                            "birthDate": "1930-02-01",
+                            "address": [{ "line": ["1 Main St"], "city": "Boston", "state": "MA", "postalCode": "02101", "country": "US" }],
+                            "photo": [{ "contentType": "image/jpeg", "data": "AAAA" }]
+                        }
+                    },
+                    {
+                        "fullUrl": "urn:uuid:condition-1",
+                        "resource": {
+                            "resourceType": "Condition",
+                            "id": "condition-1",
+                            "identifier": [{ "system": "http://hospital.example/visits", "value": "V100" }],
+                            "clinicalStatus": { "coding": [{ "system": "http://terminology.hl7.org/CodeSystem/condition-clinical", "code": "active" }] },
+                            "code": { "coding": [{ "system": "http://snomed.info/sct", "code": "44054006", "display": "Diabetes mellitus type 2" }] },
+                            "subject": { "reference": "Patient/patient-jane" },
+                            "onsetAge": { "value": 93, "unit": "years", "system": "http://unitsofmeasure.org", "code": "a" },
+                            "recordedDate": "2024-01-05T08:00:00-05:00",
+                            "note": [{ "text": "Jane Doe (MRN123456), 93 years old, seen on 01/05/2024 at 1 Main St, Boston. Call (617) 555-0100 or jane@example.com, portal https://portal.example/jane from 10.0.0.12. SSN 999-62-4431, member ID H12345." }]
+                        }
+                    },
+                    {
+                        "fullUrl": "urn:uuid:observation-1",
+                        "resource": {
+                            "resourceType": "Observation",
+                            "id": "observation-1",
+                            "status": "final",
+                            "category": [{ "coding": [{ "system": "http://terminology.hl7.org/CodeSystem/observation-category", "code": "laboratory" }] }],
+                            "code": { "coding": [{ "system": "http://loinc.org", "code": "2093-3", "display": "Cholesterol" }] },
+                            "subject": { "reference": "urn:uuid:patient-jane" },
+                            "effectiveDateTime": "2024-01-05",
+                            "valueQuantity": { "value": 185, "unit": "mg/dL", "system": "http://unitsofmeasure.org", "code": "mg/dL" },
+                            "performer": [{ "reference": "RelatedPerson/john-doe" }]
+                        }
+                    }
+                ]
+            }
+        });
+        let input = bundle.clone();
+
+        let deidentified = deidentify_bundle(&mut bundle, 2025, None);
+        let outcome = validate_profile(&bundle);
+        assert!(!outcome.has_errors(), "{}", outcome.error_summary());
+        assert!(!check_safe_harbor(&bundle, 2025).has_errors());
+
+        let resources = bundle_resources(&bundle);
+        let patient = &resources[0];
+        let patient_url = format!("urn:uuid:{}", patient["id"].as_str().unwrap());
+        assert_eq!(
+            patient["name"],
+            json!([{ "family": "***", "given": ["***"] }])
+        );
+        assert_eq!(
+            patient["identifier"],
+            json!([{ "system": "urn:ietf:rfc:3986", "value": patient_url }])
+        );
+        assert_eq!(
+            patient["address"],
+            json!([{ "state": "MA", "country": "US" }])
+        );
+        // Born in 1930, the birth year alone indicates an age over 89.
+        assert!(patient.get("birthDate").is_none());
+        for element in ["telecom", "photo"] {
+            assert!(patient.get(element).is_none());
+        }
+
+        let condition = &resources[1];
+        assert!(condition.get("identifier").is_none());
+        assert_eq!(condition["subject"]["reference"], patient_url.as_str());
+        assert_eq!(condition["recordedDate"], "2024");
+        assert_eq!(condition["onsetAge"]["value"], 90);
+        assert_eq!(condition["onsetAge"]["comparator"], ">=");
+        assert_eq!(
+            condition["note"][0]["text"],
+            "*** *** (***), 90+ years old, seen on 2024 at ***, ***. Call *** or ***, portal *** from ***. SSN ***, member ID ***."
+        );
+        assert_eq!(
+            condition["code"]["coding"][0]["display"],
+            "Diabetes mellitus type 2"
+        );
+
+        let observation = &resources[2];
+        assert_eq!(observation["subject"]["reference"], patient_url.as_str());
+        assert_eq!(observation["effectiveDateTime"], "2024");
+        // A reference to a person outside the bundle is dropped.
+        assert!(observation["performer"][0].get("reference").is_none());
+        assert!(bundle["bundle"].get("id").is_none());
+
+        // Every change is reported, without the removed values.
+        let has = |identifier, action, expression: &str| {
+            deidentified.iter().any(|e| {
+                e.identifier == identifier && e.action == action && e.expression == expression
+            })
+        };
+        assert!(has(
+            SafeHarborIdentifier::Name,
+            DeidentificationAction::Masked,
+            "Bundle.entry[0].resource.name"
+        ));
+        assert!(has(
+            SafeHarborIdentifier::Ssn,
+            DeidentificationAction::Replaced,
+            "Bundle.entry[0].resource.identifier[1]"
+        ));
+        assert!(has(
+            SafeHarborIdentifier::MedicalRecordNumber,
+            DeidentificationAction::Replaced,
+            "Bundle.entry[0].resource.identifier[0]"
+        ));
+        assert!(has(
+            SafeHarborIdentifier::Age,
+            DeidentificationAction::Removed,
+            "Bundle.entry[0].resource.birthDate"
+        ));
+        assert!(has(
+            SafeHarborIdentifier::Email,
+            DeidentificationAction::Removed,
+            "Bundle.entry[0].resource.telecom[1]"
+        ));
+        assert!(has(
+            SafeHarborIdentifier::IpAddress,
+            DeidentificationAction::Masked,
+            "Bundle.entry[1].resource.note[0].text"
+        ));
+        assert!(has(
+            SafeHarborIdentifier::Date,
+            DeidentificationAction::Generalized,
+            "Bundle.entry[2].resource.effectiveDateTime"
+        ));
+        let report = serde_json::to_string(&deidentified).unwrap();
+        for value in ["Doe", "MRN123456", "4431", "Boston", "555-0100"] {
+            assert!(!report.contains(value));
+        }
+
+        // The rules are deterministic and change nothing the second time.
+        let mut again = input.clone();
+        assert_eq!(deidentify_bundle(&mut again, 2025, None), deidentified);
+        assert_eq!(again, bundle);
+        assert!(deidentify_bundle(&mut again, 2025, None).is_empty());
+
+        // The check reports the identifiers of a bundle that was not de-identified.
+        let outcome = check_safe_harbor(&input, 2025);
+        assert!(outcome.has_errors());
+        assert_eq!(outcome.issue.len(), deidentified.len());
+        assert!(outcome.issue.iter().all(|i| i.code == IssueType::Security));
+    }
+
+    #[test]
+    fn test_aggregate_age() {
+        let aggregated = json!({ "value": 90, "comparator": ">=" });
+        let cases = [
+            (json!({ "value": 89.5, "unit": "years" }), true),
+            (json!({ "value": 92.25, "code": "a" }), true),
+            // A quantity without a unit is in years.
+            (json!({ "value": 95.0 }), true),
+            (json!({ "value": 90.0, "comparator": ">=" }), true),
+            (json!({ "value": 89.0, "unit": "years" }), false),
+            (json!({ "value": 89, "code": "a" }), false),
+            (json!({ "value": 1100, "code": "mo" }), false),
+            (json!({ "value": "95", "code": "a" }), false),
+            (aggregated.clone(), false),
+        ];
+        for (quantity, changed) in cases {
+            let mut value = quantity.clone();
+            assert_eq!(aggregate_age(&mut value), changed, "{quantity}");
+            if changed {
+                assert_eq!(value["value"], 90);
+                assert_eq!(value["comparator"], ">=");
+                assert!(!aggregate_age(&mut value));
+            } else {
+                assert_eq!(value, quantity);
+            }
+        }
+        assert!(!aggregate_age(&mut json!(95)));
+    }
+
+    #[test]
+    fn test_deidentify_ages() {
+        // Ages are aggregated wherever they are found: birth dates of any person, age
+        // observations whatever the position of their age code, and nested *Age elements.
+        let age_observation = |code: &str, quantity: Value| {
+            json!({ "resource": {
+                "resourceType": "Observation",
+                "status": "final",
+                "code": { "coding": [
+                    { "system": "http://example.org/local", "code": "AGE" },
+                    { "system": "http://loinc.org", "code": code }
+                ] },
+                "valueQuantity": quantity
+            } })
+        };
+        let mut bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [
+                { "resource": { "resourceType": "Patient", "id": "p1", "birthDate": "1936-05-01" } },
+                { "resource": { "resourceType": "RelatedPerson", "birthDate": "1935-12-31" } },
+                age_observation("21612-7", json!({ "value": 1100, "code": "mo" })),
+                age_observation("29553-5", json!({ "value": 91, "unit": "years" })),
+                age_observation("29463-7", json!({ "value": 95, "unit": "kg" })),
+                { "resource": {
+                    "resourceType": "FamilyMemberHistory",
+                    "condition": [
+                        { "code": { "text": "Stroke" }, "onsetAge": { "value": 93, "unit": "a" } },
+                        { "code": { "text": "Gout" }, "onsetAge": { "value": 60, "unit": "a" } }
+                    ]
+                } }
+            ]
+        });
+        let changed = deidentify_bundle(&mut bundle, 2025, None);
+        let resources = bundle_resources(&bundle);
+        // 89 years before the reference year is kept as a year, 90 is removed.
+        assert_eq!(resources[0]["birthDate"], "1936");
+        assert!(resources[1].get("birthDate").is_none());
+        assert_eq!(
+            resources[2]["valueQuantity"],
+            json!({ "value": 1100, "code": "mo" })
+        );
+        assert_eq!(
+            resources[3]["valueQuantity"],
+            json!({ "value": 90, "unit": "years", "comparator": ">=" })
+        );
+        assert_eq!(resources[4]["valueQuantity"]["value"], 95);
+        assert_eq!(resources[5]["condition"][1]["onsetAge"]["value"], 60);
+        let ages: Vec<&str> = changed
+            .iter()
+            .filter(|e| e.identifier == SafeHarborIdentifier::Age)
+            .map(|e| e.expression.as_str())
+            .collect();
+        assert_eq!(
+            ages,
+            [
+                "Bundle.entry[1].resource.birthDate",
+                "Bundle.entry[3].resource.valueQuantity",
+                "Bundle.entry[5].resource.condition[0].onsetAge"
+            ]
+        );
+        assert!(!check_safe_harbor(&bundle, 2025).has_errors());
+    }
+
+    #[test]
+    fn test_redact_text() {
+        use SafeHarborIdentifier::*;
+
+        // Measurements and version numbers are not identifiers.
+        for text in [
+            "Temp 98.6 F, HbA1c 7.2%, BP 120/80, weight 95.5 kg.",
+            "Interface v2.5.1 of 2024, 3 tablets of 0.25 mg.",
+            "Follow-up in 2 weeks, patient is 45 years old.",
+            "",
+        ] {
+            assert_eq!(redacted(text), (text.to_string(), Vec::new()), "{text:?}");
+        }
+
+        let cases: &[(&str, &str, &[SafeHarborIdentifier])] = &[
+            ("Seen 2024-01-05T08:15:30.25-05:00.", "Seen 2024.", &[Date]),
+            (
+                "Seen 01/05/2024 and 1/5/24.",
+                "Seen 2024 and ***.",
+                &[Date, Date],
+            ),
+            ("Seen on March 3rd, 2024.", "Seen on 2024.", &[Date]),
+            (
+                "Patient is 95.5 years old.",
+                "Patient is 90+ years old.",
+                &[Age],
+            ),
+            ("Aged 101.25, stable.", "Aged 90+, stable.", &[Age]),
+            ("Patient aged 93.", "Patient aged 90+.", &[Age]),
+            (
+                "Host 10.0.0.12, not 256.1.1.1.",
+                "Host ***, not 256.1.1.1.",
+                &[IpAddress],
+            ),
+            ("Call +1 (617) 555-0100.", "Call ***.", &[Telephone]),
+            ("Fax: 617-555-0199.", "Fax: ***.", &[Fax]),
+            (
+                "MRN: A-12345, acct # 998.",
+                "MRN: ***, acct # ***.",
+                &[MedicalRecordNumber, AccountNumber],
+            ),
+            (
+                "Lives at 12 Elm Street, Springfield, MA 01103-1234.",
+                "Lives at ***, Springfield, MA ***.",
+                &[Geography],
+            ),
+        ];
+        for (text, expected, identifiers) in cases {
+            let (text, reported) = redacted(text);
+            assert_eq!(text, *expected);
+            assert_eq!(reported, *identifiers, "{text:?}");
+            // Redacted text is left as it is the second time.
+            assert_eq!(redacted(&text), (text.clone(), Vec::new()));
+        }
+    }
+
+    #[test]
+    fn test_forged_pseudonym() {
+        use crate::app::pseudonym::{PATIENT_ID_SYSTEM, PSEUDONYM_SYSTEM};
+        use crate::app::validation::bundle_has_patient;
+
+        // A submitter copies the pseudonym of another patient into the input, hoping to have it
+        // signed as this bundle's.
+        let key = [7u8; 32];
+        let victim = Pseudonymizer::new(&key, None)
+            .unwrap()
+            .pseudonym(PATIENT_ID_SYSTEM, "patient-42");
+        let patient = |identifiers: Value| {
+            json!({
+                "resourceType": "Bundle",
+                "type": "collection",
+                "entry": [{ "resource": {
+                    "resourceType": "Patient",
+                    "id": "p1",
+                    "identifier": identifiers
+                } }]
+            })
+        };
+        let forged = json!({ "system": PSEUDONYM_SYSTEM, "value": victim });
+        let mrn = json!({ "system": "urn:oid:2.16.840.1.113883.19.5", "value": "MRN123456" });
+
+        // With the key, only the pseudonyms computed from this input remain.
+        let mut bundle = patient(json!([forged, mrn]));
+        let mut pseudonymizer = Pseudonymizer::new(&key, None).unwrap();
+        let changed = deidentify_bundle(&mut bundle, 2025, Some(&mut pseudonymizer));
+        let expected = Pseudonymizer::new(&key, None)
+            .unwrap()
+            .pseudonym("urn:oid:2.16.840.1.113883.19.5", "MRN123456");
+        assert_eq!(
+            bundle["entry"][0]["resource"]["identifier"],
+            json!([{ "system": PSEUDONYM_SYSTEM, "value": expected }])
+        );
+        assert!(changed.iter().any(|e| {
+            e.expression == "Bundle.entry[0].resource.identifier[0]"
+                && e.action == DeidentificationAction::Replaced
+        }));
+        assert_eq!(pseudonymizer.issued.len(), 1);
+        assert!(!bundle_has_patient(&bundle, "patient-42", Some(&key)));
+        assert!(!check_safe_harbor(&bundle, 2025).has_errors());
+
+        // A pseudonym alone is replaced by the local identifier, with or without the key.
+        for pseudonymizer in [None, Some(Pseudonymizer::new(&key, None).unwrap())] {
+            let mut bundle = patient(json!([forged]));
+            let mut pseudonymizer = pseudonymizer;
+            deidentify_bundle(&mut bundle, 2025, pseudonymizer.as_mut());
+            let resource = &bundle["entry"][0]["resource"];
+            let local = format!("urn:uuid:{}", resource["id"].as_str().unwrap());
+            assert_eq!(
+                resource["identifier"],
+                json!([{ "system": "urn:ietf:rfc:3986", "value": local }])
+            );
+            assert!(!bundle_has_patient(&bundle, "patient-42", Some(&key)));
+        }
+
+        // Pseudonyms are recomputed, not carried over, when the patient is submitted again.
+        let context = PatientContext {
+            patient_id: "patient-42".to_string(),
+            name: None,
+            birth_date: None,
+            gender: None,
+        };
+        let mut bundle = patient(json!([mrn]));
+        let mut pseudonymizer = Pseudonymizer::new(&key, Some(&context)).unwrap();
+        deidentify_bundle(&mut bundle, 2025, Some(&mut pseudonymizer));
+        assert!(bundle_has_patient(&bundle, "patient-42", Some(&key)));
+        let mut again = bundle.clone();
+        deidentify_bundle(&mut again, 2025, None);
+        assert!(!bundle_has_patient(&again, "patient-42", Some(&key)));
+    }
+}
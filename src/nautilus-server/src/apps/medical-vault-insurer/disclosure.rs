@@ -0,0 +1,217 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Selective disclosure of patient attributes from a FHIR bundle as an SD-JWT signed by the enclave.
+
+use super::*;
+use crate::sd_jwt::issue_sd_jwt;
+use crate::vc::did_key;
+use fastcrypto::traits::KeyPair;
+use serde_json::{Map, Value};
+
+/// Maximum number of FHIR paths accepted in a single request.
+const MAX_DISCLOSABLE_PATHS: usize = 64;
+
+/// Issue an SD-JWT over a patient's FHIR bundle where every attribute selected by
+/// `disclosable_paths` is an independently revealable claim. The bundle is either a plaintext
+/// bundle with the conversion attestation the enclave signed for it, see `resolve_bundle`, or a Seal
+/// encrypted bundle decrypted inside the enclave.
+pub async fn issue_patient_sd_jwt(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SdJwtRequest>,
+) -> Result<Json<SdJwtResponse>, EnclaveError> {
+    if request.disclosable_paths.is_empty() {
+        return Err(EnclaveError::GenericError(
+            "At least one disclosable path is required".to_string(),
+        ));
+    }
+    if request.disclosable_paths.len() > MAX_DISCLOSABLE_PATHS {
+        return Err(EnclaveError::GenericError(format!(
+            "At most {MAX_DISCLOSABLE_PATHS} disclosable paths are allowed"
+        )));
+    }
+
+    let (bundle, semantic_hash) = resolve_bundle(
+        state.eph_kp.public(),
+        request.bundle,
+        request.attestation,
+        request.encrypted_bundle,
+    )
+    .await?;
+
+    let disclosable = collect_disclosable_claims(&bundle, &request.disclosable_paths)?;
+    if disclosable.is_empty() {
+        return Err(EnclaveError::GenericError(
+            "No attributes in the bundle match the disclosable paths".to_string(),
+        ));
+    }
+
+    let mut claims = Map::new();
+    claims.insert(
+        "iss".to_string(),
+        Value::String(did_key(state.eph_kp.public())),
+    );
+    claims.insert(
+        "iat".to_string(),
+        Value::from(current_timestamp_ms()? / 1000),
+    );
+    claims.insert(
+        "semantic_hash".to_string(),
+        Value::String(semantic_hash.clone()),
+    );
+
+    let (sd_jwt, disclosures) = issue_sd_jwt(&state.eph_kp, claims, &disclosable)?;
+    info!(
+        "Issued SD-JWT with {} disclosable claims",
+        disclosures.len()
+    );
+
+    Ok(Json(SdJwtResponse {
+        sd_jwt,
+        semantic_hash,
+        disclosures,
+    }))
+}
+
+/// Evaluate every path against the bundle. Each matching resource yields one claim named
+/// `ResourceType[i].element...`, where `i` is the index of the resource among the resources of the
+/// same type, so that every attribute can be revealed on its own.
+pub fn collect_disclosable_claims(
+    bundle: &Value,
+    paths: &[String],
+) -> Result<Vec<(String, Value)>, EnclaveError> {
+    let resources = bundle_resources(bundle);
+    let mut claims = Vec::new();
+
+    for path in paths {
+        let (resource_type, elements) = path
+            .split_once('.')
+            .filter(|(rt, el)| !rt.is_empty() && !el.is_empty())
+            .ok_or_else(|| {
+                EnclaveError::GenericError(format!(
+                    "Invalid FHIR path {path}, expected ResourceType.element"
+                ))
+            })?;
+
+        let of_type = resources
+            .iter()
+            .filter(|r| r.get("resourceType").and_then(|t| t.as_str()) == Some(resource_type));
+        for (index, resource) in of_type.enumerate() {
+            let mut values = evaluate_elements(resource, elements);
+            let value = match values.len() {
+                0 => continue,
+                1 => values.remove(0).clone(),
+                _ => Value::Array(values.into_iter().cloned().collect()),
+            };
+            claims.push((format!("{resource_type}[{index}].{elements}"), value));
+        }
+    }
+    Ok(claims)
+}
+
+/// Walk a dotted element path, flattening arrays along the way as FHIRPath does.
+fn evaluate_elements<'a>(resource: &'a Value, elements: &str) -> Vec<&'a Value> {
+    let mut current = vec![resource];
+    for element in elements.split('.') {
+        current = current
+            .into_iter()
+            .filter_map(|v| v.get(element))
+            .flat_map(|v| match v {
+                Value::Array(items) => items.iter().collect::<Vec<_>>(),
+                other => vec![other],
+            })
+            .collect();
+    }
+    current
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_collect_disclosable_claims() {
+        let bundle = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "entry": [
+                    { "resource": { "resourceType": "Patient", "birthDate": "1980-02-01",
+                        "name": [{ "family": "Doe", "given": ["Jane", "A"] }] } },
+                    { "resource": { "resourceType": "Condition", "code": { "text": "Asthma" } } },
+                    { "resource": { "resourceType": "Condition", "code": { "text": "Diabetes" } } }
+                ]
+            }
+        });
+        let paths = vec![
+            "Patient.birthDate".to_string(),
+            "Patient.name.given".to_string(),
+            "Condition.code".to_string(),
+            "Observation.valueQuantity".to_string(),
+        ];
+        let claims = collect_disclosable_claims(&bundle, &paths).unwrap();
+        assert_eq!(
+            claims,
+            vec![
+                ("Patient[0].birthDate".to_string(), json!("1980-02-01")),
+                ("Patient[0].name.given".to_string(), json!(["Jane", "A"])),
+                ("Condition[0].code".to_string(), json!({ "text": "Asthma" })),
+                (
+                    "Condition[1].code".to_string(),
+                    json!({ "text": "Diabetes" })
+                ),
+            ]
+        );
+        assert!(collect_disclosable_claims(&bundle, &["Patient".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_collect_disclosable_claims_edges() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [
+                { "resource": { "resourceType": "Patient",
+                    "name": [{ "family": "Doe" }, { "family": "Roe", "given": ["Jo"] }] } },
+                { "resource": { "resourceType": "Condition" } },
+                { "resource": { "resourceType": "Condition",
+                    "code": { "coding": [{ "code": "J45" }, { "code": "E11.9" }] } } },
+                { "resource": { "resourceType": "Observation", "valueBoolean": false } }
+            ]
+        });
+        let claims = |paths: &[&str]| {
+            let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+            collect_disclosable_claims(&bundle, &paths)
+        };
+
+        // Arrays are flattened at every step, and a single value is not wrapped in an array.
+        assert_eq!(
+            claims(&["Patient.name.family", "Patient.name.given"]).unwrap(),
+            vec![
+                ("Patient[0].name.family".to_string(), json!(["Doe", "Roe"])),
+                ("Patient[0].name.given".to_string(), json!("Jo")),
+            ]
+        );
+        // Resources are numbered among those of their type, with or without the element.
+        assert_eq!(
+            claims(&["Condition.code.coding.code"]).unwrap(),
+            vec![(
+                "Condition[1].code.coding.code".to_string(),
+                json!(["J45", "E11.9"])
+            )]
+        );
+        // False values are claims like any other, missing ones are skipped.
+        assert_eq!(
+            claims(&["Observation.valueBoolean"]).unwrap(),
+            vec![("Observation[0].valueBoolean".to_string(), json!(false))]
+        );
+        assert!(claims(&["Encounter.period", "Patient.gender"])
+            .unwrap()
+            .is_empty());
+
+        for invalid in ["Patient", "Patient.", ".name", ""] {
+            assert!(claims(&[invalid]).is_err(), "{invalid:?}");
+        }
+        // One invalid path fails the request.
+        assert!(claims(&["Patient.name.family", "name"]).is_err());
+    }
+}
@@ -25,6 +25,12 @@ use sui_sdk_types::{
 };
 use tokio::sync::RwLock;
 
+use super::cache::LlmCache;
+use super::llm::LlmConfig;
+use super::pseudonym::{PseudonymSource, Pseudonymizer};
+use super::reidentification::{check_grant, verify_authority_grant, ReidentificationLog};
+use super::usage::{UsageLedger, UsageReport};
+
 lazy_static::lazy_static! {
     /// Configuration for Seal key servers, containing the Seal policy package ID, key server object
     /// IDs and its public keys, hardcoded here so they can be used to verify fetch key responses.
@@ -56,9 +62,37 @@ lazy_static::lazy_static! {
     /// Set when provisioning encrypted medical data.
     pub static ref SEAL_API_KEY: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
 
-    /// OpenRouter API key for LLM inference.
+    /// LLM provider configuration, hardcoded here as the provider is part of what the enclave
+    /// attests to.
+    pub static ref LLM_CONFIG: LlmConfig = {
+        let config_str = include_str!("llm_config.yaml");
+        serde_yaml::from_str(config_str)
+            .expect("Failed to parse llm_config.yaml")
+    };
+
+    /// Bundles of past LLM conversions, encrypted under a key generated on startup.
+    pub static ref LLM_CACHE: Arc<LlmCache> = Arc::new(LlmCache::new(LLM_CONFIG.cache_entries));
+
+    /// API key of the LLM provider for LLM inference.
     /// Set when /provision_openrouter_api_key is called.
     pub static ref OPENROUTER_API_KEY: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+
+    /// HMAC key mapping patient identifiers to pseudonyms.
+    /// Set when /provision_pseudonym_key is called.
+    pub static ref PSEUDONYM_KEY: Arc<RwLock<Option<Vec<u8>>>> = Arc::new(RwLock::new(None));
+
+    /// Source identifier of each pseudonym issued, only kept when re-identification was enabled
+    /// with the pseudonym key.
+    pub static ref REIDENTIFICATION_TABLE: Arc<RwLock<Option<HashMap<String, PseudonymSource>>>> =
+        Arc::new(RwLock::new(None));
+
+    /// Audit log of every re-identification since startup.
+    pub static ref REIDENTIFICATION_LOG: Arc<RwLock<ReidentificationLog>> =
+        Arc::new(RwLock::new(ReidentificationLog::default()));
+
+    /// Registered callers and the LLM usage charged to each caller and app since startup.
+    /// Callers are registered with /admin/register_caller.
+    pub static ref USAGE_LEDGER: Arc<RwLock<UsageLedger>> = Arc::new(RwLock::new(UsageLedger::default()));
 }
 
 /// Response for the ping endpoint
@@ -174,8 +208,8 @@ pub async fn complete_seal_key_load(
     }))
 }
 
-/// This endpoint decrypts an OpenRouter API key using cached Seal keys.
-/// The decrypted key is stored in OPENROUTER_API_KEY for LLM inference calls.
+/// This endpoint decrypts the API key of the LLM provider (OpenRouter by default) using cached Seal
+/// keys. The decrypted key is stored in OPENROUTER_API_KEY for LLM inference calls.
 pub async fn provision_openrouter_api_key(
     State(_state): State<Arc<AppState>>,
     Json(request): Json<ProvisionOpenRouterApiKeyRequest>,
@@ -187,7 +221,7 @@ pub async fn provision_openrouter_api_key(
         &cached_keys_read,
         &SEAL_CONFIG.server_pk_map,
     )
-    .map_err(|e| EnclaveError::GenericError(format!("Failed to decrypt OpenRouter API key: {e}")))?;
+    .map_err(|e| EnclaveError::GenericError(format!("Failed to decrypt LLM API key: {e}")))?;
 
     // Convert decrypted bytes to UTF-8 string.
     let api_key_str = String::from_utf8(api_key_bytes)
@@ -201,6 +235,147 @@ pub async fn provision_openrouter_api_key(
         status: "OK".to_string(),
     }))
 }
+
+/// This endpoint decrypts the pseudonym key using cached Seal keys and stores it in PSEUDONYM_KEY.
+/// Provisioning a new key forgets the pseudonyms issued under the previous one.
+pub async fn provision_pseudonym_key(
+    State(_state): State<Arc<AppState>>,
+    Json(request): Json<ProvisionPseudonymKeyRequest>,
+) -> Result<Json<ProvisionPseudonymKeyResponse>, EnclaveError> {
+    let cached_keys_read = CACHED_SEAL_KEYS.read().await;
+    let key = seal_decrypt_object(
+        &request.encrypted_object,
+        &cached_keys_read,
+        &SEAL_CONFIG.server_pk_map,
+    )
+    .map_err(|e| EnclaveError::GenericError(format!("Failed to decrypt pseudonym key: {e}")))?;
+
+    // Fail before replacing the current key.
+    Pseudonymizer::new(&key, None)?;
+
+    let mut key_guard = PSEUDONYM_KEY.write().await;
+    let mut table_guard = REIDENTIFICATION_TABLE.write().await;
+    *key_guard = Some(key);
+    *table_guard = request.enable_reidentification.then(HashMap::new);
+
+    Ok(Json(ProvisionPseudonymKeyResponse {
+        status: "OK".to_string(),
+    }))
+}
+
+/// Host-only endpoint returning the identifier a pseudonym was issued for. Only available when
+/// re-identification was enabled with the pseudonym key, and only for a grant of the pseudonym
+/// signed by a re-identification authority. Each lookup is appended to REIDENTIFICATION_LOG and
+/// returned with the signed head of the log.
+pub async fn reidentify(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ReidentifyRequest>,
+) -> Result<Json<ReidentifyResponse>, EnclaveError> {
+    let current_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| EnclaveError::GenericError(format!("Time error: {e}")))?
+        .as_millis() as u64;
+    // Only authority signatures are accepted: anyone can Seal encrypt a grant.
+    let authorized_by = verify_authority_grant(
+        &request.signed_grant,
+        &SEAL_CONFIG.reidentification_authorities,
+    )?;
+    let grant = request.signed_grant.response.data;
+    check_grant(&grant, &request.pseudonym, current_timestamp)?;
+
+    let table_guard = REIDENTIFICATION_TABLE.read().await;
+    let table = table_guard.as_ref().ok_or_else(|| {
+        EnclaveError::GenericError("Re-identification is not enabled".to_string())
+    })?;
+    let source = table
+        .get(&request.pseudonym.to_lowercase())
+        .ok_or_else(|| EnclaveError::GenericError("Unknown pseudonym".to_string()))?;
+
+    let mut log_guard = REIDENTIFICATION_LOG.write().await;
+    let audit_entry = log_guard.append(&grant, authorized_by, current_timestamp)?;
+    let audit_head = to_signed_response(
+        &state.eph_kp,
+        log_guard.head(),
+        current_timestamp,
+        IntentScope::ReidentificationLog as u8,
+    );
+
+    info!(
+        "Re-identified pseudonym {} for {}",
+        request.pseudonym, grant.requester
+    );
+    Ok(Json(ReidentifyResponse {
+        pseudonym: request.pseudonym,
+        system: source.system.clone(),
+        value: source.value.clone(),
+        audit_entry,
+        audit_head,
+    }))
+}
+
+/// Host-only endpoint returning the audit log of re-identifications with its head signed by the
+/// enclave.
+pub async fn reidentification_log(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ReidentificationLogResponse>, EnclaveError> {
+    let current_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| EnclaveError::GenericError(format!("Time error: {e}")))?
+        .as_millis() as u64;
+    let log_guard = REIDENTIFICATION_LOG.read().await;
+    Ok(Json(ReidentificationLogResponse {
+        entries: log_guard.entries().to_vec(),
+        head: to_signed_response(
+            &state.eph_kp,
+            log_guard.head(),
+            current_timestamp,
+            IntentScope::ReidentificationLog as u8,
+        ),
+    }))
+}
+
+/// Host-only endpoint registering a caller of /process_data under an app, with an optional budget.
+/// Returns the bearer token the caller authenticates with, revoking any previous one.
+pub async fn register_caller(
+    State(_state): State<Arc<AppState>>,
+    Json(request): Json<RegisterCallerRequest>,
+) -> Result<Json<RegisterCallerResponse>, EnclaveError> {
+    let token = USAGE_LEDGER.write().await.register_caller(
+        &request.caller_id,
+        &request.app,
+        request.budget,
+    )?;
+
+    info!(
+        "Registered caller {} of app {}",
+        request.caller_id, request.app
+    );
+    Ok(Json(RegisterCallerResponse {
+        caller_id: request.caller_id,
+        app: request.app,
+        token,
+    }))
+}
+
+/// Host-only endpoint setting the budget shared by the callers of an app.
+pub async fn set_app_budget(
+    State(_state): State<Arc<AppState>>,
+    Json(request): Json<AppBudgetRequest>,
+) -> Result<Json<AppBudgetResponse>, EnclaveError> {
+    USAGE_LEDGER
+        .write()
+        .await
+        .set_app_budget(&request.app, request.budget);
+
+    Ok(Json(AppBudgetResponse {
+        status: "OK".to_string(),
+    }))
+}
+
+/// Host-only endpoint returning the LLM usage per caller, per app and overall.
+pub async fn usage_report(State(_state): State<Arc<AppState>>) -> Json<UsageReport> {
+    Json(USAGE_LEDGER.read().await.report())
+}
 /// Signing payload struct that matches Move contract's struct EnclavePK. Signed by enclave ephemeral
 /// keypair.
 #[derive(serde::Serialize, Debug)]
@@ -229,7 +404,7 @@ pub async fn create_ptb(
     let signing_payload = EnclavePKPayload {
         pk: wallet_pk.clone(),
     };
-    let intent_msg = IntentMessage::new(signing_payload, timestamp, 1); // 1 = WalletPK intent for seal_approve_enclaves
+    let intent_msg = IntentMessage::new(signing_payload, timestamp, IntentScope::WalletPK as u8);
 
     // Sign with enclave ephemeral keypair.
     let signing_bytes = bcs::to_bytes(&intent_msg)?;
@@ -294,6 +469,15 @@ pub async fn spawn_host_init_server(state: Arc<AppState>) -> Result<(), EnclaveE
             "/admin/provision_openrouter_api_key",
             post(provision_openrouter_api_key),
         )
+        .route(
+            "/admin/provision_pseudonym_key",
+            post(provision_pseudonym_key),
+        )
+        .route("/admin/reidentify", post(reidentify))
+        .route("/admin/reidentification_log", get(reidentification_log))
+        .route("/admin/register_caller", post(register_caller))
+        .route("/admin/app_budget", post(set_app_budget))
+        .route("/admin/usage", get(usage_report))
         .with_state(state);
 
     let host_listener = TcpListener::bind("127.0.0.1:3001")
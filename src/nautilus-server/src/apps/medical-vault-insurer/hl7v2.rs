@@ -0,0 +1,1117 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// HL7 v2 ingestion. ADT^A01, ORU^R01 and RDE^O11 messages are parsed into segments, fields,
+// repetitions, components and subcomponents, and PID, PV1, OBX, DG1 and RXE segments are mapped to
+// Patient, Encounter, Observation, Condition and MedicationRequest resources by rules. Only the
+// free-text comments of NTE segments are left to the LLM.
+
+use super::synthea::{concept, derived_uuid, entry, insert_opt};
+use super::*;
+use serde_json::{json, Map, Value};
+
+/// `source_format` selecting this converter.
+pub const HL7V2_SOURCE_FORMAT: &str = "hl7v2";
+
+/// Message types (MSH-9) that can be converted.
+const SUPPORTED_MESSAGE_TYPES: &[(&str, &str)] = &[("ADT", "A01"), ("ORU", "R01"), ("RDE", "O11")];
+
+/// Maximum number of segments in a message.
+const MAX_SEGMENTS: usize = 1000;
+
+const SNOMED: &str = "http://snomed.info/sct";
+const LOINC: &str = "http://loinc.org";
+const RXNORM: &str = "http://www.nlm.nih.gov/research/umls/rxnorm";
+const ICD10_CM: &str = "http://hl7.org/fhir/sid/icd-10-cm";
+const NDC: &str = "http://hl7.org/fhir/sid/ndc";
+const UCUM: &str = "http://unitsofmeasure.org";
+const SSN_SYSTEM: &str = "urn:oid:2.16.840.1.113883.4.1";
+/// Prefix of the systems of identifiers and codes whose HL7 v2 system has no FHIR URI.
+const LOCAL_SYSTEM: &str = "urn:hl7v2:";
+const ACT_CODE: &str = "http://terminology.hl7.org/CodeSystem/v3-ActCode";
+const OBSERVATION_CATEGORY: &str = "http://terminology.hl7.org/CodeSystem/observation-category";
+const CONDITION_CLINICAL: &str = "http://terminology.hl7.org/CodeSystem/condition-clinical";
+const CONDITION_VERIFICATION: &str = "http://terminology.hl7.org/CodeSystem/condition-ver-status";
+
+/// LOINC codes reported in the vital-signs category.
+const VITAL_SIGNS: &[&str] = &[
+    "8480-6", "8462-4", "8867-4", "8310-5", "9279-1", "2708-6", "29463-7", "8302-2", "39156-5",
+    "85354-9",
+];
+
+/// Delimiters declared in MSH-1 and MSH-2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delimiters {
+    pub field: char,
+    pub component: char,
+    pub repetition: char,
+    pub escape: char,
+    pub subcomponent: char,
+}
+
+/// A field repetition: its components, each made of subcomponents.
+pub type Repetition = Vec<Vec<String>>;
+
+/// A segment whose fields are indexed by their HL7 position, so that `fields[5]` is PID-5. For MSH,
+/// `fields[1]` is the field separator and `fields[2]` the encoding characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub fields: Vec<Vec<Repetition>>,
+}
+
+impl Segment {
+    /// Repetitions of a field.
+    pub fn repetitions(&self, field: usize) -> &[Repetition] {
+        self.fields
+            .get(field)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// First subcomponent of a component of the first repetition, e.g. `component(5, 1)` for the
+    /// family name in PID-5.1. Empty values are None.
+    pub fn component(&self, field: usize, component: usize) -> Option<&str> {
+        self.repetitions(field)
+            .first()
+            .and_then(|rep| repetition_component(rep, component))
+    }
+
+    /// First component of a field.
+    pub fn value(&self, field: usize) -> Option<&str> {
+        self.component(field, 1)
+    }
+}
+
+/// A parsed HL7 v2 message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub delimiters: Delimiters,
+    pub segments: Vec<Segment>,
+}
+
+impl Message {
+    /// Parse a message whose segments are separated by carriage returns (line feeds are accepted
+    /// as well). Escape sequences are decoded in every subcomponent.
+    pub fn parse(data: &str) -> Result<Self, EnclaveError> {
+        let mut lines = data
+            .split(['\r', '\n'])
+            .map(|l| l.trim_start_matches('\u{feff}'))
+            .filter(|l| !l.trim().is_empty());
+        let header = lines
+            .next()
+            .filter(|l| l.starts_with("MSH"))
+            .ok_or_else(|| {
+                EnclaveError::GenericError("HL7 v2 message must start with MSH".to_string())
+            })?;
+
+        let mut chars = header.chars().skip(3);
+        let field = chars.next();
+        let encoding: Vec<char> = chars.by_ref().take_while(|c| Some(*c) != field).collect();
+        let (Some(field), [component, repetition, escape, subcomponent, ..]) =
+            (field, encoding.as_slice())
+        else {
+            return Err(EnclaveError::GenericError(
+                "Invalid MSH delimiters".to_string(),
+            ));
+        };
+        let delimiters = Delimiters {
+            field,
+            component: *component,
+            repetition: *repetition,
+            escape: *escape,
+            subcomponent: *subcomponent,
+        };
+
+        let mut segments = vec![parse_segment(header, &delimiters)?];
+        for line in lines {
+            if segments.len() >= MAX_SEGMENTS {
+                return Err(EnclaveError::GenericError(format!(
+                    "HL7 v2 message has more than {MAX_SEGMENTS} segments"
+                )));
+            }
+            segments.push(parse_segment(line, &delimiters)?);
+        }
+        Ok(Self {
+            delimiters,
+            segments,
+        })
+    }
+
+    pub fn segments<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Segment> {
+        self.segments.iter().filter(move |s| s.name == name)
+    }
+
+    /// Message type and trigger event from MSH-9, e.g. `("ORU", "R01")`.
+    pub fn message_type(&self) -> (&str, &str) {
+        let msh = &self.segments[0];
+        (
+            msh.component(9, 1).unwrap_or_default(),
+            msh.component(9, 2).unwrap_or_default(),
+        )
+    }
+}
+
+fn parse_segment(line: &str, delimiters: &Delimiters) -> Result<Segment, EnclaveError> {
+    let mut raw_fields = line.split(delimiters.field);
+    let name = raw_fields.next().unwrap_or_default().to_string();
+    if name.len() != 3
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+    {
+        return Err(EnclaveError::GenericError(format!(
+            "Invalid HL7 v2 segment name {name}"
+        )));
+    }
+
+    let mut fields = vec![vec![vec![vec![name.clone()]]]];
+    if name == "MSH" {
+        // MSH-1 is the field separator itself and MSH-2 holds the delimiters unescaped.
+        fields.push(vec![vec![vec![delimiters.field.to_string()]]]);
+        let encoding = raw_fields.next().unwrap_or_default();
+        fields.push(vec![vec![vec![encoding.to_string()]]]);
+    }
+    for raw in raw_fields {
+        fields.push(
+            raw.split(delimiters.repetition)
+                .map(|rep| {
+                    rep.split(delimiters.component)
+                        .map(|component| {
+                            component
+                                .split(delimiters.subcomponent)
+                                .map(|sub| unescape(sub, delimiters))
+                                .collect()
+                        })
+                        .collect()
+                })
+                .collect(),
+        );
+    }
+    Ok(Segment { name, fields })
+}
+
+/// Decode the escape sequences of a value: `\F\`, `\S\`, `\T\`, `\R\` and `\E\` for the
+/// delimiters, `\.br\` for a line break and `\Xhh..\` for hex encoded bytes. Formatting sequences
+/// (`\H\`, `\N\`, ...) are dropped and an unterminated sequence is kept as is.
+pub fn unescape(value: &str, delimiters: &Delimiters) -> String {
+    let escape = delimiters.escape;
+    if !value.contains(escape) {
+        return value.to_string();
+    }
+
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find(escape) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + escape.len_utf8()..];
+        let Some(end) = after.find(escape) else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        let sequence = &after[..end];
+        match sequence {
+            "F" => out.push(delimiters.field),
+            "S" => out.push(delimiters.component),
+            "T" => out.push(delimiters.subcomponent),
+            "R" => out.push(delimiters.repetition),
+            "E" => out.push(escape),
+            ".br" => out.push('\n'),
+            _ if sequence.starts_with('X') => match Hex::decode(&sequence[1..]) {
+                Ok(bytes) => out.push_str(&String::from_utf8_lossy(&bytes)),
+                Err(_) => {
+                    out.push(escape);
+                    out.push_str(sequence);
+                    out.push(escape);
+                }
+            },
+            _ => {}
+        }
+        rest = &after[end + escape.len_utf8()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn repetition_component(rep: &Repetition, component: usize) -> Option<&str> {
+    rep.get(component.checked_sub(1)?)
+        .and_then(|c| c.first())
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+}
+
+/// Result of the rule-based conversion of a message.
+pub struct Hl7Conversion {
+    /// Bundle in the `{"bundle": {...}}` envelope returned by the LLM.
+    pub bundle: Value,
+    /// Free-text comments of the NTE segments.
+    pub notes: Vec<String>,
+    patient_url: String,
+}
+
+impl Hl7Conversion {
+    /// Request converting the NTE comments with the LLM, if the message has any.
+    pub fn notes_request(&self, request: &FhirBuildRequest) -> Option<FhirBuildRequest> {
+        if self.notes.is_empty() {
+            return None;
+        }
+        Some(FhirBuildRequest {
+            raw_data: self.notes.join("\n"),
+            source_format: "text".to_string(),
+            patient_context: request.patient_context.clone(),
+            include_phi: request.include_phi,
+        })
+    }
+
+    /// Add the resources the LLM extracted from the NTE comments. Its Patient is dropped and the
+    /// other resources are linked to the Patient of the message.
+    pub fn merge_notes(&mut self, notes: &Value) {
+        let mut added = Vec::new();
+        for resource in bundle_resources(notes) {
+            if resource.get("resourceType").and_then(|t| t.as_str()) == Some("Patient") {
+                continue;
+            }
+            let mut resource = resource.clone();
+            if let Some(object) = resource.as_object_mut() {
+                object.remove("encounter");
+                for element in ["subject", "patient"] {
+                    if object.contains_key(element) {
+                        object.insert(
+                            element.to_string(),
+                            json!({ "reference": self.patient_url }),
+                        );
+                    }
+                }
+            }
+            let id = derived_uuid("hl7v2-note", &[resource.to_string()]);
+            added.push(entry(&format!("urn:uuid:{id}"), resource));
+        }
+        if let Some(entries) = self.bundle["bundle"]["entry"].as_array_mut() {
+            entries.extend(added);
+        }
+    }
+}
+
+/// Convert an HL7 v2 message to a FHIR R5 bundle. The NTE comments are returned in `notes` for the
+/// caller to convert with the LLM.
+pub fn convert_hl7v2(request: &FhirBuildRequest) -> Result<Hl7Conversion, EnclaveError> {
+    let message = Message::parse(&request.raw_data)?;
+    let (message_type, trigger) = message.message_type();
+    if !SUPPORTED_MESSAGE_TYPES.contains(&(message_type, trigger)) {
+        return Err(EnclaveError::GenericError(format!(
+            "Unsupported HL7 v2 message type {message_type}^{trigger}"
+        )));
+    }
+    // Control id (MSH-10) keeps ids derived from identical segments of different messages apart.
+    let control_id = message.segments[0].value(10).unwrap_or_default();
+
+    let pid = message.segments("PID").next().ok_or_else(|| {
+        EnclaveError::GenericError("HL7 v2 message has no PID segment".to_string())
+    })?;
+    let (patient_id, patient) = patient_resource(pid, request.include_phi)?;
+    let patient_url = format!("urn:uuid:{patient_id}");
+    let mut entries = vec![entry(&patient_url, patient)];
+
+    let mut encounter_url = None;
+    if let Some(pv1) = message.segments("PV1").next() {
+        let id = derived_uuid("hl7v2-PV1", &[control_id.to_string(), segment_key(pv1)]);
+        let url = format!("urn:uuid:{id}");
+        entries.push(entry(&url, encounter_resource(pv1, &id, &patient_url)));
+        encounter_url = Some(url);
+    }
+    let links = Links {
+        patient_url: &patient_url,
+        encounter_url: encounter_url.as_deref(),
+    };
+
+    let mut notes = Vec::new();
+    let mut obr: Option<&Segment> = None;
+    let mut orc: Option<&Segment> = None;
+    for (index, segment) in message.segments.iter().enumerate() {
+        let resource = match segment.name.as_str() {
+            "OBR" => {
+                obr = Some(segment);
+                None
+            }
+            "ORC" => {
+                orc = Some(segment);
+                None
+            }
+            "OBX" => observation_resource(segment, obr, &links),
+            "DG1" => Some(condition_resource(segment, &links)),
+            "RXE" => Some(medication_request_resource(segment, orc, &links)),
+            "NTE" => {
+                let comment: Vec<&str> = segment
+                    .repetitions(3)
+                    .iter()
+                    .filter_map(|rep| repetition_component(rep, 1))
+                    .collect();
+                if !comment.is_empty() {
+                    notes.push(comment.join("\n"));
+                }
+                None
+            }
+            _ => None,
+        };
+        if let Some(mut resource) = resource {
+            let id = derived_uuid(
+                &format!("hl7v2-{}", segment.name),
+                &[
+                    control_id.to_string(),
+                    index.to_string(),
+                    segment_key(segment),
+                ],
+            );
+            resource["id"] = json!(id);
+            entries.push(entry(&format!("urn:uuid:{id}"), resource));
+        }
+    }
+
+    info!(
+        "HL7 v2 {message_type}^{trigger} conversion complete: {} resources, {} notes",
+        entries.len(),
+        notes.len()
+    );
+
+    Ok(Hl7Conversion {
+        bundle: json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "type": "collection",
+                "entry": entries,
+            }
+        }),
+        notes,
+        patient_url,
+    })
+}
+
+/// References shared by the resources of a message.
+struct Links<'a> {
+    patient_url: &'a str,
+    encounter_url: Option<&'a str>,
+}
+
+impl Links<'_> {
+    fn link(&self, resource: &mut Map<String, Value>) {
+        resource.insert(
+            "subject".to_string(),
+            json!({ "reference": self.patient_url }),
+        );
+        if let Some(url) = self.encounter_url {
+            resource.insert("encounter".to_string(), json!({ "reference": url }));
+        }
+    }
+}
+
+/// Decoded content of a segment, used to derive stable resource ids.
+fn segment_key(segment: &Segment) -> String {
+    serde_json::to_string(&segment.fields).unwrap_or_default()
+}
+
+/// Patient resource from PID, and its id derived from the first patient identifier. Without
+/// `include_phi` names are masked, dates are reduced to the year, only the state is kept of the
+/// address and the SSN is dropped.
+fn patient_resource(pid: &Segment, include_phi: bool) -> Result<(String, Value), EnclaveError> {
+    let mut identifier = Vec::new();
+    for rep in pid.repetitions(3) {
+        let Some(value) = repetition_component(rep, 1) else {
+            continue;
+        };
+        // CX.4 assigning authority: namespace & universal id & universal id type.
+        let authority = rep.get(3);
+        let namespace = authority.and_then(|a| a.first()).map(|s| s.trim());
+        let universal_id = authority.and_then(|a| a.get(1)).map(|s| s.trim());
+        let universal_type = authority.and_then(|a| a.get(2)).map(|s| s.trim());
+        let system = match (namespace, universal_id, universal_type) {
+            (_, Some(oid), Some("ISO")) if !oid.is_empty() => format!("urn:oid:{oid}"),
+            (Some(ns), _, _) if !ns.is_empty() => format!("{LOCAL_SYSTEM}{ns}"),
+            _ => format!("{LOCAL_SYSTEM}PID-3"),
+        };
+        let mut id = Map::new();
+        id.insert("system".to_string(), json!(system));
+        id.insert("value".to_string(), json!(value));
+        if let Some(code) = repetition_component(rep, 5) {
+            id.insert("type".to_string(), json!({ "text": code }));
+        }
+        identifier.push(Value::Object(id));
+    }
+    let first = identifier
+        .first()
+        .cloned()
+        .ok_or_else(|| EnclaveError::GenericError("PID-3 has no patient identifier".to_string()))?;
+    let patient_id = derived_uuid(
+        "hl7v2-PID",
+        &[
+            first["system"].as_str().unwrap_or_default().to_string(),
+            first["value"].as_str().unwrap_or_default().to_string(),
+        ],
+    );
+    if include_phi {
+        if let Some(ssn) = pid.value(19) {
+            identifier.push(json!({ "system": SSN_SYSTEM, "value": ssn }));
+        }
+    }
+
+    let mut patient = Map::new();
+    patient.insert("resourceType".to_string(), json!("Patient"));
+    patient.insert("id".to_string(), json!(patient_id));
+    patient.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/Patient"] }),
+    );
+    patient.insert("identifier".to_string(), json!(identifier));
+
+    let name = if include_phi {
+        // XPN: family ^ given ^ middle ^ suffix ^ prefix
+        let mut name = Map::new();
+        name.insert("use".to_string(), json!("official"));
+        insert_opt(&mut name, "family", pid.component(5, 1));
+        let given: Vec<&str> = [2, 3].iter().filter_map(|c| pid.component(5, *c)).collect();
+        name.insert("given".to_string(), json!(given));
+        if let Some(suffix) = pid.component(5, 4) {
+            name.insert("suffix".to_string(), json!([suffix]));
+        }
+        if let Some(prefix) = pid.component(5, 5) {
+            name.insert("prefix".to_string(), json!([prefix]));
+        }
+        Value::Object(name)
+    } else {
+        json!({ "family": "***", "given": ["***"] })
+    };
+    patient.insert("name".to_string(), json!([name]));
+
+    let gender = match pid.value(8) {
+        Some("M") => "male",
+        Some("F") => "female",
+        Some("O") | Some("A") | Some("N") => "other",
+        _ => "unknown",
+    };
+    patient.insert("gender".to_string(), json!(gender));
+
+    let mask_date = |date: String| -> String {
+        if include_phi {
+            date
+        } else {
+            date.chars().take(4).collect()
+        }
+    };
+    insert_opt(
+        &mut patient,
+        "birthDate",
+        pid.value(7).and_then(fhir_date).map(mask_date),
+    );
+    insert_opt(
+        &mut patient,
+        "deceasedDateTime",
+        pid.value(29).and_then(fhir_date_time).map(mask_date),
+    );
+
+    // XAD: street ^ other designation ^ city ^ state ^ zip ^ country
+    let mut address = Map::new();
+    if include_phi {
+        let line: Vec<&str> = [1, 2]
+            .iter()
+            .filter_map(|c| pid.component(11, *c))
+            .collect();
+        if !line.is_empty() {
+            address.insert("line".to_string(), json!(line));
+        }
+        insert_opt(&mut address, "city", pid.component(11, 3));
+        insert_opt(&mut address, "state", pid.component(11, 4));
+        insert_opt(&mut address, "postalCode", pid.component(11, 5));
+    } else {
+        insert_opt(&mut address, "state", pid.component(11, 4));
+    }
+    address.insert(
+        "country".to_string(),
+        json!(pid.component(11, 6).unwrap_or("US")),
+    );
+    patient.insert("address".to_string(), json!([address]));
+
+    Ok((patient_id, Value::Object(patient)))
+}
+
+fn encounter_resource(pv1: &Segment, id: &str, patient_url: &str) -> Value {
+    let class = match pv1.value(2) {
+        Some("E") => ("EMER", "emergency"),
+        Some("I") | Some("B") => ("IMP", "inpatient encounter"),
+        Some("P") => ("PRENC", "pre-admission"),
+        _ => ("AMB", "ambulatory"),
+    };
+    let end = pv1.value(45).and_then(fhir_date_time);
+    let status = if end.is_some() {
+        "completed"
+    } else {
+        "in-progress"
+    };
+
+    let mut encounter = Map::new();
+    encounter.insert("resourceType".to_string(), json!("Encounter"));
+    encounter.insert("id".to_string(), json!(id));
+    if let Some(visit) = pv1.value(19) {
+        encounter.insert("identifier".to_string(), json!([{ "value": visit }]));
+    }
+    encounter.insert("status".to_string(), json!(status));
+    encounter.insert(
+        "class".to_string(),
+        json!([{ "coding": [{ "system": ACT_CODE, "code": class.0, "display": class.1 }] }]),
+    );
+    encounter.insert("subject".to_string(), json!({ "reference": patient_url }));
+    let mut period = Map::new();
+    insert_opt(&mut period, "start", pv1.value(44).and_then(fhir_date_time));
+    insert_opt(&mut period, "end", end);
+    if !period.is_empty() {
+        encounter.insert("actualPeriod".to_string(), Value::Object(period));
+    }
+    Value::Object(encounter)
+}
+
+/// Observation from OBX, or None when the value is not numeric (NM, or SN without a range), as the
+/// profile requires a valueQuantity.
+fn observation_resource(obx: &Segment, obr: Option<&Segment>, links: &Links) -> Option<Value> {
+    let value_type = obx.value(2)?;
+    let rep = obx.repetitions(5).first()?;
+    let (comparator, raw_value) = match value_type {
+        "NM" => (None, repetition_component(rep, 1)?),
+        // SN: comparator ^ num1 ^ separator/suffix ^ num2
+        "SN" if repetition_component(rep, 3).is_none() => {
+            (repetition_component(rep, 1), repetition_component(rep, 2)?)
+        }
+        _ => return None,
+    };
+    let value = raw_value.parse::<f64>().ok().filter(|v| v.is_finite())?;
+
+    let code = coded_concept(obx.repetitions(3).first()?)?;
+    let vital = code["coding"].as_array().is_some_and(|codings| {
+        codings.iter().any(|c| {
+            c["system"] == LOINC && c["code"].as_str().is_some_and(|c| VITAL_SIGNS.contains(&c))
+        })
+    });
+    let (category, profile) = if vital {
+        (
+            "vital-signs",
+            "http://hl7.org/fhir/StructureDefinition/vitalsigns",
+        )
+    } else {
+        (
+            "laboratory",
+            "http://hl7.org/fhir/StructureDefinition/Observation",
+        )
+    };
+    let status = match obx.value(11) {
+        Some("F") | None => "final",
+        Some("C") => "corrected",
+        Some("P") => "preliminary",
+        Some("X") => "cancelled",
+        Some(_) => "unknown",
+    };
+
+    let mut quantity = Map::new();
+    quantity.insert("value".to_string(), json!(value));
+    insert_opt(
+        &mut quantity,
+        "comparator",
+        comparator.filter(|c| ["<", "<=", ">=", ">"].contains(c)),
+    );
+    // CE units: identifier ^ text ^ coding system. UCUM is assumed when no system is given.
+    if let Some(units) = obx.repetitions(6).first() {
+        let unit_code = repetition_component(units, 1);
+        let unit = repetition_component(units, 2).or(unit_code);
+        insert_opt(&mut quantity, "unit", unit);
+        if let Some(unit_code) = unit_code {
+            let system = match repetition_component(units, 3) {
+                None => UCUM.to_string(),
+                Some(system) => coding_system(system),
+            };
+            quantity.insert("system".to_string(), json!(system));
+            quantity.insert("code".to_string(), json!(unit_code));
+        }
+    }
+
+    let mut observation = Map::new();
+    observation.insert("resourceType".to_string(), json!("Observation"));
+    observation.insert("meta".to_string(), json!({ "profile": [profile] }));
+    observation.insert("status".to_string(), json!(status));
+    observation.insert(
+        "category".to_string(),
+        json!([{ "coding": [{ "system": OBSERVATION_CATEGORY, "code": category }] }]),
+    );
+    observation.insert("code".to_string(), code);
+    links.link(&mut observation);
+    // OBX-14, or the observation date of the order (OBR-7).
+    insert_opt(
+        &mut observation,
+        "effectiveDateTime",
+        obx.value(14)
+            .or_else(|| obr.and_then(|obr| obr.value(7)))
+            .and_then(fhir_date_time),
+    );
+    observation.insert("valueQuantity".to_string(), Value::Object(quantity));
+    Some(Value::Object(observation))
+}
+
+fn condition_resource(dg1: &Segment, links: &Links) -> Value {
+    let code = dg1
+        .repetitions(3)
+        .first()
+        .and_then(coded_concept)
+        .or_else(|| dg1.value(4).map(|text| json!({ "text": text })));
+    let verification = match dg1.value(6) {
+        Some("F") => "confirmed",
+        _ => "provisional",
+    };
+
+    let mut condition = Map::new();
+    condition.insert("resourceType".to_string(), json!("Condition"));
+    condition.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/Condition"] }),
+    );
+    condition.insert(
+        "clinicalStatus".to_string(),
+        json!({ "coding": [{ "system": CONDITION_CLINICAL, "code": "active" }] }),
+    );
+    condition.insert(
+        "verificationStatus".to_string(),
+        json!({ "coding": [{ "system": CONDITION_VERIFICATION, "code": verification }] }),
+    );
+    insert_opt(&mut condition, "code", code);
+    links.link(&mut condition);
+    let date = dg1.value(5).and_then(fhir_date_time);
+    insert_opt(&mut condition, "onsetDateTime", date.clone());
+    insert_opt(&mut condition, "recordedDate", date);
+    Value::Object(condition)
+}
+
+fn medication_request_resource(rxe: &Segment, orc: Option<&Segment>, links: &Links) -> Value {
+    // ORC-1 order control of the order the RXE belongs to.
+    let status = match orc.and_then(|orc| orc.value(1)) {
+        Some("DC") | Some("OD") => "stopped",
+        Some("CA") | Some("OC") => "cancelled",
+        Some("HD") | Some("OH") => "on-hold",
+        _ => "active",
+    };
+
+    let mut request = Map::new();
+    request.insert("resourceType".to_string(), json!("MedicationRequest"));
+    request.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/MedicationRequest"] }),
+    );
+    if let Some(placer) = orc.and_then(|orc| orc.value(2)) {
+        request.insert("identifier".to_string(), json!([{ "value": placer }]));
+    }
+    request.insert("status".to_string(), json!(status));
+    request.insert("intent".to_string(), json!("order"));
+    if let Some(medication) = rxe.repetitions(2).first().and_then(coded_concept) {
+        request.insert("medication".to_string(), json!({ "concept": medication }));
+    }
+    links.link(&mut request);
+    insert_opt(
+        &mut request,
+        "authoredOn",
+        orc.and_then(|orc| orc.value(9)).and_then(fhir_date_time),
+    );
+    // RXE-3 give amount and RXE-5 give units.
+    if let Some(amount) = rxe.value(3) {
+        let units = rxe
+            .repetitions(5)
+            .first()
+            .and_then(|u| repetition_component(u, 2).or(repetition_component(u, 1)));
+        let text = match units {
+            Some(units) => format!("{amount} {units}"),
+            None => amount.to_string(),
+        };
+        request.insert("dosageInstruction".to_string(), json!([{ "text": text }]));
+    }
+    // RXE-27 give indication.
+    if let Some(reason) = rxe.repetitions(27).first().and_then(coded_concept) {
+        request.insert("reason".to_string(), json!([{ "concept": reason }]));
+    }
+    Value::Object(request)
+}
+
+/// CodeableConcept from a CE/CWE value: identifier ^ text ^ coding system, followed by the same
+/// three components for an alternate code.
+fn coded_concept(rep: &Repetition) -> Option<Value> {
+    let mut codings = Vec::new();
+    for offset in [0, 3] {
+        let Some(code) = repetition_component(rep, offset + 1) else {
+            continue;
+        };
+        let Some(system) = repetition_component(rep, offset + 3) else {
+            continue;
+        };
+        let display = repetition_component(rep, offset + 2);
+        codings.push(concept(&coding_system(system), code, display)["coding"][0].clone());
+    }
+    let text = repetition_component(rep, 2).or(repetition_component(rep, 5));
+    if codings.is_empty() && text.is_none() {
+        return None;
+    }
+    let mut concept = Map::new();
+    concept.insert("coding".to_string(), json!(codings));
+    insert_opt(&mut concept, "text", text);
+    Some(Value::Object(concept))
+}
+
+/// FHIR URI of an HL7 v2 coding system name (table 0396).
+fn coding_system(name: &str) -> String {
+    match name.to_ascii_uppercase().as_str() {
+        "LN" | "LOINC" => LOINC.to_string(),
+        "SCT" | "SNM" | "SNM3" | "SNOMEDCT" => SNOMED.to_string(),
+        "RXNORM" | "RXN" => RXNORM.to_string(),
+        "I10" | "I10C" | "ICD10CM" => ICD10_CM.to_string(),
+        "NDC" => NDC.to_string(),
+        "UCUM" => UCUM.to_string(),
+        _ => format!("{LOCAL_SYSTEM}{name}"),
+    }
+}
+
+/// FHIR date from an HL7 v2 DT/DTM value (YYYY[MM[DD...]]), which is also the format of the HL7
+/// v3 TS values of C-CDA documents.
+pub(crate) fn fhir_date(value: &str) -> Option<String> {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let date = match digits.len() {
+        4 => digits,
+        6 => format!("{}-{}", &digits[..4], &digits[4..6]),
+        n if n >= 8 => format!("{}-{}-{}", &digits[..4], &digits[4..6], &digits[6..8]),
+        _ => return None,
+    };
+    dates::parse_fhir_date(&date).map(|_| date)
+}
+
+/// FHIR dateTime from an HL7 v2 DTM value, YYYY[MM[DD[HH[MM[SS[.S+]]]]]][+/-ZZZZ]. FHIR requires
+/// a timezone with a time, so a time without one is dropped and only the date is kept.
+pub(crate) fn fhir_date_time(value: &str) -> Option<String> {
+    let date = fhir_date(value)?;
+    let (local, zone) = match value.find(['+', '-']) {
+        Some(i) => (&value[..i], Some(&value[i..])),
+        None => (value, None),
+    };
+    let (time, fraction) = match local.split_once('.') {
+        Some((time, fraction)) => (time, Some(fraction)),
+        None => (local, None),
+    };
+    let zone = match zone {
+        Some(z) if z.len() == 5 && z[1..].bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{}:{}", &z[..3], &z[3..])
+        }
+        _ => return Some(date),
+    };
+    if time.len() < 12 || !time.bytes().all(|b| b.is_ascii_digit()) {
+        return Some(date);
+    }
+    let seconds = time.get(12..14).unwrap_or("00");
+    let fraction = fraction
+        .filter(|f| !f.is_empty() && f.bytes().all(|b| b.is_ascii_digit()))
+        .map(|f| format!(".{f}"))
+        .unwrap_or_default();
+    Some(format!(
+        "{date}T{}:{}:{seconds}{fraction}{zone}",
+        &time[8..10],
+        &time[10..12]
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MSH: &str = "MSH|^~\\&|LAB|HOSP|EHR|CLINIC|20240105083000||ORU^R01|MSG005|P|2.5.1";
+
+    fn request(raw_data: &str, include_phi: bool) -> FhirBuildRequest {
+        FhirBuildRequest {
+            raw_data: raw_data.to_string(),
+            source_format: HL7V2_SOURCE_FORMAT.to_string(),
+            patient_context: None,
+            include_phi,
+        }
+    }
+
+    #[test]
+    fn test_hl7v2_parsing() {
+        let message = Message::parse(
+            "MSH|^~\\&|LAB|HOSP|EHR|CLINIC|20240105083000||ORU^R01|MSG001|P|2.5.1\r\
+             PID|1||MRN123^^^HOSP&2.16.840.1.113883.19.5&ISO~987^^^CLINIC||Doe^Jane^Q\r\
+             NTE|1||Fasting \\T\\ rested\\.br\\since 8pm \\F\\ \\S\\ \\R\\ \\E\\ \\X41\\ \\H\\bold\\N\\ \\Z",
+        )
+        .unwrap();
+        assert_eq!(message.message_type(), ("ORU", "R01"));
+        let msh = &message.segments[0];
+        assert_eq!(msh.value(1), Some("|"));
+        assert_eq!(msh.value(2), Some("^~\\&"));
+        assert_eq!(msh.value(10), Some("MSG001"));
+
+        let pid = message.segments("PID").next().unwrap();
+        assert_eq!(pid.component(5, 2), Some("Jane"));
+        assert_eq!(pid.repetitions(3).len(), 2);
+        assert_eq!(
+            pid.repetitions(3)[0][3],
+            vec!["HOSP", "2.16.840.1.113883.19.5", "ISO"]
+        );
+        assert_eq!(pid.value(4), None);
+        assert_eq!(pid.value(40), None);
+
+        let nte = message.segments("NTE").next().unwrap();
+        assert_eq!(
+            nte.value(3),
+            Some("Fasting & rested\nsince 8pm | ^ ~ \\ A bold \\Z")
+        );
+
+        assert!(Message::parse("PID|1||MRN123").is_err());
+        assert!(Message::parse("MSH|^~").is_err());
+        assert!(Message::parse("MSH|^~\\&|A\rpid|1").is_err());
+    }
+
+    #[test]
+    fn test_hl7v2_conversion() {
+        let pid = "PID|1||MRN123^^^HOSP&2.16.840.1.113883.19.5&ISO||Doe^Jane^Q||19800201|F|||1 Main St^^Boston^MA^02101||||||||999-62-4431";
+
+        let oru = format!(
+            "MSH|^~\\&|LAB|HOSP|EHR|CLINIC|20240105083000||ORU^R01|MSG001|P|2.5.1\r{pid}\r\
+             PV1|1|O|||||||||||||||||V100|||||||||||||||||||||||||20240105080000-0500\r\
+             OBR|1||ORD1|24331-1^Lipid panel^LN|||20240105080000-0500\r\
+             OBX|1|NM|2093-3^Cholesterol^LN||185|mg/dL^^UCUM|<200|N|||F\r\
+             OBX|2|SN|8867-4^Heart rate^LN||^72|/min|||||F|||202401050815-0500\r\
+             OBX|3|ST|11450-4^Comment^LN||see note||||||F\r\
+             DG1|1||E11.9^Type 2 diabetes^I10^44054006^Diabetes mellitus type 2^SCT|||F\r\
+             NTE|1||Patient reports mild dizziness after meals"
+        );
+        let conversion = convert_hl7v2(&request(&oru, true)).unwrap();
+        let outcome = validate_profile(&conversion.bundle);
+        assert!(!outcome.has_errors(), "{}", outcome.error_summary());
+        assert_eq!(
+            extract_resource_types(&conversion.bundle),
+            vec!["Patient", "Encounter", "Observation", "Condition"]
+        );
+        let resources = bundle_resources(&conversion.bundle);
+        // The string OBX is left out, as the profile requires a valueQuantity.
+        assert_eq!(resources.len(), 5);
+        assert_eq!(resources[0]["birthDate"], "1980-02-01");
+        assert_eq!(
+            resources[0]["identifier"][0],
+            json!({ "system": "urn:oid:2.16.840.1.113883.19.5", "value": "MRN123" })
+        );
+        assert_eq!(
+            resources[1]["actualPeriod"]["start"],
+            "2024-01-05T08:00:00-05:00"
+        );
+        assert_eq!(
+            resources[2]["effectiveDateTime"],
+            "2024-01-05T08:00:00-05:00"
+        );
+        assert_eq!(
+            resources[2]["valueQuantity"],
+            json!({ "value": 185.0, "unit": "mg/dL", "system": "http://unitsofmeasure.org", "code": "mg/dL" })
+        );
+        assert_eq!(
+            resources[3]["category"][0]["coding"][0]["code"],
+            "vital-signs"
+        );
+        assert_eq!(
+            resources[3]["effectiveDateTime"],
+            "2024-01-05T08:15:00-05:00"
+        );
+        assert_eq!(
+            resources[4]["code"]["coding"][1]["system"],
+            "http://snomed.info/sct"
+        );
+        assert_eq!(
+            conversion.notes,
+            vec!["Patient reports mild dizziness after meals"]
+        );
+
+        // The same message always yields the same bundle.
+        let again = convert_hl7v2(&request(&oru, true)).unwrap();
+        assert_eq!(
+            compute_semantic_hash(&conversion.bundle).unwrap(),
+            compute_semantic_hash(&again.bundle).unwrap()
+        );
+
+        // Resources the LLM extracts from the notes are linked to the message's Patient.
+        let mut conversion = again;
+        let notes_request = conversion.notes_request(&request(&oru, true)).unwrap();
+        assert_eq!(notes_request.source_format, "text");
+        conversion.merge_notes(&json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "entry": [
+                    { "resource": { "resourceType": "Patient", "id": "llm" } },
+                    { "resource": { "resourceType": "Condition", "subject": { "reference": "Patient/llm" },
+                        "code": { "text": "Dizziness" } } }
+                ]
+            }
+        }));
+        let resources = bundle_resources(&conversion.bundle);
+        assert_eq!(resources.len(), 6);
+        assert_eq!(
+            resources[5]["subject"]["reference"],
+            bundle_resources(&conversion.bundle)[1]["subject"]["reference"]
+        );
+
+        let rde = format!(
+            "MSH|^~\\&|PHARM|HOSP|EHR|CLINIC|20240105083000||RDE^O11|MSG002|P|2.5.1\r{pid}\r\
+             ORC|NW|PO1|||||||20240105083000+0000\r\
+             RXE|^^^20240105|197361^Amlodipine 5 MG Oral Tablet^RXNORM|5||mg^milligram^UCUM"
+        );
+        let conversion = convert_hl7v2(&request(&rde, false)).unwrap();
+        assert!(!validate_profile(&conversion.bundle).has_errors());
+        assert!(conversion.notes.is_empty());
+        let resources = bundle_resources(&conversion.bundle);
+        assert_eq!(resources[0]["name"][0]["family"], "***");
+        assert_eq!(resources[0]["birthDate"], "1980");
+        assert_eq!(resources[0]["identifier"].as_array().unwrap().len(), 1);
+        assert_eq!(resources[1]["status"], "active");
+        assert_eq!(resources[1]["authoredOn"], "2024-01-05T08:30:00+00:00");
+        assert_eq!(resources[1]["dosageInstruction"][0]["text"], "5 milligram");
+
+        let adt = format!(
+            "MSH|^~\\&|ADT|HOSP|EHR|CLINIC|20240105083000||ADT^A01|MSG003|P|2.5.1\r{pid}\rPV1|1|E"
+        );
+        let conversion = convert_hl7v2(&request(&adt, true)).unwrap();
+        assert_eq!(
+            extract_resource_types(&conversion.bundle),
+            vec!["Patient", "Encounter"]
+        );
+
+        let unsupported =
+            format!("MSH|^~\\&|ADT|HOSP|EHR|CLINIC|20240105083000||ADT^A08|MSG004|P|2.5.1\r{pid}");
+        assert!(convert_hl7v2(&request(&unsupported, true)).is_err());
+    }
+
+    #[test]
+    fn test_hl7v2_observation_values() {
+        let oru = [
+            MSH,
+            "PID|1||MRN123^^^HOSP||Doe^Jane",
+            "OBR|1||ORD1|4548-4^HbA1c^LN|||202401050800",
+            "OBX|1|NM|4548-4^Hemoglobin A1c^LN||5.70|%^percent^UCUM|||||C",
+            "OBX|2|NM|8310-5^Body temperature^LN||36.85|Cel|||||F",
+            "OBX|3|SN|2160-0^Creatinine^LN||<^0.5|mg/dL|||||F",
+            "OBX|4|SN|2160-0^Creatinine^LN||=^1.25|mg/dL",
+            "OBX|5|SN|2345-7^Glucose^LN||^70^-^110|mg/dL",
+            "OBX|6|NM|2345-7^Glucose^LN||NaN|mg/dL",
+            "OBX|7|NM|2345-7^Glucose^LN||1e999|mg/dL",
+            "OBX|8|NM|2345-7^Glucose^LN||high|mg/dL",
+            "OBX|9|NM|||4.2|mmol/L",
+            "OBX|10|NM|14749-6^Glucose^LN|| -0.25 |mmol/L",
+        ]
+        .join("\r");
+        let conversion = convert_hl7v2(&request(&oru, true)).unwrap();
+        // The profile only accepts final results.
+        assert_eq!(
+            validate_profile(&conversion.bundle).error_summary(),
+            "Bundle.entry[1].resource.status: Observation.status must be final"
+        );
+
+        // Ranges, values that are not finite numbers and uncoded results are left out.
+        let resources = bundle_resources(&conversion.bundle);
+        let quantities: Vec<&Value> = resources[1..].iter().map(|r| &r["valueQuantity"]).collect();
+        assert_eq!(
+            quantities,
+            [
+                &json!({ "value": 5.7, "unit": "percent", "system": UCUM, "code": "%" }),
+                &json!({ "value": 36.85, "unit": "Cel", "system": UCUM, "code": "Cel" }),
+                &json!({ "value": 0.5, "comparator": "<", "unit": "mg/dL", "system": UCUM, "code": "mg/dL" }),
+                &json!({ "value": 1.25, "unit": "mg/dL", "system": UCUM, "code": "mg/dL" }),
+                &json!({ "value": -0.25, "unit": "mmol/L", "system": UCUM, "code": "mmol/L" }),
+            ]
+        );
+        assert_eq!(resources[1]["status"], "corrected");
+        assert_eq!(
+            resources[1]["category"][0]["coding"][0]["code"],
+            "laboratory"
+        );
+        assert_eq!(
+            resources[2]["category"][0]["coding"][0]["code"],
+            "vital-signs"
+        );
+        // The order date has no timezone, so only its date is kept.
+        assert_eq!(resources[2]["effectiveDateTime"], "2024-01-05");
+        assert!(resources[1].get("encounter").is_none());
+
+        // Identical segments at different positions are different resources.
+        let mut ids: Vec<&str> = resources.iter().filter_map(|r| r["id"].as_str()).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), resources.len());
+    }
+
+    #[test]
+    fn test_hl7v2_malformed() {
+        let error = |raw_data: &str| match convert_hl7v2(&request(raw_data, true)) {
+            Err(EnclaveError::GenericError(message)) => message,
+            Err(other) => panic!("unexpected {other:?}"),
+            Ok(_) => panic!("{raw_data:?} converted"),
+        };
+        assert!(error("").contains("must start with MSH"));
+        assert!(error(MSH).contains("no PID segment"));
+        assert!(error(&format!("{MSH}\rPID|1||^^^HOSP")).contains("PID-3"));
+        assert!(error(&format!("{MSH}\rPID|1||A\rOBX|1|NM|||\rZZ|1")).contains("ZZ"));
+
+        // Line feeds and a byte order mark are accepted, and the delimiters come from MSH.
+        let message = Message::parse(
+            "\u{feff}MSH#!@%$#LAB#HOSP#EHR#CLINIC#20240105#\
+             #ORU!R01#MSG006#P#2.5.1\n\nPID#1##A!!!B$C%T%x@D",
+        )
+        .unwrap();
+        assert_eq!(message.message_type(), ("ORU", "R01"));
+        let pid = message.segments("PID").next().unwrap();
+        assert_eq!(pid.repetitions(3)[0][3], vec!["B", "C$x"]);
+        assert_eq!(pid.repetitions(3)[1][0], vec!["D"]);
+
+        let mut segments = vec![MSH.to_string()];
+        segments.extend((1..MAX_SEGMENTS).map(|i| format!("NTE|{i}")));
+        assert!(Message::parse(&segments.join("\r")).is_ok());
+        segments.push("NTE|last".to_string());
+        assert!(Message::parse(&segments.join("\r")).is_err());
+    }
+
+    #[test]
+    fn test_unescape() {
+        let delimiters = Message::parse(MSH).unwrap().delimiters;
+        assert_eq!(unescape("plain", &delimiters), "plain");
+        assert_eq!(unescape("\\XC3A9\\t\\X20\\", &delimiters), "\u{e9}t ");
+        // Invalid hex and unterminated sequences are kept, unknown ones dropped.
+        assert_eq!(unescape("\\XZZ\\", &delimiters), "\\XZZ\\");
+        assert_eq!(unescape("a \\F", &delimiters), "a \\F");
+        assert_eq!(unescape("\\.sp\\a\\\\b", &delimiters), "ab");
+
+        assert_eq!(coding_system("ln"), LOINC);
+        assert_eq!(coding_system("SNM3"), SNOMED);
+        assert_eq!(coding_system("I10C"), ICD10_CM);
+        assert_eq!(coding_system("99LOCAL"), "urn:hl7v2:99LOCAL");
+    }
+
+    #[test]
+    fn test_fhir_date_time() {
+        assert_eq!(fhir_date("2024").as_deref(), Some("2024"));
+        assert_eq!(fhir_date("202401").as_deref(), Some("2024-01"));
+        assert_eq!(fhir_date("20240229083000").as_deref(), Some("2024-02-29"));
+        for invalid in ["", "202", "20241", "20241301", "20230229", "x2024"] {
+            assert_eq!(fhir_date(invalid), None, "{invalid:?}");
+        }
+
+        let cases = [
+            ("20240105081530.1234+0100", "2024-01-05T08:15:30.1234+01:00"),
+            ("202401050815-0500", "2024-01-05T08:15:00-05:00"),
+            ("20240105081530.+0000", "2024-01-05T08:15:30+00:00"),
+            // A time without a valid timezone is dropped.
+            ("20240105081530", "2024-01-05"),
+            ("20240105081530+01", "2024-01-05"),
+            ("20240105081530+01:00", "2024-01-05"),
+            ("2024010508-0500", "2024-01-05"),
+            ("202401-0500", "2024-01"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                fhir_date_time(value).as_deref(),
+                Some(expected),
+                "{value:?}"
+            );
+        }
+        assert_eq!(fhir_date_time("20241301120000+0000"), None);
+    }
+}
@@ -0,0 +1,810 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// LLM providers for the free-text FHIR conversion. `FhirLlmService` builds the prompt and parses
+// the reply, the provider only carries a system and user prompt to a model and returns its text.
+// The provider is selected by `llm_config.yaml`:
+//
+// - `openrouter`: OpenRouter chat completions, the default.
+// - `openai-compatible`: any server implementing `POST {base_url}/chat/completions`, such as a local
+//   stand-in server.
+// - `anthropic`: the Anthropic Messages API.
+// - `mock`: an in-process provider replying with canned responses, without any network access.
+
+use super::usage::TokenPricing;
+use super::*;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// For Nitro Enclave, outbound traffic routes through traffic_forwarder.py which listens on
+/// 127.0.0.66 and forwards to VSOCK -> host vsock-proxy -> openrouter.ai. The /etc/hosts maps
+/// openrouter.ai -> 127.0.0.66. Each provider host must be listed in `allowed_endpoints.yaml`.
+const OPENROUTER_BASE_URL: &str = "https://openrouter.ai/api/v1";
+const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+const DEFAULT_MAX_TOKENS: u32 = 8000;
+const DEFAULT_TEMPERATURE: f64 = 0.1;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BUDGET_SECS: u64 = 120;
+const DEFAULT_MAX_CHUNK_CHARS: usize = 12_000;
+const DEFAULT_CACHE_ENTRIES: usize = 256;
+
+/// Bundle returned by the `mock` provider of `llm_config.yaml`.
+const MOCK_BUNDLE: &str = r#"{
+  "bundle": {
+    "resourceType": "Bundle",
+    "type": "collection",
+    "entry": [{
+      "fullUrl": "urn:uuid:mock-patient",
+      "resource": {
+        "resourceType": "Patient",
+        "id": "mock-patient",
+        "identifier": [{ "system": "urn:ietf:rfc:3986", "value": "urn:uuid:mock-patient" }],
+        "name": [{ "family": "Mock", "given": ["Patient"] }],
+        "gender": "unknown"
+      }
+    }]
+  }
+}"#;
+
+/// Backend serving the LLM calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmProviderKind {
+    OpenRouter,
+    #[serde(rename = "openai-compatible")]
+    OpenAiCompatible,
+    Anthropic,
+    Mock,
+}
+
+/// LLM configuration, compiled into the enclave from `llm_config.yaml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlmConfig {
+    pub provider: LlmProviderKind,
+    pub model: String,
+    /// Base URL of the API, required by `openai-compatible` and defaulting to the public API
+    /// otherwise.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: u32,
+    #[serde(default = "default_temperature")]
+    pub temperature: f64,
+    /// Maximum number of calls for one conversion, each retry feeding back why the previous output
+    /// was rejected.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Time budget of one conversion across all its attempts.
+    #[serde(default = "default_retry_budget_secs")]
+    pub retry_budget_secs: u64,
+    /// Send the JSON Schema of the reply, as `response_format` to OpenAI-compatible providers and
+    /// as a forced tool call to Anthropic. Disable for servers rejecting it.
+    #[serde(default = "default_structured_output")]
+    pub structured_output: bool,
+    /// Free text longer than this many characters is split into chunks converted in parallel and
+    /// merged, see `chunking`.
+    #[serde(default = "default_max_chunk_chars")]
+    pub max_chunk_chars: usize,
+    /// Number of converted bundles kept in the encrypted in-memory cache, see `cache`. Zero
+    /// disables the cache.
+    #[serde(default = "default_cache_entries")]
+    pub cache_entries: usize,
+    /// USD per million prompt and completion tokens, pricing the calls whose cost the provider
+    /// does not report, see `usage`.
+    #[serde(default)]
+    pub prompt_price_per_million: f64,
+    #[serde(default)]
+    pub completion_price_per_million: f64,
+    /// Reject conversions without the bearer token of a caller registered with
+    /// `/admin/register_caller`, rather than accounting them to the anonymous caller.
+    #[serde(default)]
+    pub require_caller_token: bool,
+    /// Reject input flagged as a possible prompt injection rather than only warning about it, see
+    /// `injection`.
+    #[serde(default)]
+    pub reject_suspected_injection: bool,
+    /// What happens to resources of the LLM output not supported by the input, see `provenance`.
+    #[serde(default)]
+    pub unsupported_resources: UnsupportedPolicy,
+    /// `HTTP-Referer` and `X-Title` attribution headers sent to OpenRouter, none when unset.
+    #[serde(default)]
+    pub app_url: Option<String>,
+    #[serde(default)]
+    pub app_title: Option<String>,
+}
+
+fn default_max_tokens() -> u32 {
+    DEFAULT_MAX_TOKENS
+}
+
+fn default_temperature() -> f64 {
+    DEFAULT_TEMPERATURE
+}
+
+fn default_structured_output() -> bool {
+    true
+}
+
+fn default_max_attempts() -> u32 {
+    DEFAULT_MAX_ATTEMPTS
+}
+
+fn default_retry_budget_secs() -> u64 {
+    DEFAULT_RETRY_BUDGET_SECS
+}
+
+fn default_max_chunk_chars() -> usize {
+    DEFAULT_MAX_CHUNK_CHARS
+}
+
+fn default_cache_entries() -> usize {
+    DEFAULT_CACHE_ENTRIES
+}
+
+impl LlmConfig {
+    /// Whether the provider needs the API key provisioned with `/admin/provision_openrouter_api_key`.
+    pub fn requires_api_key(&self) -> bool {
+        matches!(
+            self.provider,
+            LlmProviderKind::OpenRouter | LlmProviderKind::Anthropic
+        )
+    }
+
+    /// Prices of the calls whose cost the provider does not report.
+    pub fn pricing(&self) -> TokenPricing {
+        TokenPricing {
+            prompt_per_million: self.prompt_price_per_million,
+            completion_per_million: self.completion_price_per_million,
+        }
+    }
+}
+
+/// One completion: a system prompt and a user prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmRequest {
+    pub system: String,
+    pub prompt: String,
+    pub max_tokens: u32,
+    pub temperature: f64,
+    /// JSON Schema the reply must follow, for providers supporting structured output.
+    pub response_schema: Option<ResponseSchema>,
+}
+
+/// Named JSON Schema of a structured reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseSchema {
+    pub name: String,
+    pub schema: Value,
+}
+
+/// Reply of a completion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmResponse {
+    /// Text of the reply.
+    pub content: String,
+    /// Tokens used by the completion, when the provider reports them.
+    pub usage: Option<LlmUsage>,
+}
+
+/// Tokens used by a completion, as reported by the provider.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LlmUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Cost in USD, reported by OpenRouter.
+    pub cost_usd: Option<f64>,
+}
+
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Name of the provider, as in `llm_config.yaml`.
+    fn name(&self) -> &'static str;
+
+    /// Model answering the completions.
+    fn model(&self) -> &str;
+
+    async fn complete(&self, request: &LlmRequest) -> Result<LlmResponse, EnclaveError>;
+}
+
+/// Create the provider selected by `config`. `api_key` is the provisioned API key, if any.
+pub fn create_provider(
+    config: &LlmConfig,
+    api_key: Option<String>,
+) -> Result<Arc<dyn LlmProvider>, EnclaveError> {
+    let required_key = || {
+        api_key.clone().ok_or_else(|| {
+            EnclaveError::GenericError(
+                "LLM API key not provisioned. Please provision it first.".to_string(),
+            )
+        })
+    };
+    Ok(match config.provider {
+        LlmProviderKind::OpenRouter => {
+            let mut provider = OpenRouterProvider::new(required_key()?, config.model.clone());
+            if let Some(base_url) = &config.base_url {
+                provider.0.base_url = base_url.clone();
+            }
+            provider.0.headers = [
+                ("HTTP-Referer", &config.app_url),
+                ("X-Title", &config.app_title),
+            ]
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.clone()?)))
+            .collect();
+            Arc::new(provider)
+        }
+        LlmProviderKind::OpenAiCompatible => {
+            let base_url = config.base_url.clone().ok_or_else(|| {
+                EnclaveError::GenericError(
+                    "base_url is required by the openai-compatible LLM provider".to_string(),
+                )
+            })?;
+            Arc::new(OpenAiCompatibleProvider::new(
+                base_url,
+                api_key,
+                config.model.clone(),
+            ))
+        }
+        LlmProviderKind::Anthropic => {
+            let mut provider = AnthropicProvider::new(required_key()?, config.model.clone());
+            if let Some(base_url) = &config.base_url {
+                provider.base_url = base_url.clone();
+            }
+            Arc::new(provider)
+        }
+        LlmProviderKind::Mock => Arc::new(MockProvider::new(vec![MOCK_BUNDLE.to_string()])),
+    })
+}
+
+/// Any server implementing the OpenAI chat completions API.
+pub struct OpenAiCompatibleProvider {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    /// Sent as a bearer token when set.
+    pub api_key: Option<String>,
+    pub model: String,
+    /// Additional headers sent with every request.
+    pub headers: Vec<(String, String)>,
+}
+
+impl OpenAiCompatibleProvider {
+    pub fn new(base_url: String, api_key: Option<String>, model: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model,
+            headers: Vec::new(),
+        }
+    }
+
+    async fn chat_completion(
+        &self,
+        name: &str,
+        request: &LlmRequest,
+    ) -> Result<LlmResponse, EnclaveError> {
+        let mut body = json!({
+            "model": self.model,
+            "messages": [
+                { "role": "system", "content": request.system },
+                { "role": "user", "content": request.prompt }
+            ],
+            "max_tokens": request.max_tokens,
+            "temperature": request.temperature
+        });
+        if let Some(response_schema) = &request.response_schema {
+            // Not strict, as FHIR resources are open objects.
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": response_schema.name,
+                    "strict": false,
+                    "schema": response_schema.schema
+                }
+            });
+        }
+        let mut http_request = self
+            .client
+            .post(format!(
+                "{}/chat/completions",
+                self.base_url.trim_end_matches('/')
+            ))
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            http_request = http_request.bearer_auth(api_key);
+        }
+        for (header, value) in &self.headers {
+            http_request = http_request.header(header, value);
+        }
+        let response_json = send(name, http_request).await?;
+
+        let content = response_json["choices"]
+            .get(0)
+            .and_then(|c| c.get("message"))
+            .and_then(|m| m.get("content"))
+            .and_then(|c| c.as_str())
+            .ok_or_else(|| EnclaveError::GenericError("No content in response".to_string()))?;
+        let usage = &response_json["usage"];
+        Ok(LlmResponse {
+            content: content.to_string(),
+            usage: usage.is_object().then(|| LlmUsage {
+                prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or_default(),
+                completion_tokens: usage["completion_tokens"].as_u64().unwrap_or_default(),
+                cost_usd: usage["cost"].as_f64(),
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiCompatibleProvider {
+    fn name(&self) -> &'static str {
+        "openai-compatible"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn complete(&self, request: &LlmRequest) -> Result<LlmResponse, EnclaveError> {
+        self.chat_completion(self.name(), request).await
+    }
+}
+
+/// OpenRouter, which implements the OpenAI chat completions API.
+pub struct OpenRouterProvider(pub OpenAiCompatibleProvider);
+
+impl OpenRouterProvider {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self(OpenAiCompatibleProvider::new(
+            OPENROUTER_BASE_URL.to_string(),
+            Some(api_key),
+            model,
+        ))
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenRouterProvider {
+    fn name(&self) -> &'static str {
+        "openrouter"
+    }
+
+    fn model(&self) -> &str {
+        &self.0.model
+    }
+
+    async fn complete(&self, request: &LlmRequest) -> Result<LlmResponse, EnclaveError> {
+        self.0.chat_completion(self.name(), request).await
+    }
+}
+
+/// The Anthropic Messages API.
+pub struct AnthropicProvider {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    pub api_key: String,
+    pub model: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: ANTHROPIC_BASE_URL.to_string(),
+            api_key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn complete(&self, request: &LlmRequest) -> Result<LlmResponse, EnclaveError> {
+        let mut body = json!({
+            "model": self.model,
+            "system": request.system,
+            "messages": [{ "role": "user", "content": request.prompt }],
+            "max_tokens": request.max_tokens,
+            "temperature": request.temperature
+        });
+        // The Messages API has no response format, forcing a tool call constrains the reply to
+        // the schema of its input instead.
+        if let Some(response_schema) = &request.response_schema {
+            body["tools"] = json!([{
+                "name": response_schema.name,
+                "description": "Return the result of the conversion.",
+                "input_schema": response_schema.schema
+            }]);
+            body["tool_choice"] = json!({ "type": "tool", "name": response_schema.name });
+        }
+        let http_request = self
+            .client
+            .post(format!("{}/messages", self.base_url.trim_end_matches('/')))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body);
+        let response_json = send(self.name(), http_request).await?;
+
+        // The reply is a list of content blocks: the input of the forced tool call, otherwise the
+        // text ones.
+        let blocks = response_json["content"].as_array().into_iter().flatten();
+        let tool_input = blocks
+            .clone()
+            .find(|block| block["type"] == "tool_use")
+            .map(|block| block["input"].to_string());
+        let content: String = tool_input.unwrap_or_else(|| {
+            blocks
+                .filter(|block| block["type"] == "text")
+                .filter_map(|block| block["text"].as_str())
+                .collect()
+        });
+        if content.is_empty() {
+            return Err(EnclaveError::GenericError(
+                "No content in response".to_string(),
+            ));
+        }
+        let usage = &response_json["usage"];
+        Ok(LlmResponse {
+            content,
+            usage: usage.is_object().then(|| LlmUsage {
+                prompt_tokens: usage["input_tokens"].as_u64().unwrap_or_default(),
+                completion_tokens: usage["output_tokens"].as_u64().unwrap_or_default(),
+                cost_usd: None,
+            }),
+        })
+    }
+}
+
+/// In-process provider replying with `responses` in turn, then repeating the last one. Every
+/// request is kept so tests can inspect the prompts. Usage is reported as one token per four
+/// characters.
+pub struct MockProvider {
+    responses: Mutex<VecDeque<String>>,
+    pub requests: Mutex<Vec<LlmRequest>>,
+}
+
+impl MockProvider {
+    pub fn new(responses: Vec<String>) -> Self {
+        Self {
+            responses: Mutex::new(responses.into()),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn model(&self) -> &str {
+        "mock"
+    }
+
+    async fn complete(&self, request: &LlmRequest) -> Result<LlmResponse, EnclaveError> {
+        self.requests
+            .lock()
+            .expect("mock requests lock poisoned")
+            .push(request.clone());
+        let mut responses = self.responses.lock().expect("mock responses lock poisoned");
+        let content = if responses.len() > 1 {
+            responses.pop_front()
+        } else {
+            responses.front().cloned()
+        }
+        .ok_or_else(|| EnclaveError::GenericError("Mock provider has no response".to_string()))?;
+        let usage = LlmUsage {
+            prompt_tokens: (request.system.len() + request.prompt.len()).div_ceil(4) as u64,
+            completion_tokens: content.len().div_ceil(4) as u64,
+            cost_usd: None,
+        };
+        Ok(LlmResponse {
+            content,
+            usage: Some(usage),
+        })
+    }
+}
+
+/// Send a request and return its JSON body, failing on HTTP errors.
+async fn send(name: &str, request: reqwest::RequestBuilder) -> Result<Value, EnclaveError> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| EnclaveError::GenericError(format!("{name} request failed: {e}")))?;
+    if !response.status().is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(EnclaveError::GenericError(format!(
+            "{name} error: {error_text}"
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to parse response: {e}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::schema::FHIR_OUTPUT_SCHEMA;
+    use axum::http::HeaderMap;
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+
+    #[tokio::test]
+    async fn test_llm_providers() {
+        use axum::http::HeaderMap;
+        use axum::routing::post;
+
+        // Local stand-in for the OpenAI-compatible and Anthropic APIs, echoing the credentials and
+        // prompts they received.
+        let app = axum::Router::new()
+            .route(
+                "/v1/chat/completions",
+                post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                    let content = format!(
+                        "{:?} {} {}",
+                        headers.get("authorization"),
+                        body["model"],
+                        body["messages"][1]["content"]
+                    );
+                    Json(json!({
+                        "choices": [{ "message": { "content": content } }],
+                        "usage": { "prompt_tokens": 12, "completion_tokens": 5, "cost": 0.0001 }
+                    }))
+                }),
+            )
+            .route(
+                "/v1/messages",
+                post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                    let text = format!(
+                        "{:?} {} {}",
+                        headers.get("x-api-key"),
+                        body["system"],
+                        body["messages"][0]["content"]
+                    );
+                    Json(json!({
+                        "content": [{ "type": "text", "text": text }],
+                        "usage": { "input_tokens": 7, "output_tokens": 3 }
+                    }))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let request = LlmRequest {
+            system: "system".to_string(),
+            prompt: "prompt".to_string(),
+            max_tokens: 10,
+            temperature: 0.0,
+            response_schema: None,
+        };
+        let mut config: LlmConfig = serde_yaml::from_str(&format!(
+            "provider: openai-compatible\nmodel: local\nbase_url: {base_url}"
+        ))
+        .unwrap();
+        let provider = create_provider(&config, None).unwrap();
+        assert_eq!(provider.name(), "openai-compatible");
+        assert_eq!(
+            provider.complete(&request).await.unwrap().content,
+            r#"None "local" "prompt""#
+        );
+        let provider = create_provider(&config, Some("key".to_string())).unwrap();
+        let response = provider.complete(&request).await.unwrap();
+        assert_eq!(response.content, r#"Some("Bearer key") "local" "prompt""#);
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 5));
+        assert_eq!(usage.cost_usd, Some(0.0001));
+
+        config.provider = LlmProviderKind::Anthropic;
+        assert!(create_provider(&config, None).is_err());
+        let provider = create_provider(&config, Some("key".to_string())).unwrap();
+        let response = provider.complete(&request).await.unwrap();
+        assert_eq!(response.content, r#"Some("key") "system" "prompt""#);
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (7, 3));
+        assert_eq!(usage.cost_usd, None);
+
+        config.provider = LlmProviderKind::OpenAiCompatible;
+        config.base_url = None;
+        assert!(create_provider(&config, None).is_err());
+
+        // The bundled configuration selects OpenRouter.
+        assert_eq!(LLM_CONFIG.provider, LlmProviderKind::OpenRouter);
+        assert!(LLM_CONFIG.requires_api_key());
+    }
+
+    #[tokio::test]
+    async fn test_llm_provider_errors() {
+        // Replies keyed on the model: an HTTP error, a reply without content, or the attribution
+        // headers without any usage.
+        let app = axum::Router::new()
+            .route(
+                "/v1/chat/completions",
+                post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                    match body["model"].as_str() {
+                        Some("down") => (axum::http::StatusCode::SERVICE_UNAVAILABLE, "overloaded")
+                            .into_response(),
+                        Some("empty") => Json(json!({ "choices": [] })).into_response(),
+                        _ => {
+                            let content = format!(
+                                "{:?} {:?}",
+                                headers.get("http-referer"),
+                                headers.get("x-title")
+                            );
+                            Json(json!({ "choices": [{ "message": { "content": content } }] }))
+                                .into_response()
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/v1/messages",
+                post(|Json(body): Json<Value>| async move {
+                    let content = match body["model"].as_str() {
+                        Some("empty") => json!([]),
+                        _ => json!([
+                            { "type": "text", "text": "first " },
+                            { "type": "thinking", "thinking": "ignored" },
+                            { "type": "text", "text": "second" }
+                        ]),
+                    };
+                    Json(json!({ "content": content }))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let request = LlmRequest {
+            system: "system".to_string(),
+            prompt: "prompt".to_string(),
+            max_tokens: 10,
+            temperature: 0.0,
+            response_schema: None,
+        };
+        let config = |provider: &str, model: &str, extra: &str| -> LlmConfig {
+            serde_yaml::from_str(&format!(
+                "provider: {provider}\nmodel: {model}\nbase_url: {base_url}\n{extra}"
+            ))
+            .unwrap()
+        };
+        let key = || Some("key".to_string());
+
+        // OpenRouter only sends the attribution headers that are configured.
+        let provider =
+            create_provider(&config("openrouter", "m", "app_title: Vault"), key()).unwrap();
+        assert_eq!(provider.name(), "openrouter");
+        let response = provider.complete(&request).await.unwrap();
+        assert_eq!(response.content, r#"None Some("Vault")"#);
+        assert_eq!(response.usage, None);
+
+        let provider = create_provider(&config("openai-compatible", "down", ""), None).unwrap();
+        let error = provider.complete(&request).await.unwrap_err().to_string();
+        assert!(
+            error.contains("openai-compatible error: overloaded"),
+            "{error}"
+        );
+        let provider = create_provider(&config("openai-compatible", "empty", ""), None).unwrap();
+        assert!(provider.complete(&request).await.is_err());
+
+        // Text blocks are joined, other blocks are dropped.
+        let provider = create_provider(&config("anthropic", "m", ""), key()).unwrap();
+        let response = provider.complete(&request).await.unwrap();
+        assert_eq!(response.content, "first second");
+        assert_eq!(response.usage, None);
+        let provider = create_provider(&config("anthropic", "empty", ""), key()).unwrap();
+        assert!(provider.complete(&request).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider() {
+        let request = LlmRequest {
+            system: "sys".to_string(),
+            prompt: "prompt".to_string(),
+            max_tokens: 10,
+            temperature: 0.0,
+            response_schema: None,
+        };
+        let provider = MockProvider::new(vec!["one".to_string(), "second".to_string()]);
+        let replies = [
+            provider.complete(&request).await.unwrap(),
+            provider.complete(&request).await.unwrap(),
+            provider.complete(&request).await.unwrap(),
+        ];
+        let contents: Vec<&str> = replies.iter().map(|r| r.content.as_str()).collect();
+        assert_eq!(contents, ["one", "second", "second"]);
+        // One token per four characters, rounded up.
+        let usage = replies[1].usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (3, 2));
+        assert_eq!(provider.requests.lock().unwrap().len(), 3);
+
+        assert!(MockProvider::new(Vec::new())
+            .complete(&request)
+            .await
+            .is_err());
+
+        // The mock provider needs no key and defaults apply to what is not configured.
+        let config: LlmConfig = serde_yaml::from_str("provider: mock\nmodel: mock").unwrap();
+        assert!(!config.requires_api_key());
+        assert_eq!(config.max_attempts, DEFAULT_MAX_ATTEMPTS);
+        assert!(config.structured_output);
+        assert_eq!(create_provider(&config, None).unwrap().name(), "mock");
+    }
+
+    #[tokio::test]
+    async fn test_structured_requests() {
+        // Stand-in servers answering with what they were asked to follow.
+        let app = axum::Router::new()
+            .route(
+                "/v1/chat/completions",
+                post(|Json(body): Json<Value>| async move {
+                    let content = body["response_format"].to_string();
+                    Json(json!({ "choices": [{ "message": { "content": content } }] }))
+                }),
+            )
+            .route(
+                "/v1/messages",
+                post(|Json(body): Json<Value>| async move {
+                    let input =
+                        json!({ "tools": body["tools"], "tool_choice": body["tool_choice"] });
+                    Json(
+                        json!({ "content": [{ "type": "tool_use", "name": "x", "input": input }] }),
+                    )
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let request = LlmRequest {
+            system: "system".to_string(),
+            prompt: "prompt".to_string(),
+            max_tokens: 10,
+            temperature: 0.0,
+            response_schema: Some(ResponseSchema {
+                name: "fhir_conversion".to_string(),
+                schema: FHIR_OUTPUT_SCHEMA.clone(),
+            }),
+        };
+        let mut config: LlmConfig = serde_yaml::from_str(&format!(
+            "provider: openai-compatible\nmodel: local\nbase_url: {base_url}"
+        ))
+        .unwrap();
+        assert!(config.structured_output);
+        let provider = create_provider(&config, None).unwrap();
+        let content = provider.complete(&request).await.unwrap().content;
+        let response_format: Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(response_format["type"], "json_schema");
+        assert_eq!(response_format["json_schema"]["name"], "fhir_conversion");
+        assert_eq!(
+            response_format["json_schema"]["schema"],
+            *FHIR_OUTPUT_SCHEMA
+        );
+
+        config.provider = LlmProviderKind::Anthropic;
+        let provider = create_provider(&config, Some("key".to_string())).unwrap();
+        let content = provider.complete(&request).await.unwrap().content;
+        let tool: Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(tool["tools"][0]["input_schema"], *FHIR_OUTPUT_SCHEMA);
+        assert_eq!(
+            tool["tool_choice"],
+            json!({ "type": "tool", "name": "fhir_conversion" })
+        );
+    }
+}
@@ -0,0 +1,614 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Predicate attestations over medical records: evaluate a typed yes/no question against a bundle
+// and sign only the answer and the hash of the question, never the underlying data.
+
+use super::dates::{age_in_years, civil_from_timestamp_ms, parse_fhir_date};
+use super::*;
+use crate::common::{to_signed_response, ProcessedDataResponse};
+use crate::jcs::canonicalize_to_vec;
+use fastcrypto::hash::{HashFunction, Sha3_256};
+use fastcrypto::traits::KeyPair;
+use serde_json::Value;
+
+/// Maximum number of nodes in a predicate tree.
+const MAX_PREDICATE_NODES: usize = 32;
+
+/// Coding systems a code predicate can refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeSystem {
+    Snomed,
+    Loinc,
+    RxNorm,
+    Icd10Cm,
+}
+
+impl CodeSystem {
+    pub fn uri(&self) -> &'static str {
+        match self {
+            CodeSystem::Snomed => "http://snomed.info/sct",
+            CodeSystem::Loinc => "http://loinc.org",
+            CodeSystem::RxNorm => "http://www.nlm.nih.gov/research/umls/rxnorm",
+            CodeSystem::Icd10Cm => "http://hl7.org/fhir/sid/icd-10-cm",
+        }
+    }
+}
+
+/// A typed predicate over a patient's bundle.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Predicate {
+    /// The patient is at least `years` old at evaluation time.
+    AgeAtLeast {
+        years: u32,
+    },
+    /// The patient is younger than `years` at evaluation time.
+    AgeBelow {
+        years: u32,
+    },
+    /// A Condition, Observation, Procedure or MedicationRequest carries the code. With
+    /// `active_only`, only active Conditions and MedicationRequests count.
+    HasCode {
+        system: CodeSystem,
+        code: String,
+        #[serde(default)]
+        active_only: bool,
+    },
+    /// The most recent Observation with the LOINC code, optionally within the last `within_days`
+    /// days, has a valueQuantity in `[min, max)`.
+    ObservationInRange {
+        loinc: String,
+        #[serde(default)]
+        min: Option<f64>,
+        #[serde(default)]
+        max: Option<f64>,
+        #[serde(default)]
+        within_days: Option<u32>,
+    },
+    Not {
+        predicate: Box<Predicate>,
+    },
+    All {
+        predicates: Vec<Predicate>,
+    },
+    Any {
+        predicates: Vec<Predicate>,
+    },
+}
+
+/// Inner type T for IntentMessage<T>: the predicate answer and the bundle it was evaluated on. The
+/// BCS layout matches `PredicateAttestation` in the Move `validator` module, verified by
+/// `record_predicate_attestation`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PredicateAttestation {
+    pub semantic_hash: Vec<u8>,
+    pub resource_root: Vec<u8>,
+    pub predicate_hash: Vec<u8>,
+    pub result: bool,
+}
+
+impl Predicate {
+    /// SHA3-256 over the RFC 8785 canonical JSON of the predicate, so a verifier can recompute it
+    /// from the predicate it asked for.
+    pub fn hash(&self) -> Result<Vec<u8>, String> {
+        let value = serde_json::to_value(self).map_err(|e| format!("Invalid predicate: {e}"))?;
+        Ok(Sha3_256::digest(canonicalize_to_vec(&value)?).to_vec())
+    }
+
+    fn node_count(&self) -> usize {
+        match self {
+            Predicate::Not { predicate } => 1 + predicate.node_count(),
+            Predicate::All { predicates } | Predicate::Any { predicates } => {
+                1 + predicates.iter().map(Predicate::node_count).sum::<usize>()
+            }
+            _ => 1,
+        }
+    }
+
+    /// Evaluate the predicate against the resources of a bundle at `now_ms`.
+    pub fn evaluate(&self, resources: &[&Value], now_ms: u64) -> Result<bool, String> {
+        if self.node_count() > MAX_PREDICATE_NODES {
+            return Err(format!(
+                "Predicate has more than {MAX_PREDICATE_NODES} nodes"
+            ));
+        }
+        self.evaluate_inner(resources, now_ms)
+    }
+
+    fn evaluate_inner(&self, resources: &[&Value], now_ms: u64) -> Result<bool, String> {
+        match self {
+            Predicate::AgeAtLeast { years } => {
+                // With a partial birth date, assume the latest possible birth day.
+                let birth = birth_date(resources)?;
+                let day = birth.day.unwrap_or_else(|| {
+                    super::dates::days_in_month(birth.year, birth.month.unwrap_or(12))
+                });
+                let age = age_in_years(
+                    (birth.year, birth.month.unwrap_or(12), day),
+                    civil_from_timestamp_ms(now_ms),
+                );
+                Ok(age >= *years as i64)
+            }
+            Predicate::AgeBelow { years } => {
+                // With a partial birth date, assume the earliest possible birth day.
+                let birth = birth_date(resources)?;
+                let age = age_in_years(
+                    (birth.year, birth.month.unwrap_or(1), birth.day.unwrap_or(1)),
+                    civil_from_timestamp_ms(now_ms),
+                );
+                Ok(age < *years as i64)
+            }
+            Predicate::HasCode {
+                system,
+                code,
+                active_only,
+            } => Ok(resources
+                .iter()
+                .filter(|r| !*active_only || is_active(r))
+                .any(|r| has_coding(r, system.uri(), code))),
+            Predicate::ObservationInRange {
+                loinc,
+                min,
+                max,
+                within_days,
+            } => {
+                let today = (now_ms / 86_400_000) as i64;
+                let latest = resources
+                    .iter()
+                    .filter(|r| resource_type(r) == Some("Observation"))
+                    .filter(|r| has_coding(r, CodeSystem::Loinc.uri(), loinc))
+                    .filter_map(|r| {
+                        let day = observation_day(r)?;
+                        let value = r.get("valueQuantity")?.get("value")?.as_f64()?;
+                        Some((day, value))
+                    })
+                    .filter(|(day, _)| {
+                        within_days
+                            .is_none_or(|window| today - day <= window as i64 && *day <= today)
+                    })
+                    .max_by_key(|(day, _)| *day);
+                Ok(match latest {
+                    Some((_, value)) => {
+                        min.is_none_or(|min| value >= min) && max.is_none_or(|max| value < max)
+                    }
+                    None => false,
+                })
+            }
+            Predicate::Not { predicate } => Ok(!predicate.evaluate_inner(resources, now_ms)?),
+            Predicate::All { predicates } => {
+                for p in predicates {
+                    if !p.evaluate_inner(resources, now_ms)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Predicate::Any { predicates } => {
+                for p in predicates {
+                    if p.evaluate_inner(resources, now_ms)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn resource_type(resource: &Value) -> Option<&str> {
+    resource.get("resourceType").and_then(|t| t.as_str())
+}
+
+fn birth_date(resources: &[&Value]) -> Result<super::dates::PartialDate, String> {
+    let birth_date = resources
+        .iter()
+        .find(|r| resource_type(r) == Some("Patient"))
+        .ok_or_else(|| "Bundle has no Patient".to_string())?
+        .get("birthDate")
+        .and_then(|b| b.as_str())
+        .ok_or_else(|| "Patient has no birthDate".to_string())?;
+    parse_fhir_date(birth_date).ok_or_else(|| format!("Invalid birthDate {birth_date}"))
+}
+
+/// Codings of the resource's main concept, for R4 and R5 style MedicationRequests as well.
+pub(crate) fn has_coding(resource: &Value, system: &str, code: &str) -> bool {
+    let concepts = [
+        resource.get("code"),
+        resource.get("medicationCodeableConcept"),
+        resource.get("medication").and_then(|m| m.get("concept")),
+    ];
+    concepts
+        .into_iter()
+        .flatten()
+        .filter_map(|c| c.get("coding").and_then(|c| c.as_array()))
+        .flatten()
+        .any(|coding| {
+            coding.get("system").and_then(|s| s.as_str()) == Some(system)
+                && coding.get("code").and_then(|c| c.as_str()) == Some(code)
+        })
+}
+
+/// Whether a Condition or MedicationRequest is currently active. Other resources always count.
+fn is_active(resource: &Value) -> bool {
+    match resource_type(resource) {
+        Some("Condition") => resource
+            .get("clinicalStatus")
+            .and_then(|s| s.get("coding"))
+            .and_then(|c| c.as_array())
+            .map(|codings| {
+                codings.iter().any(|c| {
+                    matches!(
+                        c.get("code").and_then(|c| c.as_str()),
+                        Some("active" | "recurrence" | "relapse")
+                    )
+                })
+            })
+            .unwrap_or(false),
+        Some("MedicationRequest") => {
+            resource.get("status").and_then(|s| s.as_str()) == Some("active")
+        }
+        _ => true,
+    }
+}
+
+/// Day (since 1970-01-01) an Observation was made.
+fn observation_day(resource: &Value) -> Option<i64> {
+    let date = resource
+        .get("effectiveDateTime")
+        .or_else(|| resource.get("effectivePeriod").and_then(|p| p.get("start")))
+        .or_else(|| resource.get("effectiveInstant"))
+        .or_else(|| resource.get("issued"))?
+        .as_str()?;
+    parse_fhir_date(date).map(|d| d.earliest_day())
+}
+
+/// Evaluate a predicate against a bundle produced by the enclave, see `resolve_bundle`. Returns only
+/// the boolean, the predicate hash and the semantic hash and resource root of the bundle, signed
+/// with the PredicateAttestation intent scope.
+pub async fn attest_predicate(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PredicateRequest>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<PredicateAttestation>>>, EnclaveError> {
+    let current_timestamp = current_timestamp_ms()?;
+    let (bundle, semantic_hash) = resolve_bundle(
+        state.eph_kp.public(),
+        request.bundle,
+        request.attestation,
+        request.encrypted_bundle,
+    )
+    .await?;
+
+    let predicate_hash = request
+        .predicate
+        .hash()
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to hash predicate: {e}")))?;
+    let result = request
+        .predicate
+        .evaluate(&bundle_resources(&bundle), current_timestamp)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to evaluate predicate: {e}")))?;
+
+    info!("Predicate attestation evaluated");
+
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        PredicateAttestation {
+            semantic_hash: Hex::decode(&semantic_hash)
+                .map_err(|e| EnclaveError::GenericError(format!("Invalid semantic hash: {e}")))?,
+            resource_root: commit_resources(&bundle)?.root.to_vec(),
+            predicate_hash,
+            result,
+        },
+        current_timestamp,
+        IntentScope::PredicateAttestation as u8,
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    /// Patient born on `birth_date`.
+    fn born(birth_date: &str) -> Value {
+        json!({ "resourceType": "Patient", "birthDate": birth_date })
+    }
+
+    /// Observation of LOINC `code` with `value`, dated by the elements of `effective`.
+    fn observation(code: &str, effective: Value, value: f64) -> Value {
+        let mut resource = json!({
+            "resourceType": "Observation",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": code }] },
+            "valueQuantity": { "value": value }
+        });
+        resource
+            .as_object_mut()
+            .unwrap()
+            .extend(effective.as_object().unwrap().clone());
+        resource
+    }
+
+    #[test]
+    fn test_predicate_evaluation() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [
+                { "resource": { "resourceType": "Patient", "birthDate": "2007" } },
+                { "resource": {
+                    "resourceType": "Condition",
+                    "clinicalStatus": { "coding": [{ "code": "resolved" }] },
+                    "code": { "coding": [{ "system": "http://snomed.info/sct", "code": "44054006" }] }
+                } },
+                { "resource": {
+                    "resourceType": "Observation",
+                    "code": { "coding": [{ "system": "http://loinc.org", "code": "39156-5" }] },
+                    "effectiveDateTime": "2025-01-10",
+                    "valueQuantity": { "value": 31.2, "unit": "kg/m2" }
+                } },
+                { "resource": {
+                    "resourceType": "Observation",
+                    "code": { "coding": [{ "system": "http://loinc.org", "code": "39156-5" }] },
+                    "effectiveDateTime": "2025-03-01T09:00:00Z",
+                    "valueQuantity": { "value": 28.4, "unit": "kg/m2" }
+                } }
+            ]
+        });
+        let resources = bundle_resources(&bundle);
+        // 2025-04-07T15:15:00Z
+        let now = 1744038900000;
+
+        // Born in 2007, the patient may still be 17 on 2025-04-07.
+        let adult = Predicate::AgeAtLeast { years: 18 };
+        assert!(!adult.evaluate(&resources, now).unwrap());
+        assert!(Predicate::AgeBelow { years: 19 }
+            .evaluate(&resources, now)
+            .unwrap());
+
+        let diabetes = Predicate::HasCode {
+            system: CodeSystem::Snomed,
+            code: "44054006".to_string(),
+            active_only: true,
+        };
+        assert!(!diabetes.evaluate(&resources, now).unwrap());
+        let no_active_diabetes = Predicate::Not {
+            predicate: Box::new(diabetes),
+        };
+        assert!(no_active_diabetes.evaluate(&resources, now).unwrap());
+
+        // The most recent BMI in the last 12 months is used.
+        let bmi_under_30 = Predicate::ObservationInRange {
+            loinc: "39156-5".to_string(),
+            min: None,
+            max: Some(30.0),
+            within_days: Some(365),
+        };
+        assert!(bmi_under_30.evaluate(&resources, now).unwrap());
+        let all = Predicate::All {
+            predicates: vec![no_active_diabetes, bmi_under_30, adult],
+        };
+        assert!(!all.evaluate(&resources, now).unwrap());
+
+        // The predicate hash only depends on the canonical predicate.
+        let parsed: Predicate = serde_json::from_value(json!({
+            "type": "age_at_least", "years": 18
+        }))
+        .unwrap();
+        assert_eq!(
+            parsed.hash().unwrap(),
+            Predicate::AgeAtLeast { years: 18 }.hash().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_predicate_age() {
+        // 2025-04-07T15:15:00Z
+        let now = 1744038900000;
+        let answers = |birth_date: &str, years: u32| {
+            let patient = born(birth_date);
+            (
+                Predicate::AgeAtLeast { years }
+                    .evaluate(&[&patient], now)
+                    .unwrap(),
+                Predicate::AgeBelow { years }
+                    .evaluate(&[&patient], now)
+                    .unwrap(),
+            )
+        };
+        // The birthday counts from its first day.
+        assert_eq!(answers("2007-04-07", 18), (true, false));
+        assert_eq!(answers("2007-04-08", 18), (false, true));
+        assert_eq!(answers("2007-04-06T23:59:59Z", 18), (true, false));
+        // A partial birth date answers neither while the birthday may or may not have passed.
+        assert_eq!(answers("2007-04", 18), (false, false));
+        assert_eq!(answers("2007", 18), (false, false));
+        assert_eq!(answers("2007-03", 18), (true, false));
+        assert_eq!(answers("2006", 18), (true, false));
+        // Leap day births are a year older on March 1st of other years.
+        let leap = born("2008-02-29");
+        assert!(!Predicate::AgeAtLeast { years: 17 }
+            .evaluate(&[&leap], 1740700800000) // 2025-02-28
+            .unwrap());
+        assert!(Predicate::AgeAtLeast { years: 17 }
+            .evaluate(&[&leap], 1740787200000) // 2025-03-01
+            .unwrap());
+
+        let adult = Predicate::AgeAtLeast { years: 18 };
+        assert_eq!(
+            adult.evaluate(&[], now).unwrap_err(),
+            "Bundle has no Patient"
+        );
+        let unknown = json!({ "resourceType": "Patient" });
+        assert_eq!(
+            adult.evaluate(&[&unknown], now).unwrap_err(),
+            "Patient has no birthDate"
+        );
+        assert!(adult.evaluate(&[&born("2007-13-01")], now).is_err());
+        // Errors are not hidden by Not.
+        let not_adult = Predicate::Not {
+            predicate: Box::new(adult),
+        };
+        assert!(not_adult.evaluate(&[], now).is_err());
+    }
+
+    #[test]
+    fn test_predicate_observation_range() {
+        // 2025-04-07T15:15:00Z
+        let now = 1744038900000;
+        let in_range = |resources: &[Value], min: Option<f64>, max: Option<f64>, days| {
+            let resources: Vec<&Value> = resources.iter().collect();
+            Predicate::ObservationInRange {
+                loinc: "4548-4".to_string(),
+                min,
+                max,
+                within_days: days,
+            }
+            .evaluate(&resources, now)
+            .unwrap()
+        };
+        let on = |date: &str| json!({ "effectiveDateTime": date });
+
+        // The range includes its minimum and excludes its maximum.
+        let hba1c = [observation("4548-4", on("2025-04-01"), 6.5)];
+        assert!(in_range(&hba1c, Some(6.5), None, None));
+        assert!(!in_range(&hba1c, None, Some(6.5), None));
+        assert!(in_range(&hba1c, Some(5.7), Some(6.5000001), None));
+        assert!(in_range(&hba1c, None, None, None));
+
+        // The window reaches back `within_days` whole days and excludes later observations.
+        let dated = [
+            observation("4548-4", on("2024-04-07"), 7.2),
+            observation("4548-4", on("2025-04-09"), 5.1),
+        ];
+        assert!(in_range(&dated, Some(7.0), None, Some(365)));
+        assert!(!in_range(&dated, Some(7.0), None, Some(364)));
+        // Without a window, the latest observation is used even if dated after now.
+        assert!(in_range(&dated, None, Some(5.7), None));
+
+        // The date is taken from a period, an instant or the issue time, in that order.
+        let timed = [
+            observation(
+                "4548-4",
+                json!({ "effectivePeriod": { "start": "2025-03-01" } }),
+                6.0,
+            ),
+            observation(
+                "4548-4",
+                json!({ "effectiveInstant": "2025-03-02T00:00:00Z" }),
+                6.1,
+            ),
+            observation(
+                "4548-4",
+                json!({ "issued": "2025-03-03T08:00:00.123+02:00" }),
+                6.2,
+            ),
+        ];
+        assert!(in_range(&timed, Some(6.2), Some(6.3), Some(60)));
+
+        // Observations of other codes, without a date or without a quantity are not considered.
+        let unusable = [
+            observation("2345-7", on("2025-04-06"), 6.0),
+            observation("4548-4", json!({}), 6.0),
+            json!({
+                "resourceType": "Observation",
+                "code": { "coding": [{ "system": "http://loinc.org", "code": "4548-4" }] },
+                "effectiveDateTime": "2025-04-06",
+                "valueString": "6.0"
+            }),
+        ];
+        assert!(!in_range(&unusable, None, None, None));
+    }
+
+    #[test]
+    fn test_predicate_codes_and_limits() {
+        let now = 1744038900000;
+        let rxnorm = |status: &str| {
+            json!({
+                "resourceType": "MedicationRequest",
+                "status": status,
+                "medication": { "concept": { "coding": [{
+                    "system": "http://www.nlm.nih.gov/research/umls/rxnorm", "code": "860975"
+                }] } }
+            })
+        };
+        let metformin = |active_only| Predicate::HasCode {
+            system: CodeSystem::RxNorm,
+            code: "860975".to_string(),
+            active_only,
+        };
+        let (active, stopped) = (rxnorm("active"), rxnorm("stopped"));
+        assert!(metformin(true).evaluate(&[&active], now).unwrap());
+        assert!(!metformin(true).evaluate(&[&stopped], now).unwrap());
+        assert!(metformin(false).evaluate(&[&stopped], now).unwrap());
+        // Conditions without a clinical status are not active, other resources always count.
+        let condition = json!({
+            "resourceType": "Condition",
+            "code": { "coding": [{ "system": "http://hl7.org/fhir/sid/icd-10-cm", "code": "E11.9" }] }
+        });
+        let icd = Predicate::HasCode {
+            system: CodeSystem::Icd10Cm,
+            code: "E11.9".to_string(),
+            active_only: true,
+        };
+        assert!(!icd.evaluate(&[&condition], now).unwrap());
+        let mut procedure = condition.clone();
+        procedure["resourceType"] = json!("Procedure");
+        assert!(icd.evaluate(&[&procedure], now).unwrap());
+
+        // Empty combinations answer as their identity.
+        let all = Predicate::All { predicates: vec![] };
+        let any = Predicate::Any { predicates: vec![] };
+        assert!(all.evaluate(&[], now).unwrap());
+        assert!(!any.evaluate(&[], now).unwrap());
+
+        // Trees are limited to MAX_PREDICATE_NODES nodes, counting every level.
+        let leaves = |count| Predicate::Any {
+            predicates: vec![metformin(false); count],
+        };
+        assert!(leaves(MAX_PREDICATE_NODES - 1)
+            .evaluate(&[&active], now)
+            .unwrap());
+        assert!(leaves(MAX_PREDICATE_NODES)
+            .evaluate(&[&active], now)
+            .is_err());
+        let nested = Predicate::Not {
+            predicate: Box::new(leaves(MAX_PREDICATE_NODES - 1)),
+        };
+        assert!(nested.evaluate(&[&active], now).is_err());
+
+        // Defaults are part of the hash, so omitting them asks the same question.
+        let parsed: Predicate = serde_json::from_value(json!({
+            "type": "has_code", "system": "rx_norm", "code": "860975"
+        }))
+        .unwrap();
+        assert_eq!(parsed.hash().unwrap(), metformin(false).hash().unwrap());
+        assert_ne!(parsed.hash().unwrap(), metformin(true).hash().unwrap());
+        assert!(serde_json::from_value::<Predicate>(json!({ "type": "age_at_least" })).is_err());
+    }
+
+    #[test]
+    fn test_predicate_serde() {
+        // test result should be consistent with `test_predicate_serde` in
+        // `move/medical-vault/sources/validator.move`.
+        let intent_msg = IntentMessage::new(
+            PredicateAttestation {
+                semantic_hash: vec![0xcd; 32],
+                resource_root: vec![0xef; 32],
+                predicate_hash: vec![0xab; 32],
+                result: true,
+            },
+            1744038900000,
+            IntentScope::PredicateAttestation as u8,
+        );
+        let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
+        assert_eq!(
+            Hex::encode(signing_payload),
+            format!(
+                "6920b1d1109601000020{}20{}20{}01",
+                "cd".repeat(32),
+                "ef".repeat(32),
+                "ab".repeat(32)
+            )
+        );
+    }
+}
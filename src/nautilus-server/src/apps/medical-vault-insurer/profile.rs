@@ -0,0 +1,814 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Deterministic validator for the BTP Medical Vault Profile V0 constraints listed in
+// FHIR_SYSTEM_PROMPT. Issues are reported as a FHIR OperationOutcome so that callers get the same
+// shape whether a bundle is rejected after LLM conversion or checked through /validate_fhir.
+
+use super::dates::parse_fhir_date;
+use super::*;
+use serde_json::Value;
+
+const LOINC: &str = "http://loinc.org";
+const SNOMED: &str = "http://snomed.info/sct";
+const RXNORM: &str = "http://www.nlm.nih.gov/research/umls/rxnorm";
+const ATC: &str = "http://www.whocc.no/atc";
+const UCUM: &str = "http://unitsofmeasure.org";
+
+const GENDERS: &[&str] = &["male", "female", "other", "unknown"];
+const CLINICAL_STATUSES: &[&str] = &[
+    "active",
+    "recurrence",
+    "relapse",
+    "inactive",
+    "remission",
+    "resolved",
+];
+const VERIFICATION_STATUSES: &[&str] = &[
+    "unconfirmed",
+    "provisional",
+    "differential",
+    "confirmed",
+    "refuted",
+];
+const MEDICATION_REQUEST_STATUSES: &[&str] = &[
+    "active",
+    "on-hold",
+    "cancelled",
+    "completed",
+    "entered-in-error",
+    "stopped",
+    "draft",
+    "unknown",
+];
+const MEDICATION_REQUEST_INTENTS: &[&str] = &[
+    "proposal",
+    "plan",
+    "order",
+    "original-order",
+    "reflex-order",
+    "filler-order",
+    "instance-order",
+    "option",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueSeverity {
+    Fatal,
+    Error,
+    Warning,
+    Information,
+}
+
+/// Subset of the FHIR IssueType value set used by the profile validator and the Safe Harbor check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IssueType {
+    Structure,
+    Required,
+    Value,
+    CodeInvalid,
+    NotFound,
+    Security,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationOutcomeIssue {
+    pub severity: IssueSeverity,
+    pub code: IssueType,
+    pub diagnostics: String,
+    /// FHIRPath of the offending element, e.g. `Bundle.entry[2].resource.status`.
+    pub expression: Vec<String>,
+}
+
+/// FHIR R5 OperationOutcome holding the validation issues of a bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationOutcome {
+    #[serde(rename = "resourceType")]
+    pub resource_type: String,
+    pub issue: Vec<OperationOutcomeIssue>,
+}
+
+impl OperationOutcome {
+    /// Whether any issue makes the bundle invalid.
+    pub fn has_errors(&self) -> bool {
+        self.issue
+            .iter()
+            .any(|i| matches!(i.severity, IssueSeverity::Fatal | IssueSeverity::Error))
+    }
+
+    /// One line summary of the errors, for error messages.
+    pub fn error_summary(&self) -> String {
+        self.issue
+            .iter()
+            .filter(|i| matches!(i.severity, IssueSeverity::Fatal | IssueSeverity::Error))
+            .map(|i| format!("{}: {}", i.expression.join(","), i.diagnostics))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Validate a bundle, either bare or in the `{"bundle": {...}}` envelope, against the profile.
+pub fn validate_profile(bundle: &Value) -> OperationOutcome {
+    let mut v = Validator { issues: Vec::new() };
+    v.bundle(bundle.get("bundle").unwrap_or(bundle));
+    OperationOutcome {
+        resource_type: "OperationOutcome".to_string(),
+        issue: v.issues,
+    }
+}
+
+/// Validate a FHIR bundle against the BTP Medical Vault Profile V0 and return the issues found.
+pub async fn validate_fhir(
+    Json(request): Json<FhirValidationRequest>,
+) -> Result<Json<OperationOutcome>, EnclaveError> {
+    let outcome = validate_profile(&request.bundle);
+    info!("FHIR validation complete: {} issues", outcome.issue.len());
+    Ok(Json(outcome))
+}
+
+struct Validator {
+    issues: Vec<OperationOutcomeIssue>,
+}
+
+impl Validator {
+    fn issue(&mut self, severity: IssueSeverity, code: IssueType, path: &str, diagnostics: &str) {
+        self.issues.push(OperationOutcomeIssue {
+            severity,
+            code,
+            diagnostics: diagnostics.to_string(),
+            expression: vec![path.to_string()],
+        });
+    }
+
+    fn error(&mut self, code: IssueType, path: &str, diagnostics: &str) {
+        self.issue(IssueSeverity::Error, code, path, diagnostics);
+    }
+
+    fn warning(&mut self, code: IssueType, path: &str, diagnostics: &str) {
+        self.issue(IssueSeverity::Warning, code, path, diagnostics);
+    }
+
+    fn bundle(&mut self, bundle: &Value) {
+        if str_at(bundle, "resourceType") != Some("Bundle") {
+            self.issue(
+                IssueSeverity::Fatal,
+                IssueType::Structure,
+                "Bundle",
+                "resourceType must be Bundle",
+            );
+            return;
+        }
+        if str_at(bundle, "type").is_none() {
+            self.error(
+                IssueType::Required,
+                "Bundle.type",
+                "Bundle.type is required",
+            );
+        }
+        let entries = match bundle.get("entry").and_then(|e| e.as_array()) {
+            Some(entries) if !entries.is_empty() => entries,
+            _ => {
+                self.issue(
+                    IssueSeverity::Fatal,
+                    IssueType::Required,
+                    "Bundle.entry",
+                    "Bundle must have at least one entry",
+                );
+                return;
+            }
+        };
+
+        let patients: Vec<(usize, &Value)> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| {
+                e.get("resource").and_then(|r| str_at(r, "resourceType")) == Some("Patient")
+            })
+            .collect();
+        // Every way the Patient can be referenced from within the bundle.
+        let mut patient_refs = Vec::new();
+        match patients.as_slice() {
+            [] => self.error(
+                IssueType::Required,
+                "Bundle.entry",
+                "Bundle must contain a Patient",
+            ),
+            [(_, entry)] => {
+                if let Some(full_url) = str_at(entry, "fullUrl") {
+                    patient_refs.push(full_url.to_string());
+                }
+                if let Some(id) = entry.get("resource").and_then(|r| str_at(r, "id")) {
+                    patient_refs.push(format!("Patient/{id}"));
+                }
+            }
+            _ => {
+                for (index, _) in &patients[1..] {
+                    self.error(
+                        IssueType::Structure,
+                        &format!("Bundle.entry[{index}]"),
+                        "Bundle must contain exactly one Patient",
+                    );
+                }
+            }
+        }
+
+        for (index, entry) in entries.iter().enumerate() {
+            let path = format!("Bundle.entry[{index}].resource");
+            let Some(resource) = entry.get("resource") else {
+                self.error(IssueType::Required, &path, "Entry has no resource");
+                continue;
+            };
+            match str_at(resource, "resourceType") {
+                Some("Patient") => self.patient(resource, &path),
+                Some("Observation") => self.observation(resource, &path),
+                Some("Condition") => self.condition(resource, &path),
+                Some("MedicationRequest") => self.medication_request(resource, &path),
+                Some(_) => {}
+                None => self.error(
+                    IssueType::Required,
+                    &format!("{path}.resourceType"),
+                    "resourceType is required",
+                ),
+            }
+            self.patient_references(resource, &path, &patient_refs, patients.len() == 1);
+        }
+    }
+
+    fn patient(&mut self, resource: &Value, path: &str) {
+        match resource.get("identifier").and_then(|i| i.as_array()) {
+            Some(identifiers) if !identifiers.is_empty() => {
+                for (i, identifier) in identifiers.iter().enumerate() {
+                    if str_at(identifier, "system").is_none() {
+                        self.error(
+                            IssueType::Required,
+                            &format!("{path}.identifier[{i}].system"),
+                            "Patient.identifier must have a system",
+                        );
+                    }
+                }
+            }
+            _ => self.error(
+                IssueType::Required,
+                &format!("{path}.identifier"),
+                "Patient.identifier is required",
+            ),
+        }
+
+        match resource.get("name").and_then(|n| n.as_array()) {
+            Some(names) if !names.is_empty() => {
+                let complete = names.iter().any(|n| {
+                    str_at(n, "family").is_some()
+                        && n.get("given")
+                            .and_then(|g| g.as_array())
+                            .is_some_and(|g| !g.is_empty())
+                });
+                if !complete {
+                    self.error(
+                        IssueType::Required,
+                        &format!("{path}.name"),
+                        "Patient.name must have family and given names",
+                    );
+                }
+            }
+            _ => self.error(
+                IssueType::Required,
+                &format!("{path}.name"),
+                "Patient.name is required",
+            ),
+        }
+
+        self.date(resource, path, "birthDate", true);
+        match resource.get("gender") {
+            None => self.warning(
+                IssueType::Required,
+                &format!("{path}.gender"),
+                "Patient.gender should be present",
+            ),
+            Some(gender) => self.value_set(gender.as_str(), GENDERS, &format!("{path}.gender")),
+        }
+    }
+
+    fn observation(&mut self, resource: &Value, path: &str) {
+        if str_at(resource, "status") != Some("final") {
+            self.error(
+                IssueType::Value,
+                &format!("{path}.status"),
+                "Observation.status must be final",
+            );
+        }
+        self.coding(resource.get("code"), &[LOINC], &format!("{path}.code"));
+        if resource.get("category").is_none() {
+            self.warning(
+                IssueType::Required,
+                &format!("{path}.category"),
+                "Observation.category should be present",
+            );
+        }
+        self.date(resource, path, "effectiveDateTime", true);
+
+        let quantity_path = format!("{path}.valueQuantity");
+        match resource.get("valueQuantity") {
+            None => self.error(
+                IssueType::Required,
+                &quantity_path,
+                "Observation.valueQuantity is required",
+            ),
+            Some(quantity) => {
+                if !quantity.get("value").is_some_and(|v| v.is_number()) {
+                    self.error(
+                        IssueType::Required,
+                        &format!("{quantity_path}.value"),
+                        "valueQuantity.value must be a number",
+                    );
+                }
+                if str_at(quantity, "unit").is_none_or(|u| u.trim().is_empty()) {
+                    self.error(
+                        IssueType::Required,
+                        &format!("{quantity_path}.unit"),
+                        "valueQuantity.unit is required",
+                    );
+                }
+                match str_at(quantity, "system") {
+                    None => self.error(
+                        IssueType::Required,
+                        &format!("{quantity_path}.system"),
+                        "valueQuantity.system is required",
+                    ),
+                    Some(UCUM) => {
+                        if str_at(quantity, "code").is_none() {
+                            self.warning(
+                                IssueType::Required,
+                                &format!("{quantity_path}.code"),
+                                "UCUM valueQuantity should have a code",
+                            );
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+
+    fn condition(&mut self, resource: &Value, path: &str) {
+        let status_path = format!("{path}.clinicalStatus");
+        match resource.get("clinicalStatus") {
+            None => self.error(
+                IssueType::Required,
+                &status_path,
+                "Condition.clinicalStatus is required",
+            ),
+            Some(status) => self.status_coding(status, CLINICAL_STATUSES, &status_path),
+        }
+        if let Some(status) = resource.get("verificationStatus") {
+            self.status_coding(
+                status,
+                VERIFICATION_STATUSES,
+                &format!("{path}.verificationStatus"),
+            );
+        }
+        self.coding(resource.get("code"), &[SNOMED], &format!("{path}.code"));
+        self.subject(resource, path);
+        self.date(resource, path, "onsetDateTime", false);
+        self.date(resource, path, "recordedDate", false);
+    }
+
+    fn medication_request(&mut self, resource: &Value, path: &str) {
+        match resource.get("status") {
+            None => self.error(
+                IssueType::Required,
+                &format!("{path}.status"),
+                "MedicationRequest.status is required",
+            ),
+            Some(status) => self.value_set(
+                status.as_str(),
+                MEDICATION_REQUEST_STATUSES,
+                &format!("{path}.status"),
+            ),
+        }
+        match resource.get("intent") {
+            None => self.error(
+                IssueType::Required,
+                &format!("{path}.intent"),
+                "MedicationRequest.intent is required",
+            ),
+            Some(intent) => self.value_set(
+                intent.as_str(),
+                MEDICATION_REQUEST_INTENTS,
+                &format!("{path}.intent"),
+            ),
+        }
+        // R4 style medicationCodeableConcept, or the R5 CodeableReference medication.concept.
+        let (medication, medication_path) = match resource.get("medicationCodeableConcept") {
+            Some(concept) => (Some(concept), format!("{path}.medicationCodeableConcept")),
+            None => (
+                resource.get("medication").and_then(|m| m.get("concept")),
+                format!("{path}.medication.concept"),
+            ),
+        };
+        self.coding(medication, &[RXNORM, ATC], &medication_path);
+        self.subject(resource, path);
+        self.date(resource, path, "authoredOn", false);
+    }
+
+    /// A CodeableConcept must carry at least one coding with a code from one of `systems`.
+    fn coding(&mut self, concept: Option<&Value>, systems: &[&str], path: &str) {
+        let Some(concept) = concept else {
+            self.error(IssueType::Required, path, "Coded element is required");
+            return;
+        };
+        let codings = concept
+            .get("coding")
+            .and_then(|c| c.as_array())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let valid = codings.iter().any(|c| {
+            str_at(c, "system").is_some_and(|s| systems.contains(&s))
+                && str_at(c, "code").is_some_and(|c| !c.trim().is_empty())
+        });
+        if !valid {
+            self.error(
+                IssueType::CodeInvalid,
+                &format!("{path}.coding"),
+                &format!("Expected a coding from {}", systems.join(" or ")),
+            );
+        }
+    }
+
+    /// A status CodeableConcept whose coding must take a value from `allowed`.
+    fn status_coding(&mut self, concept: &Value, allowed: &[&str], path: &str) {
+        let code = concept
+            .get("coding")
+            .and_then(|c| c.as_array())
+            .and_then(|c| c.first())
+            .and_then(|c| str_at(c, "code"));
+        self.value_set(code, allowed, &format!("{path}.coding[0].code"));
+    }
+
+    fn value_set(&mut self, value: Option<&str>, allowed: &[&str], path: &str) {
+        match value {
+            Some(v) if allowed.contains(&v) => {}
+            Some(v) => self.error(
+                IssueType::CodeInvalid,
+                path,
+                &format!("{v} is not one of {}", allowed.join(" | ")),
+            ),
+            None => self.error(IssueType::Required, path, "Code is required"),
+        }
+    }
+
+    /// A date element, when present, must be a valid ISO 8601 date. A missing recommended element
+    /// is a warning.
+    fn date(&mut self, resource: &Value, path: &str, element: &str, recommended: bool) {
+        let element_path = format!("{path}.{element}");
+        match resource.get(element) {
+            None if recommended => self.warning(
+                IssueType::Required,
+                &element_path,
+                &format!("{element} should be present"),
+            ),
+            Some(value) if value.as_str().and_then(parse_fhir_date).is_none() => self.error(
+                IssueType::Value,
+                &element_path,
+                &format!("{element} must be an ISO 8601 date"),
+            ),
+            _ => {}
+        }
+    }
+
+    fn subject(&mut self, resource: &Value, path: &str) {
+        if resource.get("subject").is_none() {
+            self.warning(
+                IssueType::Required,
+                &format!("{path}.subject"),
+                "subject should reference the Patient",
+            );
+        }
+    }
+
+    /// `subject` and `patient` references must resolve to the bundle's Patient.
+    fn patient_references(
+        &mut self,
+        resource: &Value,
+        path: &str,
+        patient_refs: &[String],
+        has_patient: bool,
+    ) {
+        for element in ["subject", "patient"] {
+            let Some(reference) = resource.get(element) else {
+                continue;
+            };
+            let element_path = format!("{path}.{element}.reference");
+            match str_at(reference, "reference") {
+                None => self.error(
+                    IssueType::Required,
+                    &element_path,
+                    "Reference must have a reference",
+                ),
+                Some(r) if has_patient && !patient_refs.iter().any(|p| p == r) => self.error(
+                    IssueType::NotFound,
+                    &element_path,
+                    &format!("{r} does not resolve to the Patient in the bundle"),
+                ),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+fn str_at<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(|v| v.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_profile_validation() {
+        let mut bundle = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "type": "collection",
+                "entry": [
+                    {
+                        "fullUrl": "urn:uuid:p1",
+                        "resource": {
+                            "resourceType": "Patient",
+                            "id": "p1",
+                            "identifier": [{ "system": "urn:mrn", "value": "123" }],
+                            "name": [{ "family": "Doe", "given": ["Jane"] }],
+                            "birthDate": "1980-02-01",
+                            "gender": "female"
+                        }
+                    },
+                    {
+                        "resource": {
+                            "resourceType": "Observation",
+                            "status": "final",
+                            "category": [{ "coding": [{ "code": "vital-signs" }] }],
+                            "code": { "coding": [{ "system": "http://loinc.org", "code": "8867-4" }] },
+                            "subject": { "reference": "urn:uuid:p1" },
+                            "effectiveDateTime": "2025-04-07T10:00:00Z",
+                            "valueQuantity": {
+                                "value": 72,
+                                "unit": "beats/minute",
+                                "system": "http://unitsofmeasure.org",
+                                "code": "/min"
+                            }
+                        }
+                    },
+                    {
+                        "resource": {
+                            "resourceType": "Condition",
+                            "clinicalStatus": { "coding": [{ "code": "active" }] },
+                            "code": { "coding": [{ "system": "http://snomed.info/sct", "code": "44054006" }] },
+                            "subject": { "reference": "Patient/p1" }
+                        }
+                    },
+                    {
+                        "resource": {
+                            "resourceType": "MedicationRequest",
+                            "status": "active",
+                            "intent": "order",
+                            "medicationCodeableConcept": {
+                                "coding": [{ "system": "http://www.nlm.nih.gov/research/umls/rxnorm", "code": "860975" }]
+                            },
+                            "subject": { "reference": "Patient/p1" },
+                            "authoredOn": "2025-04-07"
+                        }
+                    }
+                ]
+            }
+        });
+        let outcome = validate_profile(&bundle);
+        assert!(!outcome.has_errors(), "{}", outcome.error_summary());
+        assert!(outcome.issue.is_empty());
+
+        let entries = &mut bundle["bundle"]["entry"];
+        entries[0]["resource"]["gender"] = json!("f");
+        entries[1]["resource"]["valueQuantity"]
+            .as_object_mut()
+            .unwrap()
+            .remove("unit");
+        entries[2]["resource"]["subject"]["reference"] = json!("Patient/other");
+        entries[3]["resource"]["authoredOn"] = json!("2025-13-01");
+        let outcome = validate_profile(&bundle);
+        let errors: Vec<(IssueType, &str)> = outcome
+            .issue
+            .iter()
+            .filter(|i| i.severity == IssueSeverity::Error)
+            .map(|i| (i.code, i.expression[0].as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (IssueType::CodeInvalid, "Bundle.entry[0].resource.gender"),
+                (
+                    IssueType::Required,
+                    "Bundle.entry[1].resource.valueQuantity.unit"
+                ),
+                (
+                    IssueType::NotFound,
+                    "Bundle.entry[2].resource.subject.reference"
+                ),
+                (IssueType::Value, "Bundle.entry[3].resource.authoredOn"),
+            ]
+        );
+
+        let outcome = validate_profile(&json!({ "resourceType": "Bundle", "entry": [] }));
+        assert!(outcome.has_errors());
+    }
+
+    /// Severity, code and path of every issue of the outcome.
+    fn issues(outcome: &OperationOutcome) -> Vec<(IssueSeverity, IssueType, &str)> {
+        outcome
+            .issue
+            .iter()
+            .map(|i| (i.severity, i.code, i.expression[0].as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_profile_bundle_structure() {
+        let outcome = validate_profile(&json!({ "resourceType": "Patient" }));
+        assert_eq!(
+            issues(&outcome),
+            vec![(IssueSeverity::Fatal, IssueType::Structure, "Bundle")]
+        );
+
+        let outcome =
+            validate_profile(&json!({ "bundle": { "resourceType": "Bundle", "entry": [] } }));
+        assert_eq!(
+            issues(&outcome),
+            vec![
+                (IssueSeverity::Error, IssueType::Required, "Bundle.type"),
+                (IssueSeverity::Fatal, IssueType::Required, "Bundle.entry"),
+            ]
+        );
+
+        // A second Patient is an error and, with no single Patient, references are not resolved.
+        let patient = json!({
+            "resourceType": "Patient",
+            "identifier": [{ "system": "urn:mrn", "value": "1" }],
+            "name": [{ "family": "Doe", "given": ["Jane"] }],
+            "birthDate": "1980",
+            "gender": "female"
+        });
+        let outcome = validate_profile(&json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                { "resource": patient },
+                { "resource": patient },
+                { "resource": { "resourceType": "Encounter", "subject": { "reference": "Patient/x" } } },
+                { "fullUrl": "urn:uuid:e" },
+                { "resource": { "patient": { "display": "Jane" } } }
+            ]
+        }));
+        assert_eq!(
+            issues(&outcome),
+            vec![
+                (
+                    IssueSeverity::Error,
+                    IssueType::Structure,
+                    "Bundle.entry[1]"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::Required,
+                    "Bundle.entry[3].resource"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::Required,
+                    "Bundle.entry[4].resource.resourceType"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::Required,
+                    "Bundle.entry[4].resource.patient.reference"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profile_resource_rules() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                { "resource": {
+                    "resourceType": "Patient",
+                    "identifier": [{ "value": "1" }],
+                    "name": [{ "family": "Doe" }]
+                } },
+                { "resource": {
+                    "resourceType": "Observation",
+                    "status": "preliminary",
+                    "code": { "coding": [{ "system": "http://snomed.info/sct", "code": "271649006" }] },
+                    "valueQuantity": { "value": "72", "unit": " ", "system": "http://unitsofmeasure.org" }
+                } },
+                { "resource": {
+                    "resourceType": "Condition",
+                    "clinicalStatus": { "coding": [{ "code": "resolved" }] },
+                    "verificationStatus": { "coding": [{ "code": "maybe" }] },
+                    "code": { "coding": [{ "system": "http://snomed.info/sct", "code": " " }] }
+                } },
+                { "resource": {
+                    "resourceType": "MedicationRequest",
+                    "status": "active",
+                    "intent": "order",
+                    "medication": { "concept": { "coding": [{ "system": "http://www.whocc.no/atc", "code": "A10BA02" }] } }
+                } }
+            ]
+        });
+        let outcome = validate_profile(&bundle);
+        assert_eq!(
+            issues(&outcome),
+            vec![
+                (
+                    IssueSeverity::Error,
+                    IssueType::Required,
+                    "Bundle.entry[0].resource.identifier[0].system"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::Required,
+                    "Bundle.entry[0].resource.name"
+                ),
+                (
+                    IssueSeverity::Warning,
+                    IssueType::Required,
+                    "Bundle.entry[0].resource.birthDate"
+                ),
+                (
+                    IssueSeverity::Warning,
+                    IssueType::Required,
+                    "Bundle.entry[0].resource.gender"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::Value,
+                    "Bundle.entry[1].resource.status"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::CodeInvalid,
+                    "Bundle.entry[1].resource.code.coding"
+                ),
+                (
+                    IssueSeverity::Warning,
+                    IssueType::Required,
+                    "Bundle.entry[1].resource.category"
+                ),
+                (
+                    IssueSeverity::Warning,
+                    IssueType::Required,
+                    "Bundle.entry[1].resource.effectiveDateTime"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::Required,
+                    "Bundle.entry[1].resource.valueQuantity.value"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::Required,
+                    "Bundle.entry[1].resource.valueQuantity.unit"
+                ),
+                (
+                    IssueSeverity::Warning,
+                    IssueType::Required,
+                    "Bundle.entry[1].resource.valueQuantity.code"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::CodeInvalid,
+                    "Bundle.entry[2].resource.verificationStatus.coding[0].code"
+                ),
+                (
+                    IssueSeverity::Error,
+                    IssueType::CodeInvalid,
+                    "Bundle.entry[2].resource.code.coding"
+                ),
+                (
+                    IssueSeverity::Warning,
+                    IssueType::Required,
+                    "Bundle.entry[2].resource.subject"
+                ),
+                (
+                    IssueSeverity::Warning,
+                    IssueType::Required,
+                    "Bundle.entry[3].resource.subject"
+                ),
+            ]
+        );
+        assert!(outcome.error_summary().contains(
+            "Bundle.entry[2].resource.verificationStatus.coding[0].code: maybe is not one of"
+        ));
+    }
+}
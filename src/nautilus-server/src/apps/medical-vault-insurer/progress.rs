@@ -0,0 +1,73 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Progress of a FHIR conversion, streamed by /process_data_stream as Server-Sent Events. The
+// conversion reports each stage it enters to a `Progress` handle, which does nothing unless a
+// stream is listening, so /process_data and the batch endpoint run the same code unchanged.
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Stage of a conversion, in the order they are entered. The model stages repeat for each attempt
+/// and, for chunked input, for each chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConversionStage {
+    ValidatingInput,
+    /// Rule-based conversion of Synthea, HL7 v2 and C-CDA input.
+    Converting,
+    CallingModel,
+    RepairingJson,
+    ValidatingProfile,
+    Deidentifying,
+    Hashing,
+}
+
+/// One progress event. `attempt` is set for the model stages, `part` and `parts` when the input is
+/// converted in chunks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversionProgress {
+    pub stage: ConversionStage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parts: Option<usize>,
+}
+
+impl ConversionProgress {
+    pub fn new(stage: ConversionStage) -> Self {
+        Self {
+            stage,
+            attempt: None,
+            part: None,
+            parts: None,
+        }
+    }
+}
+
+/// Where a conversion reports its progress. The default handle drops every event.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    sender: Option<UnboundedSender<ConversionProgress>>,
+}
+
+impl Progress {
+    pub fn new(sender: UnboundedSender<ConversionProgress>) -> Self {
+        Self {
+            sender: Some(sender),
+        }
+    }
+
+    pub fn report(&self, progress: ConversionProgress) {
+        // The listener may be gone, the conversion carries on regardless.
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(progress);
+        }
+    }
+
+    pub fn stage(&self, stage: ConversionStage) {
+        self.report(ConversionProgress::new(stage));
+    }
+}
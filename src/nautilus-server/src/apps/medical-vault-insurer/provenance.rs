@@ -0,0 +1,694 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Source provenance of the resources written by the LLM, so that a "Condition: Type 2 diabetes"
+// can be traced to the text of the raw data stating it rather than taken on the model's word.
+//
+// The model cites, for every resource but the Patient, the passage of the raw data it converted,
+// copied verbatim in a source-span extension. Models cannot count characters, so
+// `attach_provenance` locates each quote in the raw data itself, tolerating differences of case,
+// punctuation and spacing and a few changed words, and replaces it with the character offsets of
+// the passage found:
+//
+//   "extension": [{
+//     "url": "urn:btp:fhir:source-span",
+//     "extension": [{ "url": "start", "valueInteger": 120 }, { "url": "end", "valueInteger": 154 }]
+//   }]
+//
+// Every resource is then checked against the passages it cites: its texts, displays or codes must
+// be found there, and its measured values all of them. Resources failing the check, including
+// those citing nothing or a quote absent from the raw data, are dropped before the bundle is
+// hashed and signed, or kept and flagged with `unsupported_resources: flag`. `verify_provenance`
+// repeats the check for whoever holds the raw data and a signed bundle.
+
+use super::injection::{collect_evidence, normalize, numbers_in, text_supported};
+use super::profile::{IssueSeverity, IssueType, OperationOutcome, OperationOutcomeIssue};
+use super::types::ProvenanceVerificationRequest;
+use crate::EnclaveError;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// Extension citing the passage of the raw data a resource was converted from.
+pub const SOURCE_SPAN_URL: &str = "urn:btp:fhir:source-span";
+
+/// Share of the words of a quote that must be found in a passage of the raw data, as a fraction.
+const QUOTE_MATCH_NUMERATOR: usize = 4;
+const QUOTE_MATCH_DENOMINATOR: usize = 5;
+
+/// What happens to resources not supported by the raw data, set by `llm_config.yaml`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedPolicy {
+    /// Remove them from the bundle, with the references to them.
+    #[default]
+    Drop,
+    /// Keep them, with a warning in the conversion metadata.
+    Flag,
+}
+
+/// Passage of the raw data, in characters from its start, `end` excluded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Check that the resources of a bundle are supported by the raw data they were converted from.
+pub async fn verify_provenance(
+    Json(request): Json<ProvenanceVerificationRequest>,
+) -> Result<Json<OperationOutcome>, EnclaveError> {
+    let outcome = OperationOutcome {
+        resource_type: "OperationOutcome".to_string(),
+        issue: check_provenance(&request.bundle, &request.raw_data),
+    };
+    info!(
+        "Provenance verification complete: {} issues",
+        outcome.issue.len()
+    );
+    Ok(Json(outcome))
+}
+
+/// Replace the quotes cited by the LLM with the spans of `raw_data` they match, then drop or flag
+/// the resources they do not support, following `policy`. Returns one warning per resource
+/// dropped or flagged, with its path in the bundle returned by the LLM.
+pub fn attach_provenance(
+    bundle: &mut Value,
+    raw_data: &str,
+    policy: UnsupportedPolicy,
+) -> Vec<OperationOutcomeIssue> {
+    let raw: Vec<char> = raw_data.chars().collect();
+    let raw_words = words(raw_data);
+    let bundle = if bundle.get("bundle").is_some() {
+        &mut bundle["bundle"]
+    } else {
+        bundle
+    };
+    let Some(entries) = bundle.get_mut("entry").and_then(|e| e.as_array_mut()) else {
+        return Vec::new();
+    };
+
+    let mut issues = Vec::new();
+    let mut dropped = HashSet::new();
+    let mut dropped_refs = HashSet::new();
+    for (index, entry) in entries.iter_mut().enumerate() {
+        if is_patient(&entry["resource"]) {
+            continue;
+        }
+        locate_citations(&mut entry["resource"], raw_data, &raw_words);
+        let Some((code, reason)) = unsupported_reason(&entry["resource"], &raw) else {
+            continue;
+        };
+        let outcome = match policy {
+            UnsupportedPolicy::Drop => {
+                dropped.insert(index);
+                dropped_refs.extend(references_to(entry));
+                "dropped"
+            }
+            UnsupportedPolicy::Flag => "kept",
+        };
+        issues.push(OperationOutcomeIssue {
+            severity: IssueSeverity::Warning,
+            code,
+            diagnostics: format!("{reason}, {outcome}"),
+            expression: vec![format!("Bundle.entry[{index}].resource")],
+        });
+    }
+
+    if !dropped.is_empty() {
+        *entries = std::mem::take(entries)
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !dropped.contains(index))
+            .map(|(_, entry)| entry)
+            .collect();
+        for entry in entries.iter_mut() {
+            strip_references(&mut entry["resource"], &dropped_refs);
+        }
+    }
+    issues
+}
+
+/// One error per resource but the Patient not supported by the spans of `raw_data` it cites.
+pub fn check_provenance(bundle: &Value, raw_data: &str) -> Vec<OperationOutcomeIssue> {
+    let raw: Vec<char> = raw_data.chars().collect();
+    let bundle = bundle.get("bundle").unwrap_or(bundle);
+    let entries = bundle.get("entry").and_then(|e| e.as_array());
+    entries
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, entry)| !is_patient(&entry["resource"]))
+        .filter_map(|(index, entry)| {
+            let (code, diagnostics) = unsupported_reason(&entry["resource"], &raw)?;
+            Some(OperationOutcomeIssue {
+                severity: IssueSeverity::Error,
+                code,
+                diagnostics,
+                expression: vec![format!("Bundle.entry[{index}].resource")],
+            })
+        })
+        .collect()
+}
+
+/// Span of `raw_data` matching `quote`: the quote itself when found verbatim, otherwise the
+/// passage as long as the quote sharing the most words with it, when it has enough of them.
+pub fn locate_quote(raw_data: &str, quote: &str) -> Option<SourceSpan> {
+    find_quote(raw_data, &words(raw_data), quote)
+}
+
+fn find_quote(raw_data: &str, raw_words: &[Word], quote: &str) -> Option<SourceSpan> {
+    let quote = quote.trim();
+    if quote.is_empty() {
+        return None;
+    }
+    if let Some(offset) = raw_data.find(quote) {
+        let start = raw_data[..offset].chars().count();
+        return Some(SourceSpan {
+            start,
+            end: start + quote.chars().count(),
+        });
+    }
+
+    let quote_words = words(quote);
+    if quote_words.is_empty() || raw_words.is_empty() {
+        return None;
+    }
+    let mut wanted: HashMap<&str, usize> = HashMap::new();
+    for word in &quote_words {
+        *wanted.entry(word.text.as_str()).or_default() += 1;
+    }
+
+    // Slide a window of the quote's length over the raw data, counting the quote's words in it
+    let width = quote_words.len().min(raw_words.len());
+    let mut in_window: HashMap<&str, usize> = HashMap::new();
+    let mut matched = 0;
+    let mut best = (0, 0);
+    for (i, word) in raw_words.iter().enumerate() {
+        let text = word.text.as_str();
+        if let Some(&limit) = wanted.get(text) {
+            let count = in_window.entry(text).or_default();
+            *count += 1;
+            if *count <= limit {
+                matched += 1;
+            }
+        }
+        if i >= width {
+            let text = raw_words[i - width].text.as_str();
+            if let Some(&limit) = wanted.get(text) {
+                let count = in_window.entry(text).or_default();
+                if *count <= limit {
+                    matched -= 1;
+                }
+                *count -= 1;
+            }
+        }
+        if i + 1 >= width && matched > best.0 {
+            best = (matched, i + 1 - width);
+        }
+    }
+    let (matched, first) = best;
+    if matched * QUOTE_MATCH_DENOMINATOR < quote_words.len() * QUOTE_MATCH_NUMERATOR {
+        return None;
+    }
+
+    // Trim the words of the window missing from the quote
+    let window = &raw_words[first..first + width];
+    let start = window
+        .iter()
+        .find(|w| wanted.contains_key(w.text.as_str()))?;
+    let end = window
+        .iter()
+        .rfind(|w| wanted.contains_key(w.text.as_str()))?;
+    Some(SourceSpan {
+        start: start.start,
+        end: end.end,
+    })
+}
+
+/// Word of a text, as `normalize` splits it, with its span in characters.
+struct Word {
+    text: String,
+    start: usize,
+    end: usize,
+}
+
+fn words(text: &str) -> Vec<Word> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    for (i, &c) in chars.iter().enumerate() {
+        let between_digits = c == '.'
+            && i > 0
+            && chars[i - 1].is_ascii_digit()
+            && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit());
+        if c.is_alphanumeric() || between_digits {
+            let word = current.get_or_insert_with(|| Word {
+                text: String::new(),
+                start: i,
+                end: i,
+            });
+            word.text.extend(c.to_lowercase());
+            word.end = i + 1;
+        } else if let Some(word) = current.take() {
+            words.push(word);
+        }
+    }
+    words.extend(current);
+    words
+}
+
+/// Replace the quotes cited by `resource` with the spans they match. Quotes matching nothing are
+/// removed, as are spans the LLM wrote itself: only spans located here are kept.
+fn locate_citations(resource: &mut Value, raw_data: &str, raw_words: &[Word]) {
+    let Some(object) = resource.as_object_mut() else {
+        return;
+    };
+    let Some(Value::Array(extensions)) = object.remove("extension") else {
+        return;
+    };
+    let extensions: Vec<Value> = extensions
+        .into_iter()
+        .filter_map(|extension| {
+            if extension["url"] != SOURCE_SPAN_URL {
+                return Some(extension);
+            }
+            let quote = extension["valueString"].as_str()?;
+            let span = find_quote(raw_data, raw_words, quote)?;
+            Some(span_extension(span))
+        })
+        .collect();
+    if !extensions.is_empty() {
+        object.insert("extension".to_string(), Value::Array(extensions));
+    }
+}
+
+fn span_extension(span: SourceSpan) -> Value {
+    json!({
+        "url": SOURCE_SPAN_URL,
+        "extension": [
+            { "url": "start", "valueInteger": span.start },
+            { "url": "end", "valueInteger": span.end }
+        ]
+    })
+}
+
+/// Spans cited by `resource`, `None` for a source-span extension without valid offsets.
+fn cited_spans(resource: &Value) -> Vec<Option<SourceSpan>> {
+    let extensions = resource.get("extension").and_then(|e| e.as_array());
+    extensions
+        .into_iter()
+        .flatten()
+        .filter(|extension| extension["url"] == SOURCE_SPAN_URL)
+        .map(|extension| {
+            let offset = |name: &str| {
+                let parts = extension.get("extension")?.as_array()?;
+                let part = parts.iter().find(|p| p["url"] == name)?;
+                usize::try_from(part.get("valueInteger")?.as_u64()?).ok()
+            };
+            Some(SourceSpan {
+                start: offset("start")?,
+                end: offset("end")?,
+            })
+        })
+        .collect()
+}
+
+/// Why `resource` is not supported by the spans of `raw` it cites, if it is not.
+fn unsupported_reason(resource: &Value, raw: &[char]) -> Option<(IssueType, String)> {
+    let resource_type = resource["resourceType"].as_str().unwrap_or_default();
+    let spans = cited_spans(resource);
+    if spans.is_empty() {
+        return Some((
+            IssueType::Required,
+            format!("{resource_type} cites no source span found in the input"),
+        ));
+    }
+    let mut cited = Vec::new();
+    for span in spans {
+        match span {
+            Some(SourceSpan { start, end }) if start < end && end <= raw.len() => {
+                cited.push(raw[start..end].iter().collect::<String>());
+            }
+            _ => {
+                return Some((
+                    IssueType::Value,
+                    format!("{resource_type} cites a source span outside the input"),
+                ))
+            }
+        }
+    }
+    let cited = cited.join("\n");
+
+    let mut texts = Vec::new();
+    let mut numbers = Vec::new();
+    collect_evidence(resource, "", &mut texts, &mut numbers);
+    let cited_numbers = numbers_in(&cited);
+    for (_, number) in &numbers {
+        if !cited_numbers.iter().any(|n| (n - number).abs() < 1e-9) {
+            return Some((
+                IssueType::Value,
+                format!("{resource_type} value {number} not found in its source span"),
+            ));
+        }
+    }
+    let cited_text = format!(" {} ", normalize(&cited));
+    let cited_words: HashSet<&str> = cited_text.split_whitespace().collect();
+    let text_found = texts
+        .iter()
+        .any(|text| text_supported(text, &cited_text, &cited_words));
+    if texts.is_empty() || text_found || !numbers.is_empty() {
+        return None;
+    }
+    Some((
+        IssueType::Value,
+        format!("{resource_type} not supported by its source span"),
+    ))
+}
+
+fn is_patient(resource: &Value) -> bool {
+    resource["resourceType"] == "Patient"
+}
+
+/// The references other resources may use for the resource of `entry`.
+fn references_to(entry: &Value) -> Vec<String> {
+    let mut references = Vec::new();
+    if let Some(full_url) = entry["fullUrl"].as_str() {
+        references.push(full_url.to_string());
+    }
+    let resource = &entry["resource"];
+    if let (Some(resource_type), Some(id)) =
+        (resource["resourceType"].as_str(), resource["id"].as_str())
+    {
+        references.push(format!("{resource_type}/{id}"));
+    }
+    references
+}
+
+/// Remove the elements of `value` referencing one of `dropped`.
+fn strip_references(value: &mut Value, dropped: &HashSet<String>) {
+    let is_dropped = |v: &Value| {
+        v.get("reference")
+            .and_then(|r| r.as_str())
+            .is_some_and(|r| dropped.contains(r))
+    };
+    match value {
+        Value::Object(object) => {
+            object.retain(|_, child| !is_dropped(child));
+            object
+                .values_mut()
+                .for_each(|child| strip_references(child, dropped));
+        }
+        Value::Array(items) => {
+            items.retain(|item| !is_dropped(item));
+            items
+                .iter_mut()
+                .for_each(|item| strip_references(item, dropped));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::fhir::extract_resource_types;
+
+    const RAW_DATA: &str = "Visit 2024-03-02.\nAssessment: Type 2 diabetes mellitus, on metformin.\nVitals: HR 88 bpm, BP 140/90.";
+
+    fn cite(quote: &str) -> Value {
+        json!([{ "url": SOURCE_SPAN_URL, "valueString": quote }])
+    }
+
+    fn text(raw_data: &str, span: SourceSpan) -> String {
+        raw_data
+            .chars()
+            .skip(span.start)
+            .take(span.end - span.start)
+            .collect()
+    }
+
+    /// Bundle of a Patient and an Observation of `value`, citing `quote`.
+    fn observation(value: Value, quote: &str) -> Value {
+        json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                { "fullUrl": "urn:uuid:p1", "resource": { "resourceType": "Patient", "id": "p1" } },
+                { "fullUrl": "urn:uuid:o1", "resource": {
+                    "resourceType": "Observation",
+                    "code": { "text": "Body temperature" },
+                    "valueQuantity": { "value": value, "unit": "Cel" },
+                    "extension": cite(quote)
+                } }
+            ]
+        })
+    }
+
+    #[test]
+    fn test_locate_quote() {
+        // Quotes are located verbatim, then regardless of case and punctuation, then fuzzily.
+        let raw_data = RAW_DATA;
+        let text = |span: SourceSpan| text(raw_data, span);
+        let span = locate_quote(raw_data, "Type 2 diabetes mellitus").unwrap();
+        assert_eq!(span, SourceSpan { start: 30, end: 54 });
+        assert_eq!(
+            text(locate_quote(raw_data, "hr 88 BPM").unwrap()),
+            "HR 88 bpm"
+        );
+        assert_eq!(
+            text(locate_quote(raw_data, "assessment - type II diabetes mellitus").unwrap()),
+            "Assessment: Type 2 diabetes mellitus"
+        );
+        assert_eq!(locate_quote(raw_data, "Patient has hypertension"), None);
+        assert_eq!(locate_quote(raw_data, "  "), None);
+    }
+
+    #[test]
+    fn test_attach_provenance() {
+        let raw_data = RAW_DATA;
+        let llm_output = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "type": "collection",
+                "entry": [
+                    { "fullUrl": "urn:uuid:p1", "resource": { "resourceType": "Patient", "id": "p1" } },
+                    { "fullUrl": "urn:uuid:dm", "resource": {
+                        "resourceType": "Condition",
+                        "code": { "coding": [{ "code": "44054006", "display": "Type 2 diabetes mellitus" }] },
+                        "subject": { "reference": "urn:uuid:p1" },
+                        "extension": cite("Assessment: type 2 diabetes mellitus")
+                    } },
+                    { "fullUrl": "urn:uuid:hr", "resource": {
+                        "resourceType": "Observation",
+                        "code": { "text": "Heart rate" },
+                        "valueQuantity": { "value": 88, "unit": "/min" },
+                        "focus": [{ "reference": "Condition/htn" }, { "reference": "urn:uuid:dm" }],
+                        "extension": cite("HR 88 bpm")
+                    } },
+                    // Hallucinated, citing text that is not in the input
+                    { "fullUrl": "urn:uuid:htn", "resource": {
+                        "resourceType": "Condition",
+                        "id": "htn",
+                        "code": { "coding": [{ "display": "Hypertension" }] },
+                        "extension": cite("Patient has hypertension")
+                    } },
+                    // Citing real text which does not state its value
+                    { "fullUrl": "urn:uuid:bp", "resource": {
+                        "resourceType": "Observation",
+                        "code": { "text": "Blood pressure" },
+                        "valueQuantity": { "value": 150, "unit": "mm[Hg]" },
+                        "extension": cite("BP 140/90")
+                    } },
+                    // Offsets written by the model are not trusted
+                    { "fullUrl": "urn:uuid:med", "resource": {
+                        "resourceType": "MedicationRequest",
+                        "medicationCodeableConcept": { "text": "metformin" },
+                        "extension": [{
+                            "url": SOURCE_SPAN_URL,
+                            "extension": [
+                                { "url": "start", "valueInteger": 59 },
+                                { "url": "end", "valueInteger": 68 }
+                            ]
+                        }]
+                    } }
+                ]
+            }
+        });
+
+        // Unsupported resources are dropped, with the references to them.
+        let mut bundle = llm_output.clone();
+        let issues = attach_provenance(&mut bundle, raw_data, UnsupportedPolicy::Drop);
+        let flagged: Vec<&str> = issues.iter().map(|i| i.expression[0].as_str()).collect();
+        assert_eq!(
+            flagged,
+            [
+                "Bundle.entry[3].resource",
+                "Bundle.entry[4].resource",
+                "Bundle.entry[5].resource",
+            ]
+        );
+        assert!(issues.iter().all(|i| i.diagnostics.ends_with(", dropped")));
+        assert!(issues[1].diagnostics.contains("value 150"));
+        assert_eq!(
+            extract_resource_types(&bundle),
+            ["Patient", "Condition", "Observation"]
+        );
+        let condition = &bundle["bundle"]["entry"][1]["resource"];
+        assert_eq!(
+            condition["extension"],
+            json!([{
+                "url": SOURCE_SPAN_URL,
+                "extension": [
+                    { "url": "start", "valueInteger": 18 },
+                    { "url": "end", "valueInteger": 54 }
+                ]
+            }])
+        );
+        let observation = &bundle["bundle"]["entry"][2]["resource"];
+        assert_eq!(
+            observation["focus"],
+            json!([{ "reference": "urn:uuid:dm" }])
+        );
+        assert!(check_provenance(&bundle, raw_data).is_empty());
+
+        // Or kept and flagged.
+        let mut flagged_bundle = llm_output.clone();
+        let issues = attach_provenance(&mut flagged_bundle, raw_data, UnsupportedPolicy::Flag);
+        assert_eq!(issues.len(), 3);
+        assert!(issues.iter().all(|i| i.diagnostics.ends_with(", kept")));
+        assert_eq!(
+            flagged_bundle["bundle"]["entry"].as_array().unwrap().len(),
+            6
+        );
+        assert_eq!(check_provenance(&flagged_bundle, raw_data).len(), 3);
+
+        // The verifier rejects spans moved to other text or outside the input.
+        let span = &mut bundle["bundle"]["entry"][1]["resource"]["extension"][0]["extension"];
+        span[0]["valueInteger"] = json!(73);
+        span[1]["valueInteger"] = json!(82);
+        let issues = check_provenance(&bundle, raw_data);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, IssueSeverity::Error);
+        assert_eq!(issues[0].expression, ["Bundle.entry[1].resource"]);
+        assert!(check_provenance(&bundle, "too short").len() == 2);
+    }
+
+    #[test]
+    fn test_locate_quote_multibyte() {
+        // Spans count characters, not bytes.
+        let raw_data = "Café visit — patient reports céphalée.\nTempérature 38.2 °C.";
+        let span = locate_quote(raw_data, "Température 38.2 °C").unwrap();
+        assert_eq!(span, SourceSpan { start: 39, end: 58 });
+        assert_eq!(text(raw_data, span), "Température 38.2 °C");
+        let span = locate_quote(raw_data, "TEMPÉRATURE: 38.2 °c").unwrap();
+        assert_eq!(span, SourceSpan { start: 39, end: 58 });
+        assert_eq!(
+            text(
+                raw_data,
+                locate_quote(raw_data, "patient reports CÉPHALÉE").unwrap()
+            ),
+            "patient reports céphalée"
+        );
+        assert_eq!(locate_quote(raw_data, "temperature 38.2"), None);
+
+        let mut bundle = observation(json!(38.2), "Température 38.2 °C");
+        assert!(attach_provenance(&mut bundle, raw_data, UnsupportedPolicy::Drop).is_empty());
+        assert_eq!(
+            bundle["entry"][1]["resource"]["extension"],
+            json!([span_extension(span)])
+        );
+        assert!(check_provenance(&bundle, raw_data).is_empty());
+    }
+
+    #[test]
+    fn test_words() {
+        // Dots between digits are kept, so that decimal values stay one word.
+        let texts: Vec<String> = words("BP 140/90, T 37.5. E11.9 Café")
+            .into_iter()
+            .map(|w| w.text)
+            .collect();
+        assert_eq!(texts, ["bp", "140", "90", "t", "37.5", "e11.9", "café"]);
+        let word = &words("  Température 38.2")[1];
+        assert_eq!((word.start, word.end), (14, 18));
+        assert!(words(" -- / ").is_empty());
+    }
+
+    #[test]
+    fn test_provenance_citations() {
+        let raw_data = RAW_DATA;
+        let condition = |text: &str, extension: Value| {
+            json!({ "resource": {
+                "resourceType": "Condition",
+                "code": { "text": text },
+                "extension": extension
+            } })
+        };
+        let other = json!({ "url": "http://example.org/other", "valueString": "x" });
+        let mut bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [
+                // Quotes not found are removed, other extensions are left alone.
+                condition(
+                    "Type 2 diabetes mellitus",
+                    json!([
+                        other,
+                        cite("Patient has hypertension")[0],
+                        cite("Type 2 diabetes mellitus")[0]
+                    ])
+                ),
+                // A real quote stating something else.
+                condition("Asthma", cite("HR 88 bpm")),
+                condition("Asthma", json!([other]))
+            ]
+        });
+        let issues = attach_provenance(&mut bundle, raw_data, UnsupportedPolicy::Flag);
+        let diagnostics: Vec<&str> = issues.iter().map(|i| i.diagnostics.as_str()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "Condition not supported by its source span, kept",
+                "Condition cites no source span found in the input, kept"
+            ]
+        );
+        assert_eq!(issues[1].code, IssueType::Required);
+        assert_eq!(
+            bundle["entry"][0]["resource"]["extension"],
+            json!([other, span_extension(SourceSpan { start: 30, end: 54 })])
+        );
+        assert_eq!(bundle["entry"][2]["resource"]["extension"], json!([other]));
+
+        // Offsets must be integers within the input.
+        let mut bundle = observation(json!(88), "HR 88 bpm");
+        assert!(attach_provenance(&mut bundle, raw_data, UnsupportedPolicy::Drop).is_empty());
+        let span = &bundle["entry"][1]["resource"]["extension"][0]["extension"];
+        assert_eq!(span[0]["valueInteger"], 78);
+        assert_eq!(span[1]["valueInteger"], 87);
+        for (start, end) in [
+            (json!(78.0), json!(87)),
+            (json!(-1), json!(87)),
+            (json!(87), json!(78)),
+            (json!(78), json!(78)),
+            (json!(78), json!(1000)),
+            (json!(78), Value::Null),
+        ] {
+            let mut moved = bundle.clone();
+            let span = &mut moved["entry"][1]["resource"]["extension"][0]["extension"];
+            span[0]["valueInteger"] = start;
+            span[1]["valueInteger"] = end;
+            let issues = check_provenance(&moved, raw_data);
+            assert_eq!(issues.len(), 1);
+            assert_eq!(
+                issues[0].diagnostics,
+                "Observation cites a source span outside the input"
+            );
+        }
+
+        // Bundles without entries have nothing to check.
+        let mut empty = json!({ "resourceType": "Bundle", "type": "collection" });
+        assert!(attach_provenance(&mut empty, raw_data, UnsupportedPolicy::Drop).is_empty());
+        assert!(check_provenance(&empty, raw_data).is_empty());
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Keyed pseudonymization of patient identifiers. De-identification replaces the identifiers of the
+// Patient, so bundles of the same patient could no longer be joined. With a pseudonym key
+// provisioned through Seal, each identifier (MRN, SSN, ..., and `patient_id` of the patient
+// context) is replaced by the HMAC-SHA3-256 of its system and value under that key instead. The
+// key never leaves the enclave, so the same identifier always maps to the same pseudonym but a
+// pseudonym cannot be traced back without asking the enclave.
+
+use super::endpoints::REIDENTIFICATION_TABLE;
+use super::*;
+use fastcrypto::hmac::{hmac_sha3_256, HmacKey};
+use serde_json::{json, Value};
+
+/// `Identifier.system` of pseudonyms.
+pub const PSEUDONYM_SYSTEM: &str = "urn:medical-vault:pseudonym";
+
+/// System under which `patient_id` of the patient context is pseudonymized.
+pub const PATIENT_ID_SYSTEM: &str = "urn:medical-vault:patient-id";
+
+/// Minimum length of the pseudonym key in bytes.
+pub const MIN_PSEUDONYM_KEY_LENGTH: usize = 32;
+
+/// Maximum number of pseudonyms kept for re-identification.
+const MAX_REIDENTIFICATION_ENTRIES: usize = 100_000;
+
+/// Systems of US social security numbers, whose values are compared on their digits only.
+const SSN_SYSTEMS: &[&str] = &[
+    "http://hl7.org/fhir/sid/us-ssn",
+    "urn:oid:2.16.840.1.113883.4.1",
+];
+
+/// The identifier a pseudonym was issued for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PseudonymSource {
+    pub system: String,
+    pub value: String,
+}
+
+/// Maps the identifiers of one conversion to pseudonyms and keeps the source of each pseudonym.
+pub struct Pseudonymizer {
+    key: HmacKey,
+    /// Identifiers of the patient context, added to the Patient's identifiers.
+    context: Vec<PseudonymSource>,
+    /// Pseudonym and source of every identifier mapped so far.
+    pub issued: Vec<(String, PseudonymSource)>,
+}
+
+impl Pseudonymizer {
+    pub fn new(key: &[u8], patient_context: Option<&PatientContext>) -> Result<Self, EnclaveError> {
+        if key.len() < MIN_PSEUDONYM_KEY_LENGTH {
+            return Err(EnclaveError::GenericError(format!(
+                "Pseudonym key must be at least {MIN_PSEUDONYM_KEY_LENGTH} bytes"
+            )));
+        }
+        let key = HmacKey::from_bytes(key)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid pseudonym key: {e}")))?;
+        let context = patient_context
+            .map(|c| c.patient_id.trim())
+            .filter(|id| !id.is_empty())
+            .map(|id| PseudonymSource {
+                system: PATIENT_ID_SYSTEM.to_string(),
+                value: id.to_string(),
+            })
+            .into_iter()
+            .collect();
+        Ok(Self {
+            key,
+            context,
+            issued: Vec::new(),
+        })
+    }
+
+    /// Hex encoded `hmac_sha3_256(key, system || 0x00 || value)`. SSNs are reduced to their digits
+    /// and other values trimmed, so that formatting differences map to the same pseudonym.
+    pub fn pseudonym(&mut self, system: &str, value: &str) -> String {
+        let value = if SSN_SYSTEMS.contains(&system) {
+            value.chars().filter(char::is_ascii_digit).collect()
+        } else {
+            value.trim().to_string()
+        };
+        let mut message = system.as_bytes().to_vec();
+        message.push(0);
+        message.extend_from_slice(value.as_bytes());
+        let pseudonym = Hex::encode(hmac_sha3_256(&self.key, &message).digest);
+
+        let source = PseudonymSource {
+            system: system.to_string(),
+            value,
+        };
+        if !self.issued.iter().any(|(p, _)| *p == pseudonym) {
+            self.issued.push((pseudonym.clone(), source));
+        }
+        pseudonym
+    }
+
+    /// Pseudonym identifiers replacing `identifiers` of a Patient, together with those of the
+    /// patient context. Identifiers without a system or value are dropped.
+    pub fn patient_identifiers(&mut self, identifiers: Option<&Value>) -> Vec<Value> {
+        let sources: Vec<PseudonymSource> = identifiers
+            .and_then(|i| i.as_array())
+            .into_iter()
+            .flatten()
+            .filter(|identifier| !is_pseudonym(identifier))
+            .filter_map(|identifier| {
+                Some(PseudonymSource {
+                    system: identifier.get("system")?.as_str()?.to_string(),
+                    value: identifier.get("value")?.as_str()?.to_string(),
+                })
+            })
+            .chain(self.context.clone())
+            .collect();
+
+        let mut pseudonyms = Vec::new();
+        for source in sources {
+            let pseudonym = self.pseudonym(&source.system, &source.value);
+            let identifier = json!({ "system": PSEUDONYM_SYSTEM, "value": pseudonym });
+            if !pseudonyms.contains(&identifier) {
+                pseudonyms.push(identifier);
+            }
+        }
+        pseudonyms
+    }
+}
+
+/// Whether an identifier is a pseudonym issued by the enclave: a SHA3-256 sized Hex value under
+/// `PSEUDONYM_SYSTEM`.
+pub fn is_pseudonym(identifier: &Value) -> bool {
+    identifier.get("system").and_then(|s| s.as_str()) == Some(PSEUDONYM_SYSTEM)
+        && identifier
+            .get("value")
+            .and_then(|v| v.as_str())
+            .is_some_and(|v| v.len() == 64 && v.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Keep the sources of newly issued pseudonyms for re-identification, when it was enabled with the
+/// pseudonym key.
+pub async fn remember_pseudonyms(issued: Vec<(String, PseudonymSource)>) {
+    let mut table_guard = REIDENTIFICATION_TABLE.write().await;
+    let Some(table) = table_guard.as_mut() else {
+        return;
+    };
+    for (pseudonym, source) in issued {
+        if table.len() >= MAX_REIDENTIFICATION_ENTRIES && !table.contains_key(&pseudonym) {
+            tracing::warn!("Re-identification table is full, pseudonym not kept");
+            continue;
+        }
+        table.insert(pseudonym, source);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_patient_pseudonymization() {
+        let patient = |id: &str, ssn: &str| {
+            json!({
+                "resourceType": "Bundle",
+                "type": "collection",
+                "entry": [{
+                    "fullUrl": format!("urn:uuid:{id}"),
+                    "resource": {
+                        "resourceType": "Patient",
+                        "id": id,
+                        "identifier": [
+                            { "system": "urn:oid:2.16.840.1.113883.19.5", "value": "MRN123456" },
+                            { "system": "http://hl7.org/fhir/sid/us-ssn", "value": ssn }
+                        ],
+                        "gender": "female"
+                    }
+                }]
+            })
+        };
+        let context = PatientContext {
+            patient_id: "patient-42".to_string(),
+            name: None,
+            birth_date: None,
+            gender: None,
+        };
+        let key = [7u8; 32];
+
+        // The same patient in two bundles, with differently formatted SSNs, gets the same
+        // pseudonyms while the local resource ids differ.
+        let mut first = patient("a", "999-62-4431");
+        let mut pseudonymizer = Pseudonymizer::new(&key, Some(&context)).unwrap();
+        deidentify_bundle(&mut first, 2025, Some(&mut pseudonymizer));
+        let mut second = patient("b", " 999624431 ");
+        deidentify_bundle(
+            &mut second,
+            2025,
+            Some(&mut Pseudonymizer::new(&key, Some(&context)).unwrap()),
+        );
+        let identifiers = bundle_resources(&first)[0]["identifier"].clone();
+        assert_eq!(identifiers.as_array().unwrap().len(), 3);
+        assert!(identifiers
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|i| i["system"] == PSEUDONYM_SYSTEM && i["value"].as_str().unwrap().len() == 64));
+        assert_eq!(bundle_resources(&second)[0]["identifier"], identifiers);
+        assert!(!serde_json::to_string(&first).unwrap().contains("4431"));
+
+        // Pseudonyms pass the Safe Harbor check.
+        assert!(!check_safe_harbor(&first, 2025).has_errors());
+
+        // A different key gives unrelated pseudonyms.
+        let mut other = patient("a", "999-62-4431");
+        deidentify_bundle(
+            &mut other,
+            2025,
+            Some(&mut Pseudonymizer::new(&[8u8; 32], Some(&context)).unwrap()),
+        );
+        assert_ne!(bundle_resources(&other)[0]["identifier"], identifiers);
+        assert!(Pseudonymizer::new(&[7u8; 16], None).is_err());
+
+        // Sources are only kept once re-identification is enabled.
+        let patient_id_pseudonym = identifiers[2]["value"].as_str().unwrap().to_string();
+        remember_pseudonyms(pseudonymizer.issued.clone()).await;
+        assert!(REIDENTIFICATION_TABLE.read().await.is_none());
+        *REIDENTIFICATION_TABLE.write().await = Some(std::collections::HashMap::new());
+        remember_pseudonyms(pseudonymizer.issued).await;
+        let table = REIDENTIFICATION_TABLE.write().await.take().unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(
+            table[&patient_id_pseudonym],
+            PseudonymSource {
+                system: "urn:medical-vault:patient-id".to_string(),
+                value: "patient-42".to_string(),
+            }
+        );
+        let ssn_pseudonym = identifiers[1]["value"].as_str().unwrap();
+        assert_eq!(table[ssn_pseudonym].value, "999624431");
+    }
+
+    #[test]
+    fn test_pseudonym_normalization() {
+        let mut pseudonymizer = Pseudonymizer::new(&[7u8; 32], None).unwrap();
+        let mrn = "urn:oid:2.16.840.1.113883.19.5";
+        let ssn = "urn:oid:2.16.840.1.113883.4.1";
+
+        // Only SSNs are reduced to their digits, other values are only trimmed.
+        assert_eq!(
+            pseudonymizer.pseudonym(ssn, "999-62-4431"),
+            pseudonymizer.pseudonym(ssn, "999 62 4431")
+        );
+        assert_eq!(
+            pseudonymizer.pseudonym(mrn, " MRN-1 "),
+            pseudonymizer.pseudonym(mrn, "MRN-1")
+        );
+        assert_ne!(
+            pseudonymizer.pseudonym(mrn, "MRN-1"),
+            pseudonymizer.pseudonym(mrn, "MRN1")
+        );
+        // The system is part of the pseudonym.
+        assert_ne!(
+            pseudonymizer.pseudonym(mrn, "999624431"),
+            pseudonymizer.pseudonym(ssn, "999624431")
+        );
+        let sources: Vec<&str> = pseudonymizer
+            .issued
+            .iter()
+            .map(|(_, source)| source.value.as_str())
+            .collect();
+        assert_eq!(sources, ["999624431", "MRN-1", "MRN1", "999624431"]);
+    }
+
+    #[test]
+    fn test_patient_identifiers() {
+        let blank = PatientContext {
+            patient_id: "  ".to_string(),
+            name: None,
+            birth_date: None,
+            gender: None,
+        };
+        let mut pseudonymizer = Pseudonymizer::new(&[7u8; 32], Some(&blank)).unwrap();
+        let mrn = pseudonymizer.pseudonym("urn:mrn", "1");
+        let identifiers = json!([
+            { "system": "urn:mrn", "value": "1" },
+            { "system": "urn:mrn", "value": " 1" },
+            { "value": "no system" },
+            { "system": "urn:mrn", "value": 2 },
+            { "system": PSEUDONYM_SYSTEM, "value": "ab".repeat(32) }
+        ]);
+
+        // Duplicates collapse, incomplete identifiers and input pseudonyms are dropped and a
+        // blank patient id adds nothing.
+        assert_eq!(
+            pseudonymizer.patient_identifiers(Some(&identifiers)),
+            vec![json!({ "system": PSEUDONYM_SYSTEM, "value": mrn })]
+        );
+        assert!(pseudonymizer.patient_identifiers(None).is_empty());
+        assert!(pseudonymizer
+            .patient_identifiers(Some(&json!({ "system": "urn:mrn", "value": "1" })))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_is_pseudonym() {
+        let identifier = |system: &str, value: Value| json!({ "system": system, "value": value });
+        assert!(is_pseudonym(&identifier(
+            PSEUDONYM_SYSTEM,
+            json!("AB".repeat(32))
+        )));
+        assert!(!is_pseudonym(&identifier(
+            PSEUDONYM_SYSTEM,
+            json!("ab".repeat(31))
+        )));
+        assert!(!is_pseudonym(&identifier(
+            PSEUDONYM_SYSTEM,
+            json!("zz".repeat(32))
+        )));
+        assert!(!is_pseudonym(&identifier(PSEUDONYM_SYSTEM, json!(1))));
+        assert!(!is_pseudonym(&identifier(
+            PATIENT_ID_SYSTEM,
+            json!("ab".repeat(32))
+        )));
+    }
+}
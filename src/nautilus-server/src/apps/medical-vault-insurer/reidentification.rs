@@ -0,0 +1,257 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Authorization and audit of re-identification. The host-only server only maps a pseudonym back to
+// its identifier for a `ReidentificationGrant` naming that pseudonym and signed by one of the
+// `reidentification_authorities` of `seal_config.yaml`. Seal encrypted grants are not accepted:
+// anyone can encrypt to the enclave, so decrypting a grant proves nothing about who wrote it. Every
+// lookup is appended to a hash chained log whose head the enclave signs, so that the host can
+// neither hide nor reorder lookups.
+
+use super::*;
+use fastcrypto::hash::{HashFunction, Sha3_256};
+use fastcrypto::traits::ToFromBytes;
+
+/// Permission to re-identify one pseudonym until `expires_at_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReidentificationGrant {
+    pub pseudonym: String,
+    /// Who the identifier is disclosed to, kept in the audit log.
+    pub requester: String,
+    pub expires_at_ms: u64,
+}
+
+/// A re-identification as kept in the audit log. `authorized_by` is the Hex encoded public key of
+/// the authority that signed the grant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReidentificationAuditEntry {
+    pub sequence: u64,
+    pub pseudonym: String,
+    pub requester: String,
+    pub authorized_by: String,
+    pub reidentified_at: u64,
+}
+
+/// Inner type T for IntentMessage<T> with scope ReidentificationLog: the head of the audit log after
+/// `entry_count` entries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReidentificationLogHead {
+    pub head: Vec<u8>,
+    pub entry_count: u64,
+}
+
+/// Append only log of re-identifications. The head starts as 32 zero bytes and each entry replaces
+/// it with `sha3_256(head || bcs(entry))`.
+#[derive(Debug, Clone, Default)]
+pub struct ReidentificationLog {
+    entries: Vec<ReidentificationAuditEntry>,
+    head: Option<[u8; 32]>,
+}
+
+impl ReidentificationLog {
+    pub fn entries(&self) -> &[ReidentificationAuditEntry] {
+        &self.entries
+    }
+
+    pub fn head(&self) -> ReidentificationLogHead {
+        ReidentificationLogHead {
+            head: self.head.unwrap_or_default().to_vec(),
+            entry_count: self.entries.len() as u64,
+        }
+    }
+
+    /// Record the re-identification of a grant.
+    pub fn append(
+        &mut self,
+        grant: &ReidentificationGrant,
+        authorized_by: String,
+        reidentified_at: u64,
+    ) -> Result<ReidentificationAuditEntry, EnclaveError> {
+        let entry = ReidentificationAuditEntry {
+            sequence: self.entries.len() as u64,
+            pseudonym: grant.pseudonym.clone(),
+            requester: grant.requester.clone(),
+            authorized_by,
+            reidentified_at,
+        };
+        self.head = Some(chain_head(&self.head.unwrap_or_default(), &entry)?);
+        self.entries.push(entry.clone());
+        Ok(entry)
+    }
+}
+
+/// Head of the log holding `entries`, to check them against a signed head.
+pub fn log_head(entries: &[ReidentificationAuditEntry]) -> Result<Vec<u8>, EnclaveError> {
+    entries
+        .iter()
+        .try_fold([0u8; 32], |head, entry| chain_head(&head, entry))
+        .map(|head| head.to_vec())
+}
+
+fn chain_head(
+    head: &[u8; 32],
+    entry: &ReidentificationAuditEntry,
+) -> Result<[u8; 32], EnclaveError> {
+    let bytes = bcs::to_bytes(entry)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to serialize audit entry: {e}")))?;
+    let mut hasher = Sha3_256::default();
+    hasher.update(head);
+    hasher.update(&bytes);
+    Ok(hasher.finalize().digest)
+}
+
+/// Check that a grant allows re-identifying `pseudonym` at `now_ms`.
+pub fn check_grant(
+    grant: &ReidentificationGrant,
+    pseudonym: &str,
+    now_ms: u64,
+) -> Result<(), EnclaveError> {
+    if !grant.pseudonym.eq_ignore_ascii_case(pseudonym) {
+        return Err(EnclaveError::UnauthorizedError(
+            "Grant is for a different pseudonym".to_string(),
+        ));
+    }
+    if grant.requester.trim().is_empty() {
+        return Err(EnclaveError::UnauthorizedError(
+            "Grant must name its requester".to_string(),
+        ));
+    }
+    if grant.expires_at_ms <= now_ms {
+        return Err(EnclaveError::UnauthorizedError(
+            "Grant has expired".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Hex encoded public key of the authority among `authorities` that signed a grant with the
+/// ReidentificationGrant intent scope.
+pub fn verify_authority_grant(
+    signed: &ProcessedDataResponse<IntentMessage<ReidentificationGrant>>,
+    authorities: &[String],
+) -> Result<String, EnclaveError> {
+    authorities
+        .iter()
+        .find(|authority| {
+            Hex::decode(authority)
+                .ok()
+                .and_then(|bytes| Ed25519PublicKey::from_bytes(&bytes).ok())
+                .is_some_and(|pk| {
+                    verify_signed_response(&pk, signed, IntentScope::ReidentificationGrant as u8)
+                        .is_ok()
+                })
+        })
+        .cloned()
+        .ok_or_else(|| {
+            EnclaveError::UnauthorizedError(
+                "Grant is not signed by a re-identification authority".to_string(),
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fastcrypto::ed25519::Ed25519KeyPair;
+    use fastcrypto::traits::KeyPair;
+
+    fn grant(pseudonym: &str) -> ReidentificationGrant {
+        ReidentificationGrant {
+            pseudonym: pseudonym.to_string(),
+            requester: "dr-smith".to_string(),
+            expires_at_ms: 1744038960000,
+        }
+    }
+
+    #[test]
+    fn test_check_grant() {
+        let pseudonym = "ab".repeat(32);
+        assert!(check_grant(&grant(&pseudonym), &pseudonym, 1744038900000).is_ok());
+        assert!(check_grant(&grant(&pseudonym), &pseudonym.to_uppercase(), 1744038900000).is_ok());
+
+        assert!(matches!(
+            check_grant(&grant(&pseudonym), &"cd".repeat(32), 1744038900000),
+            Err(EnclaveError::UnauthorizedError(_))
+        ));
+        // Expired at exactly `expires_at_ms`.
+        assert!(check_grant(&grant(&pseudonym), &pseudonym, 1744038960000).is_err());
+        let anonymous = ReidentificationGrant {
+            requester: " ".to_string(),
+            ..grant(&pseudonym)
+        };
+        assert!(check_grant(&anonymous, &pseudonym, 1744038900000).is_err());
+    }
+
+    #[test]
+    fn test_authority_grant() {
+        let authority = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        let other = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        let authorities = vec![
+            "not hex".to_string(),
+            Hex::encode(other.public().as_bytes()),
+            Hex::encode(authority.public().as_bytes()),
+        ];
+        let sign = |kp: &Ed25519KeyPair, intent: IntentScope| {
+            to_signed_response(kp, grant("ab"), 1744038900000, intent as u8)
+        };
+
+        assert_eq!(
+            verify_authority_grant(
+                &sign(&authority, IntentScope::ReidentificationGrant),
+                &authorities
+            )
+            .unwrap(),
+            authorities[2]
+        );
+        assert!(verify_authority_grant(
+            &sign(&authority, IntentScope::ReidentificationGrant),
+            &authorities[..2]
+        )
+        .is_err());
+        assert!(verify_authority_grant(
+            &sign(&authority, IntentScope::ReidentificationLog),
+            &authorities
+        )
+        .is_err());
+
+        // The signature covers the grant.
+        let mut tampered = sign(&authority, IntentScope::ReidentificationGrant);
+        tampered.response.data.expires_at_ms = u64::MAX;
+        assert!(verify_authority_grant(&tampered, &authorities).is_err());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut log = ReidentificationLog::default();
+        assert_eq!(log.head().head, vec![0u8; 32]);
+        assert_eq!(log.head().entry_count, 0);
+
+        let first = log
+            .append(&grant("ab"), "ab12".to_string(), 1744038900000)
+            .unwrap();
+        assert_eq!(first.sequence, 0);
+        let after_first = log.head();
+        let second = log
+            .append(&grant("cd"), "ab12".to_string(), 1744038901000)
+            .unwrap();
+        assert_eq!(second.sequence, 1);
+        assert_eq!(log.head().entry_count, 2);
+        assert_ne!(log.head().head, after_first.head);
+
+        // The head can be recomputed from the entries, and changes with their order or content.
+        assert_eq!(log_head(log.entries()).unwrap(), log.head().head);
+        assert_eq!(
+            log_head(std::slice::from_ref(&first)).unwrap(),
+            after_first.head
+        );
+        assert_ne!(
+            log_head(&[second.clone(), first.clone()]).unwrap(),
+            log.head().head
+        );
+        let forged = ReidentificationAuditEntry {
+            requester: "someone-else".to_string(),
+            ..second
+        };
+        assert_ne!(log_head(&[first, forged]).unwrap(), log.head().head);
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Repair of LLM output cut off by the token limit. `JsonRepair` scans the output as it arrives,
+// tracking open containers, strings, escapes and object keys, and remembers the last point where
+// every value so far was complete. A truncated output is cut back to that point and its open
+// containers closed, so a half-written value is dropped rather than guessed.
+
+/// Incremental scanner repairing truncated JSON.
+#[derive(Debug, Default)]
+pub struct JsonRepair {
+    /// Output from the first `{` or `[` on.
+    buffer: String,
+    /// Open containers, `{` or `[`.
+    stack: Vec<u8>,
+    /// For each open object, whether a member value rather than a key comes next.
+    expect_value: Vec<bool>,
+    in_string: bool,
+    escaped: bool,
+    /// Inside a number or a `true`, `false` or `null` literal.
+    in_scalar: bool,
+    /// Length of the buffer and open containers at the last point every value was complete.
+    safe: Option<(usize, Vec<u8>)>,
+    /// The top-level value was closed, anything after it is ignored.
+    complete: bool,
+}
+
+impl JsonRepair {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scan the next chunk of output. Text before the first `{` or `[`, such as a preamble of the
+    /// model, and after the top-level value is skipped.
+    pub fn push(&mut self, chunk: &str) {
+        for c in chunk.chars() {
+            if self.complete {
+                return;
+            }
+            if self.stack.is_empty() {
+                if c == '{' || c == '[' {
+                    self.buffer.push(c);
+                    self.open(c);
+                }
+                continue;
+            }
+            self.buffer.push(c);
+            self.scan(c);
+        }
+    }
+
+    /// Whether the top-level value was closed.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The output so far as valid JSON: the top-level value when it was closed, otherwise the
+    /// output cut back to the last complete value with its open containers closed. `None` before
+    /// any container was opened.
+    pub fn finish(&self) -> Option<String> {
+        if self.complete {
+            return Some(self.buffer.clone());
+        }
+        // A scalar at the very end may be whole, such as `1` in `[1`, but could as well be cut
+        // short, so it is dropped too.
+        let (length, stack) = self.safe.as_ref()?;
+        let mut repaired = self.buffer[..*length].to_string();
+        for open in stack.iter().rev() {
+            repaired.push(if *open == b'{' { '}' } else { ']' });
+        }
+        Some(repaired)
+    }
+
+    fn scan(&mut self, c: char) {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == '"' {
+                self.in_string = false;
+                // A closed key only completes once its value does.
+                if self.is_key() {
+                    return;
+                }
+                self.value_end(self.buffer.len());
+            }
+            return;
+        }
+
+        let is_delimiter =
+            matches!(c, ',' | ':' | '}' | ']' | '"' | '{' | '[') || c.is_whitespace();
+        if self.in_scalar && is_delimiter {
+            self.in_scalar = false;
+            self.value_end(self.buffer.len() - c.len_utf8());
+        }
+        match c {
+            '"' => self.in_string = true,
+            '{' | '[' => self.open(c),
+            '}' | ']' => {
+                self.stack.pop();
+                if c == '}' {
+                    self.expect_value.pop();
+                }
+                if self.stack.is_empty() {
+                    self.complete = true;
+                } else {
+                    self.value_end(self.buffer.len());
+                }
+            }
+            ':' => {
+                if let Some(expect_value) = self.expect_value.last_mut() {
+                    *expect_value = true;
+                }
+            }
+            ',' if self.stack.last() == Some(&b'{') => {
+                if let Some(expect_value) = self.expect_value.last_mut() {
+                    *expect_value = false;
+                }
+            }
+            ',' => {}
+            _ if !c.is_whitespace() => self.in_scalar = true,
+            _ => {}
+        }
+    }
+
+    fn open(&mut self, c: char) {
+        self.stack.push(c as u8);
+        if c == '{' {
+            self.expect_value.push(false);
+        }
+        // An empty nested container adds nothing, it only counts once it holds a complete value.
+        if self.stack.len() == 1 {
+            self.value_end(self.buffer.len());
+        }
+    }
+
+    /// Whether the string just closed is an object key.
+    fn is_key(&self) -> bool {
+        self.stack.last() == Some(&b'{') && self.expect_value.last() == Some(&false)
+    }
+
+    fn value_end(&mut self, length: usize) {
+        self.safe = Some((length, self.stack.clone()));
+    }
+}
+
+/// Repair `output` at once, see `JsonRepair`.
+pub fn repair_json(output: &str) -> Option<String> {
+    let mut repair = JsonRepair::new();
+    repair.push(output);
+    repair.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_json_repair() {
+        let cases = [
+            // Unbalanced brackets used to loop forever.
+            (r#"{"a":[1,2"#, r#"{"a":[1]}"#),
+            // Brackets and escaped quotes inside strings are not structure.
+            (r#"{"a":"x}]\"y","b":"trunc"#, r#"{"a":"x}]\"y"}"#),
+            // A key without its value is dropped.
+            (r#"{"a":{"b":true},"c""#, r#"{"a":{"b":true}}"#),
+            (r#"{"a":{"b":true},"c":"#, r#"{"a":{"b":true}}"#),
+            (r#"{"a":[{"b":null}, {"c":"#, r#"{"a":[{"b":null}]}"#),
+            // Text around the value, such as markdown fences, is skipped.
+            (
+                "Here you go:\n```json\n{\"a\": [1, 2]}\n```",
+                r#"{"a": [1, 2]}"#,
+            ),
+        ];
+        for (output, repaired) in cases {
+            let result = repair_json(output).unwrap();
+            assert_eq!(result, repaired);
+            assert!(serde_json::from_str::<serde_json::Value>(&result).is_ok());
+        }
+        assert_eq!(repair_json("no json here"), None);
+
+        // Chunks split anywhere give the same result.
+        let output = r#"{"entry":[{"text":"a \"quoted\" [value]"},{"text":"cut"#;
+        let mut repair = JsonRepair::new();
+        for c in output.chars() {
+            repair.push(&c.to_string());
+        }
+        assert!(!repair.is_complete());
+        assert_eq!(repair.finish(), repair_json(output));
+        assert_eq!(
+            repair.finish().unwrap(),
+            r#"{"entry":[{"text":"a \"quoted\" [value]"}]}"#
+        );
+    }
+
+    #[test]
+    fn test_json_repair_scalars() {
+        let cases = [
+            // A number or literal at the end may be cut short, so it is dropped.
+            (r#"{"count":12"#, "{}"),
+            (r#"{"a":1,"b":2"#, r#"{"a":1}"#),
+            (r#"[-1, 1e5, 6E2, -"#, "[-1, 1e5, 6E2]"),
+            (r#"{"a":1e"#, "{}"),
+            (r#"{"ok":true,"n":nul"#, r#"{"ok":true}"#),
+            (r#"[true, fals"#, "[true]"),
+            // A scalar followed by a delimiter is complete.
+            (r#"{"a": 12 "#, r#"{"a": 12}"#),
+            (r#"{"a":[false,"#, r#"{"a":[false]}"#),
+            (r#"{"a":[null]"#, r#"{"a":[null]}"#),
+            // Closing brackets are restored at every depth.
+            (r#"{"a":{"b":{"c":[1,{"d":2"#, r#"{"a":{"b":{"c":[1]}}}"#),
+        ];
+        for (output, repaired) in cases {
+            assert_eq!(repair_json(output).unwrap(), repaired, "{output:?}");
+            assert!(serde_json::from_str::<Value>(repaired).is_ok());
+        }
+        // Only objects and arrays are repaired.
+        assert_eq!(repair_json("12"), None);
+        assert_eq!(repair_json(r#""text""#), None);
+    }
+
+    #[test]
+    fn test_json_repair_edges() {
+        assert_eq!(repair_json(""), None);
+        assert_eq!(repair_json("}]"), None);
+        assert_eq!(repair_json("{").unwrap(), "{}");
+        assert_eq!(repair_json("[").unwrap(), "[]");
+        assert_eq!(repair_json("[]").unwrap(), "[]");
+        // Empty nested containers add nothing until they hold a value.
+        assert_eq!(repair_json(r#"{"a":[{"#).unwrap(), "{}");
+        assert_eq!(repair_json(r#"{"a":[{}"#).unwrap(), r#"{"a":[{}]}"#);
+        // Colons and commas inside keys are not structure.
+        assert_eq!(repair_json(r#"{"a:b,c":1,"d"#).unwrap(), r#"{"a:b,c":1}"#);
+        // A string cut in an escape or in multi-byte text is dropped.
+        assert_eq!(repair_json(r#"["x","\u00e"#).unwrap(), r#"["x"]"#);
+        assert_eq!(repair_json(r#"["Zoë","Zoé"#).unwrap(), r#"["Zoë"]"#);
+        assert_eq!(repair_json(r#"["a\\"#).unwrap(), "[]");
+        assert_eq!(repair_json(r#"["a\\\\","#).unwrap(), r#"["a\\\\"]"#);
+        // Text after the top-level value is ignored.
+        let mut repair = JsonRepair::new();
+        repair.push(r#"{"a":1} and {"b":2}"#);
+        assert!(repair.is_complete());
+        repair.push("]");
+        assert_eq!(repair.finish().unwrap(), r#"{"a":1}"#);
+    }
+}
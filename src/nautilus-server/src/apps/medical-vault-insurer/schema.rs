@@ -0,0 +1,301 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// JSON Schema of the `{bundle}` / `{error}` envelope the LLM answers with. Providers supporting
+// structured output receive it with the request, through `response_format` or a forced tool call,
+// and every answer is checked against it locally before profile validation, whichever provider
+// produced it. Only the keywords used by the schema are implemented: `type`, `enum`, `properties`,
+// `required`, `additionalProperties`, `items`, `minLength`, `minItems`, `minProperties` and
+// `maxProperties`.
+
+use serde_json::{json, Value};
+
+/// Name under which the schema is sent to the providers.
+pub const FHIR_OUTPUT_SCHEMA_NAME: &str = "fhir_conversion";
+
+lazy_static::lazy_static! {
+    /// Either a FHIR Bundle or the error returned for non-medical input, see FHIR_SYSTEM_PROMPT.
+    /// Resources are left open, the profile validator checks them.
+    pub static ref FHIR_OUTPUT_SCHEMA: Value = json!({
+        "type": "object",
+        "properties": {
+            "bundle": {
+                "type": "object",
+                "properties": {
+                    "resourceType": { "type": "string", "enum": ["Bundle"] },
+                    "type": { "type": "string", "minLength": 1 },
+                    "entry": {
+                        "type": "array",
+                        "minItems": 1,
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "fullUrl": { "type": "string" },
+                                "resource": {
+                                    "type": "object",
+                                    "properties": {
+                                        "resourceType": { "type": "string", "minLength": 1 },
+                                        "id": { "type": "string" }
+                                    },
+                                    "required": ["resourceType"]
+                                }
+                            },
+                            "required": ["resource"]
+                        }
+                    }
+                },
+                "required": ["resourceType", "type", "entry"]
+            },
+            "error": {
+                "type": "object",
+                "properties": {
+                    "type": { "type": "string" },
+                    "message": { "type": "string" },
+                    "details": { "type": "string" }
+                },
+                "required": ["type", "message"]
+            }
+        },
+        "additionalProperties": false,
+        "minProperties": 1,
+        "maxProperties": 1
+    });
+}
+
+/// A value violating the schema: the path of the value, e.g. `bundle.entry[0].resource`, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub message: String,
+}
+
+/// Check `value` against `schema` and return every violation found.
+pub fn validate_json_schema(schema: &Value, value: &Value) -> Vec<SchemaViolation> {
+    let mut violations = Vec::new();
+    check(schema, value, "$", &mut violations);
+    violations
+}
+
+fn check(schema: &Value, value: &Value, path: &str, violations: &mut Vec<SchemaViolation>) {
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+            _ => Vec::new(),
+        };
+        if !types.iter().any(|t| has_type(value, t)) {
+            push(violations, path, format!("expected {}", types.join(" or ")));
+            // The other keywords do not apply to a value of the wrong type.
+            return;
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(|e| e.as_array()) {
+        if !allowed.contains(value) {
+            push(
+                violations,
+                path,
+                format!("must be one of {}", Value::Array(allowed.clone())),
+            );
+        }
+    }
+    if let (Some(min), Some(text)) = (
+        schema.get("minLength").and_then(|m| m.as_u64()),
+        value.as_str(),
+    ) {
+        if (text.chars().count() as u64) < min {
+            push(
+                violations,
+                path,
+                format!("must be at least {min} characters long"),
+            );
+        }
+    }
+
+    if let Some(items) = value.as_array() {
+        if let Some(min) = schema.get("minItems").and_then(|m| m.as_u64()) {
+            if (items.len() as u64) < min {
+                push(violations, path, format!("must have at least {min} items"));
+            }
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                check(item_schema, item, &format!("{path}[{index}]"), violations);
+            }
+        }
+        return;
+    }
+
+    let Some(object) = value.as_object() else {
+        return;
+    };
+    if let Some(min) = schema.get("minProperties").and_then(|m| m.as_u64()) {
+        if (object.len() as u64) < min {
+            push(
+                violations,
+                path,
+                format!("must have at least {min} properties"),
+            );
+        }
+    }
+    if let Some(max) = schema.get("maxProperties").and_then(|m| m.as_u64()) {
+        if (object.len() as u64) > max {
+            push(
+                violations,
+                path,
+                format!("must have at most {max} properties"),
+            );
+        }
+    }
+    for required in schema
+        .get("required")
+        .and_then(|r| r.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|r| r.as_str())
+    {
+        if !object.contains_key(required) {
+            push(
+                violations,
+                path,
+                format!("missing required property {required}"),
+            );
+        }
+    }
+    let properties = schema.get("properties").and_then(|p| p.as_object());
+    for (key, child) in object {
+        let child_path = if path == "$" {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        match properties.and_then(|p| p.get(key)) {
+            Some(child_schema) => check(child_schema, child, &child_path, violations),
+            None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                push(violations, &child_path, "unexpected property".to_string())
+            }
+            None => {}
+        }
+    }
+}
+
+fn push(violations: &mut Vec<SchemaViolation>, path: &str, message: String) {
+    violations.push(SchemaViolation {
+        path: path.to_string(),
+        message,
+    });
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Path and message of every violation of `value`.
+    fn violations(schema: &Value, value: &Value) -> Vec<(String, String)> {
+        validate_json_schema(schema, value)
+            .into_iter()
+            .map(|v| (v.path, v.message))
+            .collect()
+    }
+
+    fn violation(path: &str, message: &str) -> (String, String) {
+        (path.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn test_fhir_output_schema() {
+        // The schema accepts either envelope and points at what breaks it.
+        let valid = json!({ "bundle": {
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [{ "resource": { "resourceType": "Patient", "id": "p1" } }]
+        } });
+        assert!(validate_json_schema(&FHIR_OUTPUT_SCHEMA, &valid).is_empty());
+        let error = json!({ "error": { "type": "INVALID_INPUT", "message": "Not medical" } });
+        assert!(validate_json_schema(&FHIR_OUTPUT_SCHEMA, &error).is_empty());
+        let mut invalid = valid.clone();
+        invalid["bundle"]["entry"][0]["resource"]
+            .as_object_mut()
+            .unwrap()
+            .remove("resourceType");
+        invalid["notes"] = json!("extra");
+        let found = violations(&FHIR_OUTPUT_SCHEMA, &invalid);
+        assert!(found.contains(&violation(
+            "bundle.entry[0].resource",
+            "missing required property resourceType"
+        )));
+        assert!(found.contains(&violation("notes", "unexpected property")));
+
+        // Exactly one of the two envelopes.
+        assert_eq!(
+            violations(&FHIR_OUTPUT_SCHEMA, &json!({})),
+            vec![violation("$", "must have at least 1 properties")]
+        );
+        let mut both = valid.clone();
+        both["error"] = error["error"].clone();
+        assert_eq!(
+            violations(&FHIR_OUTPUT_SCHEMA, &both),
+            vec![violation("$", "must have at most 1 properties")]
+        );
+        assert_eq!(
+            violations(&FHIR_OUTPUT_SCHEMA, &json!([valid])),
+            vec![violation("$", "expected object")]
+        );
+        assert_eq!(
+            violations(
+                &FHIR_OUTPUT_SCHEMA,
+                &json!({ "bundle": { "resourceType": "bundle", "type": "", "entry": [] } })
+            ),
+            vec![
+                violation("bundle.entry", "must have at least 1 items"),
+                violation("bundle.resourceType", "must be one of [\"Bundle\"]"),
+                violation("bundle.type", "must be at least 1 characters long"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schema_keywords() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": ["string", "null"], "minLength": 2 },
+                "count": { "type": "integer" },
+                "tags": { "type": "array", "items": { "type": "string", "minLength": 1 } }
+            }
+        });
+        // Properties not described are allowed unless `additionalProperties` is false, and a
+        // length counts characters rather than bytes.
+        let value = json!({ "name": "é.", "count": 3, "tags": ["a"], "other": 1 });
+        assert!(violations(&schema, &value).is_empty());
+        assert!(violations(&schema, &json!({ "name": null })).is_empty());
+
+        let value = json!({ "name": "x", "count": 3.5, "tags": ["a", "", 7] });
+        assert_eq!(
+            violations(&schema, &value),
+            vec![
+                violation("count", "expected integer"),
+                violation("name", "must be at least 2 characters long"),
+                violation("tags[1]", "must be at least 1 characters long"),
+                violation("tags[2]", "expected string"),
+            ]
+        );
+        // Keywords of a mistyped value are not checked.
+        assert_eq!(
+            violations(&schema, &json!({ "tags": { "0": "" } })),
+            vec![violation("tags", "expected array")]
+        );
+    }
+}
@@ -0,0 +1,942 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Rule-based converter for Synthea CSV exports (https://github.com/synthetichealth/synthea). The
+// patients, encounters, conditions, medications and observations tables are mapped to a bundle
+// following the BTP Medical Vault Profile V0 without calling the LLM, so the same export always
+// yields the same bundle and semantic hash.
+//
+// `raw_data` holds the CSV files one after the other, separated by an empty line. Each table is
+// recognized from its header row, columns are looked up by name and other tables (allergies,
+// procedures, ...) are ignored.
+
+use super::*;
+use fastcrypto::hash::{HashFunction, Sha3_256};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+
+/// `source_format` selecting this converter.
+pub const SYNTHEA_SOURCE_FORMAT: &str = "synthea";
+
+/// Maximum number of resources in a converted bundle.
+const MAX_SYNTHEA_RESOURCES: usize = 1000;
+
+const SNOMED: &str = "http://snomed.info/sct";
+const LOINC: &str = "http://loinc.org";
+const RXNORM: &str = "http://www.nlm.nih.gov/research/umls/rxnorm";
+const ICD10_CM: &str = "http://hl7.org/fhir/sid/icd-10-cm";
+const UCUM: &str = "http://unitsofmeasure.org";
+const SSN_SYSTEM: &str = "urn:oid:2.16.840.1.113883.4.1";
+const SYNTHEA_ID_SYSTEM: &str = "https://github.com/synthetichealth/synthea";
+const ACT_CODE: &str = "http://terminology.hl7.org/CodeSystem/v3-ActCode";
+const OBSERVATION_CATEGORY: &str = "http://terminology.hl7.org/CodeSystem/observation-category";
+const CONDITION_CLINICAL: &str = "http://terminology.hl7.org/CodeSystem/condition-clinical";
+const CONDITION_VERIFICATION: &str = "http://terminology.hl7.org/CodeSystem/condition-ver-status";
+
+/// Columns of the Synthea conditions table, which has no column of its own to recognize it by.
+const CONDITION_COLUMNS: &[&str] = &[
+    "START",
+    "STOP",
+    "PATIENT",
+    "ENCOUNTER",
+    "SYSTEM",
+    "CODE",
+    "DESCRIPTION",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TableKind {
+    Patients,
+    Encounters,
+    Conditions,
+    Medications,
+    Observations,
+}
+
+impl TableKind {
+    fn detect(columns: &HashMap<String, usize>) -> Option<Self> {
+        let has = |c: &str| columns.contains_key(c);
+        if has("Id") && has("BIRTHDATE") {
+            Some(Self::Patients)
+        } else if has("Id") && has("ENCOUNTERCLASS") {
+            Some(Self::Encounters)
+        } else if has("DISPENSES") && has("CODE") {
+            Some(Self::Medications)
+        } else if has("DATE") && has("VALUE") && has("UNITS") && has("TYPE") {
+            Some(Self::Observations)
+        } else if has("START")
+            && has("CODE")
+            && columns
+                .keys()
+                .all(|c| CONDITION_COLUMNS.contains(&c.as_str()))
+        {
+            Some(Self::Conditions)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Patients => "patients",
+            Self::Encounters => "encounters",
+            Self::Conditions => "conditions",
+            Self::Medications => "medications",
+            Self::Observations => "observations",
+        }
+    }
+}
+
+/// A CSV table whose cells are looked up by column name.
+struct Table {
+    columns: HashMap<String, usize>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Non-empty cell of `row` in `column`.
+    fn get<'a>(&self, row: &'a [String], column: &str) -> Option<&'a str> {
+        self.columns
+            .get(column)
+            .and_then(|i| row.get(*i))
+            .map(|cell| cell.trim())
+            .filter(|cell| !cell.is_empty())
+    }
+
+    fn require<'a>(&self, row: &'a [String], column: &str) -> Result<&'a str, EnclaveError> {
+        self.get(row, column)
+            .ok_or_else(|| EnclaveError::GenericError(format!("Synthea row is missing {column}")))
+    }
+
+    /// Rows belonging to the given patient.
+    fn rows_of<'a>(&'a self, patient_id: &'a str) -> impl Iterator<Item = &'a Vec<String>> {
+        self.rows
+            .iter()
+            .filter(move |row| self.get(row, "PATIENT") == Some(patient_id))
+    }
+}
+
+/// Convert a Synthea CSV export to a FHIR R5 bundle in the `{"bundle": {...}}` envelope returned
+/// by the LLM. The patient is taken from `patient_context` when the export holds several.
+pub fn convert_synthea(request: &FhirBuildRequest) -> Result<Value, EnclaveError> {
+    let mut tables: HashMap<&'static str, Table> = HashMap::new();
+    for rows in parse_csv_tables(&request.raw_data)? {
+        let mut rows = rows.into_iter();
+        let Some(header) = rows.next() else { continue };
+        let columns: HashMap<String, usize> = header
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| (c.trim().to_string(), i))
+            .collect();
+        let Some(kind) = TableKind::detect(&columns) else {
+            continue;
+        };
+        if tables.contains_key(kind.name()) {
+            return Err(EnclaveError::GenericError(format!(
+                "Synthea export contains more than one {} table",
+                kind.name()
+            )));
+        }
+        tables.insert(
+            kind.name(),
+            Table {
+                columns,
+                rows: rows.collect(),
+            },
+        );
+    }
+
+    let patients = tables.get(TableKind::Patients.name()).ok_or_else(|| {
+        EnclaveError::GenericError("Synthea export has no patients table".to_string())
+    })?;
+    let patient = select_patient(patients, request.patient_context.as_ref())?;
+    let patient_id = patients.require(patient, "Id")?;
+    let patient_url = format!("urn:uuid:{patient_id}");
+
+    let mut entries = vec![entry(
+        &patient_url,
+        patient_resource(patients, patient, patient_id, request.include_phi),
+    )];
+
+    let mut encounter_urls = HashMap::new();
+    if let Some(encounters) = tables.get(TableKind::Encounters.name()) {
+        for row in encounters.rows_of(patient_id) {
+            let id = encounters.require(row, "Id")?;
+            let url = format!("urn:uuid:{id}");
+            entries.push(entry(
+                &url,
+                encounter_resource(encounters, row, id, &patient_url)?,
+            ));
+            encounter_urls.insert(id.to_string(), url);
+        }
+    }
+    let context = RowContext {
+        patient_url: &patient_url,
+        encounter_urls: &encounter_urls,
+    };
+
+    for kind in [
+        TableKind::Conditions,
+        TableKind::Medications,
+        TableKind::Observations,
+    ] {
+        let Some(table) = tables.get(kind.name()) else {
+            continue;
+        };
+        let mut seen = HashSet::new();
+        for row in table.rows_of(patient_id) {
+            let resource = match kind {
+                TableKind::Conditions => Some(condition_resource(table, row, &context)?),
+                TableKind::Medications => Some(medication_request_resource(table, row, &context)?),
+                // Text observations have no valueQuantity, which the profile requires.
+                TableKind::Observations => observation_resource(table, row, &context)?,
+                TableKind::Patients | TableKind::Encounters => None,
+            };
+            let Some(mut resource) = resource else {
+                continue;
+            };
+            // Identical rows (e.g. a condition recorded twice) map to the same resource.
+            let id = derived_uuid(kind.name(), row);
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            resource["id"] = json!(id);
+            entries.push(entry(&format!("urn:uuid:{id}"), resource));
+        }
+    }
+
+    if entries.len() > MAX_SYNTHEA_RESOURCES {
+        return Err(EnclaveError::GenericError(format!(
+            "Synthea export produces {} resources, at most {MAX_SYNTHEA_RESOURCES} are allowed",
+            entries.len()
+        )));
+    }
+
+    info!("Synthea conversion complete: {} resources", entries.len());
+
+    Ok(json!({
+        "bundle": {
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": entries,
+        }
+    }))
+}
+
+/// References shared by the resources of one patient.
+struct RowContext<'a> {
+    patient_url: &'a str,
+    encounter_urls: &'a HashMap<String, String>,
+}
+
+impl RowContext<'_> {
+    /// Add the subject and, when the encounter is part of the bundle, the encounter references.
+    fn link(&self, resource: &mut Map<String, Value>, table: &Table, row: &[String]) {
+        resource.insert(
+            "subject".to_string(),
+            json!({ "reference": self.patient_url }),
+        );
+        if let Some(url) = table
+            .get(row, "ENCOUNTER")
+            .and_then(|id| self.encounter_urls.get(id))
+        {
+            resource.insert("encounter".to_string(), json!({ "reference": url }));
+        }
+    }
+}
+
+fn select_patient<'a>(
+    patients: &'a Table,
+    context: Option<&PatientContext>,
+) -> Result<&'a [String], EnclaveError> {
+    if let Some(context) = context {
+        return patients
+            .rows
+            .iter()
+            .find(|row| patients.get(row, "Id") == Some(context.patient_id.as_str()))
+            .map(Vec::as_slice)
+            .ok_or_else(|| {
+                EnclaveError::GenericError(format!(
+                    "Patient {} is not in the Synthea export",
+                    context.patient_id
+                ))
+            });
+    }
+    match patients.rows.as_slice() {
+        [row] => Ok(row),
+        [] => Err(EnclaveError::GenericError(
+            "Synthea patients table is empty".to_string(),
+        )),
+        rows => Err(EnclaveError::GenericError(format!(
+            "Synthea export contains {} patients, set patient_context.patient_id",
+            rows.len()
+        ))),
+    }
+}
+
+/// Patient resource. Without `include_phi` names are masked, dates are reduced to the year and
+/// only the state is kept of the address, as the LLM is instructed to do.
+fn patient_resource(table: &Table, row: &[String], id: &str, include_phi: bool) -> Value {
+    let mut patient = Map::new();
+    patient.insert("resourceType".to_string(), json!("Patient"));
+    patient.insert("id".to_string(), json!(id));
+    patient.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/Patient"] }),
+    );
+
+    let mut identifier = vec![json!({ "system": SYNTHEA_ID_SYSTEM, "value": id })];
+    if include_phi {
+        if let Some(ssn) = table.get(row, "SSN") {
+            identifier.push(json!({ "system": SSN_SYSTEM, "value": ssn }));
+        }
+    }
+    patient.insert("identifier".to_string(), json!(identifier));
+
+    let name = if include_phi {
+        let mut name = Map::new();
+        name.insert("use".to_string(), json!("official"));
+        insert_opt(&mut name, "family", table.get(row, "LAST"));
+        let given: Vec<&str> = ["FIRST", "MIDDLE"]
+            .iter()
+            .filter_map(|c| table.get(row, c))
+            .collect();
+        name.insert("given".to_string(), json!(given));
+        if let Some(prefix) = table.get(row, "PREFIX") {
+            name.insert("prefix".to_string(), json!([prefix]));
+        }
+        if let Some(suffix) = table.get(row, "SUFFIX") {
+            name.insert("suffix".to_string(), json!([suffix]));
+        }
+        Value::Object(name)
+    } else {
+        json!({ "family": "***", "given": ["***"] })
+    };
+    patient.insert("name".to_string(), json!([name]));
+
+    let gender = match table.get(row, "GENDER") {
+        Some("M") => "male",
+        Some("F") => "female",
+        _ => "unknown",
+    };
+    patient.insert("gender".to_string(), json!(gender));
+
+    let mask_date = |date: &str| -> String {
+        if include_phi {
+            date.to_string()
+        } else {
+            date.chars().take(4).collect()
+        }
+    };
+    insert_opt(
+        &mut patient,
+        "birthDate",
+        table.get(row, "BIRTHDATE").map(mask_date),
+    );
+    insert_opt(
+        &mut patient,
+        "deceasedDateTime",
+        table.get(row, "DEATHDATE").map(mask_date),
+    );
+
+    let mut address = Map::new();
+    if include_phi {
+        if let Some(line) = table.get(row, "ADDRESS") {
+            address.insert("line".to_string(), json!([line]));
+        }
+        insert_opt(&mut address, "city", table.get(row, "CITY"));
+        insert_opt(&mut address, "state", table.get(row, "STATE"));
+        insert_opt(&mut address, "postalCode", table.get(row, "ZIP"));
+    } else {
+        insert_opt(&mut address, "state", table.get(row, "STATE"));
+    }
+    address.insert("country".to_string(), json!("US"));
+    patient.insert("address".to_string(), json!([address]));
+
+    Value::Object(patient)
+}
+
+fn encounter_resource(
+    table: &Table,
+    row: &[String],
+    id: &str,
+    patient_url: &str,
+) -> Result<Value, EnclaveError> {
+    let class = match table.get(row, "ENCOUNTERCLASS") {
+        Some("emergency") => ("EMER", "emergency"),
+        Some("inpatient") => ("IMP", "inpatient encounter"),
+        Some("home") => ("HH", "home health"),
+        Some("virtual") => ("VR", "virtual"),
+        _ => ("AMB", "ambulatory"),
+    };
+
+    let mut encounter = Map::new();
+    encounter.insert("resourceType".to_string(), json!("Encounter"));
+    encounter.insert("id".to_string(), json!(id));
+    let status = if table.get(row, "STOP").is_some() {
+        "completed"
+    } else {
+        "in-progress"
+    };
+    encounter.insert("status".to_string(), json!(status));
+    encounter.insert(
+        "class".to_string(),
+        json!([{ "coding": [{ "system": ACT_CODE, "code": class.0, "display": class.1 }] }]),
+    );
+    encounter.insert(
+        "type".to_string(),
+        json!([concept(
+            SNOMED,
+            table.require(row, "CODE")?,
+            table.get(row, "DESCRIPTION")
+        )]),
+    );
+    encounter.insert("subject".to_string(), json!({ "reference": patient_url }));
+
+    let mut period = Map::new();
+    period.insert("start".to_string(), json!(table.require(row, "START")?));
+    insert_opt(&mut period, "end", table.get(row, "STOP"));
+    encounter.insert("actualPeriod".to_string(), Value::Object(period));
+
+    if let Some(code) = table.get(row, "REASONCODE") {
+        let reason = concept(SNOMED, code, table.get(row, "REASONDESCRIPTION"));
+        encounter.insert(
+            "reason".to_string(),
+            json!([{ "value": [{ "concept": reason }] }]),
+        );
+    }
+    Ok(Value::Object(encounter))
+}
+
+fn condition_resource(
+    table: &Table,
+    row: &[String],
+    context: &RowContext,
+) -> Result<Value, EnclaveError> {
+    let system = match table.get(row, "SYSTEM") {
+        Some(s) if s.to_ascii_uppercase().starts_with("ICD") => ICD10_CM,
+        _ => SNOMED,
+    };
+    let (clinical_status, abatement) = match table.get(row, "STOP") {
+        Some(stop) => ("resolved", Some(stop)),
+        None => ("active", None),
+    };
+    let start = table.require(row, "START")?;
+
+    let mut condition = Map::new();
+    condition.insert("resourceType".to_string(), json!("Condition"));
+    condition.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/Condition"] }),
+    );
+    condition.insert(
+        "clinicalStatus".to_string(),
+        json!({ "coding": [{ "system": CONDITION_CLINICAL, "code": clinical_status }] }),
+    );
+    condition.insert(
+        "verificationStatus".to_string(),
+        json!({ "coding": [{ "system": CONDITION_VERIFICATION, "code": "confirmed" }] }),
+    );
+    condition.insert(
+        "code".to_string(),
+        concept(
+            system,
+            table.require(row, "CODE")?,
+            table.get(row, "DESCRIPTION"),
+        ),
+    );
+    context.link(&mut condition, table, row);
+    condition.insert("onsetDateTime".to_string(), json!(start));
+    insert_opt(&mut condition, "abatementDateTime", abatement);
+    condition.insert("recordedDate".to_string(), json!(start));
+    Ok(Value::Object(condition))
+}
+
+fn medication_request_resource(
+    table: &Table,
+    row: &[String],
+    context: &RowContext,
+) -> Result<Value, EnclaveError> {
+    let status = if table.get(row, "STOP").is_some() {
+        "completed"
+    } else {
+        "active"
+    };
+
+    let mut request = Map::new();
+    request.insert("resourceType".to_string(), json!("MedicationRequest"));
+    request.insert(
+        "meta".to_string(),
+        json!({ "profile": ["http://hl7.org/fhir/StructureDefinition/MedicationRequest"] }),
+    );
+    request.insert("status".to_string(), json!(status));
+    request.insert("intent".to_string(), json!("order"));
+    request.insert(
+        "medication".to_string(),
+        json!({
+            "concept": concept(RXNORM, table.require(row, "CODE")?, table.get(row, "DESCRIPTION"))
+        }),
+    );
+    context.link(&mut request, table, row);
+    request.insert(
+        "authoredOn".to_string(),
+        json!(table.require(row, "START")?),
+    );
+    if let Some(code) = table.get(row, "REASONCODE") {
+        let reason = concept(SNOMED, code, table.get(row, "REASONDESCRIPTION"));
+        request.insert("reason".to_string(), json!([{ "concept": reason }]));
+    }
+    Ok(Value::Object(request))
+}
+
+/// Observation resource, or None for a non-numeric observation.
+fn observation_resource(
+    table: &Table,
+    row: &[String],
+    context: &RowContext,
+) -> Result<Option<Value>, EnclaveError> {
+    if table.get(row, "TYPE") != Some("numeric") {
+        return Ok(None);
+    }
+    let raw_value = table.require(row, "VALUE")?;
+    let value = raw_value
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| {
+            EnclaveError::GenericError(format!("Invalid numeric observation value {raw_value}"))
+        })?;
+    let category = table.get(row, "CATEGORY").unwrap_or("laboratory");
+    let profile = if category == "vital-signs" {
+        "http://hl7.org/fhir/StructureDefinition/vitalsigns"
+    } else {
+        "http://hl7.org/fhir/StructureDefinition/Observation"
+    };
+
+    let mut quantity = Map::new();
+    quantity.insert("value".to_string(), json!(value));
+    if let Some(unit) = table.get(row, "UNITS") {
+        quantity.insert("unit".to_string(), json!(unit));
+        quantity.insert("system".to_string(), json!(UCUM));
+        quantity.insert("code".to_string(), json!(unit));
+    }
+
+    let mut observation = Map::new();
+    observation.insert("resourceType".to_string(), json!("Observation"));
+    observation.insert("meta".to_string(), json!({ "profile": [profile] }));
+    observation.insert("status".to_string(), json!("final"));
+    observation.insert(
+        "category".to_string(),
+        json!([{ "coding": [{ "system": OBSERVATION_CATEGORY, "code": category }] }]),
+    );
+    observation.insert(
+        "code".to_string(),
+        concept(
+            LOINC,
+            table.require(row, "CODE")?,
+            table.get(row, "DESCRIPTION"),
+        ),
+    );
+    context.link(&mut observation, table, row);
+    observation.insert(
+        "effectiveDateTime".to_string(),
+        json!(table.require(row, "DATE")?),
+    );
+    observation.insert("valueQuantity".to_string(), Value::Object(quantity));
+    Ok(Some(Value::Object(observation)))
+}
+
+pub(crate) fn entry(full_url: &str, resource: Value) -> Value {
+    json!({ "fullUrl": full_url, "resource": resource })
+}
+
+pub(crate) fn concept(system: &str, code: &str, display: Option<&str>) -> Value {
+    let mut coding = Map::new();
+    coding.insert("system".to_string(), json!(system));
+    coding.insert("code".to_string(), json!(code));
+    insert_opt(&mut coding, "display", display);
+    let mut concept = Map::new();
+    concept.insert("coding".to_string(), json!([coding]));
+    insert_opt(&mut concept, "text", display);
+    Value::Object(concept)
+}
+
+pub(crate) fn insert_opt<V: Into<Value>>(
+    object: &mut Map<String, Value>,
+    key: &str,
+    value: Option<V>,
+) {
+    if let Some(value) = value {
+        object.insert(key.to_string(), value.into());
+    }
+}
+
+/// Name based UUID (RFC 9562 version 8) of a row without an Id column, so that resource ids and
+/// full URLs only depend on the row content.
+pub(crate) fn derived_uuid(table: &str, row: &[String]) -> String {
+    let mut hasher = Sha3_256::default();
+    hasher.update(table.as_bytes());
+    for cell in row {
+        hasher.update([0u8]);
+        hasher.update(cell.as_bytes());
+    }
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hasher.finalize().digest[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = Hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Split `data` into CSV tables separated by empty lines. Fields follow RFC 4180: they may be
+/// quoted, with `""` standing for a quote inside a quoted field.
+fn parse_csv_tables(data: &str) -> Result<Vec<Vec<Vec<String>>>, EnclaveError> {
+    let mut tables = Vec::new();
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut quoted = false;
+
+    let mut chars = data.chars().peekable();
+    loop {
+        let c = chars.next();
+        if in_quotes {
+            match c {
+                Some('"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                Some('"') => in_quotes = false,
+                Some(c) => field.push(c),
+                None => {
+                    return Err(EnclaveError::GenericError(
+                        "Unterminated quoted field in Synthea CSV".to_string(),
+                    ))
+                }
+            }
+            continue;
+        }
+        match c {
+            Some('"') if field.is_empty() && !quoted => {
+                in_quotes = true;
+                quoted = true;
+            }
+            Some(',') => {
+                row.push(std::mem::take(&mut field));
+                quoted = false;
+            }
+            Some('\r') => {}
+            Some('\n') | None => {
+                if row.is_empty() && field.is_empty() && !quoted {
+                    // An empty line ends the current table.
+                    if !rows.is_empty() {
+                        tables.push(std::mem::take(&mut rows));
+                    }
+                } else {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                    quoted = false;
+                }
+                if c.is_none() {
+                    break;
+                }
+            }
+            Some(c) => field.push(c),
+        }
+    }
+    if !rows.is_empty() {
+        tables.push(rows);
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(raw_data: &str, patient_id: Option<&str>) -> FhirBuildRequest {
+        FhirBuildRequest {
+            raw_data: raw_data.to_string(),
+            source_format: SYNTHEA_SOURCE_FORMAT.to_string(),
+            patient_context: patient_id.map(|id| PatientContext {
+                patient_id: id.to_string(),
+                name: None,
+                birth_date: None,
+                gender: None,
+            }),
+            include_phi: false,
+        }
+    }
+
+    /// Export of a single patient followed by `tables`.
+    fn export(tables: &str) -> String {
+        format!("Id,BIRTHDATE,GENDER,STATE\np1,1990-05-06,F,Ohio\n\n{tables}")
+    }
+
+    fn rows(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_synthea_conversion() {
+        let export = "\
+Id,BIRTHDATE,DEATHDATE,SSN,DRIVERS,PASSPORT,PREFIX,FIRST,LAST,SUFFIX,MAIDEN,MARITAL,RACE,ETHNICITY,GENDER,BIRTHPLACE,ADDRESS,CITY,STATE,COUNTY,ZIP
+b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85,1980-02-01,,999-62-4431,S99912345,,Mrs.,Jane,Doe,,,M,white,nonhispanic,F,Boston,1 Main St,Boston,Massachusetts,Suffolk County,02101
+7e4c5a1b-0d2f-4e6a-9b7c-2f1e3d4c5b6a,1975-06-15,,999-11-2222,,,Mr.,John,Roe,,,S,white,nonhispanic,M,Salem,2 Elm St,Salem,Massachusetts,Essex County,01970
+
+Id,START,STOP,PATIENT,ORGANIZATION,PROVIDER,PAYER,ENCOUNTERCLASS,CODE,DESCRIPTION,BASE_ENCOUNTER_COST,TOTAL_CLAIM_COST,PAYER_COVERAGE,REASONCODE,REASONDESCRIPTION
+d1a2b3c4-e5f6-4a7b-8c9d-0e1f2a3b4c5d,2019-02-17T05:07:38Z,2019-02-17T05:22:38Z,b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85,org,prov,payer,ambulatory,185345009,Encounter for symptom,129.16,129.16,0,195662009,Acute viral pharyngitis (disorder)
+
+START,STOP,PATIENT,ENCOUNTER,CODE,DESCRIPTION
+2019-02-17,,b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85,d1a2b3c4-e5f6-4a7b-8c9d-0e1f2a3b4c5d,195662009,Acute viral pharyngitis (disorder)
+2019-02-17,,7e4c5a1b-0d2f-4e6a-9b7c-2f1e3d4c5b6a,,44054006,Diabetes
+
+START,STOP,PATIENT,PAYER,ENCOUNTER,CODE,DESCRIPTION,BASE_COST,PAYER_COVERAGE,DISPENSES,TOTALCOST,REASONCODE,REASONDESCRIPTION
+2019-02-17T05:07:38Z,,b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85,payer,d1a2b3c4-e5f6-4a7b-8c9d-0e1f2a3b4c5d,834061,\"Penicillin V Potassium 250 MG, Oral Tablet\",12.5,0,1,12.5,195662009,Acute viral pharyngitis (disorder)
+
+DATE,PATIENT,ENCOUNTER,CATEGORY,CODE,DESCRIPTION,VALUE,UNITS,TYPE
+2019-02-17T05:07:38Z,b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85,d1a2b3c4-e5f6-4a7b-8c9d-0e1f2a3b4c5d,vital-signs,8867-4,Heart rate,72.0,/min,numeric
+2019-02-17T05:07:38Z,b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85,d1a2b3c4-e5f6-4a7b-8c9d-0e1f2a3b4c5d,survey,72166-2,Tobacco smoking status,Never smoked,,text
+
+START,STOP,PATIENT,ENCOUNTER,SYSTEM,CODE,DESCRIPTION,BASE_COST,REASONCODE,REASONDESCRIPTION
+2019-02-17T05:07:38Z,,b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85,d1a2b3c4-e5f6-4a7b-8c9d-0e1f2a3b4c5d,SNOMED-CT,117015009,Throat culture,100,,
+";
+        let request = |patient_id: Option<&str>, include_phi: bool| FhirBuildRequest {
+            raw_data: export.to_string(),
+            source_format: "synthea".to_string(),
+            patient_context: patient_id.map(|id| PatientContext {
+                patient_id: id.to_string(),
+                name: None,
+                birth_date: None,
+                gender: None,
+            }),
+            include_phi,
+        };
+
+        // Two patients in the export, one must be chosen.
+        assert!(convert_synthea(&request(None, false)).is_err());
+        assert!(convert_synthea(&request(Some("unknown"), false)).is_err());
+
+        let jane = Some("b9c610cd-28a6-4636-ccb6-c7a0d2a4cb85");
+        let bundle = convert_synthea(&request(jane, true)).unwrap();
+        let outcome = validate_profile(&bundle);
+        assert!(!outcome.has_errors(), "{}", outcome.error_summary());
+        // The text observation and the procedures table are left out.
+        assert_eq!(
+            extract_resource_types(&bundle),
+            vec![
+                "Patient",
+                "Encounter",
+                "Condition",
+                "MedicationRequest",
+                "Observation"
+            ]
+        );
+        let resources = bundle_resources(&bundle);
+        assert_eq!(resources.len(), 5);
+        assert_eq!(resources[0]["name"][0]["given"], json!(["Jane"]));
+        assert_eq!(resources[0]["birthDate"], "1980-02-01");
+        assert_eq!(
+            resources[3]["medication"]["concept"]["coding"][0]["display"],
+            "Penicillin V Potassium 250 MG, Oral Tablet"
+        );
+        assert_eq!(
+            resources[4]["valueQuantity"],
+            json!({ "value": 72.0, "unit": "/min", "system": "http://unitsofmeasure.org", "code": "/min" })
+        );
+        assert_eq!(
+            resources[4]["encounter"]["reference"],
+            "urn:uuid:d1a2b3c4-e5f6-4a7b-8c9d-0e1f2a3b4c5d"
+        );
+
+        // The same export always yields the same semantic hash.
+        let again = convert_synthea(&request(jane, true)).unwrap();
+        assert_eq!(
+            compute_semantic_hash(&bundle).unwrap(),
+            compute_semantic_hash(&again).unwrap()
+        );
+
+        let masked = convert_synthea(&request(jane, false)).unwrap();
+        let patient = bundle_resources(&masked)[0];
+        assert_eq!(patient["name"][0]["family"], "***");
+        assert_eq!(patient["birthDate"], "1980");
+        assert_eq!(
+            patient["address"],
+            json!([{ "state": "Massachusetts", "country": "US" }])
+        );
+        assert_eq!(patient["identifier"].as_array().unwrap().len(), 1);
+        assert!(!validate_profile(&masked).has_errors());
+    }
+
+    #[test]
+    fn test_parse_csv_tables() {
+        // Quoted fields keep their commas, quotes and line breaks, and a run of empty lines ends
+        // a single table.
+        let tables =
+            parse_csv_tables("a,b\r\n1,\"x, \"\"y\"\"\"\n\n\n\"\",c\n\"1\n2\",\n").unwrap();
+        assert_eq!(
+            tables,
+            vec![
+                rows(&[&["a", "b"], &["1", "x, \"y\""]]),
+                rows(&[&["", "c"], &["1\n2", ""]]),
+            ]
+        );
+        // The last row needs no line break.
+        assert_eq!(
+            parse_csv_tables("a\n1").unwrap(),
+            vec![rows(&[&["a"], &["1"]])]
+        );
+        assert!(parse_csv_tables("").unwrap().is_empty());
+        assert!(parse_csv_tables("\n\r\n\n").unwrap().is_empty());
+        assert!(parse_csv_tables("a,\"b\n").is_err());
+    }
+
+    #[test]
+    fn test_synthea_observation_values() {
+        let observations = "\
+DATE,PATIENT,ENCOUNTER,CATEGORY,CODE,DESCRIPTION,VALUE,UNITS,TYPE
+2020-01-01,p1,,vital-signs,8310-5,Body temperature,37.35,Cel,numeric
+2020-01-01,p1,,laboratory,4548-4,Hemoglobin A1c,5.70,%,numeric
+2020-01-01,p1,,,2339-0,Glucose,1e2,mg/dL,numeric
+2020-01-01,p1,,vital-signs,8867-4,Heart rate,72,/min,numeric
+2020-01-01,p1,,vital-signs,8302-2,Body height, 0.5 ,,numeric
+";
+        let bundle = convert_synthea(&request(&export(observations), None)).unwrap();
+        // The profile requires a unit, so only the last observation is invalid.
+        let outcome = validate_profile(&bundle);
+        assert!(outcome
+            .issue
+            .iter()
+            .filter(|i| i.severity == profile::IssueSeverity::Error)
+            .all(|i| i.expression[0].starts_with("Bundle.entry[5].resource.valueQuantity")));
+        assert!(outcome.has_errors());
+        let resources = bundle_resources(&bundle);
+        let values: Vec<&Value> = resources[1..]
+            .iter()
+            .map(|r| &r["valueQuantity"]["value"])
+            .collect();
+        assert_eq!(
+            values,
+            [
+                &json!(37.35),
+                &json!(5.7),
+                &json!(100.0),
+                &json!(72.0),
+                &json!(0.5)
+            ]
+        );
+        assert!(values.iter().all(|v| v.is_f64()));
+        // Without a unit, the quantity has no system and code.
+        assert_eq!(resources[5]["valueQuantity"], json!({ "value": 0.5 }));
+        assert_eq!(
+            resources[3]["category"][0]["coding"][0]["code"],
+            "laboratory"
+        );
+        assert_eq!(
+            resources[1]["meta"]["profile"][0],
+            "http://hl7.org/fhir/StructureDefinition/vitalsigns"
+        );
+        assert_eq!(
+            compute_semantic_hash(&bundle).unwrap(),
+            compute_semantic_hash(&convert_synthea(&request(&export(observations), None)).unwrap())
+                .unwrap()
+        );
+
+        // Values that are not finite numbers are rejected rather than dropped.
+        for value in ["NaN", "inf", "-inf", "1e400", "37,5", "high", ""] {
+            let observation = format!(
+                "DATE,PATIENT,ENCOUNTER,CATEGORY,CODE,DESCRIPTION,VALUE,UNITS,TYPE\n\
+                 2020-01-01,p1,,laboratory,2339-0,Glucose,\"{value}\",mg/dL,numeric\n"
+            );
+            assert!(
+                convert_synthea(&request(&export(&observation), None)).is_err(),
+                "{value:?} accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn test_synthea_tables() {
+        // A single patient is chosen without a patient context, and unknown tables are ignored.
+        let conditions = "\
+ID,PATIENT,DESCRIPTION
+x,p1,Allergy to peanuts
+
+START,STOP,PATIENT,ENCOUNTER,SYSTEM,CODE,DESCRIPTION
+2019-01-01,,p1,e-other,SNOMED-CT,44054006,Diabetes
+2019-01-01,,p1,e-other,SNOMED-CT,44054006,Diabetes
+2018-03-01,2018-04-01,p1,,ICD10,J02.9,Acute pharyngitis
+2018-03-01,,p2,,SNOMED-CT,38341003,Hypertension
+";
+        let bundle = convert_synthea(&request(&export(conditions), None)).unwrap();
+        // The profile expects SNOMED CT codes, so only the ICD-10-CM condition is invalid.
+        assert_eq!(
+            validate_profile(&bundle).error_summary(),
+            "Bundle.entry[2].resource.code.coding: Expected a coding from http://snomed.info/sct"
+        );
+        let resources = bundle_resources(&bundle);
+        // The duplicated row yields one Condition, and the row of another patient none.
+        let types: Vec<&Value> = resources.iter().map(|r| &r["resourceType"]).collect();
+        assert_eq!(types, ["Patient", "Condition", "Condition"]);
+        // An encounter missing from the bundle is not referenced.
+        assert!(resources[1].get("encounter").is_none());
+        assert_eq!(
+            resources[1]["clinicalStatus"]["coding"][0]["code"],
+            "active"
+        );
+        assert_eq!(resources[2]["code"]["coding"][0]["system"], ICD10_CM);
+        assert_eq!(
+            resources[2]["clinicalStatus"]["coding"][0]["code"],
+            "resolved"
+        );
+        assert_eq!(resources[2]["abatementDateTime"], "2018-04-01");
+        assert!(convert_synthea(&request(&export(conditions), Some("p1"))).is_ok());
+
+        let error = |raw_data: &str, patient_id: Option<&str>| match convert_synthea(&request(
+            raw_data, patient_id,
+        )) {
+            Err(EnclaveError::GenericError(message)) => message,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert!(error(conditions, None).contains("no patients table"));
+        assert!(error("", None).contains("no patients table"));
+        assert!(error(&export("Id,BIRTHDATE\np2,2000-01-01\n"), None).contains("more than one"));
+        assert!(error("Id,BIRTHDATE\n", None).contains("empty"));
+        assert!(error(&export(""), Some("p2")).contains("p2"));
+        assert!(error(&export("START,PATIENT,CODE\n,p1,44054006\n"), None).contains("START"));
+
+        // An export producing too many resources is rejected.
+        let mut many = "START,PATIENT,CODE\n".to_string();
+        for i in 0..MAX_SYNTHEA_RESOURCES {
+            many.push_str(&format!("2019-01-01,p1,{i}\n"));
+        }
+        assert!(error(&export(&many), None).contains("at most"));
+        many.truncate(many.trim_end().rfind('\n').unwrap() + 1);
+        let bundle = convert_synthea(&request(&export(&many), None)).unwrap();
+        assert_eq!(bundle_resources(&bundle).len(), MAX_SYNTHEA_RESOURCES);
+    }
+
+    #[test]
+    fn test_derived_uuid() {
+        let row = rows(&[&["2019-01-01", "p1", "44054006"]]).remove(0);
+        let id = derived_uuid("conditions", &row);
+        assert_eq!(id, derived_uuid("conditions", &row));
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "8");
+        assert!(["8", "9", "a", "b"].contains(&&id[19..20]));
+        assert_ne!(id, derived_uuid("medications", &row));
+        // Cells are delimited, so moving a character between them changes the id.
+        let shifted = rows(&[&["2019-01-01p", "1", "44054006"]]).remove(0);
+        assert_ne!(id, derived_uuid("conditions", &shifted));
+    }
+}
@@ -1,8 +1,10 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use fastcrypto::ed25519::Ed25519PublicKey;
 use fastcrypto::encoding::{Encoding, Hex};
 use fastcrypto::serde_helpers::ToFromByteArray;
+use fastcrypto::traits::ToFromBytes;
 use seal_sdk::types::FetchKeyResponse;
 use seal_sdk::{EncryptedObject, IBEPublicKey};
 use serde::{Deserialize, Deserializer, Serialize};
@@ -78,6 +80,24 @@ where
     Ok(responses)
 }
 
+/// Custom deserializer for an optional hex string to EncryptedObject
+fn deserialize_optional_encrypted_object<'de, D>(
+    deserializer: D,
+) -> Result<Option<EncryptedObject>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(hex_string) => {
+            let bytes = Hex::decode(&hex_string).map_err(serde::de::Error::custom)?;
+            let object: EncryptedObject =
+                bcs::from_bytes(&bytes).map_err(serde::de::Error::custom)?;
+            Ok(Some(object))
+        }
+        None => Ok(None),
+    }
+}
+
 /// Configuration for Seal key servers
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(try_from = "SealConfigRaw")]
@@ -86,6 +106,8 @@ pub struct SealConfig {
     pub public_keys: Vec<IBEPublicKey>,
     pub package_id: Address,
     pub server_pk_map: HashMap<Address, IBEPublicKey>,
+    /// Hex encoded Ed25519 public keys whose signed grants authorize re-identification.
+    pub reidentification_authorities: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -96,6 +118,8 @@ struct SealConfigRaw {
     public_keys: Vec<IBEPublicKey>,
     #[serde(deserialize_with = "deserialize_object_id")]
     package_id: Address,
+    #[serde(default)]
+    reidentification_authorities: Vec<String>,
 }
 
 impl TryFrom<SealConfigRaw> for SealConfig {
@@ -110,6 +134,13 @@ impl TryFrom<SealConfigRaw> for SealConfig {
             ));
         }
 
+        for authority in &raw.reidentification_authorities {
+            Hex::decode(authority)
+                .ok()
+                .and_then(|bytes| Ed25519PublicKey::from_bytes(&bytes).ok())
+                .ok_or_else(|| format!("Invalid re-identification authority {authority}"))?;
+        }
+
         let server_pk_map: HashMap<Address, IBEPublicKey> = raw
             .key_servers
             .iter()
@@ -122,6 +153,7 @@ impl TryFrom<SealConfigRaw> for SealConfig {
             public_keys: raw.public_keys,
             package_id: raw.package_id,
             server_pk_map,
+            reidentification_authorities: raw.reidentification_authorities,
         })
     }
 }
@@ -177,3 +209,195 @@ pub struct ProvisionOpenRouterApiKeyRequest {
 pub struct ProvisionOpenRouterApiKeyResponse {
     pub status: String,
 }
+
+/// Request for provisioning the encrypted pseudonym key
+#[derive(Serialize, Deserialize)]
+pub struct ProvisionPseudonymKeyRequest {
+    #[serde(deserialize_with = "deserialize_encrypted_object")]
+    pub encrypted_object: EncryptedObject,
+    /// Keep the identifier behind each pseudonym issued so it can be re-identified on the host-only
+    /// server.
+    #[serde(default)]
+    pub enable_reidentification: bool,
+}
+
+/// Response for provisioning the pseudonym key
+#[derive(Serialize, Deserialize)]
+pub struct ProvisionPseudonymKeyResponse {
+    pub status: String,
+}
+
+/// Request for re-identifying a pseudonym, authorized by a grant of a re-identification authority.
+#[derive(Serialize, Deserialize)]
+pub struct ReidentifyRequest {
+    pub pseudonym: String,
+    /// `ReidentificationGrant` signed with scope ReidentificationGrant by a re-identification
+    /// authority of `seal_config.yaml`.
+    pub signed_grant: crate::common::ProcessedDataResponse<
+        crate::common::IntentMessage<super::reidentification::ReidentificationGrant>,
+    >,
+}
+
+/// Identifier a pseudonym was issued for, with the audit entry of the lookup and the signed head of
+/// the audit log after it
+#[derive(Serialize, Deserialize)]
+pub struct ReidentifyResponse {
+    pub pseudonym: String,
+    pub system: String,
+    pub value: String,
+    pub audit_entry: super::reidentification::ReidentificationAuditEntry,
+    pub audit_head: crate::common::ProcessedDataResponse<
+        crate::common::IntentMessage<super::reidentification::ReidentificationLogHead>,
+    >,
+}
+
+/// Every re-identification since startup and the signed head of the audit log
+#[derive(Serialize, Deserialize)]
+pub struct ReidentificationLogResponse {
+    pub entries: Vec<super::reidentification::ReidentificationAuditEntry>,
+    pub head: crate::common::ProcessedDataResponse<
+        crate::common::IntentMessage<super::reidentification::ReidentificationLogHead>,
+    >,
+}
+
+/// Request for registering a caller charged with the LLM usage of its conversions
+#[derive(Serialize, Deserialize)]
+pub struct RegisterCallerRequest {
+    pub caller_id: String,
+    pub app: String,
+    /// Usage after which the caller's conversions are refused, unlimited by default.
+    #[serde(default, flatten)]
+    pub budget: super::usage::UsageBudget,
+}
+
+/// Bearer token of a registered caller, only returned once
+#[derive(Serialize, Deserialize)]
+pub struct RegisterCallerResponse {
+    pub caller_id: String,
+    pub app: String,
+    pub token: String,
+}
+
+/// Request for setting the usage budget shared by the callers of an app
+#[derive(Serialize, Deserialize)]
+pub struct AppBudgetRequest {
+    pub app: String,
+    #[serde(default, flatten)]
+    pub budget: super::usage::UsageBudget,
+}
+
+/// Response for setting an app budget
+#[derive(Serialize, Deserialize)]
+pub struct AppBudgetResponse {
+    pub status: String,
+}
+
+/// A `FhirConversionAttestation` intent message and the enclave signature over it.
+pub type SignedFhirConversionAttestation = crate::common::ProcessedDataResponse<
+    crate::common::IntentMessage<super::FhirConversionAttestation>,
+>;
+
+/// Request for issuing an SD-JWT over a patient's FHIR bundle. Exactly one of `bundle` or
+/// `encrypted_bundle` must be set.
+#[derive(Serialize, Deserialize)]
+pub struct SdJwtRequest {
+    /// Plaintext FHIR bundle as returned by /process_data. Must come with its `attestation`.
+    #[serde(default)]
+    pub bundle: Option<serde_json::Value>,
+    /// The signed `FhirConversionAttestation` returned with `bundle` by /process_data. Its signature
+    /// is checked against the enclave key and its semantic hash against `bundle`.
+    #[serde(default)]
+    pub attestation: Option<SignedFhirConversionAttestation>,
+    /// Seal encrypted FHIR bundle, decrypted inside the enclave with the cached Seal keys.
+    #[serde(default, deserialize_with = "deserialize_optional_encrypted_object")]
+    pub encrypted_bundle: Option<EncryptedObject>,
+    /// FHIR paths made selectively disclosable, e.g. "Patient.birthDate" or "Condition.code".
+    pub disclosable_paths: Vec<String>,
+}
+
+/// Response for SD-JWT issuance
+#[derive(Serialize, Deserialize)]
+pub struct SdJwtResponse {
+    /// The SD-JWT with every disclosure appended, to be kept by the patient.
+    pub sd_jwt: String,
+    /// Semantic hash of the bundle the claims were taken from.
+    pub semantic_hash: String,
+    /// Each disclosable claim with its digest and encoded disclosure.
+    pub disclosures: Vec<crate::sd_jwt::Disclosure>,
+}
+
+/// Request for a predicate attestation over a patient's FHIR bundle. Exactly one of `bundle` or
+/// `encrypted_bundle` must be set.
+#[derive(Serialize, Deserialize)]
+pub struct PredicateRequest {
+    /// Plaintext FHIR bundle as returned by /process_data. Must come with its `attestation`.
+    #[serde(default)]
+    pub bundle: Option<serde_json::Value>,
+    /// The signed `FhirConversionAttestation` returned with `bundle` by /process_data. Its signature
+    /// is checked against the enclave key and its semantic hash against `bundle`.
+    #[serde(default)]
+    pub attestation: Option<SignedFhirConversionAttestation>,
+    /// Seal encrypted FHIR bundle, decrypted inside the enclave with the cached Seal keys.
+    #[serde(default, deserialize_with = "deserialize_optional_encrypted_object")]
+    pub encrypted_bundle: Option<EncryptedObject>,
+    /// The predicate to evaluate.
+    pub predicate: super::predicate::Predicate,
+}
+
+/// Request for /validate_fhir
+#[derive(Serialize, Deserialize)]
+pub struct FhirValidationRequest {
+    /// FHIR bundle, bare or in the `{"bundle": {...}}` envelope returned by /process_data.
+    pub bundle: serde_json::Value,
+}
+
+/// Request for /verify_provenance
+#[derive(Serialize, Deserialize)]
+pub struct ProvenanceVerificationRequest {
+    /// FHIR bundle, bare or in the `{"bundle": {...}}` envelope returned by /process_data.
+    pub bundle: serde_json::Value,
+    /// Raw data the bundle was converted from, which its source spans index in characters.
+    pub raw_data: String,
+}
+
+/// Request for /validate_bundle
+#[derive(Serialize, Deserialize)]
+pub struct ValidateBundleRequest {
+    /// Walrus blob holding the Seal encrypted FHIR bundle.
+    pub walrus_blob_id: String,
+    /// Reference of the patient the bundle belongs to. The bundle is not validated unless it holds
+    /// this Patient, see `bundle_has_patient`.
+    pub patient_id: String,
+    /// Number of resources the uploader claims the bundle holds.
+    pub resource_count: u64,
+    /// Resource types the uploader claims the bundle holds.
+    pub resource_types: Vec<String>,
+}
+
+/// Request for /verify_bundle
+#[derive(Serialize, Deserialize)]
+pub struct VerifyBundleRequest {
+    /// Walrus blob holding the Seal encrypted FHIR bundle.
+    pub walrus_blob_id: String,
+    /// Hex encoded semantic hash recorded when the bundle was validated.
+    pub semantic_hash: String,
+}
+
+/// Request for /validate_claim. Exactly one of `bundle` or `encrypted_bundle` must be set.
+#[derive(Serialize, Deserialize)]
+pub struct ValidateClaimRequest {
+    pub claim_id: String,
+    pub policy_id: String,
+    /// Plaintext FHIR bundle as returned by /process_data. Must come with its `attestation`.
+    #[serde(default)]
+    pub bundle: Option<serde_json::Value>,
+    /// The signed `FhirConversionAttestation` returned with `bundle` by /process_data. Its signature
+    /// is checked against the enclave key and its semantic hash against `bundle`.
+    #[serde(default)]
+    pub attestation: Option<SignedFhirConversionAttestation>,
+    /// Seal encrypted FHIR bundle, decrypted inside the enclave with the cached Seal keys.
+    #[serde(default, deserialize_with = "deserialize_optional_encrypted_object")]
+    pub encrypted_bundle: Option<EncryptedObject>,
+    /// Codes billed on the claim.
+    pub codes: Vec<super::validation::ClaimCode>,
+}
@@ -0,0 +1,816 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Token and cost accounting of the LLM calls. Each completion reports the tokens it used, which a
+// `UsageMeter` adds up over the attempts and chunks of one conversion. The `UsageLedger` then
+// charges the total to the caller of the conversion and to its app, and the enclave signs a
+// `UsageReceipt` the caller can be billed with. Before a conversion calls the LLM, an estimate of
+// its usage is reserved against the budgets of the caller and its app, so that conversions running
+// at the same time cannot together spend past a budget each of them was checked against. The
+// `Reservation` is settled when the conversion ends, or when it is dropped if the conversion is
+// cancelled.
+//
+// Callers are registered by the host with /admin/register_caller, which returns the bearer token
+// they authenticate with. Requests without a token are charged to the anonymous caller, unless
+// `require_caller_token` is set in `llm_config.yaml`.
+
+use super::llm::LlmUsage;
+use super::FhirConversionResponse;
+use crate::EnclaveError;
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::hash::{HashFunction, Sha3_256};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+
+/// Caller and app of requests without a bearer token.
+pub const ANONYMOUS_CALLER: &str = "anonymous";
+
+/// Tokens and cost of LLM calls.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub llm_calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Cost in millionths of a US dollar.
+    pub cost_micro_usd: u64,
+}
+
+impl TokenUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens.saturating_add(self.completion_tokens)
+    }
+
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.llm_calls = self.llm_calls.saturating_add(other.llm_calls);
+        self.prompt_tokens = self.prompt_tokens.saturating_add(other.prompt_tokens);
+        self.completion_tokens = self
+            .completion_tokens
+            .saturating_add(other.completion_tokens);
+        self.cost_micro_usd = self.cost_micro_usd.saturating_add(other.cost_micro_usd);
+    }
+
+    pub fn sub(&mut self, other: &TokenUsage) {
+        self.llm_calls = self.llm_calls.saturating_sub(other.llm_calls);
+        self.prompt_tokens = self.prompt_tokens.saturating_sub(other.prompt_tokens);
+        self.completion_tokens = self
+            .completion_tokens
+            .saturating_sub(other.completion_tokens);
+        self.cost_micro_usd = self.cost_micro_usd.saturating_sub(other.cost_micro_usd);
+    }
+}
+
+/// Prices of the model in USD per million tokens, that is in micro-USD per token.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TokenPricing {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+impl TokenPricing {
+    /// Usage of one call, at the cost reported by the provider or else at these prices. A call
+    /// whose usage was not reported is counted without tokens.
+    pub fn price(&self, usage: Option<&LlmUsage>) -> TokenUsage {
+        let Some(usage) = usage else {
+            return TokenUsage {
+                llm_calls: 1,
+                ..TokenUsage::default()
+            };
+        };
+        let cost_micro_usd = usage.cost_usd.map(|cost| cost * 1e6).unwrap_or_else(|| {
+            usage.prompt_tokens as f64 * self.prompt_per_million
+                + usage.completion_tokens as f64 * self.completion_per_million
+        });
+        TokenUsage {
+            llm_calls: 1,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost_micro_usd: cost_micro_usd.max(0.0).round() as u64,
+        }
+    }
+
+    /// Usage reserved for a conversion of `input_chars` characters before it runs: one call with
+    /// a prompt of a token per four characters, and a completion of `max_tokens`.
+    pub fn estimate(&self, input_chars: usize, max_tokens: u32) -> TokenUsage {
+        self.price(Some(&LlmUsage {
+            prompt_tokens: (input_chars as u64).div_ceil(4),
+            completion_tokens: max_tokens.into(),
+            cost_usd: None,
+        }))
+    }
+}
+
+/// Usage of one conversion, shared by the services converting its chunks.
+#[derive(Debug, Default)]
+pub struct UsageMeter(Mutex<TokenUsage>);
+
+impl UsageMeter {
+    pub fn record(&self, usage: &TokenUsage) {
+        self.0.lock().expect("usage meter lock").add(usage);
+    }
+
+    pub fn total(&self) -> TokenUsage {
+        *self.0.lock().expect("usage meter lock")
+    }
+}
+
+/// Who a conversion is charged to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Caller {
+    pub caller_id: String,
+    pub app: String,
+}
+
+impl Caller {
+    pub fn anonymous() -> Self {
+        Self {
+            caller_id: ANONYMOUS_CALLER.to_string(),
+            app: ANONYMOUS_CALLER.to_string(),
+        }
+    }
+}
+
+/// Limits on the usage of a caller or an app, none when unset. A conversion is refused once the
+/// usage spent and reserved by running conversions reaches a limit; the one reaching it may go
+/// past it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageBudget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cost_micro_usd: Option<u64>,
+}
+
+impl UsageBudget {
+    fn check(&self, owner: &str, spent: &TokenUsage) -> Result<(), EnclaveError> {
+        if let Some(max_tokens) = self.max_tokens {
+            if spent.total_tokens() >= max_tokens {
+                return Err(EnclaveError::PaymentRequiredError(format!(
+                    "{owner} has used {} of its {max_tokens} tokens",
+                    spent.total_tokens()
+                )));
+            }
+        }
+        if let Some(max_cost) = self.max_cost_micro_usd {
+            if spent.cost_micro_usd >= max_cost {
+                return Err(EnclaveError::PaymentRequiredError(format!(
+                    "{owner} has spent {} of its {max_cost} micro-USD",
+                    spent.cost_micro_usd
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Conversions and LLM usage charged to a caller or an app.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub conversions: u64,
+    /// Conversions that failed, whose calls are charged all the same.
+    pub failed_conversions: u64,
+    #[serde(flatten)]
+    pub usage: TokenUsage,
+}
+
+impl UsageTotals {
+    fn record(&mut self, usage: &TokenUsage, succeeded: bool) {
+        if succeeded {
+            self.conversions += 1;
+        } else {
+            self.failed_conversions += 1;
+        }
+        self.usage.add(usage);
+    }
+}
+
+/// Usage of a caller, as reported by /admin/usage.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallerUsage {
+    pub app: String,
+    pub budget: UsageBudget,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+/// Usage of an app, across its callers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppUsage {
+    pub budget: UsageBudget,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+/// Usage per caller, per app and overall.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub callers: BTreeMap<String, CallerUsage>,
+    pub apps: BTreeMap<String, AppUsage>,
+    pub total: UsageTotals,
+}
+
+/// Registered callers and the usage charged to every caller and app since startup.
+#[derive(Debug, Default)]
+pub struct UsageLedger {
+    /// Caller of each token, by the SHA3-256 of the token.
+    tokens: HashMap<[u8; 32], String>,
+    callers: BTreeMap<String, CallerUsage>,
+    apps: BTreeMap<String, AppUsage>,
+    /// Usage reserved by the running conversions of each caller and app.
+    reserved_by_caller: HashMap<String, TokenUsage>,
+    reserved_by_app: HashMap<String, TokenUsage>,
+}
+
+impl UsageLedger {
+    /// Register `caller_id` under `app` with `budget` and return its new bearer token. Registering
+    /// a caller again moves it to `app`, replaces its budget and revokes its previous token, its
+    /// past usage is kept.
+    pub fn register_caller(
+        &mut self,
+        caller_id: &str,
+        app: &str,
+        budget: UsageBudget,
+    ) -> Result<String, EnclaveError> {
+        if caller_id.is_empty() || app.is_empty() {
+            return Err(EnclaveError::GenericError(
+                "caller_id and app must not be empty".to_string(),
+            ));
+        }
+        if caller_id == ANONYMOUS_CALLER {
+            return Err(EnclaveError::GenericError(format!(
+                "{ANONYMOUS_CALLER} is reserved for requests without a token"
+            )));
+        }
+        let mut token = [0u8; 32];
+        thread_rng().fill_bytes(&mut token);
+        let token = Hex::encode(token);
+
+        self.tokens.retain(|_, id| id != caller_id);
+        self.tokens
+            .insert(token_hash(&token), caller_id.to_string());
+        let account = self.callers.entry(caller_id.to_string()).or_default();
+        account.app = app.to_string();
+        account.budget = budget;
+        self.apps.entry(app.to_string()).or_default();
+        Ok(token)
+    }
+
+    /// Set the budget of `app`, shared by all its callers.
+    pub fn set_app_budget(&mut self, app: &str, budget: UsageBudget) {
+        self.apps.entry(app.to_string()).or_default().budget = budget;
+    }
+
+    /// Caller of the `Authorization: Bearer` token of a request, the anonymous caller without one
+    /// unless `require_token`.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        require_token: bool,
+    ) -> Result<Caller, EnclaveError> {
+        let Some(authorization) = headers.get(AUTHORIZATION) else {
+            if require_token {
+                return Err(EnclaveError::UnauthorizedError(
+                    "A caller token is required".to_string(),
+                ));
+            }
+            return Ok(Caller::anonymous());
+        };
+        let token = authorization
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| {
+                EnclaveError::UnauthorizedError("Authorization must be a bearer token".to_string())
+            })?;
+        let caller_id = self
+            .tokens
+            .get(&token_hash(token.trim()))
+            .ok_or_else(|| EnclaveError::UnauthorizedError("Unknown caller token".to_string()))?;
+        Ok(Caller {
+            caller_id: caller_id.clone(),
+            app: self.callers[caller_id].app.clone(),
+        })
+    }
+
+    /// Reserve `estimate` for a conversion of `caller`, refused once the budget of the caller or of
+    /// its app is used up by what was spent and what running conversions reserved. The reservation
+    /// is released by `settle`.
+    pub fn reserve(&mut self, caller: &Caller, estimate: &TokenUsage) -> Result<(), EnclaveError> {
+        if let Some(account) = self.callers.get(&caller.caller_id) {
+            let owner = format!("Caller {}", caller.caller_id);
+            let mut spent = account.totals.usage;
+            spent.add(&reserved(&self.reserved_by_caller, &caller.caller_id));
+            account.budget.check(&owner, &spent)?;
+        }
+        if let Some(account) = self.apps.get(&caller.app) {
+            let owner = format!("App {}", caller.app);
+            let mut spent = account.totals.usage;
+            spent.add(&reserved(&self.reserved_by_app, &caller.app));
+            account.budget.check(&owner, &spent)?;
+        }
+        for (reservations, owner) in [
+            (&mut self.reserved_by_caller, &caller.caller_id),
+            (&mut self.reserved_by_app, &caller.app),
+        ] {
+            reservations.entry(owner.clone()).or_default().add(estimate);
+        }
+        Ok(())
+    }
+
+    /// Release the `estimate` reserved for a conversion of `caller` and charge it its actual usage.
+    pub fn settle(
+        &mut self,
+        caller: &Caller,
+        estimate: &TokenUsage,
+        usage: &TokenUsage,
+        succeeded: bool,
+    ) {
+        for (reservations, owner) in [
+            (&mut self.reserved_by_caller, &caller.caller_id),
+            (&mut self.reserved_by_app, &caller.app),
+        ] {
+            if let Some(reserved) = reservations.get_mut(owner) {
+                reserved.sub(estimate);
+                if *reserved == TokenUsage::default() {
+                    reservations.remove(owner);
+                }
+            }
+        }
+        self.record(caller, usage, succeeded);
+    }
+
+    /// Charge the usage of a conversion to `caller` and its app.
+    pub fn record(&mut self, caller: &Caller, usage: &TokenUsage, succeeded: bool) {
+        let account = self.callers.entry(caller.caller_id.clone()).or_default();
+        account.app = caller.app.clone();
+        account.totals.record(usage, succeeded);
+        self.apps
+            .entry(caller.app.clone())
+            .or_default()
+            .totals
+            .record(usage, succeeded);
+    }
+
+    pub fn report(&self) -> UsageReport {
+        let mut total = UsageTotals::default();
+        for account in self.callers.values() {
+            total.conversions += account.totals.conversions;
+            total.failed_conversions += account.totals.failed_conversions;
+            total.usage.add(&account.totals.usage);
+        }
+        UsageReport {
+            callers: self.callers.clone(),
+            apps: self.apps.clone(),
+            total,
+        }
+    }
+}
+
+/// Usage reserved in a ledger for a running conversion of `caller`, whose calls are metered by
+/// `usage`. Dropped without `settle`, when the conversion was cancelled, it charges the usage
+/// metered so far as a failed conversion, so that the reservation is never left behind.
+pub struct Reservation {
+    ledger: Arc<RwLock<UsageLedger>>,
+    caller: Caller,
+    estimate: TokenUsage,
+    usage: Arc<UsageMeter>,
+    settled: bool,
+}
+
+impl Reservation {
+    /// Reserve `estimate` in `ledger`, see `UsageLedger::reserve`.
+    pub async fn new(
+        ledger: &Arc<RwLock<UsageLedger>>,
+        caller: &Caller,
+        estimate: TokenUsage,
+        usage: &Arc<UsageMeter>,
+    ) -> Result<Self, EnclaveError> {
+        ledger.write().await.reserve(caller, &estimate)?;
+        Ok(Self {
+            ledger: ledger.clone(),
+            caller: caller.clone(),
+            estimate,
+            usage: usage.clone(),
+            settled: false,
+        })
+    }
+
+    /// Release the reservation and charge the metered usage, see `UsageLedger::settle`.
+    pub async fn settle(mut self, succeeded: bool) {
+        let ledger = self.ledger.clone();
+        let mut ledger = ledger.write().await;
+        ledger.settle(&self.caller, &self.estimate, &self.usage.total(), succeeded);
+        self.settled = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        let (caller, estimate, usage) = (self.caller.clone(), self.estimate, self.usage.total());
+        if let Ok(mut ledger) = self.ledger.try_write() {
+            ledger.settle(&caller, &estimate, &usage, false);
+            return;
+        }
+        // The ledger is in use, settle once it is released.
+        let ledger = self.ledger.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    ledger
+                        .write()
+                        .await
+                        .settle(&caller, &estimate, &usage, false);
+                });
+            }
+            Err(_) => tracing::warn!(
+                "Usage reservation of {} dropped without a runtime, not released",
+                caller.caller_id
+            ),
+        }
+    }
+}
+
+fn reserved(reservations: &HashMap<String, TokenUsage>, owner: &str) -> TokenUsage {
+    reservations.get(owner).copied().unwrap_or_default()
+}
+
+fn token_hash(token: &str) -> [u8; 32] {
+    Sha3_256::digest(token.as_bytes()).digest
+}
+
+/// Inner type T for IntentMessage<T>: the LLM usage of one conversion and who it is charged to,
+/// for billing the caller. The conversion is identified by the semantic hash of its bundle and the
+/// hash of its input, and its calls are priced in micro-USD. A conversion served from the cache
+/// made no call. The BCS layout matches `UsageReceipt` in the Move `validator` module, verified by
+/// `record_usage_receipt`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UsageReceipt {
+    pub caller_id: String,
+    pub app: String,
+    pub semantic_hash: Vec<u8>,
+    pub input_hash: Vec<u8>,
+    pub model: String,
+    pub llm_calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_micro_usd: u64,
+}
+
+impl UsageReceipt {
+    pub fn new(caller: &Caller, response: &FhirConversionResponse) -> Result<Self, EnclaveError> {
+        let semantic_hash = Hex::decode(&response.semantic_hash)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid semantic hash: {e}")))?;
+        let metadata = &response.metadata;
+        let input_hash = Hex::decode(&metadata.input_hash)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid input hash: {e}")))?;
+        Ok(Self {
+            caller_id: caller.caller_id.clone(),
+            app: caller.app.clone(),
+            semantic_hash,
+            input_hash,
+            model: metadata.model.clone().unwrap_or_default(),
+            llm_calls: metadata.usage.llm_calls,
+            prompt_tokens: metadata.usage.prompt_tokens,
+            completion_tokens: metadata.usage.completion_tokens,
+            cost_micro_usd: metadata.usage.cost_micro_usd,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::{ConversionMetadata, IntentScope};
+    use crate::common::IntentMessage;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64, cost_micro_usd: u64) -> TokenUsage {
+        TokenUsage {
+            llm_calls: 1,
+            prompt_tokens,
+            completion_tokens,
+            cost_micro_usd,
+        }
+    }
+
+    #[test]
+    fn test_token_pricing() {
+        let pricing = TokenPricing {
+            prompt_per_million: 2.0,
+            completion_per_million: 8.0,
+        };
+        let reported = |cost_usd| LlmUsage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            cost_usd,
+        };
+        assert_eq!(pricing.price(Some(&reported(None))).cost_micro_usd, 60);
+        // A cost reported by the provider is used as is, rounded to the micro-USD.
+        assert_eq!(
+            pricing.price(Some(&reported(Some(0.00042)))).cost_micro_usd,
+            420
+        );
+        assert_eq!(
+            pricing
+                .price(Some(&reported(Some(0.0000004))))
+                .cost_micro_usd,
+            0
+        );
+        assert_eq!(pricing.price(Some(&reported(Some(-1.0)))).cost_micro_usd, 0);
+        // A call without reported usage is still a call.
+        assert_eq!(
+            pricing.price(None),
+            TokenUsage {
+                llm_calls: 1,
+                ..TokenUsage::default()
+            }
+        );
+
+        // A token per four characters of input, rounded up, and the whole completion.
+        assert_eq!(pricing.estimate(9, 100), usage(3, 100, 806));
+        assert_eq!(pricing.estimate(0, 0), usage(0, 0, 0));
+        let fractional = TokenPricing {
+            prompt_per_million: 0.15,
+            completion_per_million: 0.6,
+        };
+        assert_eq!(fractional.estimate(4000, 1000).cost_micro_usd, 750);
+
+        let mut total = usage(u64::MAX, 1, 5);
+        total.add(&usage(1, 1, 5));
+        assert_eq!(total.prompt_tokens, u64::MAX);
+        assert_eq!(total.total_tokens(), u64::MAX);
+        total.sub(&usage(1, 5, 5));
+        assert_eq!(total, usage(u64::MAX - 1, 0, 5));
+    }
+
+    #[test]
+    fn test_authentication() {
+        let mut ledger = UsageLedger::default();
+        let budget = UsageBudget::default();
+        assert!(ledger
+            .register_caller(ANONYMOUS_CALLER, "app", budget)
+            .is_err());
+        assert!(ledger.register_caller("", "app", budget).is_err());
+        assert!(ledger.register_caller("clinic-1", "", budget).is_err());
+
+        // Registering again revokes the previous token.
+        let revoked = ledger.register_caller("clinic-1", "app", budget).unwrap();
+        let token = ledger
+            .register_caller("clinic-1", "other-app", budget)
+            .unwrap();
+        let clinic = ledger.authenticate(&bearer(&token), true).unwrap();
+        assert_eq!(clinic.caller_id, "clinic-1");
+        assert_eq!(clinic.app, "other-app");
+        assert!(matches!(
+            ledger.authenticate(&bearer(&revoked), false),
+            Err(EnclaveError::UnauthorizedError(_))
+        ));
+        assert_eq!(
+            ledger
+                .authenticate(&bearer(&format!(" {token} ")), true)
+                .unwrap(),
+            clinic
+        );
+
+        // A missing token is the anonymous caller unless one is required, a malformed one never is.
+        assert_eq!(
+            ledger.authenticate(&HeaderMap::new(), false).unwrap(),
+            Caller::anonymous()
+        );
+        assert!(ledger.authenticate(&HeaderMap::new(), true).is_err());
+        let mut basic = HeaderMap::new();
+        basic.insert(AUTHORIZATION, format!("Basic {token}").parse().unwrap());
+        assert!(ledger.authenticate(&basic, false).is_err());
+    }
+
+    #[test]
+    fn test_budget_reservation() {
+        let mut ledger = UsageLedger::default();
+        let token = ledger
+            .register_caller(
+                "clinic-1",
+                "app",
+                UsageBudget {
+                    max_tokens: Some(100),
+                    max_cost_micro_usd: None,
+                },
+            )
+            .unwrap();
+        let clinic = ledger.authenticate(&bearer(&token), true).unwrap();
+        let estimate = usage(40, 20, 0);
+
+        // Conversions running at the same time are refused once their reservations reach the
+        // budget, before anything was spent.
+        ledger.reserve(&clinic, &estimate).unwrap();
+        ledger.reserve(&clinic, &estimate).unwrap();
+        assert!(matches!(
+            ledger.reserve(&clinic, &estimate),
+            Err(EnclaveError::PaymentRequiredError(_))
+        ));
+
+        // Settling charges the actual usage in place of the estimate.
+        ledger.settle(&clinic, &estimate, &usage(10, 5, 0), true);
+        ledger.reserve(&clinic, &estimate).unwrap();
+        ledger.settle(&clinic, &estimate, &usage(10, 5, 0), false);
+        ledger.settle(&clinic, &estimate, &usage(50, 19, 0), true);
+        assert_eq!(
+            ledger.report().callers["clinic-1"]
+                .totals
+                .usage
+                .total_tokens(),
+            99
+        );
+        assert!(ledger.reserved_by_caller.is_empty() && ledger.reserved_by_app.is_empty());
+
+        // The limit is reached at exactly its value, and the conversion reaching it may pass it.
+        ledger.reserve(&clinic, &TokenUsage::default()).unwrap();
+        ledger.settle(&clinic, &TokenUsage::default(), &usage(1, 1, 0), true);
+        assert!(ledger.reserve(&clinic, &TokenUsage::default()).is_err());
+
+        // The app budget is shared by its callers.
+        let other = ledger
+            .register_caller("clinic-2", "app", UsageBudget::default())
+            .unwrap();
+        let other = ledger.authenticate(&bearer(&other), false).unwrap();
+        ledger.set_app_budget(
+            "app",
+            UsageBudget {
+                max_tokens: None,
+                max_cost_micro_usd: Some(1000),
+            },
+        );
+        ledger.reserve(&other, &usage(0, 0, 600)).unwrap();
+        ledger.reserve(&other, &usage(0, 0, 600)).unwrap();
+        assert!(matches!(
+            ledger.reserve(&other, &TokenUsage::default()),
+            Err(EnclaveError::PaymentRequiredError(message)) if message.starts_with("App app")
+        ));
+        // Callers and apps without an account have no budget.
+        ledger.reserve(&Caller::anonymous(), &estimate).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_reservation() {
+        let ledger = Arc::new(RwLock::new(UsageLedger::default()));
+        let token = ledger
+            .write()
+            .await
+            .register_caller(
+                "clinic-1",
+                "app",
+                UsageBudget {
+                    max_tokens: Some(100),
+                    max_cost_micro_usd: None,
+                },
+            )
+            .unwrap();
+        let clinic = ledger
+            .read()
+            .await
+            .authenticate(&bearer(&token), true)
+            .unwrap();
+
+        // A conversion cancelled after its first call, as when the client disconnects.
+        let meter = Arc::new(UsageMeter::default());
+        let conversion = async {
+            let reservation = Reservation::new(&ledger, &clinic, usage(40, 59, 0), &meter).await?;
+            meter.record(&usage(10, 5, 0));
+            std::future::pending::<()>().await;
+            reservation.settle(true).await;
+            Ok::<_, EnclaveError>(())
+        };
+        tokio::select! {
+            biased;
+            _ = conversion => unreachable!(),
+            _ = tokio::task::yield_now() => {}
+        }
+        {
+            let ledger = ledger.read().await;
+            assert!(ledger.reserved_by_caller.is_empty() && ledger.reserved_by_app.is_empty());
+            let totals = ledger.report().callers["clinic-1"].totals;
+            assert_eq!(totals.failed_conversions, 1);
+            assert_eq!(totals.usage.total_tokens(), 15);
+        }
+
+        // Dropped while the ledger is in use, it is settled once the ledger is released.
+        let reservation = Reservation::new(&ledger, &clinic, usage(40, 59, 0), &meter)
+            .await
+            .unwrap();
+        let in_use = ledger.write().await;
+        drop(reservation);
+        assert!(!in_use.reserved_by_caller.is_empty());
+        drop(in_use);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let ledger = ledger.read().await;
+        assert!(ledger.reserved_by_caller.is_empty() && ledger.reserved_by_app.is_empty());
+        assert_eq!(
+            ledger.report().callers["clinic-1"]
+                .totals
+                .failed_conversions,
+            2
+        );
+    }
+
+    #[test]
+    fn test_usage_report() {
+        let mut ledger = UsageLedger::default();
+        let token = ledger
+            .register_caller(
+                "clinic-1",
+                "app",
+                UsageBudget {
+                    max_tokens: Some(100),
+                    max_cost_micro_usd: None,
+                },
+            )
+            .unwrap();
+        let clinic = ledger.authenticate(&bearer(&token), true).unwrap();
+        ledger.record(&clinic, &usage(90, 20, 700), true);
+        ledger.record(&clinic, &usage(90, 20, 700), false);
+        ledger.record(&Caller::anonymous(), &TokenUsage::default(), true);
+
+        let report = ledger.report();
+        assert_eq!(report.callers["clinic-1"].totals.conversions, 1);
+        assert_eq!(report.callers["clinic-1"].totals.failed_conversions, 1);
+        assert_eq!(report.apps["app"].totals.usage.cost_micro_usd, 1400);
+        assert_eq!(report.apps[ANONYMOUS_CALLER].totals.conversions, 1);
+        assert_eq!(report.total.conversions, 2);
+        assert_eq!(report.total.failed_conversions, 1);
+        assert_eq!(report.total.usage.total_tokens(), 220);
+        let report_json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            report_json["callers"]["clinic-1"]["budget"]["max_tokens"],
+            100
+        );
+        assert!(report_json["apps"]["app"]["budget"]
+            .get("max_tokens")
+            .is_none());
+        assert_eq!(report_json["apps"]["app"]["prompt_tokens"], 180);
+    }
+
+    #[test]
+    fn test_usage_receipt_serde() {
+        // test result should be consistent with test_usage_receipt_serde in
+        // `move/medical-vault/sources/validator.move`.
+        let clinic = Caller {
+            caller_id: "clinic-1".to_string(),
+            app: "app".to_string(),
+        };
+        let mut response = FhirConversionResponse {
+            bundle: serde_json::json!({ "resourceType": "Bundle", "entry": [] }),
+            semantic_hash: "cd".repeat(32),
+            resource_root: "ef".repeat(32),
+            resources_created: vec!["Patient".to_string()],
+            created_at: 1744038900000,
+            deidentified: Vec::new(),
+            metadata: ConversionMetadata {
+                converter: "llm".to_string(),
+                model: Some("mock:mock".to_string()),
+                prompt_hash: Some("34".repeat(32)),
+                temperature: Some(0.1),
+                input_hash: "12".repeat(32),
+                processing_time_ms: 10,
+                usage: TokenUsage {
+                    llm_calls: 2,
+                    prompt_tokens: 1000,
+                    completion_tokens: 200,
+                    cost_micro_usd: 4200,
+                },
+                warnings: Vec::new(),
+            },
+        };
+        let intent_msg = IntentMessage::new(
+            UsageReceipt::new(&clinic, &response).unwrap(),
+            1744038900000,
+            IntentScope::UsageReceipt as u8,
+        );
+        let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
+        assert_eq!(
+            Hex::encode(signing_payload),
+            "6a20b1d1109601000008636c696e69632d310361707020cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd201212121212121212121212121212121212121212121212121212121212121212096d6f636b3a6d6f636b0200000000000000e803000000000000c8000000000000006810000000000000"
+        );
+
+        // A rule-based conversion made no call and has no model.
+        response.metadata.model = None;
+        response.metadata.usage = TokenUsage::default();
+        let receipt = UsageReceipt::new(&clinic, &response).unwrap();
+        assert_eq!(receipt.model, "");
+        assert_eq!(receipt.llm_calls, 0);
+
+        response.semantic_hash = "not hex".to_string();
+        assert!(UsageReceipt::new(&clinic, &response).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Bundle validation, bundle verification and insurance claim validation. Each result is signed with
+// its own intent scope and its BCS layout matches the structs of the Move `validator` module.
+
+use super::predicate::{has_coding, CodeSystem};
+use super::pseudonym::PATIENT_ID_SYSTEM;
+use super::*;
+use crate::common::{to_signed_response, ProcessedDataResponse};
+use fastcrypto::traits::KeyPair;
+
+/// Walrus aggregator the enclave fetches bundles from, see allowed_endpoints.yaml.
+const WALRUS_AGGREGATOR_URL: &str = "https://aggregator.walrus-testnet.walrus.space";
+
+/// Maximum number of codes accepted in a single claim.
+const MAX_CLAIM_CODES: usize = 64;
+
+/// Inner type T for IntentMessage<T> with scope ValidateBundle. BCS layout matches
+/// `validator::BundleValidation`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleValidation {
+    pub walrus_blob_id: String,
+    pub semantic_hash: Vec<u8>,
+    pub patient_id: String,
+    pub resource_count: u64,
+    pub resource_types: Vec<String>,
+    pub validated: bool,
+    pub validated_at: u64,
+}
+
+/// Inner type T for IntentMessage<T> with scope VerifyBundle. BCS layout matches
+/// `validator::BundleVerification`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleVerification {
+    pub walrus_blob_id: String,
+    pub semantic_hash: Vec<u8>,
+    pub verified: bool,
+    pub verified_at: u64,
+}
+
+/// Inner type T for IntentMessage<T> with scope ValidateClaim. BCS layout matches
+/// `validator::ClaimValidation`. Unsupported codes are given as `system|code`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClaimValidation {
+    pub claim_id: String,
+    pub policy_id: String,
+    pub semantic_hash: Vec<u8>,
+    pub validated: bool,
+    pub unsupported_codes: Vec<String>,
+    pub validated_at: u64,
+}
+
+/// A diagnosis, procedure or medication code billed on a claim.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClaimCode {
+    pub system: CodeSystem,
+    pub code: String,
+}
+
+/// Fetch a Seal encrypted bundle from Walrus and validate it against the BTP Medical Vault Profile,
+/// the patient and the resource count and types claimed by the uploader.
+pub async fn validate_bundle(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ValidateBundleRequest>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<BundleValidation>>>, EnclaveError> {
+    let current_timestamp = current_timestamp_ms()?;
+    let bundle = fetch_walrus_bundle(&request.walrus_blob_id).await?;
+    let semantic_hash = semantic_hash_bytes(&bundle)?;
+
+    let resources = bundle_resources(&bundle);
+    let mut resource_types = Vec::new();
+    for resource_type in resources
+        .iter()
+        .filter_map(|r| r.get("resourceType").and_then(|t| t.as_str()))
+    {
+        if !resource_types.iter().any(|t| t == resource_type) {
+            resource_types.push(resource_type.to_string());
+        }
+    }
+
+    let mut claimed_types = request.resource_types.clone();
+    claimed_types.sort();
+    let mut found_types = resource_types.clone();
+    found_types.sort();
+    let patient_matches = {
+        let key_guard = PSEUDONYM_KEY.read().await;
+        bundle_has_patient(&bundle, &request.patient_id, key_guard.as_deref())
+    };
+    let validated = !validate_profile(&bundle).has_errors()
+        && patient_matches
+        && resources.len() as u64 == request.resource_count
+        && claimed_types == found_types;
+
+    info!("Bundle validation complete: validated={validated}");
+
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        BundleValidation {
+            walrus_blob_id: request.walrus_blob_id,
+            semantic_hash,
+            patient_id: request.patient_id,
+            resource_count: resources.len() as u64,
+            resource_types,
+            validated,
+            validated_at: current_timestamp,
+        },
+        current_timestamp,
+        IntentScope::ValidateBundle as u8,
+    )))
+}
+
+/// Fetch a Seal encrypted bundle from Walrus and check that it still hashes to the semantic hash
+/// recorded when it was validated.
+pub async fn verify_bundle(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<VerifyBundleRequest>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<BundleVerification>>>, EnclaveError> {
+    let current_timestamp = current_timestamp_ms()?;
+    let expected = Hex::decode(&request.semantic_hash)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid semantic hash: {e}")))?;
+    let bundle = fetch_walrus_bundle(&request.walrus_blob_id).await?;
+    let verified = semantic_hash_bytes(&bundle)? == expected;
+
+    info!("Bundle verification complete: verified={verified}");
+
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        BundleVerification {
+            walrus_blob_id: request.walrus_blob_id,
+            semantic_hash: expected,
+            verified,
+            verified_at: current_timestamp,
+        },
+        current_timestamp,
+        IntentScope::VerifyBundle as u8,
+    )))
+}
+
+/// Check that every code billed on a claim is supported by a resource of the patient's bundle.
+pub async fn validate_claim(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ValidateClaimRequest>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<ClaimValidation>>>, EnclaveError> {
+    if request.codes.is_empty() {
+        return Err(EnclaveError::GenericError(
+            "Claim must contain at least one code".to_string(),
+        ));
+    }
+    if request.codes.len() > MAX_CLAIM_CODES {
+        return Err(EnclaveError::GenericError(format!(
+            "At most {MAX_CLAIM_CODES} claim codes are allowed"
+        )));
+    }
+
+    let current_timestamp = current_timestamp_ms()?;
+    let (bundle, semantic_hash) = resolve_bundle(
+        state.eph_kp.public(),
+        request.bundle,
+        request.attestation,
+        request.encrypted_bundle,
+    )
+    .await?;
+
+    let unsupported_codes = unsupported_claim_codes(&bundle, &request.codes);
+    let validated = unsupported_codes.is_empty();
+
+    info!("Claim validation complete: validated={validated}");
+
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        ClaimValidation {
+            claim_id: request.claim_id,
+            policy_id: request.policy_id,
+            semantic_hash: Hex::decode(&semantic_hash)
+                .map_err(|e| EnclaveError::GenericError(format!("Invalid semantic hash: {e}")))?,
+            validated,
+            unsupported_codes,
+            validated_at: current_timestamp,
+        },
+        current_timestamp,
+        IntentScope::ValidateClaim as u8,
+    )))
+}
+
+/// Whether `patient_id` refers to a Patient of the bundle: its id, with or without the `Patient/`
+/// prefix, the value of one of its identifiers, or with `pseudonym_key` the identifier the enclave
+/// pseudonymized `patient_id` of the patient context to.
+pub fn bundle_has_patient(
+    bundle: &serde_json::Value,
+    patient_id: &str,
+    pseudonym_key: Option<&[u8]>,
+) -> bool {
+    let patient_id = patient_id.trim();
+    if patient_id.is_empty() {
+        return false;
+    }
+    let pseudonym = pseudonym_key
+        .and_then(|key| Pseudonymizer::new(key, None).ok())
+        .map(|mut p| p.pseudonym(PATIENT_ID_SYSTEM, patient_id));
+    bundle_resources(bundle)
+        .iter()
+        .filter(|r| r.get("resourceType").and_then(|t| t.as_str()) == Some("Patient"))
+        .any(|patient| {
+            let id = patient.get("id").and_then(|id| id.as_str());
+            id.is_some_and(|id| id == patient_id.strip_prefix("Patient/").unwrap_or(patient_id))
+                || patient
+                    .get("identifier")
+                    .and_then(|i| i.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|i| i.get("value").and_then(|v| v.as_str()))
+                    .any(|value| value == patient_id || Some(value) == pseudonym.as_deref())
+        })
+}
+
+/// Codes of the claim that no resource of the bundle carries, as `system|code`.
+pub fn unsupported_claim_codes(bundle: &serde_json::Value, codes: &[ClaimCode]) -> Vec<String> {
+    let resources = bundle_resources(bundle);
+    codes
+        .iter()
+        .filter(|c| {
+            !resources
+                .iter()
+                .any(|r| has_coding(r, c.system.uri(), &c.code))
+        })
+        .map(|c| format!("{}|{}", c.system.uri(), c.code))
+        .collect()
+}
+
+fn semantic_hash_bytes(bundle: &serde_json::Value) -> Result<Vec<u8>, EnclaveError> {
+    let semantic_hash = compute_semantic_hash(bundle)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to compute semantic hash: {e}")))?;
+    Hex::decode(&semantic_hash)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid semantic hash: {e}")))
+}
+
+/// Fetch a blob holding a BCS encoded Seal `EncryptedObject` from Walrus and decrypt it.
+async fn fetch_walrus_bundle(blob_id: &str) -> Result<serde_json::Value, EnclaveError> {
+    if blob_id.is_empty()
+        || !blob_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err(EnclaveError::GenericError(format!(
+            "Invalid Walrus blob id {blob_id}"
+        )));
+    }
+
+    let url = format!("{WALRUS_AGGREGATOR_URL}/v1/blobs/{blob_id}");
+    let response = reqwest::get(&url)
+        .await
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to fetch Walrus blob: {e}")))?;
+    if !response.status().is_success() {
+        return Err(EnclaveError::GenericError(format!(
+            "Failed to fetch Walrus blob {blob_id}: {}",
+            response.status()
+        )));
+    }
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to read Walrus blob: {e}")))?;
+    let encrypted: EncryptedObject = bcs::from_bytes(&bytes).map_err(|e| {
+        EnclaveError::GenericError(format!("Walrus blob is not a Seal encrypted object: {e}"))
+    })?;
+    decrypt_bundle(&encrypted).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::pseudonym::PSEUDONYM_SYSTEM;
+    use serde_json::json;
+
+    #[test]
+    fn test_validation_serde() {
+        // test results should be consistent with the tests in
+        // `move/medical-vault/sources/validator.move`.
+        let timestamp = 1744038900000;
+        let intent_msg = IntentMessage::new(
+            BundleValidation {
+                walrus_blob_id: "blob123".to_string(),
+                semantic_hash: vec![0xab, 0xcd],
+                patient_id: "patient456".to_string(),
+                resource_count: 5,
+                resource_types: vec!["Patient".to_string(), "Observation".to_string()],
+                validated: true,
+                validated_at: timestamp,
+            },
+            timestamp,
+            IntentScope::ValidateBundle as u8,
+        );
+        let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
+        assert!(
+            signing_payload
+                == Hex::decode("6420b1d1109601000007626c6f6231323302abcd0a70617469656e743435360500000000000000020750617469656e740b4f62736572766174696f6e0120b1d11096010000")
+                    .unwrap()
+        );
+
+        let intent_msg = IntentMessage::new(
+            BundleVerification {
+                walrus_blob_id: "blob123".to_string(),
+                semantic_hash: vec![0xab, 0xcd],
+                verified: true,
+                verified_at: timestamp,
+            },
+            timestamp,
+            IntentScope::VerifyBundle as u8,
+        );
+        let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
+        assert!(
+            signing_payload
+                == Hex::decode("6520b1d1109601000007626c6f6231323302abcd0120b1d11096010000")
+                    .unwrap()
+        );
+
+        let intent_msg = IntentMessage::new(
+            ClaimValidation {
+                claim_id: "claim789".to_string(),
+                policy_id: "policy42".to_string(),
+                semantic_hash: vec![0xab, 0xcd],
+                validated: false,
+                unsupported_codes: vec!["http://snomed.info/sct|44054006".to_string()],
+                validated_at: timestamp,
+            },
+            timestamp,
+            IntentScope::ValidateClaim as u8,
+        );
+        let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
+        assert!(
+            signing_payload
+                == Hex::decode("6620b1d1109601000008636c61696d37383908706f6c696379343202abcd00011f687474703a2f2f736e6f6d65642e696e666f2f7363747c343430353430303620b1d11096010000")
+                    .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_claim_codes() {
+        let bundle = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "entry": [
+                    { "resource": { "resourceType": "Patient", "id": "p1" } },
+                    { "resource": {
+                        "resourceType": "Condition",
+                        "code": { "coding": [{ "system": "http://hl7.org/fhir/sid/icd-10-cm", "code": "E11.9" }] }
+                    } }
+                ]
+            }
+        });
+
+        let codes = vec![
+            ClaimCode {
+                system: CodeSystem::Icd10Cm,
+                code: "E11.9".to_string(),
+            },
+            ClaimCode {
+                system: CodeSystem::Snomed,
+                code: "44054006".to_string(),
+            },
+        ];
+        assert_eq!(
+            unsupported_claim_codes(&bundle, &codes),
+            vec!["http://snomed.info/sct|44054006".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_bundle_patient() {
+        let key = [7u8; 32];
+        let pseudonym = Pseudonymizer::new(&key, None)
+            .unwrap()
+            .pseudonym(PATIENT_ID_SYSTEM, "P-1");
+        let bundle = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "entry": [
+                    { "resource": {
+                        "resourceType": "Patient",
+                        "id": "p1",
+                        "identifier": [
+                            { "system": "http://hospital.example/mrn", "value": "MRN-42" },
+                            { "system": PSEUDONYM_SYSTEM, "value": pseudonym }
+                        ]
+                    } },
+                    { "resource": { "resourceType": "Observation", "id": "o1" } }
+                ]
+            }
+        });
+
+        assert!(bundle_has_patient(&bundle, "p1", None));
+        assert!(bundle_has_patient(&bundle, "Patient/p1", None));
+        assert!(bundle_has_patient(&bundle, " MRN-42 ", None));
+        assert!(bundle_has_patient(&bundle, &pseudonym, None));
+        assert!(bundle_has_patient(&bundle, "P-1", Some(&key)));
+
+        assert!(!bundle_has_patient(&bundle, "P-1", None));
+        assert!(!bundle_has_patient(&bundle, "P-1", Some(&[8u8; 32])));
+        assert!(!bundle_has_patient(&bundle, "p2", None));
+        assert!(!bundle_has_patient(&bundle, "", None));
+        // Only Patient resources identify the patient.
+        assert!(!bundle_has_patient(&bundle, "o1", None));
+        assert!(!bundle_has_patient(&bundle, "Patient/o1", None));
+    }
+
+    fn claim_code(system: CodeSystem, code: &str) -> ClaimCode {
+        ClaimCode {
+            system,
+            code: code.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_claim_codes_medications() {
+        // Entries of a bare Bundle, without the `bundle` wrapper of a conversion response.
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [
+                { "resource": {
+                    "resourceType": "MedicationRequest",
+                    "medicationCodeableConcept": { "coding": [
+                        { "system": "http://www.nlm.nih.gov/research/umls/rxnorm", "code": "860975" }
+                    ] }
+                } },
+                { "resource": {
+                    "resourceType": "MedicationRequest",
+                    "medication": { "concept": { "coding": [
+                        { "system": "http://www.nlm.nih.gov/research/umls/rxnorm", "code": "197361" }
+                    ] } }
+                } },
+                { "resource": {
+                    "resourceType": "Condition",
+                    "code": { "coding": [{ "system": "http://snomed.info/sct", "code": "E11.9" }] }
+                } }
+            ]
+        });
+
+        let codes = vec![
+            claim_code(CodeSystem::RxNorm, "860975"),
+            claim_code(CodeSystem::RxNorm, "197361"),
+            // The code exists, but under another system.
+            claim_code(CodeSystem::Icd10Cm, "E11.9"),
+            claim_code(CodeSystem::Loinc, "4548-4"),
+            claim_code(CodeSystem::Loinc, "4548-4"),
+        ];
+        assert_eq!(
+            unsupported_claim_codes(&bundle, &codes),
+            vec![
+                "http://hl7.org/fhir/sid/icd-10-cm|E11.9".to_string(),
+                "http://loinc.org|4548-4".to_string(),
+                "http://loinc.org|4548-4".to_string(),
+            ]
+        );
+
+        // Nothing supports a claim against a bundle without entries.
+        assert_eq!(
+            unsupported_claim_codes(&json!({ "bundle": {} }), &codes[..1]),
+            vec!["http://www.nlm.nih.gov/research/umls/rxnorm|860975".to_string()]
+        );
+        assert!(unsupported_claim_codes(&bundle, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_bundle_patient_edges() {
+        let bundle = json!({
+            "bundle": {
+                "resourceType": "Bundle",
+                "entry": [
+                    { "resource": { "resourceType": "Patient", "id": "p1" } },
+                    { "resource": {
+                        "resourceType": "Patient",
+                        "identifier": [
+                            { "system": "http://hospital.example/mrn" },
+                            { "system": "http://hospital.example/mrn", "value": 42 },
+                            { "system": "http://hospital.example/mrn", "value": "MRN-7" }
+                        ]
+                    } },
+                    { "resource": { "id": "p3" } }
+                ]
+            }
+        });
+
+        // Any Patient of the bundle matches.
+        assert!(bundle_has_patient(&bundle, " Patient/p1 ", None));
+        assert!(bundle_has_patient(&bundle, "MRN-7", None));
+        // Identifiers without a string value and resources without a type are ignored.
+        assert!(!bundle_has_patient(&bundle, "42", None));
+        assert!(!bundle_has_patient(&bundle, "p3", None));
+        assert!(!bundle_has_patient(&bundle, "   ", None));
+        // The prefix is only stripped from the requested id.
+        assert!(!bundle_has_patient(&bundle, "Patient/MRN-7", None));
+        // A key too short for a pseudonymizer only disables the pseudonym match.
+        assert!(bundle_has_patient(&bundle, "MRN-7", Some(&[7u8; 4])));
+    }
+}
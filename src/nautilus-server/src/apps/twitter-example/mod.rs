@@ -1,12 +1,16 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::common::IntentMessage;
-use crate::common::{to_signed_response, ProcessDataRequest, ProcessedDataResponse};
+use crate::common::{to_signed_response, ProcessDataRequest};
+use crate::jose::OutputFormatQuery;
+use crate::vc::{issue_credential, wants_vc, VerifiableCredential, VC_CONTENT_TYPE};
 use crate::AppState;
 use crate::EnclaveError;
-use axum::extract::State;
+use axum::extract::{Query, State};
+use axum::http::{header::CONTENT_TYPE, HeaderMap};
+use axum::response::{IntoResponse, Response};
 use axum::Json;
+use fastcrypto::ed25519::Ed25519KeyPair;
 use fastcrypto::encoding::{Encoding, Hex};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -26,6 +30,9 @@ pub enum IntentScope {
     ProcessData = 0,
 }
 
+/// CAIP-2 reference of the Sui network the linked addresses belong to.
+pub const SUI_CHAIN: &str = "testnet";
+
 /// Inner type for IntentMessage<T>
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserData {
@@ -39,10 +46,14 @@ pub struct UserRequest {
     pub user_url: String,
 }
 
+/// Returns the signed intent message, or a W3C verifiable credential linking the twitter handle to
+/// the Sui address when requested with `?format=vc` or `Accept: application/vc`.
 pub async fn process_data(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(format): Query<OutputFormatQuery>,
     Json(request): Json<ProcessDataRequest<UserRequest>>,
-) -> Result<Json<ProcessedDataResponse<IntentMessage<UserData>>>, EnclaveError> {
+) -> Result<Response, EnclaveError> {
     let user_url = request.payload.user_url.clone();
     info!("Processing data for user URL: {}", user_url);
 
@@ -52,15 +63,45 @@ pub async fn process_data(
         .as_millis() as u64;
     // Fetch tweet content
     let (twitter_name, sui_address) = fetch_tweet_content(&state.api_key, &user_url).await?;
+    let user_data = UserData {
+        twitter_name: twitter_name.as_bytes().to_vec(),
+        sui_address: sui_address.clone(),
+    };
+
+    if wants_vc(&headers, &format) {
+        let credential = to_account_link_credential(&state.eph_kp, &user_data, current_timestamp)?;
+        return Ok(([(CONTENT_TYPE, VC_CONTENT_TYPE)], Json(credential)).into_response());
+    }
+
     Ok(Json(to_signed_response(
         &state.eph_kp,
-        UserData {
-            twitter_name: twitter_name.as_bytes().to_vec(),
-            sui_address: sui_address.clone(),
-        },
+        user_data,
         current_timestamp,
         IntentScope::ProcessData as u8,
-    )))
+    ))
+    .into_response())
+}
+
+/// Wrap the verified twitter handle and Sui address as a verifiable credential. The subject is the
+/// Sui address, identified by its CAIP-10 account id as `did:pkh:sui:<chain>:<address>`.
+pub fn to_account_link_credential(
+    kp: &Ed25519KeyPair,
+    user_data: &UserData,
+    timestamp_ms: u64,
+) -> Result<VerifiableCredential, EnclaveError> {
+    let twitter_name = String::from_utf8(user_data.twitter_name.clone())
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid twitter name: {e}")))?;
+    let sui_address = format!("0x{}", Hex::encode(&user_data.sui_address));
+    issue_credential(
+        kp,
+        &["TwitterAccountLinkCredential"],
+        serde_json::json!({
+            "id": format!("did:pkh:sui:{SUI_CHAIN}:{sui_address}"),
+            "suiAddress": sui_address,
+            "twitterHandle": twitter_name,
+        }),
+        timestamp_ms,
+    )
 }
 
 async fn fetch_tweet_content(
@@ -213,4 +254,41 @@ mod test {
         let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
         assert!(signing_payload == Hex::decode("003f41dd0d960100000c6d797374656e696e7465726e20101ce8865558e08408b83f60ee9e78843d03d547c850cbe12cb599e17833dd3e").unwrap());
     }
+
+    #[test]
+    fn test_account_link_credential() {
+        use crate::vc::verify_credential;
+        use fastcrypto::traits::KeyPair;
+
+        let kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        let user_data = UserData {
+            twitter_name: "mystenintern".as_bytes().to_vec(),
+            sui_address: Hex::decode(
+                "0x101ce8865558e08408b83f60ee9e78843d03d547c850cbe12cb599e17833dd3e",
+            )
+            .unwrap(),
+        };
+        let credential = to_account_link_credential(&kp, &user_data, 1743989326143).unwrap();
+        assert_eq!(
+            credential.credential_type,
+            ["VerifiableCredential", "TwitterAccountLinkCredential"]
+        );
+        assert_eq!(
+            credential.credential_subject,
+            serde_json::json!({
+                "id": "did:pkh:sui:testnet:0x101ce8865558e08408b83f60ee9e78843d03d547c850cbe12cb599e17833dd3e",
+                "suiAddress": "0x101ce8865558e08408b83f60ee9e78843d03d547c850cbe12cb599e17833dd3e",
+                "twitterHandle": "mystenintern",
+            })
+        );
+        assert!(verify_credential(&credential, kp.public()).is_ok());
+
+        let other = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        assert!(verify_credential(&credential, other.public()).is_err());
+        let invalid = UserData {
+            twitter_name: vec![0xff],
+            ..user_data
+        };
+        assert!(to_account_link_credential(&kp, &invalid, 1743989326143).is_err());
+    }
 }
@@ -1,10 +1,11 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::merkle::{MerkleProof, MerkleTree};
 use crate::AppState;
 use crate::EnclaveError;
 use axum::{extract::State, Json};
-use fastcrypto::traits::Signer;
+use fastcrypto::traits::{Signer, VerifyingKey};
 use fastcrypto::{encoding::Encoding, traits::ToFromBytes};
 use fastcrypto::{encoding::Hex, traits::KeyPair as FcKeyPair};
 use nsm_api::api::{Request as NsmRequest, Response as NsmResponse};
@@ -18,7 +19,7 @@ use std::sync::Arc;
 use std::time::Duration;
 use tracing::info;
 
-use fastcrypto::ed25519::Ed25519KeyPair;
+use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PublicKey, Ed25519Signature};
 /// ==== COMMON TYPES ====
 /// Intent message wrapper struct containing the intent scope and timestamp.
 /// This standardizes the serialized payload for signing.
@@ -70,6 +71,111 @@ pub fn to_signed_response<T: Serialize + Clone>(
     }
 }
 
+/// Check that a signed response carries the given intent scope and was signed by `pk` over the bcs
+/// bytes of its intent message.
+pub fn verify_signed_response<T: Serialize>(
+    pk: &Ed25519PublicKey,
+    signed: &ProcessedDataResponse<IntentMessage<T>>,
+    intent: u8,
+) -> Result<(), EnclaveError> {
+    if signed.response.intent != intent {
+        return Err(EnclaveError::GenericError(format!(
+            "Expected intent {intent}, got {}",
+            signed.response.intent
+        )));
+    }
+    let sig = Hex::decode(&signed.signature)
+        .ok()
+        .and_then(|bytes| Ed25519Signature::from_bytes(&bytes).ok())
+        .ok_or_else(|| EnclaveError::GenericError("Invalid signature".to_string()))?;
+    let signing_payload = bcs::to_bytes(&signed.response)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to serialize response: {e}")))?;
+    pk.verify(&signing_payload, &sig)
+        .map_err(|_| EnclaveError::GenericError("Signature verification failed".to_string()))
+}
+
+/// ==== BATCH SIGNING ====
+/// Maximum number of requests accepted in a single batch.
+pub const MAX_BATCH_SIZE: usize = 64;
+
+/// Wrapper struct containing many request payloads processed as one batch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchProcessDataRequest<T> {
+    pub requests: Vec<ProcessDataRequest<T>>,
+}
+
+/// Payload signed for a batch: the Merkle root over the BCS bytes of every item's intent message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchRoot {
+    pub root: Vec<u8>,
+    pub leaf_count: u64,
+}
+
+/// A single batch item, its intent message is a leaf of the batch Merkle tree.
+#[derive(Serialize, Deserialize)]
+pub struct BatchItem<T> {
+    pub response: T,
+    pub proof: MerkleProof,
+}
+
+/// Wrapper struct containing the signed batch root and every item with its inclusion proof.
+#[derive(Serialize, Deserialize)]
+pub struct BatchProcessedDataResponse<T> {
+    pub root: IntentMessage<BatchRoot>,
+    pub signature: String,
+    pub items: Vec<BatchItem<T>>,
+}
+
+/// Build a Merkle tree over the bcs bytes of each payload's intent message and sign only the root.
+/// Every item is returned with its inclusion proof, so a single signature check on the root covers
+/// the whole batch. Payloads must be BCS serializable, which rules out floating point values.
+pub fn to_signed_batch_response<T: Serialize>(
+    kp: &Ed25519KeyPair,
+    payloads: Vec<T>,
+    timestamp_ms: u64,
+    intent: u8,
+    root_intent: u8,
+) -> Result<BatchProcessedDataResponse<IntentMessage<T>>, EnclaveError> {
+    let intent_msgs: Vec<IntentMessage<T>> = payloads
+        .into_iter()
+        .map(|payload| IntentMessage::new(payload, timestamp_ms, intent))
+        .collect();
+    let leaves = intent_msgs
+        .iter()
+        .map(bcs::to_bytes)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to serialize batch item: {e}")))?;
+    let tree = MerkleTree::new(leaves)
+        .ok_or_else(|| EnclaveError::GenericError("Batch must not be empty".to_string()))?;
+
+    let root = IntentMessage::new(
+        BatchRoot {
+            root: tree.root().to_vec(),
+            leaf_count: tree.leaf_count() as u64,
+        },
+        timestamp_ms,
+        root_intent,
+    );
+    let signing_payload = bcs::to_bytes(&root)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to serialize batch root: {e}")))?;
+    let sig = kp.sign(&signing_payload);
+
+    let items = intent_msgs
+        .into_iter()
+        .enumerate()
+        .map(|(i, response)| BatchItem {
+            response,
+            proof: tree.proof(i).expect("index within tree"),
+        })
+        .collect();
+
+    Ok(BatchProcessedDataResponse {
+        root,
+        signature: Hex::encode(sig),
+        items,
+    })
+}
+
 /// ==== HEALTHCHECK, GET ATTESTASTION ENDPOINT IMPL ====
 /// Response for get attestation.
 #[derive(Debug, Serialize, Deserialize)]
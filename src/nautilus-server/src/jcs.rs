@@ -0,0 +1,253 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! JSON Canonicalization Scheme (RFC 8785).
+//!
+//! Produces the canonical UTF-8 serialization of a JSON value: no whitespace, object members
+//! sorted by the UTF-16 code units of their names, strings escaped as ECMAScript `JSON.stringify`
+//! does, and numbers serialized with the ECMAScript `Number.prototype.toString` algorithm.
+
+use serde_json::{Number, Value};
+
+/// Canonicalize a JSON value to its RFC 8785 string form.
+pub fn canonicalize(value: &Value) -> Result<String, String> {
+    let mut out = String::new();
+    write_value(value, &mut out)?;
+    Ok(out)
+}
+
+/// Canonicalize a JSON value to its RFC 8785 UTF-8 bytes.
+pub fn canonicalize_to_vec(value: &Value) -> Result<Vec<u8>, String> {
+    canonicalize(value).map(String::into_bytes)
+}
+
+fn write_value(value: &Value, out: &mut String) -> Result<(), String> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => out.push_str(&serialize_number(n)?),
+        Value::String(s) => write_string(s, out),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(key, out);
+                out.push(':');
+                write_value(item, out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Serialize a JSON number as an IEEE 754 double using the ECMAScript algorithm. Integers outside
+/// the safe range are therefore rounded exactly as a JavaScript implementation would.
+fn serialize_number(n: &Number) -> Result<String, String> {
+    let f = n
+        .as_f64()
+        .ok_or_else(|| format!("Number {n} is not representable as a double"))?;
+    serialize_f64(f)
+}
+
+/// ECMAScript `Number.prototype.toString` for a finite double (ECMA-262 Number::toString).
+pub fn serialize_f64(f: f64) -> Result<String, String> {
+    if !f.is_finite() {
+        return Err("NaN and Infinity are not valid JSON numbers".to_string());
+    }
+    if f == 0.0 {
+        // Covers negative zero as well.
+        return Ok("0".to_string());
+    }
+
+    // `{:e}` yields the shortest round-tripping digits, e.g. "1.2345e-7".
+    let sci = format!("{:e}", f.abs());
+    let (mantissa, exp) = sci
+        .split_once('e')
+        .ok_or_else(|| "Unexpected float format".to_string())?;
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let exp: i32 = exp
+        .parse()
+        .map_err(|e| format!("Unexpected float exponent: {e}"))?;
+    let (digits, exp) = prefer_even_digits(f.abs(), digits, exp);
+
+    // The value is 0.d1d2...dk * 10^n.
+    let k = digits.len() as i32;
+    let n = exp + 1;
+
+    let mut out = String::new();
+    if f < 0.0 {
+        out.push('-');
+    }
+    if k <= n && n <= 21 {
+        out.push_str(&digits);
+        out.push_str(&"0".repeat((n - k) as usize));
+    } else if 0 < n && n <= 21 {
+        out.push_str(&digits[..n as usize]);
+        out.push('.');
+        out.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        out.push_str("0.");
+        out.push_str(&"0".repeat((-n) as usize));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        out.push('e');
+        out.push(if n - 1 < 0 { '-' } else { '+' });
+        out.push_str(&(n - 1).abs().to_string());
+    }
+    Ok(out)
+}
+
+/// When two shortest digit strings are equally close to the value, ECMAScript picks the even one
+/// while Rust rounds up, e.g. 1424953923781206.25 must serialize as "1424953923781206.2".
+fn prefer_even_digits(f: f64, digits: String, exp: i32) -> (String, i32) {
+    let Some(last) = digits.bytes().last().map(|b| b - b'0') else {
+        return (digits, exp);
+    };
+    if last % 2 == 0 {
+        return (digits, exp);
+    }
+
+    // The exact decimal expansion of the double, which never needs more than 767 digits.
+    let exact = format!("{f:.800e}");
+    let Some((exact_mantissa, exact_exp)) = exact.split_once('e') else {
+        return (digits, exp);
+    };
+    let exact_digits: String = exact_mantissa.chars().filter(|c| *c != '.').collect();
+    let exact_digits = exact_digits.trim_end_matches('0');
+
+    // Midpoint between the shortest digits and their neighbour one unit below.
+    let below = format!("{}{}", &digits[..digits.len() - 1], last - 1);
+    if exact_exp.parse() == Ok(exp) && exact_digits == format!("{below}5") {
+        let candidate = format!("{below}e{}", exp - (below.len() as i32 - 1));
+        if candidate.parse::<f64>() == Ok(f) {
+            return (below.trim_end_matches('0').to_string(), exp);
+        }
+    }
+    (digits, exp)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_canonical_layout() {
+        let value = json!({ "b": [1, true, null], "a": { "d": "x", "c": 2.5 } });
+        assert_eq!(
+            canonicalize(&value).unwrap(),
+            r#"{"a":{"c":2.5,"d":"x"},"b":[1,true,null]}"#
+        );
+    }
+
+    #[test]
+    fn test_number_serialization() {
+        assert_eq!(serialize_f64(1e21).unwrap(), "1e+21");
+        assert_eq!(serialize_f64(1e20).unwrap(), "100000000000000000000");
+        assert_eq!(serialize_f64(1e-7).unwrap(), "1e-7");
+        assert_eq!(serialize_f64(0.000001).unwrap(), "0.000001");
+        assert_eq!(serialize_f64(-0.0).unwrap(), "0");
+        assert_eq!(serialize_f64(1744038900000.0).unwrap(), "1744038900000");
+        assert!(serialize_f64(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_rfc8785_vectors() {
+        // RFC 8785 section 3.2.2.
+        let input = r#"{
+            "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+            "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+            "literals": [null, true, false]
+        }"#;
+        let value: Value = serde_json::from_str(input).unwrap();
+        assert_eq!(
+            canonicalize(&value).unwrap(),
+            r#"{"literals":[null,true,false],"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"string":"€$\u000f\nA'B\"\\\\\"/"}"#
+        );
+
+        // RFC 8785 section 3.2.3, members sorted by UTF-16 code units.
+        let input = r#"{
+            "\u20ac": "Euro Sign",
+            "\r": "Carriage Return",
+            "\ufb33": "Hebrew Letter Dalet With Dagesh",
+            "1": "One",
+            "\ud83d\ude00": "Emoji: Grinning Face",
+            "\u0080": "Control",
+            "\u00f6": "Latin Small Letter O With Diaeresis"
+        }"#;
+        let value: Value = serde_json::from_str(input).unwrap();
+        assert_eq!(
+            canonicalize(&value).unwrap(),
+            "{\"\\r\":\"Carriage Return\",\"1\":\"One\",\"\u{80}\":\"Control\",\"\u{f6}\":\"Latin Small Letter O With Diaeresis\",\"\u{20ac}\":\"Euro Sign\",\"\u{1f600}\":\"Emoji: Grinning Face\",\"\u{fb33}\":\"Hebrew Letter Dalet With Dagesh\"}"
+        );
+
+        // RFC 8785 appendix B, IEEE 754 bit patterns and their serialization.
+        let numbers: &[(u64, &str)] = &[
+            (0x0000000000000000, "0"),
+            (0x8000000000000000, "0"),
+            (0x0000000000000001, "5e-324"),
+            (0x8000000000000001, "-5e-324"),
+            (0x7fefffffffffffff, "1.7976931348623157e+308"),
+            (0xffefffffffffffff, "-1.7976931348623157e+308"),
+            (0x4340000000000000, "9007199254740992"),
+            (0xc340000000000000, "-9007199254740992"),
+            (0x4430000000000000, "295147905179352830000"),
+            (0x44b52d02c7e14af5, "9.999999999999997e+22"),
+            (0x44b52d02c7e14af6, "1e+23"),
+            (0x44b52d02c7e14af7, "1.0000000000000001e+23"),
+            (0x444b1ae4d6e2ef4e, "999999999999999700000"),
+            (0x444b1ae4d6e2ef4f, "999999999999999900000"),
+            (0x444b1ae4d6e2ef50, "1e+21"),
+            (0x3eb0c6f7a0b5ed8c, "9.999999999999997e-7"),
+            (0x3eb0c6f7a0b5ed8d, "0.000001"),
+            (0x41b3de4355555553, "333333333.3333332"),
+            (0x41b3de4355555554, "333333333.33333325"),
+            (0x41b3de4355555555, "333333333.3333333"),
+            (0x41b3de4355555556, "333333333.3333334"),
+            (0x41b3de4355555557, "333333333.33333343"),
+            (0xbecbf647612f3696, "-0.0000033333333333333333"),
+            (0x43143ff3c1cb0959, "1424953923781206.2"),
+        ];
+        for (bits, expected) in numbers {
+            assert_eq!(serialize_f64(f64::from_bits(*bits)).unwrap(), *expected);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compact JWS (RFC 7515) output signed with the enclave ephemeral key (EdDSA, RFC 8037), so
+//! enclave responses can be verified with standard JOSE libraries instead of replicating the BCS
+//! `IntentMessage` layout.
+
+use crate::common::IntentMessage;
+use crate::EnclaveError;
+use axum::http::{header::ACCEPT, HeaderMap};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PublicKey, Ed25519Signature};
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::traits::{KeyPair, Signer, ToFromBytes, VerifyingKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Media type for a compact serialized JWT/JWS.
+pub const JWS_CONTENT_TYPE: &str = "application/jwt";
+
+/// Query parameters selecting the output format of a signed response.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct OutputFormatQuery {
+    /// Set to "jws" to receive a compact JWS instead of the default JSON response.
+    pub format: Option<String>,
+}
+
+/// Whether the caller asked for a compact JWS, either with `?format=jws` or with an
+/// `Accept: application/jwt` header.
+pub fn wants_jws(headers: &HeaderMap, query: &OutputFormatQuery) -> bool {
+    if let Some(format) = &query.format {
+        return format.eq_ignore_ascii_case("jws") || format.eq_ignore_ascii_case("jwt");
+    }
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.split(';').next().unwrap_or("").trim() == JWS_CONTENT_TYPE)
+}
+
+/// JOSE header of every JWS issued by the enclave.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwsHeader {
+    pub alg: String,
+    pub typ: String,
+    /// Hex encoded enclave public key, same as returned by /health_check and committed to in the
+    /// attestation document.
+    pub kid: String,
+}
+
+/// Key id of the enclave key, the Hex encoded public key bytes.
+pub fn key_id(pk: &Ed25519PublicKey) -> String {
+    Hex::encode(pk.as_bytes())
+}
+
+/// Sign arbitrary claims as a compact JWS with the given header type.
+pub fn sign_compact<C: Serialize>(
+    kp: &Ed25519KeyPair,
+    typ: &str,
+    claims: &C,
+) -> Result<String, EnclaveError> {
+    let header = JwsHeader {
+        alg: "EdDSA".to_string(),
+        typ: typ.to_string(),
+        kid: key_id(kp.public()),
+    };
+    let header_json = serde_json::to_vec(&header)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to encode JWS header: {e}")))?;
+    let claims_json = serde_json::to_vec(claims)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to encode JWS payload: {e}")))?;
+
+    let signing_input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header_json),
+        URL_SAFE_NO_PAD.encode(claims_json)
+    );
+    let sig: Ed25519Signature = kp.sign(signing_input.as_bytes());
+    Ok(format!(
+        "{signing_input}.{}",
+        URL_SAFE_NO_PAD.encode(sig.as_bytes())
+    ))
+}
+
+/// Wrap the payload in an intent message and sign it as a compact JWS. The JWS payload is the JSON
+/// form of the same intent message that `to_signed_response` signs in BCS.
+pub fn to_jws_response<T: Serialize>(
+    kp: &Ed25519KeyPair,
+    payload: T,
+    timestamp_ms: u64,
+    intent: u8,
+) -> Result<String, EnclaveError> {
+    sign_compact(
+        kp,
+        "JWT",
+        &IntentMessage::new(payload, timestamp_ms, intent),
+    )
+}
+
+/// Verify a compact JWS against the enclave public key and return its header and claims.
+pub fn verify_compact<C: DeserializeOwned>(
+    pk: &Ed25519PublicKey,
+    token: &str,
+) -> Result<(JwsHeader, C), EnclaveError> {
+    let mut parts = token.split('.');
+    let (header_b64, claims_b64, sig_b64) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(h), Some(c), Some(s)) if parts.next().is_none() => (h, c, s),
+        _ => {
+            return Err(EnclaveError::GenericError(
+                "Malformed compact JWS".to_string(),
+            ))
+        }
+    };
+
+    let decode = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid base64url in JWS: {e}")))
+    };
+    let header: JwsHeader = serde_json::from_slice(&decode(header_b64)?)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid JWS header: {e}")))?;
+    if header.alg != "EdDSA" {
+        return Err(EnclaveError::GenericError(format!(
+            "Unsupported JWS alg: {}",
+            header.alg
+        )));
+    }
+    if header.kid != key_id(pk) {
+        return Err(EnclaveError::GenericError(
+            "JWS kid does not match enclave public key".to_string(),
+        ));
+    }
+
+    let sig = Ed25519Signature::from_bytes(&decode(sig_b64)?)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid JWS signature: {e}")))?;
+    let signing_input = &token[..header_b64.len() + 1 + claims_b64.len()];
+    pk.verify(signing_input.as_bytes(), &sig)
+        .map_err(|_| EnclaveError::GenericError("JWS signature verification failed".to_string()))?;
+
+    let claims = serde_json::from_slice(&decode(claims_b64)?)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid JWS payload: {e}")))?;
+    Ok((header, claims))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_jws_roundtrip() {
+        let kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        let token =
+            to_jws_response(&kp, json!({ "semantic_hash": "ab" }), 1744038900000, 103).unwrap();
+
+        let (header, claims): (JwsHeader, Value) = verify_compact(kp.public(), &token).unwrap();
+        assert_eq!(header.alg, "EdDSA");
+        assert_eq!(header.kid, key_id(kp.public()));
+        assert_eq!(
+            claims,
+            json!({ "intent": 103, "timestamp_ms": 1744038900000u64, "data": { "semantic_hash": "ab" } })
+        );
+
+        // Tampering with the payload invalidates the signature.
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(br#"{"intent":103,"timestamp_ms":0,"data":{}}"#);
+        let forged_token = format!("{}.{}.{}", parts[0], forged, parts[2]);
+        assert!(verify_compact::<Value>(kp.public(), &forged_token).is_err());
+
+        // A different key is rejected by kid.
+        let other = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        assert!(verify_compact::<Value>(other.public(), &token).is_err());
+    }
+
+    #[test]
+    fn test_wants_jws() {
+        let mut headers = HeaderMap::new();
+        assert!(!wants_jws(&headers, &OutputFormatQuery::default()));
+
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("application/json, application/jwt;q=0.9"),
+        );
+        assert!(wants_jws(&headers, &OutputFormatQuery::default()));
+
+        let query = OutputFormatQuery {
+            format: Some("json".to_string()),
+        };
+        assert!(!wants_jws(&headers, &query));
+    }
+}
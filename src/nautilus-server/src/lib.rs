@@ -13,14 +13,24 @@ pub mod apps {
     #[cfg(feature = "medical-vault-insurer")]
     #[path = "medical-vault-insurer/mod.rs"]
     pub mod medical_vault_insurer;
+    #[cfg(feature = "twitter-example")]
+    #[path = "twitter-example/mod.rs"]
+    pub mod twitter_example;
 }
 
 pub mod app {
     #[cfg(feature = "medical-vault-insurer")]
     pub use crate::apps::medical_vault_insurer::*;
+    #[cfg(feature = "twitter-example")]
+    pub use crate::apps::twitter_example::*;
 }
 
 pub mod common;
+pub mod jcs;
+pub mod jose;
+pub mod merkle;
+pub mod sd_jwt;
+pub mod vc;
 
 /// App state, at minimum needs to maintain the ephemeral keypair.  
 pub struct AppState {
@@ -33,13 +43,8 @@ pub struct AppState {
 /// Implement IntoResponse for EnclaveError.
 impl IntoResponse for EnclaveError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            EnclaveError::GenericError(e) => (StatusCode::BAD_REQUEST, e),
-        };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        let (status, body) = self.into_status_and_body();
+        (status, Json(body)).into_response()
     }
 }
 
@@ -47,12 +52,42 @@ impl IntoResponse for EnclaveError {
 #[derive(Debug)]
 pub enum EnclaveError {
     GenericError(String),
+    /// Input that was understood but could not be processed, with structured `details` on why.
+    UnprocessableError {
+        message: String,
+        details: serde_json::Value,
+    },
+    /// Request without valid caller credentials.
+    UnauthorizedError(String),
+    /// Request refused because a usage budget is used up.
+    PaymentRequiredError(String),
+}
+
+impl EnclaveError {
+    /// HTTP status and JSON body the error is returned with.
+    pub fn into_status_and_body(self) -> (StatusCode, serde_json::Value) {
+        match self {
+            EnclaveError::GenericError(e) => (StatusCode::BAD_REQUEST, json!({ "error": e })),
+            EnclaveError::UnprocessableError { message, details } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({ "error": message, "details": details }),
+            ),
+            EnclaveError::UnauthorizedError(e) => (StatusCode::UNAUTHORIZED, json!({ "error": e })),
+            EnclaveError::PaymentRequiredError(e) => {
+                (StatusCode::PAYMENT_REQUIRED, json!({ "error": e }))
+            }
+        }
+    }
 }
 
 impl fmt::Display for EnclaveError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             EnclaveError::GenericError(e) => write!(f, "{e}"),
+            EnclaveError::UnprocessableError { message, .. } => write!(f, "{message}"),
+            EnclaveError::UnauthorizedError(e) | EnclaveError::PaymentRequiredError(e) => {
+                write!(f, "{e}")
+            }
         }
     }
 }
@@ -5,7 +5,11 @@ use anyhow::Result;
 use axum::{routing::get, routing::post, Router};
 use fastcrypto::{ed25519::Ed25519KeyPair, traits::KeyPair};
 #[cfg(feature = "medical-vault-insurer")]
-use nautilus_server::apps::medical_vault_insurer::{process_data, spawn_host_init_server};
+use nautilus_server::apps::medical_vault_insurer::{
+    attest_predicate, issue_patient_sd_jwt, process_data, process_data_batch, process_data_stream,
+    spawn_host_init_server, validate_bundle, validate_claim, validate_fhir, verify_bundle,
+    verify_provenance,
+};
 #[cfg(not(feature = "medical-vault-insurer"))]
 use nautilus_server::app::process_data;
 use nautilus_server::common::{get_attestation, health_check};
@@ -35,9 +39,21 @@ async fn main() -> Result<()> {
         .route("/", get(ping))
         .route("/get_attestation", get(get_attestation))
         .route("/process_data", post(process_data))
-        .route("/health_check", get(health_check))
-        .with_state(state)
-        .layer(cors);
+        .route("/health_check", get(health_check));
+
+    #[cfg(feature = "medical-vault-insurer")]
+    let app = app
+        .route("/process_data_batch", post(process_data_batch))
+        .route("/process_data_stream", post(process_data_stream))
+        .route("/issue_sd_jwt", post(issue_patient_sd_jwt))
+        .route("/attest_predicate", post(attest_predicate))
+        .route("/validate_bundle", post(validate_bundle))
+        .route("/verify_bundle", post(verify_bundle))
+        .route("/validate_claim", post(validate_claim))
+        .route("/validate_fhir", post(validate_fhir))
+        .route("/verify_provenance", post(verify_provenance));
+
+    let app = app.with_state(state).layer(cors);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
     info!("listening on {}", listener.local_addr().unwrap());
@@ -0,0 +1,215 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binary SHA3-256 Merkle tree used to commit to many enclave outputs with one signature.
+//!
+//! Leaves and internal nodes are domain separated (RFC 6962 style) so that a leaf can never be
+//! reinterpreted as an internal node:
+//!
+//! - `leaf = sha3_256(0x00 || data)`
+//! - `node = sha3_256(0x01 || left || right)`
+//!
+//! When a level has an odd number of nodes, the last node is promoted to the next level unchanged
+//! instead of being duplicated. A proof therefore carries the leaf index and the leaf count, which
+//! is enough for a verifier (e.g. a Move module using `std::hash::sha3_256`) to know on which side
+//! each sibling goes and on which levels no sibling is consumed.
+
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::hash::{HashFunction, Sha3_256};
+use serde::{Deserialize, Serialize};
+
+/// Domain separation prefix for leaf hashes.
+pub const LEAF_PREFIX: u8 = 0x00;
+/// Domain separation prefix for internal node hashes.
+pub const NODE_PREFIX: u8 = 0x01;
+
+pub type Node = [u8; 32];
+
+/// Hash raw leaf data into a leaf node.
+pub fn leaf_hash(data: &[u8]) -> Node {
+    let mut hasher = Sha3_256::default();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().digest
+}
+
+/// Hash two child nodes into their parent node.
+pub fn node_hash(left: &Node, right: &Node) -> Node {
+    let mut hasher = Sha3_256::default();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().digest
+}
+
+/// Inclusion proof for a single leaf. Siblings are Hex encoded and ordered from the leaf level
+/// up to the level right below the root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub leaf_index: u64,
+    pub leaf_count: u64,
+    pub siblings: Vec<String>,
+}
+
+/// A fully materialized Merkle tree, keeping every level so proofs can be produced for any leaf.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    /// levels[0] are the leaf hashes, the last level holds only the root.
+    levels: Vec<Vec<Node>>,
+}
+
+impl MerkleTree {
+    /// Build a tree over the given leaf data. Returns None if there are no leaves.
+    pub fn new<I, D>(leaves: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = D>,
+        D: AsRef<[u8]>,
+    {
+        let leaf_level: Vec<Node> = leaves.into_iter().map(|d| leaf_hash(d.as_ref())).collect();
+        Self::from_leaf_hashes(leaf_level)
+    }
+
+    /// Build a tree over already hashed leaves. Returns None if there are no leaves.
+    pub fn from_leaf_hashes(leaf_level: Vec<Node>) -> Option<Self> {
+        if leaf_level.is_empty() {
+            return None;
+        }
+
+        let mut levels = vec![leaf_level];
+        while levels.last().map(|l| l.len()).unwrap_or(0) > 1 {
+            let current = levels.last().expect("at least one level");
+            let next: Vec<Node> = current
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!("chunks(2) yields one or two nodes"),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Some(Self { levels })
+    }
+
+    pub fn root(&self) -> Node {
+        self.levels.last().expect("tree is never empty")[0]
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// Produce the inclusion proof for the leaf at `index`.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.leaf_count() {
+            return None;
+        }
+
+        let mut siblings = Vec::new();
+        let mut idx = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = idx ^ 1;
+            if sibling < level.len() {
+                siblings.push(Hex::encode(level[sibling]));
+            }
+            idx /= 2;
+        }
+
+        Some(MerkleProof {
+            leaf_index: index as u64,
+            leaf_count: self.leaf_count() as u64,
+            siblings,
+        })
+    }
+}
+
+/// Recompute the root from a leaf hash and its proof.
+pub fn compute_root(leaf: Node, proof: &MerkleProof) -> Result<Node, String> {
+    if proof.leaf_index >= proof.leaf_count {
+        return Err("Leaf index out of range".to_string());
+    }
+
+    let mut siblings = proof.siblings.iter();
+    let mut node = leaf;
+    let mut idx = proof.leaf_index;
+    let mut width = proof.leaf_count;
+    while width > 1 {
+        // The last node of an odd level has no sibling and is promoted as is.
+        if idx % 2 == 1 || idx + 1 < width {
+            let sibling_hex = siblings
+                .next()
+                .ok_or_else(|| "Proof is missing siblings".to_string())?;
+            let sibling: Node = Hex::decode(sibling_hex)
+                .map_err(|e| format!("Invalid sibling encoding: {e}"))?
+                .try_into()
+                .map_err(|_| "Invalid sibling length".to_string())?;
+            node = if idx % 2 == 1 {
+                node_hash(&sibling, &node)
+            } else {
+                node_hash(&node, &sibling)
+            };
+        }
+        idx /= 2;
+        width = width.div_ceil(2);
+    }
+
+    if siblings.next().is_some() {
+        return Err("Proof has unused siblings".to_string());
+    }
+    Ok(node)
+}
+
+/// Verify that `data` is included under `root` according to `proof`.
+pub fn verify_proof(root: &Node, data: &[u8], proof: &MerkleProof) -> bool {
+    compute_root(leaf_hash(data), proof)
+        .map(|computed| &computed == root)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_proofs_roundtrip() {
+        for n in 1..=9usize {
+            let leaves: Vec<Vec<u8>> = (0..n).map(|i| vec![i as u8; 3]).collect();
+            let tree = MerkleTree::new(&leaves).unwrap();
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(i).unwrap();
+                assert!(verify_proof(&tree.root(), leaf, &proof), "n={n} i={i}");
+                assert!(!verify_proof(&tree.root(), b"other", &proof));
+            }
+            assert!(tree.proof(n).is_none());
+        }
+    }
+
+    #[test]
+    fn test_root_layout() {
+        // Three leaves: root = node(node(l0, l1), l2), the odd leaf is promoted.
+        let tree = MerkleTree::new([b"a", b"b", b"c"]).unwrap();
+        let expected = node_hash(
+            &node_hash(&leaf_hash(b"a"), &leaf_hash(b"b")),
+            &leaf_hash(b"c"),
+        );
+        assert_eq!(tree.root(), expected);
+        assert_eq!(tree.proof(2).unwrap().siblings.len(), 1);
+
+        let single = MerkleTree::new([b"a"]).unwrap();
+        assert_eq!(single.root(), leaf_hash(b"a"));
+        assert!(MerkleTree::new(Vec::<Vec<u8>>::new()).is_none());
+    }
+
+    #[test]
+    fn test_tampered_proof() {
+        let tree = MerkleTree::new([b"a", b"b", b"c", b"d"]).unwrap();
+        let mut proof = tree.proof(1).unwrap();
+        proof.leaf_index = 0;
+        assert!(!verify_proof(&tree.root(), b"b", &proof));
+
+        let mut proof = tree.proof(1).unwrap();
+        proof.siblings.push(Hex::encode([0u8; 32]));
+        assert!(!verify_proof(&tree.root(), b"b", &proof));
+    }
+}
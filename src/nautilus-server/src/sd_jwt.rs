@@ -0,0 +1,180 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Selective Disclosure JWT (SD-JWT, RFC 9901) issued by the enclave.
+//!
+//! Every selectively disclosable claim becomes a disclosure `[salt, name, value]`. Only the
+//! base64url SHA-256 digest of each disclosure is placed in the signed payload's `_sd` array. The
+//! issuance is `<jws>~<disclosure>~...~`. The holder keeps all disclosures and presents only the ones
+//! they want to reveal.
+
+use crate::jose::{sign_compact, verify_compact};
+use crate::EnclaveError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PublicKey};
+use fastcrypto::hash::{HashFunction, Sha256};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Header `typ` of an SD-JWT issued by the enclave.
+pub const SD_JWT_TYP: &str = "sd+jwt";
+/// Hash algorithm of the disclosure digests.
+pub const SD_ALG: &str = "sha-256";
+
+/// A single disclosure with its digest as it appears in the `_sd` array.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Disclosure {
+    pub claim: String,
+    pub digest: String,
+    pub disclosure: String,
+}
+
+impl Disclosure {
+    /// Create a salted disclosure for a named claim.
+    pub fn new(claim: &str, value: &Value) -> Result<Self, EnclaveError> {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let array = Value::Array(vec![
+            Value::String(URL_SAFE_NO_PAD.encode(salt)),
+            Value::String(claim.to_string()),
+            value.clone(),
+        ]);
+        let json = serde_json::to_string(&array)
+            .map_err(|e| EnclaveError::GenericError(format!("Failed to encode disclosure: {e}")))?;
+        let disclosure = URL_SAFE_NO_PAD.encode(json);
+        Ok(Self {
+            claim: claim.to_string(),
+            digest: disclosure_digest(&disclosure),
+            disclosure,
+        })
+    }
+}
+
+/// Base64url SHA-256 digest over the ASCII bytes of an encoded disclosure.
+pub fn disclosure_digest(disclosure: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(disclosure.as_bytes()))
+}
+
+/// Issue an SD-JWT. `claims` are always visible, every entry of `disclosable` can be revealed
+/// independently by the holder.
+pub fn issue_sd_jwt(
+    kp: &Ed25519KeyPair,
+    mut claims: Map<String, Value>,
+    disclosable: &[(String, Value)],
+) -> Result<(String, Vec<Disclosure>), EnclaveError> {
+    let disclosures = disclosable
+        .iter()
+        .map(|(name, value)| Disclosure::new(name, value))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Digests are sorted so their order does not reveal the order of the claims.
+    let mut digests: Vec<Value> = disclosures
+        .iter()
+        .map(|d| Value::String(d.digest.clone()))
+        .collect();
+    digests.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+    claims.insert("_sd".to_string(), Value::Array(digests));
+    claims.insert("_sd_alg".to_string(), Value::String(SD_ALG.to_string()));
+
+    let jws = sign_compact(kp, SD_JWT_TYP, &claims)?;
+    let mut sd_jwt = format!("{jws}~");
+    for d in &disclosures {
+        sd_jwt.push_str(&d.disclosure);
+        sd_jwt.push('~');
+    }
+    Ok((sd_jwt, disclosures))
+}
+
+/// Verify an SD-JWT presentation against the enclave public key. Returns the always visible claims
+/// plus every disclosed claim, and rejects disclosures whose digest is not in the signed payload.
+pub fn verify_sd_jwt(
+    pk: &Ed25519PublicKey,
+    sd_jwt: &str,
+) -> Result<Map<String, Value>, EnclaveError> {
+    let mut parts = sd_jwt.split('~');
+    let jws = parts
+        .next()
+        .ok_or_else(|| EnclaveError::GenericError("Empty SD-JWT".to_string()))?;
+    let (_header, mut claims): (_, Map<String, Value>) = verify_compact(pk, jws)?;
+
+    let digests: Vec<String> = match claims.remove("_sd") {
+        Some(Value::Array(items)) => items
+            .into_iter()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    };
+    if claims
+        .remove("_sd_alg")
+        .and_then(|v| v.as_str().map(str::to_string))
+        != Some(SD_ALG.to_string())
+    {
+        return Err(EnclaveError::GenericError(
+            "Unsupported _sd_alg".to_string(),
+        ));
+    }
+
+    for disclosure in parts.filter(|p| !p.is_empty()) {
+        if !digests.contains(&disclosure_digest(disclosure)) {
+            return Err(EnclaveError::GenericError(
+                "Disclosure is not part of the signed SD-JWT".to_string(),
+            ));
+        }
+        let decoded = URL_SAFE_NO_PAD
+            .decode(disclosure)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid disclosure encoding: {e}")))?;
+        let array: Vec<Value> = serde_json::from_slice(&decoded)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid disclosure: {e}")))?;
+        match <[Value; 3]>::try_from(array) {
+            Ok([_salt, Value::String(name), value]) => {
+                if claims.insert(name.clone(), value).is_some() {
+                    return Err(EnclaveError::GenericError(format!(
+                        "Claim {name} disclosed more than once"
+                    )));
+                }
+            }
+            _ => {
+                return Err(EnclaveError::GenericError(
+                    "Disclosure must be [salt, name, value]".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fastcrypto::traits::KeyPair;
+    use serde_json::json;
+
+    #[test]
+    fn test_selective_presentation() {
+        let kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        let mut claims = Map::new();
+        claims.insert("iss".to_string(), json!("did:key:z6Mk"));
+        let disclosable = vec![
+            ("Patient[0].birthDate".to_string(), json!("1980-02-01")),
+            ("Condition[0].code".to_string(), json!({ "text": "Asthma" })),
+        ];
+        let (sd_jwt, disclosures) = issue_sd_jwt(&kp, claims, &disclosable).unwrap();
+        assert_eq!(disclosures.len(), 2);
+        assert_eq!(sd_jwt.matches('~').count(), 3);
+
+        // The holder reveals only the birth date.
+        let jws = sd_jwt.split('~').next().unwrap();
+        let presentation = format!("{jws}~{}~", disclosures[0].disclosure);
+        let revealed = verify_sd_jwt(kp.public(), &presentation).unwrap();
+        assert_eq!(revealed["Patient[0].birthDate"], "1980-02-01");
+        assert!(!revealed.contains_key("Condition[0].code"));
+        assert_eq!(revealed["iss"], "did:key:z6Mk");
+
+        // A disclosure that was not issued is rejected.
+        let forged = Disclosure::new("Condition[0].code", &json!({ "text": "None" })).unwrap();
+        let presentation = format!("{jws}~{}~", forged.disclosure);
+        assert!(verify_sd_jwt(kp.public(), &presentation).is_err());
+    }
+}
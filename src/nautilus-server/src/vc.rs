@@ -0,0 +1,293 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! W3C Verifiable Credentials 2.0 issued by the enclave.
+//!
+//! Credentials are secured with a Data Integrity proof using the `eddsa-jcs-2022` cryptosuite and
+//! signed by the enclave ephemeral key. The issuer is the `did:key` of that key, so a verifier that
+//! trusts the attested public key can check the credential with any VC library supporting the
+//! cryptosuite, or with `verify_credential`.
+
+use crate::jcs::canonicalize_to_vec;
+use crate::EnclaveError;
+use axum::http::{header::ACCEPT, HeaderMap};
+use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PublicKey, Ed25519Signature};
+use fastcrypto::encoding::{Base58, Encoding};
+use fastcrypto::hash::{HashFunction, Sha256};
+use fastcrypto::traits::{KeyPair, Signer, ToFromBytes, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::jose::OutputFormatQuery;
+
+/// Base context of every VC 2.0 credential.
+pub const VC_V2_CONTEXT: &str = "https://www.w3.org/ns/credentials/v2";
+/// Media type of a VC 2.0 credential.
+pub const VC_CONTENT_TYPE: &str = "application/vc";
+pub const DATA_INTEGRITY_PROOF: &str = "DataIntegrityProof";
+pub const EDDSA_JCS_2022: &str = "eddsa-jcs-2022";
+
+/// Multicodec prefix of an Ed25519 public key (0xed, varint encoded).
+const ED25519_MULTICODEC: [u8; 2] = [0xed, 0x01];
+
+/// Whether the caller asked for a verifiable credential, either with `?format=vc` or with an
+/// `Accept: application/vc` header.
+pub fn wants_vc(headers: &HeaderMap, query: &OutputFormatQuery) -> bool {
+    if let Some(format) = &query.format {
+        return format.eq_ignore_ascii_case("vc");
+    }
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.split(';').next().unwrap_or("").trim() == VC_CONTENT_TYPE)
+}
+
+/// The `did:key` identifier of an Ed25519 public key.
+pub fn did_key(pk: &Ed25519PublicKey) -> String {
+    format!("did:key:{}", multibase_public_key(pk))
+}
+
+/// The verification method of the enclave key within its `did:key` document.
+pub fn verification_method(pk: &Ed25519PublicKey) -> String {
+    format!("{}#{}", did_key(pk), multibase_public_key(pk))
+}
+
+/// Resolve the Ed25519 public key of a `did:key` identifier or verification method.
+pub fn public_key_from_did(did: &str) -> Result<Ed25519PublicKey, EnclaveError> {
+    let id = did
+        .strip_prefix("did:key:")
+        .and_then(|rest| rest.split('#').next())
+        .ok_or_else(|| EnclaveError::GenericError(format!("Not a did:key: {did}")))?;
+    let bytes = id
+        .strip_prefix('z')
+        .and_then(|b58| Base58::decode(b58).ok())
+        .ok_or_else(|| EnclaveError::GenericError("Invalid did:key multibase".to_string()))?;
+    match bytes.split_at_checked(ED25519_MULTICODEC.len()) {
+        Some((prefix, key)) if prefix == ED25519_MULTICODEC => Ed25519PublicKey::from_bytes(key)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid did:key public key: {e}"))),
+        _ => Err(EnclaveError::GenericError(
+            "did:key is not an Ed25519 key".to_string(),
+        )),
+    }
+}
+
+fn multibase_public_key(pk: &Ed25519PublicKey) -> String {
+    let mut bytes = ED25519_MULTICODEC.to_vec();
+    bytes.extend_from_slice(pk.as_bytes());
+    format!("z{}", Base58::encode(bytes))
+}
+
+/// Data Integrity proof attached to a credential.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataIntegrityProof {
+    #[serde(rename = "@context", skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<String>>,
+    #[serde(rename = "type")]
+    pub proof_type: String,
+    pub cryptosuite: String,
+    pub created: String,
+    pub verification_method: String,
+    pub proof_purpose: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof_value: Option<String>,
+}
+
+/// A VC 2.0 credential. The proof is absent on the unsecured document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifiableCredential {
+    #[serde(rename = "@context")]
+    pub context: Vec<String>,
+    #[serde(rename = "type")]
+    pub credential_type: Vec<String>,
+    pub issuer: String,
+    pub valid_from: String,
+    pub credential_subject: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof: Option<DataIntegrityProof>,
+}
+
+/// Issue a credential of the given types about `subject`, signed by the enclave key.
+pub fn issue_credential(
+    kp: &Ed25519KeyPair,
+    credential_types: &[&str],
+    subject: Value,
+    timestamp_ms: u64,
+) -> Result<VerifiableCredential, EnclaveError> {
+    let pk = kp.public();
+    let created = format_datetime(timestamp_ms);
+    let mut credential = VerifiableCredential {
+        context: vec![VC_V2_CONTEXT.to_string()],
+        credential_type: std::iter::once("VerifiableCredential")
+            .chain(credential_types.iter().copied())
+            .map(str::to_string)
+            .collect(),
+        issuer: did_key(pk),
+        valid_from: created.clone(),
+        credential_subject: subject,
+        proof: None,
+    };
+
+    let mut proof = DataIntegrityProof {
+        context: Some(credential.context.clone()),
+        proof_type: DATA_INTEGRITY_PROOF.to_string(),
+        cryptosuite: EDDSA_JCS_2022.to_string(),
+        created,
+        verification_method: verification_method(pk),
+        proof_purpose: "assertionMethod".to_string(),
+        proof_value: None,
+    };
+
+    let hash_data = proof_hash_data(&credential, &proof)?;
+    let sig: Ed25519Signature = kp.sign(&hash_data);
+    proof.proof_value = Some(format!("z{}", Base58::encode(sig.as_bytes())));
+    credential.proof = Some(proof);
+    Ok(credential)
+}
+
+/// Verify a credential issued by `issue_credential`. The issuer `did:key` must match the expected
+/// enclave public key, i.e. the key committed to in the attestation document.
+pub fn verify_credential(
+    credential: &VerifiableCredential,
+    expected_pk: &Ed25519PublicKey,
+) -> Result<(), EnclaveError> {
+    let proof = credential
+        .proof
+        .as_ref()
+        .ok_or_else(|| EnclaveError::GenericError("Credential has no proof".to_string()))?;
+    if proof.proof_type != DATA_INTEGRITY_PROOF || proof.cryptosuite != EDDSA_JCS_2022 {
+        return Err(EnclaveError::GenericError(
+            "Unsupported proof type or cryptosuite".to_string(),
+        ));
+    }
+    if proof.proof_purpose != "assertionMethod" {
+        return Err(EnclaveError::GenericError(
+            "Unexpected proof purpose".to_string(),
+        ));
+    }
+    if let Some(context) = &proof.context {
+        if context != &credential.context {
+            return Err(EnclaveError::GenericError(
+                "Proof context does not match credential context".to_string(),
+            ));
+        }
+    }
+
+    let pk = public_key_from_did(&proof.verification_method)?;
+    if public_key_from_did(&credential.issuer)? != pk || &pk != expected_pk {
+        return Err(EnclaveError::GenericError(
+            "Credential was not issued by the enclave key".to_string(),
+        ));
+    }
+
+    let sig_bytes = proof
+        .proof_value
+        .as_deref()
+        .and_then(|v| v.strip_prefix('z'))
+        .and_then(|b58| Base58::decode(b58).ok())
+        .ok_or_else(|| EnclaveError::GenericError("Invalid proofValue".to_string()))?;
+    let sig = Ed25519Signature::from_bytes(&sig_bytes)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid proof signature: {e}")))?;
+
+    let unsecured = VerifiableCredential {
+        proof: None,
+        ..credential.clone()
+    };
+    let proof_config = DataIntegrityProof {
+        proof_value: None,
+        ..proof.clone()
+    };
+    let hash_data = proof_hash_data(&unsecured, &proof_config)?;
+    pk.verify(&hash_data, &sig)
+        .map_err(|_| EnclaveError::GenericError("Credential proof verification failed".to_string()))
+}
+
+/// sha256(JCS(proof config)) || sha256(JCS(unsecured document)), as defined by eddsa-jcs-2022.
+fn proof_hash_data(
+    unsecured: &VerifiableCredential,
+    proof_config: &DataIntegrityProof,
+) -> Result<Vec<u8>, EnclaveError> {
+    let to_canonical = |value: Value| {
+        canonicalize_to_vec(&value)
+            .map_err(|e| EnclaveError::GenericError(format!("Canonicalization failed: {e}")))
+    };
+    let document = serde_json::to_value(unsecured)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to encode credential: {e}")))?;
+    let config = serde_json::to_value(proof_config)
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to encode proof: {e}")))?;
+
+    let mut hash_data = Sha256::digest(to_canonical(config)?).to_vec();
+    hash_data.extend_from_slice(Sha256::digest(to_canonical(document)?).as_ref());
+    Ok(hash_data)
+}
+
+/// Format a unix timestamp in milliseconds as an XML Schema dateTime in UTC, e.g.
+/// "2025-04-07T15:15:00Z".
+pub fn format_datetime(timestamp_ms: u64) -> String {
+    let secs = timestamp_ms / 1000;
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_issue_and_verify_credential() {
+        let kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        let credential = issue_credential(
+            &kp,
+            &["FhirBundleValidationCredential"],
+            json!({ "semanticHash": "ab", "resourceCount": 3 }),
+            1744038900000,
+        )
+        .unwrap();
+
+        assert_eq!(credential.issuer, did_key(kp.public()));
+        assert!(credential.issuer.starts_with("did:key:z6Mk"));
+        assert_eq!(credential.valid_from, "2025-04-07T15:15:00Z");
+        assert!(verify_credential(&credential, kp.public()).is_ok());
+
+        // Altering the subject breaks the proof.
+        let mut tampered = credential.clone();
+        tampered.credential_subject["resourceCount"] = json!(4);
+        assert!(verify_credential(&tampered, kp.public()).is_err());
+
+        // Another key is not the attested issuer.
+        let other = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        assert!(verify_credential(&credential, other.public()).is_err());
+    }
+
+    #[test]
+    fn test_did_key_roundtrip() {
+        let kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
+        let pk = public_key_from_did(&verification_method(kp.public())).unwrap();
+        assert_eq!(&pk, kp.public());
+        assert!(public_key_from_did("did:web:example.com").is_err());
+    }
+}